@@ -0,0 +1,33 @@
+# Prints a greeting on the serial console and exits with status 0.
+#
+#     riscv_emulator --headless programs/hello.s
+
+.equ UART, 0x10000000
+.equ LSR, 5             # line status register
+.equ THRE, 0x20         # transmit holding register empty
+
+.text
+.globl _start
+_start:
+    la      a0, message
+    call    puts
+    li      a0, 0
+    li      a7, 10      # exit
+    ecall
+
+# print the zero terminated string at a0
+puts:
+    li      t0, UART
+1:  lbu     t1, 0(a0)
+    beqz    t1, 3f
+2:  lbu     t2, LSR(t0)
+    andi    t2, t2, THRE
+    beqz    t2, 2b
+    sb      t1, 0(t0)
+    addi    a0, a0, 1
+    j       1b
+3:  ret
+
+.data
+message:
+    .asciz  "Hello from RV32IM!\n"
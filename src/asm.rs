@@ -0,0 +1,1135 @@
+use crate::chips::csr_file::csr_address;
+use crate::chips::U32;
+use crate::disasm::ABI_NAMES;
+use crate::loader::{Image, Segment, Symbol};
+use std::collections::HashMap;
+use std::fmt;
+use std::num::Wrapping;
+
+/**A line of assembly that can't be assembled*/
+#[derive(Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum Section {
+    #[default]
+    Text,
+    Data,
+}
+
+#[derive(Debug)]
+enum Kind<'a> {
+    Instruction(String, Vec<&'a str>),
+    // .word, .half and .byte: the size of each value and the expressions
+    Values(u32, Vec<&'a str>),
+    // strings, .space and alignment padding
+    Bytes(Vec<u8>),
+}
+
+// a statement placed by the first pass, encoded by the second once every label is known
+#[derive(Debug)]
+struct Statement<'a> {
+    line: usize,
+    section: Section,
+    offset: u32,
+    size: u32,
+    kind: Kind<'a>,
+}
+
+/**Assemble RV32IM source text into a loadable image. `.text` goes to the rom at `text_base`,
+`.data` (and `.rodata` and `.bss`) to the ram at `data_base`. Execution starts at the `_start`
+label, or at the start of the code if there is none.
+
+Besides the instructions the source may use labels, numeric local labels (`1:` referenced as
+`1b` or `1f`), the directives .text .data .section .word .half .byte .ascii .asciz .space .align
+and .equ, `%hi()`/`%lo()` and the pseudo-instructions nop li la mv not neg seqz snez j jr ret
+call tail beqz bnez bltz bgez blez bgtz bgt ble bgtu bleu csrr csrw csrs csrc csrwi csrsi csrci.
+Branch and jump targets are resolved to pc relative offsets*/
+pub fn assemble(source: &str, text_base: U32, data_base: U32) -> Result<Image, AsmError> {
+    let mut assembler = Assembler {
+        bases: [text_base.0, data_base.0],
+        ..Assembler::default()
+    };
+    for (i, line) in source.lines().enumerate() {
+        assembler.place(i + 1, line).map_err(|reason| AsmError {
+            line: i + 1,
+            reason,
+        })?;
+    }
+    assembler.encode()
+}
+
+#[derive(Default)]
+struct Assembler<'a> {
+    bases: [u32; 2],
+    offsets: [u32; 2],
+    section: Section,
+    statements: Vec<Statement<'a>>,
+    labels: HashMap<&'a str, (Section, u32)>,
+    constants: HashMap<&'a str, i64>,
+    // numeric labels with the number of statements placed before them
+    locals: Vec<(&'a str, usize, u32)>,
+}
+
+impl<'a> Assembler<'a> {
+    fn address(&self) -> u32 {
+        let section = self.section as usize;
+        self.bases[section].wrapping_add(self.offsets[section])
+    }
+
+    // first pass: define the labels and give the line's statement its place and size
+    fn place(&mut self, number: usize, line: &'a str) -> Result<(), String> {
+        let mut rest = strip_comment(line).trim();
+        while let Some((label, tail)) = split_label(rest) {
+            self.define(label)?;
+            rest = tail.trim_start();
+        }
+        if rest.is_empty() {
+            return Ok(());
+        }
+        let (mnemonic, operands) = match rest.split_once(char::is_whitespace) {
+            Some((mnemonic, operands)) => (mnemonic, split_operands(operands.trim())),
+            None => (rest, vec![]),
+        };
+        let mnemonic = mnemonic.to_ascii_lowercase();
+
+        let kind = match mnemonic.as_str() {
+            ".text" => return self.switch(Section::Text),
+            ".data" | ".rodata" | ".bss" => return self.switch(Section::Data),
+            ".section" => {
+                let name = operands.first().copied().unwrap_or_default();
+                let section = match name.split('.').nth(1) {
+                    Some("text") => Section::Text,
+                    Some("data" | "rodata" | "bss" | "sdata" | "sbss") => Section::Data,
+                    _ => return Err(format!("unknown section '{name}'")),
+                };
+                return self.switch(section);
+            }
+            // symbol visibility and assembler settings don't change the image
+            ".globl" | ".global" | ".local" | ".type" | ".size" | ".option" | ".file"
+            | ".attribute" => return Ok(()),
+            ".equ" | ".set" => {
+                let [name, value] = operands[..] else {
+                    return Err(format!("{mnemonic} takes a name and a value"));
+                };
+                let value = self.evaluate(value, self.statements.len())?;
+                if self.is_defined(name) {
+                    return Err(format!("'{name}' is already defined"));
+                }
+                self.constants.insert(name, value);
+                return Ok(());
+            }
+            ".align" | ".p2align" => {
+                let [power] = operands[..] else {
+                    return Err(format!("{mnemonic} takes a power of two"));
+                };
+                let power = self.evaluate(power, self.statements.len())?;
+                if !(0..=12).contains(&power) {
+                    return Err(format!("can't align to 2^{power} bytes"));
+                }
+                let align = 1u32 << power;
+                let offset = self.offsets[self.section as usize];
+                let padding = offset.next_multiple_of(align) - offset;
+                // code is padded with nops so execution can run through the gap
+                let bytes = match self.section == Section::Text && offset.is_multiple_of(4) {
+                    true => NOP.to_le_bytes().repeat(padding as usize / 4),
+                    false => vec![0; padding as usize],
+                };
+                Kind::Bytes(bytes)
+            }
+            ".space" | ".zero" | ".skip" => {
+                let [size] = operands[..] else {
+                    return Err(format!("{mnemonic} takes a size"));
+                };
+                let size = self.evaluate(size, self.statements.len())?;
+                if !(0..=1 << 24).contains(&size) {
+                    return Err(format!("bad size {size}"));
+                }
+                Kind::Bytes(vec![0; size as usize])
+            }
+            ".ascii" | ".asciz" | ".string" => {
+                let mut bytes = vec![];
+                for operand in operands.iter() {
+                    bytes.extend(string(operand)?);
+                    if mnemonic != ".ascii" {
+                        bytes.push(0);
+                    }
+                }
+                Kind::Bytes(bytes)
+            }
+            ".word" | ".4byte" | ".long" => Kind::Values(4, operands),
+            ".half" | ".2byte" | ".short" => Kind::Values(2, operands),
+            ".byte" => Kind::Values(1, operands),
+            directive if directive.starts_with('.') => {
+                return Err(format!("unknown directive '{directive}'"))
+            }
+            _ => {
+                if self.section != Section::Text {
+                    return Err("instructions belong in .text".to_string());
+                }
+                if !self.offsets[Section::Text as usize].is_multiple_of(4) {
+                    return Err("instruction is not on a word boundary".to_string());
+                }
+                Kind::Instruction(mnemonic, operands)
+            }
+        };
+
+        let size = match &kind {
+            Kind::Instruction(mnemonic, operands) => self.instruction_size(mnemonic, operands),
+            Kind::Values(width, values) => width * values.len() as u32,
+            Kind::Bytes(bytes) => bytes.len() as u32,
+        };
+        let section = self.section;
+        self.statements.push(Statement {
+            line: number,
+            section,
+            offset: self.offsets[section as usize],
+            size,
+            kind,
+        });
+        self.offsets[section as usize] += size;
+        Ok(())
+    }
+
+    fn switch(&mut self, section: Section) -> Result<(), String> {
+        self.section = section;
+        Ok(())
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.labels.contains_key(name) || self.constants.contains_key(name)
+    }
+
+    fn define(&mut self, label: &'a str) -> Result<(), String> {
+        let address = self.address();
+        if label.bytes().all(|b| b.is_ascii_digit()) {
+            self.locals.push((label, self.statements.len(), address));
+            return Ok(());
+        }
+        if self.is_defined(label) {
+            return Err(format!("'{label}' is already defined"));
+        }
+        self.labels.insert(label, (self.section, address));
+        Ok(())
+    }
+
+    // li only needs one instruction when the value is known now and either fits in 12 bits
+    // or has nothing in its low 12 bits for the addi to add
+    fn instruction_size(&self, mnemonic: &str, operands: &[&str]) -> u32 {
+        match mnemonic {
+            "li" => {
+                let value = operands
+                    .get(1)
+                    .and_then(|value| self.evaluate(value, self.statements.len()).ok());
+                // 0xFFFFF800 is -2048 for a 32 bit register
+                match value.map(|value| value as i32 as i64) {
+                    Some(value) if fits_signed(value, 12) || lo(value) == 0 => 4,
+                    _ => 8,
+                }
+            }
+            "la" | "call" | "tail" => 8,
+            _ => 4,
+        }
+    }
+
+    // the value of an expression seen from the statement with index `at`
+    fn evaluate(&self, text: &str, at: usize) -> Result<i64, String> {
+        let lookup = |name: &str| -> Option<i64> {
+            if let Some(number) = name.strip_suffix('b').filter(|n| is_number(n)) {
+                let before = self
+                    .locals
+                    .iter()
+                    .rfind(|(n, i, _)| *n == number && *i <= at);
+                return before.map(|&(_, _, address)| address as i64);
+            }
+            if let Some(number) = name.strip_suffix('f').filter(|n| is_number(n)) {
+                let after = self.locals.iter().find(|(n, i, _)| *n == number && *i > at);
+                return after.map(|&(_, _, address)| address as i64);
+            }
+            match self.labels.get(name) {
+                Some(&(_, address)) => Some(address as i64),
+                None => self.constants.get(name).copied(),
+            }
+        };
+        Expression {
+            text: text.as_bytes(),
+            pos: 0,
+            lookup: &lookup,
+        }
+        .parse()
+    }
+
+    // second pass
+    fn encode(self) -> Result<Image, AsmError> {
+        let mut sections: [Vec<u8>; 2] = [vec![], vec![]];
+        for (index, statement) in self.statements.iter().enumerate() {
+            let bytes = self.bytes(index, statement).map_err(|reason| AsmError {
+                line: statement.line,
+                reason,
+            })?;
+            debug_assert_eq!(bytes.len() as u32, statement.size);
+            sections[statement.section as usize].extend(bytes);
+        }
+
+        let [text, data] = sections;
+        let [text_base, data_base] = self.bases.map(Wrapping);
+        let mut symbols: Vec<Symbol> = self
+            .labels
+            .iter()
+            .map(|(&name, &(section, address))| Symbol {
+                name: name.to_string(),
+                address: Wrapping(address),
+                size: 0,
+                code: section == Section::Text,
+            })
+            .collect();
+        symbols.sort_by_key(|symbol| symbol.address);
+        let mut ram = vec![];
+        if !data.is_empty() {
+            ram.push(Segment {
+                name: ".data".to_string(),
+                address: data_base,
+                data,
+                zeros: 0,
+            });
+        }
+        Ok(Image {
+            entry: self
+                .labels
+                .get("_start")
+                .map_or(text_base, |&(_, address)| Wrapping(address)),
+            rom: vec![Segment {
+                name: ".text".to_string(),
+                address: text_base,
+                data: text,
+                zeros: 0,
+            }],
+            ram,
+            symbols,
+        })
+    }
+
+    fn bytes(&self, index: usize, statement: &Statement) -> Result<Vec<u8>, String> {
+        match &statement.kind {
+            Kind::Bytes(bytes) => Ok(bytes.clone()),
+            Kind::Values(width, values) => {
+                let mut bytes = vec![];
+                for value in values {
+                    let value = self.evaluate(value, index)?;
+                    if !fits_signed(value, 8 * width) && !fits_unsigned(value, 8 * width) {
+                        return Err(format!("{value} doesn't fit in {width} bytes"));
+                    }
+                    bytes.extend(&value.to_le_bytes()[..*width as usize]);
+                }
+                Ok(bytes)
+            }
+            Kind::Instruction(mnemonic, operands) => {
+                let pc = self.bases[0].wrapping_add(statement.offset);
+                let operands = Operands {
+                    assembler: self,
+                    operands,
+                    index,
+                    pc,
+                };
+                let words = operands.encode(mnemonic, statement.size)?;
+                Ok(words.iter().flat_map(|word| word.to_le_bytes()).collect())
+            }
+        }
+    }
+}
+
+const NOP: u32 = 0x0000_0013;
+const RA: u32 = 1;
+const T1: u32 = 6;
+
+fn fits_signed(value: i64, bits: u32) -> bool {
+    let half = 1i64 << (bits - 1);
+    (-half..half).contains(&value)
+}
+
+fn fits_unsigned(value: i64, bits: u32) -> bool {
+    (0..1i64 << bits).contains(&value)
+}
+
+fn is_number(text: &str) -> bool {
+    !text.is_empty() && text.bytes().all(|b| b.is_ascii_digit())
+}
+
+// everything before a `#` that is not inside a string or a character
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_label(text: &str) -> Option<(&str, &str)> {
+    let end = text
+        .find(|c: char| !(c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '$')))
+        .unwrap_or(text.len());
+    match text[end..].strip_prefix(':') {
+        Some(tail) if end > 0 => Some((&text[..end], tail)),
+        _ => None,
+    }
+}
+
+// operands are separated by commas, except the ones in strings
+fn split_operands(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        return vec![];
+    }
+    let mut operands = vec![];
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 0;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, ',') => {
+                operands.push(text[start..i].trim());
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    operands.push(text[start..].trim());
+    operands
+}
+
+fn unescape(c: u8) -> Result<u8, String> {
+    let byte = match c {
+        b'n' => b'\n',
+        b't' => b'\t',
+        b'r' => b'\r',
+        b'0' => 0,
+        b'\\' | b'"' | b'\'' => c,
+        _ => return Err(format!("unknown escape '\\{}'", c as char)),
+    };
+    Ok(byte)
+}
+
+fn string(text: &str) -> Result<Vec<u8>, String> {
+    let inner = text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a string, got '{text}'"))?;
+    let mut bytes = vec![];
+    let mut chars = inner.bytes();
+    while let Some(byte) = chars.next() {
+        match byte {
+            b'\\' => bytes.push(unescape(chars.next().unwrap_or_default())?),
+            _ => bytes.push(byte),
+        }
+    }
+    Ok(bytes)
+}
+
+fn register(name: &str) -> Result<u32, String> {
+    let index = match name {
+        "fp" => Some(8),
+        _ => match name.strip_prefix('x').filter(|n| is_number(n)) {
+            Some(number) => number.parse().ok().filter(|&n| n < 32),
+            None => ABI_NAMES
+                .iter()
+                .position(|&abi| abi == name)
+                .map(|n| n as u32),
+        },
+    };
+    index.ok_or_else(|| format!("unknown register '{name}'"))
+}
+
+/**A `+`/`-` expression of numbers, characters, symbols, local label references and
+%hi()/%lo(), with parentheses and unary minus*/
+struct Expression<'t> {
+    text: &'t [u8],
+    pos: usize,
+    lookup: &'t dyn Fn(&str) -> Option<i64>,
+}
+
+impl Expression<'_> {
+    fn parse(mut self) -> Result<i64, String> {
+        let value = self.sum()?;
+        self.skip_space();
+        match self.pos == self.text.len() {
+            true => Ok(value),
+            false => Err(format!(
+                "unexpected '{}' in expression",
+                String::from_utf8_lossy(&self.text[self.pos..])
+            )),
+        }
+    }
+
+    fn skip_space(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_space();
+        self.text.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        match self.peek() {
+            Some(b) if b == byte => {
+                self.pos += 1;
+                Ok(())
+            }
+            _ => Err(format!("expected '{}' in expression", byte as char)),
+        }
+    }
+
+    fn sum(&mut self) -> Result<i64, String> {
+        let mut value = self.term()?;
+        loop {
+            match self.peek() {
+                Some(b'+') => {
+                    self.pos += 1;
+                    value = value.wrapping_add(self.term()?);
+                }
+                Some(b'-') => {
+                    self.pos += 1;
+                    value = value.wrapping_sub(self.term()?);
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    // a run of name characters
+    fn word(&mut self) -> &str {
+        let start = self.pos;
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|&b| b.is_ascii_alphanumeric() || matches!(b, b'_' | b'.' | b'$'))
+        {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.text[start..self.pos]).unwrap_or_default()
+    }
+
+    fn term(&mut self) -> Result<i64, String> {
+        match self.peek() {
+            Some(b'-') => {
+                self.pos += 1;
+                Ok(self.term()?.wrapping_neg())
+            }
+            Some(b'(') => {
+                self.pos += 1;
+                let value = self.sum()?;
+                self.expect(b')')?;
+                Ok(value)
+            }
+            Some(b'%') => {
+                self.pos += 1;
+                let function = self.word().to_string();
+                self.expect(b'(')?;
+                let value = self.sum()?;
+                self.expect(b')')?;
+                match function.as_str() {
+                    "hi" => Ok(hi(value) as i64),
+                    "lo" => Ok(lo(value)),
+                    _ => Err(format!("unknown function %{function}")),
+                }
+            }
+            Some(b'\'') => {
+                self.pos += 1;
+                let byte = match self.text.get(self.pos).copied() {
+                    Some(b'\\') => {
+                        self.pos += 1;
+                        unescape(self.text.get(self.pos).copied().unwrap_or_default())?
+                    }
+                    Some(byte) => byte,
+                    None => return Err("unterminated character".to_string()),
+                };
+                self.pos += 1;
+                self.expect(b'\'')?;
+                Ok(byte as i64)
+            }
+            Some(_) => {
+                let word = self.word().to_string();
+                if word.is_empty() {
+                    return Err("expected a value".to_string());
+                }
+                if word.starts_with(|c: char| c.is_ascii_digit()) {
+                    if let Some(value) = number(&word) {
+                        return Ok(value);
+                    }
+                }
+                (self.lookup)(&word).ok_or_else(|| format!("unknown symbol '{word}'"))
+            }
+            None => Err("expected a value".to_string()),
+        }
+    }
+}
+
+fn number(text: &str) -> Option<i64> {
+    let text = text.replace('_', "");
+    let (digits, radix) = match text.get(..2) {
+        Some("0x" | "0X") => (&text[2..], 16),
+        Some("0b" | "0B") if text.len() > 2 => (&text[2..], 2),
+        _ => (text.as_str(), 10),
+    };
+    i64::from_str_radix(digits, radix).ok()
+}
+
+// the upper 20 bits for lui/auipc, rounded so the sign extended low 12 bits add back up
+fn hi(value: i64) -> u32 {
+    ((value.wrapping_add(0x800) as u32) >> 12) & 0xF_FFFF
+}
+
+fn lo(value: i64) -> i64 {
+    ((value as u32) << 20) as i32 as i64 >> 20
+}
+
+fn r_type(funct7: u32, funct3: u32, rd: u32, rs1: u32, rs2: u32) -> u32 {
+    funct7 << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | 0b0110011
+}
+
+fn i_type(imm: i64, rs1: u32, funct3: u32, rd: u32, opcode: u32) -> u32 {
+    (imm as u32 & 0xFFF) << 20 | rs1 << 15 | funct3 << 12 | rd << 7 | opcode
+}
+
+fn s_type(imm: i64, rs2: u32, rs1: u32, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    (imm >> 5 & 0x7F) << 25 | rs2 << 20 | rs1 << 15 | funct3 << 12 | (imm & 0x1F) << 7 | 0b0100011
+}
+
+fn b_type(offset: i64, rs1: u32, rs2: u32, funct3: u32) -> u32 {
+    let imm = offset as u32;
+    (imm >> 12 & 1) << 31
+        | (imm >> 5 & 0x3F) << 25
+        | rs2 << 20
+        | rs1 << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xF) << 8
+        | (imm >> 11 & 1) << 7
+        | 0b1100011
+}
+
+fn u_type(imm: u32, rd: u32, opcode: u32) -> u32 {
+    (imm & 0xF_FFFF) << 12 | rd << 7 | opcode
+}
+
+fn j_type(offset: i64, rd: u32) -> u32 {
+    let imm = offset as u32;
+    (imm >> 20 & 1) << 31
+        | (imm >> 1 & 0x3FF) << 21
+        | (imm >> 11 & 1) << 20
+        | (imm >> 12 & 0xFF) << 12
+        | rd << 7
+        | 0b1101111
+}
+
+const OP_IMM: u32 = 0b0010011;
+const LOAD: u32 = 0b0000011;
+const JALR: u32 = 0b1100111;
+const LUI: u32 = 0b0110111;
+const AUIPC: u32 = 0b0010111;
+const SYSTEM: u32 = 0b1110011;
+
+// the operands of one instruction and what it takes to turn them into numbers
+struct Operands<'s> {
+    assembler: &'s Assembler<'s>,
+    operands: &'s [&'s str],
+    index: usize,
+    pc: u32,
+}
+
+impl Operands<'_> {
+    fn count(&self, count: usize) -> Result<(), String> {
+        match self.operands.len() == count {
+            true => Ok(()),
+            false => Err(format!(
+                "expected {count} operands, got {}",
+                self.operands.len()
+            )),
+        }
+    }
+
+    fn reg(&self, i: usize) -> Result<u32, String> {
+        register(self.operands[i])
+    }
+
+    fn value(&self, text: &str) -> Result<i64, String> {
+        self.assembler.evaluate(text, self.index)
+    }
+
+    fn imm(&self, i: usize, bits: u32) -> Result<i64, String> {
+        let value = self.value(self.operands[i])?;
+        match fits_signed(value, bits) {
+            true => Ok(value),
+            false => Err(format!("immediate {value} doesn't fit in {bits} bits")),
+        }
+    }
+
+    fn unsigned(&self, i: usize, bits: u32) -> Result<u32, String> {
+        let value = self.value(self.operands[i])?;
+        match fits_unsigned(value, bits) {
+            true => Ok(value as u32),
+            false => Err(format!("{value} is out of range 0..{}", 1u64 << bits)),
+        }
+    }
+
+    // `offset(reg)`, the offset may be left out
+    fn memory(&self, i: usize) -> Result<(i64, u32), String> {
+        let operand = self.operands[i];
+        let (offset, base) = operand
+            .strip_suffix(')')
+            .and_then(|operand| operand.rsplit_once('('))
+            .ok_or_else(|| format!("expected offset(register), got '{operand}'"))?;
+        let offset = match offset.trim() {
+            "" => 0,
+            offset => self.value(offset)?,
+        };
+        if !fits_signed(offset, 12) {
+            return Err(format!("offset {offset} doesn't fit in 12 bits"));
+        }
+        Ok((offset, register(base.trim())?))
+    }
+
+    // pc relative offset to a branch or jump target, checked against the range of `bits`
+    fn target(&self, i: usize, bits: u32) -> Result<i64, String> {
+        let target = self.value(self.operands[i])?;
+        let offset = (target as u32).wrapping_sub(self.pc) as i32 as i64;
+        if offset % 2 != 0 {
+            return Err(format!("target {target:#x} is not aligned"));
+        }
+        if !fits_signed(offset, bits) {
+            return Err(format!("target {target:#x} is out of reach"));
+        }
+        Ok(offset)
+    }
+
+    fn csr(&self, i: usize) -> Result<i64, String> {
+        let operand = self.operands[i];
+        match csr_address(operand) {
+            Some(addr) => Ok(addr as i64),
+            None => self
+                .value(operand)
+                .ok()
+                .filter(|&addr| fits_unsigned(addr, 12))
+                .ok_or_else(|| format!("unknown csr '{operand}'")),
+        }
+    }
+
+    // pc relative offset of a symbol for an auipc pair
+    fn far(&self, i: usize) -> Result<i64, String> {
+        let target = self.value(self.operands[i])?;
+        Ok((target as u32).wrapping_sub(self.pc) as i32 as i64)
+    }
+
+    fn encode(&self, mnemonic: &str, size: u32) -> Result<Vec<u32>, String> {
+        let word = match mnemonic {
+            "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "srl" | "sra" | "or" | "and"
+            | "mul" | "mulh" | "mulhsu" | "mulhu" | "div" | "divu" | "rem" | "remu" => {
+                let (funct7, funct3) = match mnemonic {
+                    "add" => (0, 0),
+                    "sub" => (0b0100000, 0),
+                    "sll" => (0, 1),
+                    "slt" => (0, 2),
+                    "sltu" => (0, 3),
+                    "xor" => (0, 4),
+                    "srl" => (0, 5),
+                    "sra" => (0b0100000, 5),
+                    "or" => (0, 6),
+                    "and" => (0, 7),
+                    "mul" => (1, 0),
+                    "mulh" => (1, 1),
+                    "mulhsu" => (1, 2),
+                    "mulhu" => (1, 3),
+                    "div" => (1, 4),
+                    "divu" => (1, 5),
+                    "rem" => (1, 6),
+                    _ => (1, 7),
+                };
+                self.count(3)?;
+                r_type(funct7, funct3, self.reg(0)?, self.reg(1)?, self.reg(2)?)
+            }
+            "addi" | "slti" | "sltiu" | "xori" | "ori" | "andi" => {
+                let funct3 = match mnemonic {
+                    "addi" => 0,
+                    "slti" => 2,
+                    "sltiu" => 3,
+                    "xori" => 4,
+                    "ori" => 6,
+                    _ => 7,
+                };
+                self.count(3)?;
+                i_type(self.imm(2, 12)?, self.reg(1)?, funct3, self.reg(0)?, OP_IMM)
+            }
+            "slli" | "srli" | "srai" => {
+                let (funct7, funct3) = match mnemonic {
+                    "slli" => (0, 1),
+                    "srli" => (0, 5),
+                    _ => (0b0100000, 5),
+                };
+                self.count(3)?;
+                let shamt = self.unsigned(2, 5)? as i64 | funct7 << 5;
+                i_type(shamt, self.reg(1)?, funct3, self.reg(0)?, OP_IMM)
+            }
+            "lb" | "lh" | "lw" | "lbu" | "lhu" => {
+                let funct3 = match mnemonic {
+                    "lb" => 0,
+                    "lh" => 1,
+                    "lw" => 2,
+                    "lbu" => 4,
+                    _ => 5,
+                };
+                self.count(2)?;
+                let (offset, base) = self.memory(1)?;
+                i_type(offset, base, funct3, self.reg(0)?, LOAD)
+            }
+            "sb" | "sh" | "sw" => {
+                let funct3 = match mnemonic {
+                    "sb" => 0,
+                    "sh" => 1,
+                    _ => 2,
+                };
+                self.count(2)?;
+                let (offset, base) = self.memory(1)?;
+                s_type(offset, self.reg(0)?, base, funct3)
+            }
+            "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
+                self.count(3)?;
+                let funct3 = branch_funct3(mnemonic);
+                b_type(self.target(2, 13)?, self.reg(0)?, self.reg(1)?, funct3)
+            }
+            // the swapped branches
+            "bgt" | "ble" | "bgtu" | "bleu" => {
+                self.count(3)?;
+                let funct3 = match mnemonic {
+                    "bgt" => branch_funct3("blt"),
+                    "ble" => branch_funct3("bge"),
+                    "bgtu" => branch_funct3("bltu"),
+                    _ => branch_funct3("bgeu"),
+                };
+                b_type(self.target(2, 13)?, self.reg(1)?, self.reg(0)?, funct3)
+            }
+            "beqz" | "bnez" | "bltz" | "bgez" => {
+                self.count(2)?;
+                let funct3 = branch_funct3(&mnemonic[..3]);
+                b_type(self.target(1, 13)?, self.reg(0)?, 0, funct3)
+            }
+            "blez" | "bgtz" => {
+                self.count(2)?;
+                let funct3 = match mnemonic {
+                    "blez" => branch_funct3("bge"),
+                    _ => branch_funct3("blt"),
+                };
+                b_type(self.target(1, 13)?, 0, self.reg(0)?, funct3)
+            }
+            "jal" if self.operands.len() == 1 => j_type(self.target(0, 21)?, RA),
+            "jal" => {
+                self.count(2)?;
+                j_type(self.target(1, 21)?, self.reg(0)?)
+            }
+            "j" => {
+                self.count(1)?;
+                j_type(self.target(0, 21)?, 0)
+            }
+            "jalr" if self.operands.len() == 1 => i_type(0, self.reg(0)?, 0, RA, JALR),
+            "jalr" if self.operands.len() == 3 => {
+                i_type(self.imm(2, 12)?, self.reg(1)?, 0, self.reg(0)?, JALR)
+            }
+            "jalr" => {
+                self.count(2)?;
+                let (offset, base) = self.memory(1)?;
+                i_type(offset, base, 0, self.reg(0)?, JALR)
+            }
+            "jr" => {
+                self.count(1)?;
+                i_type(0, self.reg(0)?, 0, 0, JALR)
+            }
+            "ret" => {
+                self.count(0)?;
+                i_type(0, RA, 0, 0, JALR)
+            }
+            "lui" | "auipc" => {
+                self.count(2)?;
+                let opcode = match mnemonic {
+                    "lui" => LUI,
+                    _ => AUIPC,
+                };
+                u_type(self.unsigned(1, 20)?, self.reg(0)?, opcode)
+            }
+            "nop" => {
+                self.count(0)?;
+                NOP
+            }
+            "mv" => {
+                self.count(2)?;
+                i_type(0, self.reg(1)?, 0, self.reg(0)?, OP_IMM)
+            }
+            "not" => {
+                self.count(2)?;
+                i_type(-1, self.reg(1)?, 4, self.reg(0)?, OP_IMM)
+            }
+            "neg" => {
+                self.count(2)?;
+                r_type(0b0100000, 0, self.reg(0)?, 0, self.reg(1)?)
+            }
+            "seqz" => {
+                self.count(2)?;
+                i_type(1, self.reg(1)?, 3, self.reg(0)?, OP_IMM)
+            }
+            "snez" => {
+                self.count(2)?;
+                r_type(0, 3, self.reg(0)?, 0, self.reg(1)?)
+            }
+            "li" => {
+                self.count(2)?;
+                let rd = self.reg(0)?;
+                let value = self.value(self.operands[1])?;
+                if !fits_signed(value, 32) && !fits_unsigned(value, 32) {
+                    return Err(format!("{value} doesn't fit in 32 bits"));
+                }
+                let value = value as i32 as i64;
+                return Ok(match size {
+                    4 if fits_signed(value, 12) => vec![i_type(value, 0, 0, rd, OP_IMM)],
+                    4 => vec![u_type(hi(value), rd, LUI)],
+                    _ => vec![
+                        u_type(hi(value), rd, LUI),
+                        i_type(lo(value), rd, 0, rd, OP_IMM),
+                    ],
+                });
+            }
+            "la" => {
+                self.count(2)?;
+                let rd = self.reg(0)?;
+                let offset = self.far(1)?;
+                return Ok(vec![
+                    u_type(hi(offset), rd, AUIPC),
+                    i_type(lo(offset), rd, 0, rd, OP_IMM),
+                ]);
+            }
+            "call" | "tail" => {
+                self.count(1)?;
+                let (link, scratch) = match mnemonic {
+                    "call" => (RA, RA),
+                    _ => (0, T1),
+                };
+                let offset = self.far(0)?;
+                return Ok(vec![
+                    u_type(hi(offset), scratch, AUIPC),
+                    i_type(lo(offset), scratch, 0, link, JALR),
+                ]);
+            }
+            "csrrw" | "csrrs" | "csrrc" => {
+                self.count(3)?;
+                let funct3 = csr_funct3(mnemonic);
+                i_type(self.csr(1)?, self.reg(2)?, funct3, self.reg(0)?, SYSTEM)
+            }
+            "csrrwi" | "csrrsi" | "csrrci" => {
+                self.count(3)?;
+                let funct3 = csr_funct3(mnemonic);
+                i_type(
+                    self.csr(1)?,
+                    self.unsigned(2, 5)?,
+                    funct3,
+                    self.reg(0)?,
+                    SYSTEM,
+                )
+            }
+            "csrr" => {
+                self.count(2)?;
+                i_type(self.csr(1)?, 0, csr_funct3("csrrs"), self.reg(0)?, SYSTEM)
+            }
+            "csrw" | "csrs" | "csrc" => {
+                self.count(2)?;
+                let funct3 = csr_funct3(&format!("csrr{}", &mnemonic[3..]));
+                i_type(self.csr(0)?, self.reg(1)?, funct3, 0, SYSTEM)
+            }
+            "csrwi" | "csrsi" | "csrci" => {
+                self.count(2)?;
+                let funct3 = csr_funct3(&format!("csrr{}", &mnemonic[3..]));
+                i_type(self.csr(0)?, self.unsigned(1, 5)?, funct3, 0, SYSTEM)
+            }
+            "ecall" | "ebreak" | "mret" | "wfi" | "fence" => {
+                self.count(0)?;
+                match mnemonic {
+                    "ecall" => 0x0000_0073,
+                    "ebreak" => 0x0010_0073,
+                    "mret" => 0x3020_0073,
+                    "wfi" => 0x1050_0073,
+                    // fence iorw, iorw
+                    _ => 0x0FF0_000F,
+                }
+            }
+            _ => return Err(format!("unknown instruction '{mnemonic}'")),
+        };
+        Ok(vec![word])
+    }
+}
+
+fn branch_funct3(mnemonic: &str) -> u32 {
+    match mnemonic {
+        "beq" => 0,
+        "bne" => 1,
+        "blt" => 4,
+        "bge" => 5,
+        "bltu" => 6,
+        _ => 7,
+    }
+}
+
+fn csr_funct3(mnemonic: &str) -> u32 {
+    match mnemonic {
+        "csrrw" => 1,
+        "csrrs" => 2,
+        "csrrc" => 3,
+        "csrrwi" => 5,
+        "csrrsi" => 6,
+        _ => 7,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::ZERO;
+
+    fn words(source: &str) -> Vec<u32> {
+        let image = assemble(source, ZERO, Wrapping(0x1000)).unwrap();
+        let text = &image.rom[0].data;
+        text.chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect()
+    }
+
+    fn error(source: &str) -> AsmError {
+        assemble(source, ZERO, ZERO).unwrap_err()
+    }
+
+    #[test]
+    fn instructions() {
+        let source = "
+            add  a0, a1, a2
+            lw   a5, -24(s0)
+            sw   a5, 12(sp)
+            srai a0, a1, 7
+            csrrs a0, mstatus, zero
+            lui  a0, 0x12345
+        ";
+        assert_eq!(
+            words(source),
+            [0x00c58533, 0xfe842783, 0x00f12623, 0x4075d513, 0x30002573, 0x12345537]
+        );
+    }
+
+    #[test]
+    fn branches_and_jumps_are_pc_relative() {
+        let source = "
+            _start: beqz a0, done
+            loop:   addi a0, a0, -1
+                    bnez a0, loop
+                    jal  loop
+            done:   ret
+        ";
+        assert_eq!(
+            words(source),
+            [0x00050863, 0xfff50513, 0xfe051ee3, 0xff9ff0ef, 0x00008067]
+        );
+    }
+
+    #[test]
+    fn li_takes_one_or_two_instructions() {
+        assert_eq!(words("li a0, -5"), [0xffb00513]);
+        assert_eq!(words("li a0, 0x12345678"), [0x12345537, 0x67850513]);
+        // the low part is negative, so the upper part is rounded up
+        assert_eq!(words("li a0, 0xfff"), [0x00001537, 0xfff50513]);
+        assert_eq!(words("li a0, 0x12345000"), [0x12345537]);
+        assert_eq!(words("li a0, -4096"), [0xfffff537]);
+        // a value that is only known later keeps the room for both
+        assert_eq!(
+            words("li a0, later\n.equ later, 0x10000"),
+            [0x00010537, 0x00050513]
+        );
+        // the next instruction lands right after the single lui
+        assert_eq!(words("li a0, 0x2000\n1: j 1b"), [0x00002537, 0x0000006f]);
+    }
+
+    #[test]
+    fn local_labels() {
+        let source = "
+            1: j 1f
+               j 1b
+            1: j 1b
+        ";
+        // a label on the same line comes before the instruction, so the last jump is to itself
+        assert_eq!(words(source), [0x0080006f, 0xffdff06f, 0x0000006f]);
+    }
+
+    #[test]
+    fn data_goes_to_ram() {
+        let source = r#"
+            .equ COUNT, 2
+            .data
+            table: .word COUNT, table + 4
+            .byte 'A', -1
+            .align 2
+            text: .asciz "hi\n"
+            .text
+            _start: la a0, text
+        "#;
+        let image = assemble(source, Wrapping(0x100), Wrapping(0x1000)).unwrap();
+        assert_eq!(image.entry, Wrapping(0x100));
+        assert_eq!(image.ram[0].address, Wrapping(0x1000));
+        assert_eq!(
+            image.ram[0].data,
+            [2, 0, 0, 0, 4, 0x10, 0, 0, b'A', 0xFF, 0, 0, b'h', b'i', b'\n', 0]
+        );
+        // la finds the string 0xF0C bytes past the auipc
+        let text: Vec<u8> = [0x00001517u32, 0xf0c50513]
+            .iter()
+            .flat_map(|word| word.to_le_bytes())
+            .collect();
+        assert_eq!(image.rom[0].data, text);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        assert_eq!(
+            error("nop\naddi a0, a0, 4096"),
+            AsmError {
+                line: 2,
+                reason: "immediate 4096 doesn't fit in 12 bits".to_string()
+            }
+        );
+        assert_eq!(error("j nowhere").reason, "unknown symbol 'nowhere'");
+        assert_eq!(error("x: nop\nx: nop").line, 2);
+        assert_eq!(error("add a0, a1, q2").reason, "unknown register 'q2'");
+        assert_eq!(error(".data\nnop").reason, "instructions belong in .text");
+    }
+
+    #[test]
+    fn example_programs_assemble() {
+        let hello = include_str!("../programs/hello.s");
+        assemble(hello, ZERO, ZERO).unwrap();
+    }
+}
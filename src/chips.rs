@@ -2,18 +2,32 @@ use std::cell::RefCell;
 use std::num::Wrapping;
 use std::rc::Rc;
 
+pub mod bus;
+pub mod clint;
 pub mod cpu;
+pub mod csr_file;
 pub mod decode;
 pub mod dff;
 pub mod execute;
 pub mod fetch;
+pub mod hazard;
+pub mod input;
 pub mod memory;
 pub mod pc;
+pub mod pipeline;
+pub mod plic;
+pub mod probe;
 pub mod ram;
 pub mod register;
 pub mod register_file;
 pub mod rom;
 pub mod screen;
+pub mod single_cycle;
+#[cfg(test)]
+pub mod testing;
+pub mod trap;
+pub mod uart;
+pub mod write_back;
 
 /**
    For sequential circuits the chip trait should be implemented
@@ -0,0 +1,292 @@
+use crate::chips::clint::{CLINT_BASE, CLINT_SIZE};
+use crate::chips::input::{INPUT_BASE, INPUT_SIZE};
+use crate::chips::memory::Width;
+use crate::chips::plic::{PLIC_BASE, PLIC_SIZE};
+use crate::chips::probe::Probe;
+use crate::chips::screen::{SCREEN_BASE, SCREEN_SIZE};
+use crate::chips::uart::{UART_BASE, UART_SIZE};
+use crate::chips::{Wire, U32};
+use std::fmt;
+use std::num::Wrapping;
+
+/**Anything that answers loads and stores on the bus. Offsets are relative to the start of
+the device's window, values travel on the byte lanes of the offset like a memory word*/
+pub trait Device: Probe {
+    /**Load `width` bytes at `offset`, None when there is nothing to read there*/
+    fn read(&mut self, offset: U32, width: Width) -> Option<U32>;
+    /**Store the lanes of `value` selected by `width` and `offset`, None when the store is refused.
+    A refused store has no effect*/
+    fn write(&mut self, offset: U32, value: U32, width: Width) -> Option<()>;
+    /**The word holding `offset` as a debugger sees it. Unlike `read` it never has side effects,
+    so devices whose reads change their state (a receive fifo, an event queue) give None*/
+    fn inspect(&self, _offset: U32) -> Option<U32> {
+        None
+    }
+}
+
+/**Where the memories and devices sit in the address space. The rom is only seen by fetch,
+so it may overlap the data side*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub rom_base: u32,
+    pub ram_base: u32,
+    pub screen_base: u32,
+    pub clint_base: u32,
+    pub plic_base: u32,
+    pub uart_base: u32,
+    pub input_base: u32,
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self {
+            rom_base: 0,
+            ram_base: 0,
+            screen_base: SCREEN_BASE,
+            clint_base: CLINT_BASE,
+            plic_base: PLIC_BASE,
+            uart_base: UART_BASE,
+            input_base: INPUT_BASE,
+        }
+    }
+}
+
+impl MemoryMap {
+    /**Move `region` (rom, ram, screen, clint, plic, uart or input), false if there is no such
+    region*/
+    pub fn set_base(&mut self, region: &str, base: u32) -> bool {
+        let field = match region {
+            "rom" => &mut self.rom_base,
+            "ram" => &mut self.ram_base,
+            "screen" => &mut self.screen_base,
+            "clint" => &mut self.clint_base,
+            "plic" => &mut self.plic_base,
+            "uart" => &mut self.uart_base,
+            "input" => &mut self.input_base,
+            _ => return false,
+        };
+        *field = base;
+        true
+    }
+
+    /**Move the screen out of the way of `ram_size` bytes of ram, to the first boundary of its
+    own size past the ram that is clear of the devices. Gives the new base, None if the screen
+    was already clear or there is no room for it*/
+    pub fn make_room_for_ram(&mut self, ram_size: u32) -> Option<u32> {
+        let size = SCREEN_SIZE as u64;
+        let overlaps = |base: u64, (_, other, other_size): &(&str, u32, u32)| {
+            base < *other as u64 + *other_size as u64 && (*other as u64) < base + size
+        };
+        let windows = self.windows(ram_size);
+        let (ram, devices) = windows.split_first().unwrap();
+        if !overlaps(self.screen_base as u64, ram) {
+            return None;
+        }
+        let start = (ram.1 as u64 + ram.2 as u64).next_multiple_of(size);
+        let base = (start..1 << 32)
+            .step_by(size as usize)
+            .find(|&base| base + size <= 1 << 32 && !devices.iter().any(|w| overlaps(base, w)))?;
+        self.screen_base = base as u32;
+        Some(self.screen_base)
+    }
+
+    // The windows on the data side other than the screen, the ram first
+    fn windows(&self, ram_size: u32) -> [(&'static str, u32, u32); 5] {
+        [
+            ("ram", self.ram_base, ram_size),
+            ("clint", self.clint_base, CLINT_SIZE),
+            ("plic", self.plic_base, PLIC_SIZE),
+            ("uart", self.uart_base, UART_SIZE),
+            ("input", self.input_base, INPUT_SIZE),
+        ]
+    }
+}
+
+/**Two windows of the memory map share addresses*/
+#[derive(Debug, PartialEq, Eq)]
+pub struct MapError {
+    pub name: &'static str,
+    pub base: u32,
+    pub size: u32,
+    pub other: &'static str,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at {:#010x}..{:#010x} overlaps {}",
+            self.name,
+            self.base,
+            self.base as u64 + self.size as u64,
+            self.other
+        )
+    }
+}
+
+impl std::error::Error for MapError {}
+
+struct Window {
+    name: &'static str,
+    base: u32,
+    size: u32,
+    device: Wire<dyn Device>,
+}
+
+impl Window {
+    fn contains(&self, addr: U32) -> bool {
+        (self.base as u64..self.base as u64 + self.size as u64).contains(&(addr.0 as u64))
+    }
+}
+
+/**Routes loads and stores to the device whose window holds the address.
+An access outside every window is an access fault*/
+#[derive(Default)]
+pub struct Bus {
+    windows: Vec<Window>,
+}
+
+impl Bus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /**Put `device` at `base..base + size`, windows can't overlap*/
+    pub fn map(
+        &mut self,
+        name: &'static str,
+        base: u32,
+        size: u32,
+        device: Wire<dyn Device>,
+    ) -> Result<(), MapError> {
+        let end = base as u64 + size as u64;
+        let overlaps =
+            |w: &&Window| (base as u64) < w.base as u64 + w.size as u64 && (w.base as u64) < end;
+        if let Some(other) = self.windows.iter().find(overlaps) {
+            return Err(MapError {
+                name,
+                base,
+                size,
+                other: other.name,
+            });
+        }
+        self.windows.push(Window {
+            name,
+            base,
+            size,
+            device,
+        });
+        Ok(())
+    }
+
+    fn window(&self, addr: U32) -> Option<&Window> {
+        self.windows.iter().find(|window| window.contains(addr))
+    }
+}
+
+impl Device for Bus {
+    fn read(&mut self, addr: U32, width: Width) -> Option<U32> {
+        let window = self.window(addr)?;
+        let offset = addr - Wrapping(window.base);
+        window.device.borrow_mut().read(offset, width)
+    }
+
+    fn write(&mut self, addr: U32, value: U32, width: Width) -> Option<()> {
+        let window = self.window(addr)?;
+        let offset = addr - Wrapping(window.base);
+        window.device.borrow_mut().write(offset, value, width)
+    }
+
+    fn inspect(&self, addr: U32) -> Option<U32> {
+        let window = self.window(addr)?;
+        let offset = addr - Wrapping(window.base);
+        window.device.borrow().inspect(offset)
+    }
+}
+
+// the windows are the children, named like the regions of the memory map
+impl Probe for Bus {
+    fn name(&self) -> &'static str {
+        "bus"
+    }
+
+    fn children(&self, visit: &mut dyn FnMut(&str, &dyn Probe)) {
+        for window in &self.windows {
+            visit(window.name, &*window.device.borrow());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::wire;
+
+    // Answers every load with its offset, so a test can see where an access landed
+    struct Echo;
+
+    impl Probe for Echo {
+        fn name(&self) -> &'static str {
+            "echo"
+        }
+    }
+
+    impl Device for Echo {
+        fn read(&mut self, offset: U32, _width: Width) -> Option<U32> {
+            Some(offset)
+        }
+
+        fn write(&mut self, _offset: U32, _value: U32, _width: Width) -> Option<()> {
+            Some(())
+        }
+    }
+
+    fn echo() -> Wire<dyn Device> {
+        wire(Echo)
+    }
+
+    #[test]
+    fn overlapping_windows_are_rejected() {
+        let mut bus = Bus::new();
+        bus.map("ram", 0, 0x1000, echo()).unwrap();
+        bus.map("uart", 0x1000, 0x100, echo()).unwrap();
+        let err = bus.map("screen", 0x0800, 0x1000, echo()).unwrap_err();
+        assert_eq!((err.name, err.other), ("screen", "ram"));
+        assert_eq!(
+            err.to_string(),
+            "screen at 0x00000800..0x00001800 overlaps ram"
+        );
+        let err = bus.map("plic", 0x10ff, 1, echo()).unwrap_err();
+        assert_eq!(err.other, "uart");
+    }
+
+    #[test]
+    fn unmapped_addresses_fault() {
+        let mut bus = Bus::new();
+        bus.map("uart", 0x1000, 0x100, echo()).unwrap();
+        assert_eq!(
+            bus.read(Wrapping(0x1010), Width::Word),
+            Some(Wrapping(0x10))
+        );
+        assert_eq!(bus.read(Wrapping(0x0fff), Width::Byte), None);
+        assert_eq!(bus.read(Wrapping(0x1100), Width::Word), None);
+        assert_eq!(bus.write(Wrapping(0x1100), Wrapping(1), Width::Word), None);
+        assert_eq!(bus.inspect(Wrapping(0)), None);
+    }
+
+    #[test]
+    fn a_bigger_ram_pushes_the_screen_up() {
+        let mut map = MemoryMap::default();
+        assert_eq!(map.make_room_for_ram(SCREEN_BASE), None);
+        assert_eq!(map.screen_base, SCREEN_BASE);
+        assert_eq!(
+            map.make_room_for_ram(SCREEN_BASE + 1),
+            Some(SCREEN_BASE + SCREEN_SIZE)
+        );
+        assert_eq!(map.make_room_for_ram(0x80_0000), Some(0x80_0000));
+        // a ram that fills the address space leaves no room at all
+        let mut map = MemoryMap::default();
+        assert_eq!(map.make_room_for_ram(u32::MAX), None);
+        assert_eq!(map.screen_base, SCREEN_BASE);
+    }
+}
@@ -0,0 +1,278 @@
+use crate::chips::bus::Device;
+use crate::chips::memory::{lane_mask, strobe, Width};
+use crate::chips::probe::{Port, Probe};
+use crate::chips::register::Register;
+use crate::chips::{wire, Chip, Wire, ONE, U32, ZERO};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
+use std::num::Wrapping;
+
+/**Where the clint sits in the memory map, the SiFive/QEMU virt address*/
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub const CLINT_SIZE: u32 = 0x1_0000;
+
+const MSIP: u32 = 0x0;
+const MTIMECMP_LO: u32 = 0x4000;
+const MTIMECMP_HI: u32 = 0x4004;
+const MTIME_LO: u32 = 0xBFF8;
+const MTIME_HI: u32 = 0xBFFC;
+
+/**Core-local interruptor: machine timer and software interrupt for the single hart.
+mtime counts clock cycles, the timer interrupt is pending while mtime >= mtimecmp*/
+pub struct Clint {
+    pub input: Wire<U32>,
+    pub output: Wire<U32>,
+    // offset from CLINT_BASE
+    pub address: Wire<U32>,
+    pub load: Wire<bool>,
+    pub strobe: Wire<u8>,
+    // interrupt lines to the hart
+    pub software_interrupt: Wire<bool>,
+    pub timer_interrupt: Wire<bool>,
+    msip: Register<U32>,
+    mtimecmp_lo: Register<U32>,
+    mtimecmp_hi: Register<U32>,
+    mtime_lo: Register<U32>,
+    mtime_hi: Register<U32>,
+}
+
+impl Clint {
+    pub fn new() -> Self {
+        let clint = Self {
+            input: wire(ZERO),
+            output: wire(ZERO),
+            address: wire(ZERO),
+            load: wire(false),
+            strobe: wire(0),
+            software_interrupt: wire(false),
+            timer_interrupt: wire(false),
+            msip: Register::default(),
+            mtimecmp_lo: Register::default(),
+            mtimecmp_hi: Register::default(),
+            mtime_lo: Register::default(),
+            mtime_hi: Register::default(),
+        };
+        // mtimecmp resets to the largest value so the timer doesn't fire before it is set
+        *clint.mtimecmp_lo.output.borrow_mut() = Wrapping(u32::MAX);
+        *clint.mtimecmp_hi.output.borrow_mut() = Wrapping(u32::MAX);
+        clint
+    }
+
+    fn register(&mut self, offset: u32) -> Option<&mut Register<U32>> {
+        match offset {
+            MSIP => Some(&mut self.msip),
+            MTIMECMP_LO => Some(&mut self.mtimecmp_lo),
+            MTIMECMP_HI => Some(&mut self.mtimecmp_hi),
+            MTIME_LO => Some(&mut self.mtime_lo),
+            MTIME_HI => Some(&mut self.mtime_hi),
+            _ => None,
+        }
+    }
+
+    // The word at the address on the port, what a load sees
+    fn value(&self) -> U32 {
+        let register = match self.address.borrow().0 & !3 {
+            MSIP => &self.msip,
+            MTIMECMP_LO => &self.mtimecmp_lo,
+            MTIMECMP_HI => &self.mtimecmp_hi,
+            MTIME_LO => &self.mtime_lo,
+            MTIME_HI => &self.mtime_hi,
+            _ => return ZERO,
+        };
+        *register.output.borrow()
+    }
+
+    pub fn mtime(&self) -> u64 {
+        (self.mtime_hi.output.borrow().0 as u64) << 32 | self.mtime_lo.output.borrow().0 as u64
+    }
+
+    pub fn mtimecmp(&self) -> u64 {
+        (self.mtimecmp_hi.output.borrow().0 as u64) << 32
+            | self.mtimecmp_lo.output.borrow().0 as u64
+    }
+}
+
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Chip for Clint {
+    fn compute(&mut self) {
+        // mtime counts up every cycle, carrying into the high word
+        let lo = *self.mtime_lo.output.borrow();
+        let hi = *self.mtime_hi.output.borrow();
+        *self.mtime_lo.input.borrow_mut() = lo + ONE;
+        *self.mtime_hi.input.borrow_mut() = hi + Wrapping((lo.0 == u32::MAX) as u32);
+        *self.mtime_lo.load.borrow_mut() = true;
+        *self.mtime_hi.load.borrow_mut() = true;
+        *self.msip.load.borrow_mut() = false;
+        *self.mtimecmp_lo.load.borrow_mut() = false;
+        *self.mtimecmp_hi.load.borrow_mut() = false;
+
+        // a store from the bus wins over the counting
+        *self.output.borrow_mut() = self.value();
+        let offset = self.address.borrow().0 & !3;
+        let input = *self.input.borrow();
+        let mask = lane_mask(*self.strobe.borrow());
+        let load = *self.load.borrow();
+        if let Some(register) = self.register(offset).filter(|_| load) {
+            // msip only has one implemented bit
+            let value = (*register.output.borrow() & !mask) | (input & mask);
+            *register.input.borrow_mut() = match offset {
+                MSIP => value & ONE,
+                _ => value,
+            };
+            *register.load.borrow_mut() = true;
+        }
+
+        self.msip.compute();
+        self.mtimecmp_lo.compute();
+        self.mtimecmp_hi.compute();
+        self.mtime_lo.compute();
+        self.mtime_hi.compute();
+    }
+
+    fn clk(&mut self) {
+        self.msip.clk();
+        self.mtimecmp_lo.clk();
+        self.mtimecmp_hi.clk();
+        self.mtime_lo.clk();
+        self.mtime_hi.clk();
+        // a store lasts a single cycle
+        *self.load.borrow_mut() = false;
+
+        *self.software_interrupt.borrow_mut() = *self.msip.output.borrow() != ZERO;
+        *self.timer_interrupt.borrow_mut() = self.mtime() >= self.mtimecmp();
+    }
+}
+
+// An access only drives the port, the cpu computes the clint once a cycle after the memory
+// stage. Reads are combinational, a store latches when the cpu clocks the clint
+impl Device for Clint {
+    fn read(&mut self, offset: U32, width: Width) -> Option<U32> {
+        *self.address.borrow_mut() = offset;
+        *self.load.borrow_mut() = false;
+        *self.strobe.borrow_mut() = strobe(offset, width);
+        Some(self.value())
+    }
+
+    fn write(&mut self, offset: U32, value: U32, width: Width) -> Option<()> {
+        *self.address.borrow_mut() = offset;
+        *self.input.borrow_mut() = value;
+        *self.load.borrow_mut() = true;
+        *self.strobe.borrow_mut() = strobe(offset, width);
+        Some(())
+    }
+}
+
+impl Probe for Clint {
+    fn name(&self) -> &'static str {
+        "clint"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("input", &self.input),
+            Port::wire("output", &self.output),
+            Port::wire("address", &self.address),
+            Port::wire("load", &self.load),
+            Port::wire("strobe", &self.strobe),
+            Port::wire("software_interrupt", &self.software_interrupt),
+            Port::wire("timer_interrupt", &self.timer_interrupt),
+        ]
+    }
+}
+
+// the interrupt lines are set at clk and only read by the next cycle, they are state too
+impl Snapshot for Clint {
+    fn save(&self, out: &mut Writer) {
+        for register in [
+            &self.msip,
+            &self.mtimecmp_lo,
+            &self.mtimecmp_hi,
+            &self.mtime_lo,
+            &self.mtime_hi,
+        ] {
+            register.save(out);
+        }
+        out.put(&*self.software_interrupt.borrow());
+        out.put(&*self.timer_interrupt.borrow());
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        for register in [
+            &mut self.msip,
+            &mut self.mtimecmp_lo,
+            &mut self.mtimecmp_hi,
+            &mut self.mtime_lo,
+            &mut self.mtime_hi,
+        ] {
+            register.restore(input)?;
+        }
+        *self.software_interrupt.borrow_mut() = input.get()?;
+        *self.timer_interrupt.borrow_mut() = input.get()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One clock cycle with the port as the last access left it
+    fn tick(clint: &mut Clint) {
+        clint.compute();
+        clint.clk();
+    }
+
+    fn store(clint: &mut Clint, offset: u32, value: u32) {
+        clint.write(Wrapping(offset), Wrapping(value), Width::Word);
+        tick(clint);
+    }
+
+    fn load(clint: &mut Clint, offset: u32) -> u32 {
+        let value = clint.read(Wrapping(offset), Width::Word).unwrap().0;
+        tick(clint);
+        value
+    }
+
+    #[test]
+    fn the_timer_fires_once_mtime_gets_to_mtimecmp() {
+        let mut clint = Clint::new();
+        store(&mut clint, MTIMECMP_HI, 0);
+        store(&mut clint, MTIMECMP_LO, 10);
+        assert_eq!(clint.mtimecmp(), 10);
+        while clint.mtime() < 12 {
+            assert_eq!(*clint.timer_interrupt.borrow(), clint.mtime() >= 10);
+            tick(&mut clint);
+        }
+        assert!(*clint.timer_interrupt.borrow());
+        // moving mtimecmp ahead drops it again
+        store(&mut clint, MTIMECMP_LO, 100);
+        assert!(!*clint.timer_interrupt.borrow());
+        assert_eq!(load(&mut clint, MTIME_LO), clint.mtime() as u32 - 1);
+    }
+
+    #[test]
+    fn mtime_carries_into_the_high_word() {
+        let mut clint = Clint::new();
+        store(&mut clint, MTIME_LO, u32::MAX);
+        assert_eq!(clint.mtime(), u32::MAX as u64);
+        tick(&mut clint);
+        assert_eq!(clint.mtime(), 1 << 32);
+        assert_eq!(load(&mut clint, MTIME_HI), 1);
+    }
+
+    #[test]
+    fn msip_raises_the_software_interrupt() {
+        let mut clint = Clint::new();
+        assert!(!*clint.software_interrupt.borrow());
+        store(&mut clint, MSIP, u32::MAX);
+        assert!(*clint.software_interrupt.borrow());
+        // only bit 0 is there
+        assert_eq!(load(&mut clint, MSIP), 1);
+        store(&mut clint, MSIP, 0);
+        assert!(!*clint.software_interrupt.borrow());
+    }
+}
@@ -1,61 +1,469 @@
-use crate::chips::decode::{Decode, Instruction};
-use crate::chips::execute::Execute;
-use crate::chips::fetch::Fetch;
+use crate::chips::bus::{Bus, Device, MapError, MemoryMap};
+use crate::chips::clint::{Clint, CLINT_SIZE};
+use crate::chips::csr_file::{CsrFile, EXTERNAL_INTERRUPT, SOFTWARE_INTERRUPT, TIMER_INTERRUPT};
+use crate::chips::execute::ExMem;
+use crate::chips::input::{Input, InputSource, INPUT_IRQ, INPUT_SIZE};
+use crate::chips::memory::{from_lanes, lane_mask, strobe, to_lanes, Memory, Width};
 use crate::chips::pc::PC;
+use crate::chips::pipeline::Pipeline;
+use crate::chips::plic::{Plic, PLIC_SIZE};
+use crate::chips::probe::{Port, Probe};
 use crate::chips::ram::RAM;
 use crate::chips::register_file::RegFile;
 use crate::chips::rom::ROM;
-use crate::chips::{wire, Chip, Wire, U32};
+use crate::chips::single_cycle::SingleCycle;
+use crate::chips::uart::{Terminal, Uart, UART_IRQ, UART_SIZE};
+use crate::chips::{mux2, wire, Chip, Wire, U32};
+use crate::history::History;
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
+use crate::vcd::Vcd;
+use std::num::Wrapping;
 
-use super::memory::Memory;
-use super::screen::Screen;
+use super::screen::{Screen, SCREEN_SIZE};
+use super::trap::Trap;
 use super::ZERO;
 
-pub struct CPU<T = U32> {
-    pub fetch: Fetch<T>,
-    pub decode: Decode<T>,
-    pub execute: Execute<T>,
+/**The two cores the machine can be built with*/
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Model {
+    // five stages with hazard detection and forwarding
+    #[default]
+    Pipelined,
+    // one instruction per clock, the reference the pipeline is checked against
+    SingleCycle,
+}
+
+impl Model {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "pipelined" => Some(Model::Pipelined),
+            "single-cycle" => Some(Model::SingleCycle),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Model::Pipelined => "pipelined",
+            Model::SingleCycle => "single-cycle",
+        }
+    }
+}
+
+/**What runs the instructions. Both cores share the pc, the rom, the register file and the
+memory stage, where instructions commit with the machine's traps, tracing and watchpoints, so
+the rest of the emulator doesn't care which one it drives*/
+pub trait Core: Probe + Snapshot {
+    fn model(&self) -> Model;
+    /**The logic of one cycle, the memory stage included, leaving the pc's next value on its
+    inputs*/
+    fn compute(&mut self, memory: &mut Memory);
+    fn clk(&mut self);
+    /**Addresses of the fetched instructions that haven't committed, oldest first*/
+    fn in_flight(&self) -> Vec<U32>;
+    /**Whether an instruction reaches the memory stage next cycle*/
+    fn busy(&self) -> bool;
+    /**Drop the instructions in flight*/
+    fn flush(&mut self);
+    /**The stages, for a core that has them*/
+    fn pipeline(&self) -> Option<&Pipeline> {
+        None
+    }
+}
+
+pub struct CPU {
+    pub core: Box<dyn Core>,
+    pub memory: Memory,
+    pub clint: Wire<Clint>,
+    pub plic: Wire<Plic>,
+    pub uart: Wire<Uart>,
+    pub input: Wire<Input>,
+    pub screen: Wire<Screen>,
+    pub rom: Wire<ROM>,
+    reg_file: Wire<RegFile<U32>>,
+    csr_file: Wire<CsrFile>,
     pc: Wire<PC>,
+    // the bus only knows the ram as a device, snapshots and waveforms need it as memory
+    pub ram: Wire<RAM<U32>>,
+    map: MemoryMap,
+    /**Checkpoints and input of the run so far, when it is recorded for going back in time*/
+    pub history: Option<History>,
+    /**Waveform of the signals, sampled every cycle*/
+    pub vcd: Option<Vcd>,
 }
 
 impl CPU {
-    pub fn new(ram: RAM<U32>, rom: ROM, screen: Screen) -> Self {
+    /**Build the machine with the `model` core, putting the ram and the devices on the bus
+    where `map` says*/
+    pub fn new(
+        ram: RAM<U32>,
+        rom: ROM,
+        screen: Screen,
+        terminal: Box<dyn Terminal>,
+        input: Box<dyn InputSource>,
+        map: &MemoryMap,
+        model: Model,
+    ) -> Result<Self, MapError> {
         let pc = wire(PC::default());
         let reg_file = wire(RegFile::new(32));
+        let csr_file = wire(CsrFile::new());
         let rom = wire(rom);
-        let memory = Memory::new(wire(ZERO), wire(ZERO), wire(false), screen, ram);
+        let clint = wire(Clint::new());
+        let plic = wire(Plic::new());
+        let uart = wire(Uart::new(terminal, plic.borrow().source(UART_IRQ)));
+        let input = wire(Input::new(input, plic.borrow().source(INPUT_IRQ)));
 
-        let fetch = Fetch::new(pc.borrow().output.clone(), rom.clone());
-        let decode = Decode::new(rom.clone(), wire(Instruction::default()));
-        let execute = Execute::new(
-            decode.output.clone(),
-            memory,
-            rom,
+        let mut bus = Bus::new();
+        // the command line only takes sizes the 32 bit address space can hold
+        let size = u32::try_from(ram.size()).expect("the ram is larger than the address space");
+        let ram = wire(ram);
+        bus.map("ram", map.ram_base, size, ram.clone())?;
+        let screen = wire(screen);
+        bus.map("screen", map.screen_base, SCREEN_SIZE, screen.clone())?;
+        bus.map("clint", map.clint_base, CLINT_SIZE, clint.clone())?;
+        bus.map("plic", map.plic_base, PLIC_SIZE, plic.clone())?;
+        bus.map("uart", map.uart_base, UART_SIZE, uart.clone())?;
+        bus.map("input", map.input_base, INPUT_SIZE, input.clone())?;
+
+        let memory = Memory::new(
+            wire(ExMem::default()),
+            bus,
+            rom.clone(),
+            uart.clone(),
             reg_file.clone(),
-            pc.clone(),
+            csr_file.clone(),
         );
+        let core: Box<dyn Core> = match model {
+            Model::Pipelined => Box::new(Pipeline::new(&pc, &rom, &reg_file, &memory)),
+            Model::SingleCycle => Box::new(SingleCycle::new(&pc, &rom, &reg_file)),
+        };
 
-        Self {
-            fetch,
-            decode,
-            execute,
+        Ok(Self {
+            core,
+            memory,
+            clint,
+            plic,
+            uart,
+            input,
+            screen,
+            rom,
+            reg_file,
+            csr_file,
             pc,
+            ram,
+            map: map.clone(),
+            history: None,
+            vcd: None,
+        })
+    }
+}
+
+impl CPU {
+    /**Start executing from `entry` instead of address 0*/
+    pub fn set_entry(&mut self, entry: U32) {
+        self.pc.borrow_mut().set_reset_vector(entry);
+    }
+
+    /**Number of instructions executed so far*/
+    pub fn retired(&self) -> u64 {
+        self.memory.retired
+    }
+
+    /**Run a single clock cycle, returning the trap if an instruction could not be executed*/
+    pub fn step(&mut self) -> Result<(), Trap> {
+        if let Some(history) = &self.history {
+            history.begin(self.memory.cycle + 1);
+        }
+        self.compute();
+        if let Some(mut vcd) = self.vcd.take() {
+            vcd.sample(self);
+            self.vcd = Some(vcd);
+        }
+        self.clk();
+        if let Some(mut history) = self.history.take() {
+            history.record(self);
+            self.history = Some(history);
+        }
+        match self.memory.trap.take() {
+            Some(trap) => Err(trap),
+            None => Ok(()),
+        }
+    }
+
+    /**The exit code once the guest program has asked to exit*/
+    pub fn exit_code(&self) -> Option<i32> {
+        self.memory.exit_code
+    }
+}
+
+// What debuggers see and change between two cycles
+impl CPU {
+    /**Address of the next instruction to commit, the oldest one in the pipeline that isn't a
+    bubble*/
+    pub fn pc(&self) -> U32 {
+        self.in_flight()
+            .first()
+            .copied()
+            .unwrap_or(*self.pc.borrow().output.borrow())
+    }
+
+    /**Addresses of the instructions in the pipeline that haven't committed, oldest first*/
+    pub fn in_flight(&self) -> Vec<U32> {
+        self.core.in_flight()
+    }
+
+    /**Whether an instruction reaches the memory stage next cycle, to commit, trap or be
+    interrupted*/
+    pub fn busy(&self) -> bool {
+        self.core.busy()
+    }
+
+    /**Continue from `addr`, dropping the instructions in flight. What is in write back has
+    committed and still gets written*/
+    pub fn set_pc(&mut self, addr: U32) {
+        *self.pc.borrow().output.borrow_mut() = addr;
+        self.core.flush();
+        self.changed();
+    }
+
+    // Everything a debugger changes goes through here: the recorded history after this cycle
+    // no longer happens
+    fn changed(&mut self) {
+        if let Some(mut history) = self.history.take() {
+            history.changed(self);
+            self.history = Some(history);
+        }
+    }
+
+    /**Run cycles until the memory stage has handled one instruction: retired it, trapped on
+    it or taken an interrupt instead*/
+    pub fn step_instruction(&mut self) -> Result<(), Trap> {
+        loop {
+            let busy = self.busy();
+            self.step()?;
+            if busy {
+                return Ok(());
+            }
+        }
+    }
+
+    /**An integer register as the committed instructions left it*/
+    pub fn register(&self, index: usize) -> U32 {
+        self.memory.register(Wrapping(index as u32))
+    }
+
+    /**Overwrite an integer register, writes to x0 are dropped. The instructions in flight
+    already read the old value, so they are fetched again*/
+    pub fn set_register(&mut self, index: usize, value: U32) {
+        if index != 0 {
+            // a write back still to come would undo the change
+            let mut mem_wb = self.memory.output.borrow_mut();
+            if mem_wb.writes(Wrapping(index as u32)).is_some() {
+                mem_wb.write = false;
+            }
+            drop(mem_wb);
+            self.reg_file.borrow_mut().get(index).set(value);
+            let pc = self.pc();
+            self.set_pc(pc);
+        }
+    }
+
+    pub fn csr(&self, addr: u16) -> Option<U32> {
+        self.csr_file.borrow().read(addr)
+    }
+
+    /**Overwrite a csr right away, the value is legalised like a csr instruction's would be*/
+    pub fn set_csr(&mut self, addr: u16, value: U32) -> Option<()> {
+        let mut csr_file = self.csr_file.borrow_mut();
+        csr_file.write(addr, value)?;
+        csr_file.clk();
+        drop(csr_file);
+        self.changed();
+        Some(())
+    }
+
+    /**The rom word at `addr`, None outside the rom*/
+    pub fn code(&self, addr: U32) -> Option<U32> {
+        let rom = self.rom.borrow();
+        rom.contains(addr).then(|| rom.peek(addr))
+    }
+
+    /**Replace the rom word at `addr` and refetch, so the pipeline doesn't run the old
+    instruction. Returns the word that was there*/
+    pub fn patch(&mut self, addr: U32, word: U32) -> Option<U32> {
+        let old = self.code(addr)?;
+        self.rom.borrow_mut().poke(addr, word);
+        let pc = self.pc();
+        self.set_pc(pc);
+        Some(old)
+    }
+
+    /**A byte of memory as a debugger sees it. Code and data share one address space there, so
+    where the rom and the ram overlap the rom wins. Devices are read without side effects,
+    those that can't be are unreadable*/
+    pub fn read_memory(&self, addr: U32) -> Option<u8> {
+        let word = match self.code(addr) {
+            Some(word) => word,
+            None => self.memory.bus.inspect(addr & !Wrapping(3u32))?,
+        };
+        Some(from_lanes(word, addr, Width::Byte, false).0 as u8)
+    }
+
+    /**Store a byte for a debugger, into the rom or through the bus*/
+    pub fn write_memory(&mut self, addr: U32, byte: u8) -> Option<()> {
+        let aligned = addr & !Wrapping(3u32);
+        if let Some(word) = self.code(aligned) {
+            let mask = lane_mask(strobe(addr, Width::Byte));
+            let byte = to_lanes(Wrapping(byte as u32), addr);
+            self.patch(aligned, word & !mask | byte);
+            return Some(());
+        }
+        let value = to_lanes(Wrapping(byte as u32), addr);
+        // the ram only takes a store at the clock edge, a debugger's goes straight in
+        let offset = addr - Wrapping(self.map.ram_base);
+        if (offset.0 as usize) < self.ram.borrow().size() {
+            let aligned = offset & !Wrapping(3u32);
+            let mask = lane_mask(strobe(offset, Width::Byte));
+            let mut ram = self.ram.borrow_mut();
+            let word = ram.peek(aligned);
+            ram.poke(aligned, word & !mask | value);
+        } else {
+            self.memory.bus.write(addr, value, Width::Byte)?;
         }
+        self.changed();
+        Some(())
     }
 }
 
+// the core's parts hang right off the cpu, so a path is the same whichever core runs
+impl Probe for CPU {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        self.core.ports()
+    }
+
+    fn children(&self, visit: &mut dyn FnMut(&str, &dyn Probe)) {
+        visit("pc", &*self.pc.borrow());
+        self.core.children(visit);
+        visit("memory", &self.memory);
+        visit("reg_file", &*self.reg_file.borrow());
+        visit("csr_file", &*self.csr_file.borrow());
+    }
+}
+
+// Sections in pipeline order, the core's own first, then the devices. Taken between two
+// cycles, so nothing is half way through a clock
+impl Snapshot for CPU {
+    fn save(&self, out: &mut Writer) {
+        out.section("machine");
+        out.put(&bases(&self.map).to_vec());
+        out.put(&(self.core.model() == Model::SingleCycle));
+        out.section("pc");
+        self.pc.borrow().save(out);
+        self.core.save(out);
+        out.section("memory");
+        self.memory.save(out);
+        out.section("registers");
+        self.reg_file.borrow().save(out);
+        self.csr_file.borrow().save(out);
+        out.section("ram");
+        self.ram.borrow().save(out);
+        out.section("devices");
+        self.clint.borrow().save(out);
+        self.plic.borrow().save(out);
+        self.uart.borrow().save(out);
+        self.input.borrow().save(out);
+        Snapshot::save(&*self.screen.borrow(), out);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        input.section("machine")?;
+        let map: Vec<u32> = input.get_exact(7, "the memory map")?;
+        if map != bases(&self.map) {
+            return Err(SnapshotError::Mismatch(
+                "it was taken with another memory map".to_string(),
+            ));
+        }
+        let model = match input.get()? {
+            true => Model::SingleCycle,
+            false => Model::Pipelined,
+        };
+        if model != self.core.model() {
+            return Err(SnapshotError::Mismatch(format!(
+                "it was taken with the {} core",
+                model.name()
+            )));
+        }
+        input.section("pc")?;
+        self.pc.borrow_mut().restore(input)?;
+        self.core.restore(input)?;
+        input.section("memory")?;
+        self.memory.restore(input)?;
+        input.section("registers")?;
+        self.reg_file.borrow_mut().restore(input)?;
+        self.csr_file.borrow_mut().restore(input)?;
+        input.section("ram")?;
+        self.ram.borrow_mut().restore(input)?;
+        input.section("devices")?;
+        self.clint.borrow_mut().restore(input)?;
+        self.plic.borrow_mut().restore(input)?;
+        self.uart.borrow_mut().restore(input)?;
+        self.input.borrow_mut().restore(input)?;
+        self.screen.borrow_mut().restore(input)?;
+        match input.take(1) {
+            Err(_) => Ok(()),
+            Ok(_) => Err(SnapshotError::Mismatch(
+                "there is more state than this machine has".to_string(),
+            )),
+        }
+    }
+}
+
+fn bases(map: &MemoryMap) -> [u32; 7] {
+    [
+        map.rom_base,
+        map.ram_base,
+        map.screen_base,
+        map.clint_base,
+        map.plic_base,
+        map.uart_base,
+        map.input_base,
+    ]
+}
+
 impl Chip for CPU {
     fn compute(&mut self) {
-        self.fetch.compute();
-        self.decode.compute();
-        self.execute.compute();
+        self.core.compute(&mut self.memory);
         self.pc.borrow_mut().compute();
+
+        // the devices run after memory so they see this cycle's bus access
+        self.clint.borrow_mut().compute();
+        self.uart.borrow_mut().compute();
+        self.input.borrow_mut().compute();
+        self.plic.borrow_mut().compute();
+
+        // gather the interrupt lines into mip
+        let clint = self.clint.borrow();
+        let plic = self.plic.borrow();
+        let line = |wire: &Wire<bool>, bit: u32| mux2(ZERO, Wrapping(1 << bit), *wire.borrow());
+        let pending = line(&clint.software_interrupt, SOFTWARE_INTERRUPT)
+            | line(&clint.timer_interrupt, TIMER_INTERRUPT)
+            | line(&plic.interrupt, EXTERNAL_INTERRUPT);
+        self.csr_file.borrow_mut().set_pending(pending);
     }
 
     fn clk(&mut self) {
-        self.fetch.clk();
-        self.decode.clk();
-        self.execute.clk();
+        self.core.clk();
+        self.memory.clk();
         self.pc.borrow_mut().clk();
+        self.clint.borrow_mut().clk();
+        self.uart.borrow_mut().clk();
+        self.input.borrow_mut().clk();
+        self.plic.borrow_mut().clk();
+        self.screen.borrow_mut().clk();
+        self.ram.borrow_mut().clk();
     }
 }
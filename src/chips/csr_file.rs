@@ -0,0 +1,292 @@
+use crate::chips::probe::Probe;
+use crate::chips::register::Register;
+use crate::chips::{mux2, Chip, ONE, U32, ZERO};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
+use std::num::Wrapping;
+
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+
+// every csr the file implements, the position is the index of its register
+pub const ADDRESSES: [u16; 13] = [
+    MSTATUS, MISA, MIE, MTVEC, MSCRATCH, MEPC, MCAUSE, MTVAL, MIP, MVENDORID, MARCHID, MIMPID,
+    MHARTID,
+];
+
+pub const MSTATUS_MIE: U32 = Wrapping(1 << 3);
+pub const MSTATUS_MPIE: U32 = Wrapping(1 << 7);
+// only machine mode exists, so MPP is hardwired to M
+pub const MSTATUS_MPP: U32 = Wrapping(0b11 << 11);
+
+// set in mcause for interrupts
+pub const MCAUSE_INTERRUPT: U32 = Wrapping(1 << 31);
+
+// interrupt numbers, also the bit in mie and mip
+pub const SOFTWARE_INTERRUPT: u32 = 3;
+pub const TIMER_INTERRUPT: u32 = 7;
+pub const EXTERNAL_INTERRUPT: u32 = 11;
+const INTERRUPTS: U32 =
+    Wrapping(1 << SOFTWARE_INTERRUPT | 1 << TIMER_INTERRUPT | 1 << EXTERNAL_INTERRUPT);
+
+// MXL = 32 bit, extensions I and M
+const MISA_VALUE: U32 = Wrapping(1 << 30 | 1 << 8 | 1 << 12);
+
+/**Control and status registers, one Register per implemented csr*/
+#[derive(Clone)]
+pub struct CsrFile<T = U32> {
+    registers: Vec<Register<T>>,
+    // mtvec has been written, traps go to the guest from then on even with the vector at 0
+    vector_set: bool,
+}
+
+impl CsrFile {
+    pub fn new() -> Self {
+        let registers = ADDRESSES
+            .iter()
+            .map(|&addr| {
+                let register = Register::default();
+                *register.output.borrow_mut() = reset_value(addr);
+                register
+            })
+            .collect();
+        Self {
+            registers,
+            vector_set: false,
+        }
+    }
+
+    /**Current value of a csr, None if it is not implemented*/
+    pub fn read(&self, addr: u16) -> Option<U32> {
+        let index = ADDRESSES.iter().position(|&a| a == addr)?;
+        Some(*self.registers[index].output.borrow())
+    }
+
+    /**Latch a write to a csr, it takes effect at the next clk. The value is legalised
+    following the WARL rules of the csr, fields that can't take the value keep a legal one*/
+    pub fn write(&mut self, addr: u16, value: U32) -> Option<()> {
+        let index = ADDRESSES.iter().position(|&a| a == addr)?;
+        let register = &mut self.registers[index];
+        let old = *register.output.borrow();
+        *register.input.borrow_mut() = legalize(addr, old, value);
+        self.vector_set |= addr == MTVEC;
+        *register.load.borrow_mut() = true;
+        register.compute();
+        Some(())
+    }
+
+    /**The value latched by a write this cycle, None if the csr wasn't written*/
+    pub fn latched(&self, addr: u16) -> Option<U32> {
+        let index = ADDRESSES.iter().position(|&a| a == addr)?;
+        let register = &self.registers[index];
+        let written = *register.load.borrow();
+        written.then(|| *register.input.borrow())
+    }
+
+    /**Whether the guest has set up a trap handler by writing mtvec, whatever it wrote*/
+    pub fn has_handler(&self) -> bool {
+        self.vector_set
+    }
+
+    /**Latch the state of the interrupt lines into mip*/
+    pub fn set_pending(&mut self, pending: U32) {
+        let index = ADDRESSES.iter().position(|&a| a == MIP).unwrap();
+        let register = &mut self.registers[index];
+        *register.input.borrow_mut() = pending & INTERRUPTS;
+        *register.load.borrow_mut() = true;
+        register.compute();
+    }
+
+    /**The mcause of the interrupt to take now, if any. External beats software beats timer*/
+    pub fn pending_interrupt(&self) -> Option<U32> {
+        let mstatus = self.read(MSTATUS).unwrap_or_default();
+        if mstatus & MSTATUS_MIE == ZERO {
+            return None;
+        }
+        let pending = self.read(MIP).unwrap_or_default() & self.read(MIE).unwrap_or_default();
+        [EXTERNAL_INTERRUPT, SOFTWARE_INTERRUPT, TIMER_INTERRUPT]
+            .into_iter()
+            .find(|bit| pending.0 >> bit & 1 == 1)
+            .map(|bit| MCAUSE_INTERRUPT | Wrapping(bit))
+    }
+
+    /**Enter the trap handler: save pc and the cause, stack MIE and return the handler address*/
+    pub fn trap_entry(&mut self, pc: U32, cause: U32, tval: U32) -> U32 {
+        let mstatus = self.read(MSTATUS).unwrap_or_default();
+        let mpie = mux2(ZERO, MSTATUS_MPIE, mstatus & MSTATUS_MIE != ZERO);
+        self.write(MSTATUS, mstatus & !(MSTATUS_MIE | MSTATUS_MPIE) | mpie);
+        self.write(MEPC, pc);
+        self.write(MCAUSE, cause);
+        self.write(MTVAL, tval);
+
+        // in vectored mode interrupts go to base + 4 * cause, exceptions always go to base
+        let mtvec = self.read(MTVEC).unwrap_or_default();
+        let base = mtvec & !Wrapping(0b11u32);
+        let interrupt = cause & MCAUSE_INTERRUPT != ZERO;
+        match mtvec & ONE == ONE && interrupt {
+            true => base + ((cause & !MCAUSE_INTERRUPT) << 2),
+            false => base,
+        }
+    }
+
+    /**MRET: restore MIE from MPIE and set MPIE, the caller jumps to mepc*/
+    pub fn trap_return(&mut self) {
+        let mstatus = self.read(MSTATUS).unwrap_or_default();
+        let mie = mux2(ZERO, MSTATUS_MIE, mstatus & MSTATUS_MPIE != ZERO);
+        self.write(MSTATUS, mstatus & !MSTATUS_MIE | mie | MSTATUS_MPIE);
+    }
+}
+
+impl Default for CsrFile {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/**Name of a csr for disassembly and traces, None for addresses the file doesn't implement*/
+pub fn csr_name(addr: u16) -> Option<&'static str> {
+    let name = match addr {
+        MSTATUS => "mstatus",
+        MISA => "misa",
+        MIE => "mie",
+        MTVEC => "mtvec",
+        MSCRATCH => "mscratch",
+        MEPC => "mepc",
+        MCAUSE => "mcause",
+        MTVAL => "mtval",
+        MIP => "mip",
+        MVENDORID => "mvendorid",
+        MARCHID => "marchid",
+        MIMPID => "mimpid",
+        MHARTID => "mhartid",
+        _ => return None,
+    };
+    Some(name)
+}
+
+/**Address of the csr called `name`, the reverse of csr_name*/
+pub fn csr_address(name: &str) -> Option<u16> {
+    ADDRESSES
+        .iter()
+        .copied()
+        .find(|&addr| csr_name(addr) == Some(name))
+}
+
+/**Csrs in the 0xC00-0xFFF range can only be read*/
+pub fn is_read_only(addr: u16) -> bool {
+    addr >> 10 == 0b11
+}
+
+fn reset_value(addr: u16) -> U32 {
+    match addr {
+        MSTATUS => MSTATUS_MPP,
+        MISA => MISA_VALUE,
+        _ => Wrapping(0),
+    }
+}
+
+fn legalize(addr: u16, old: U32, value: U32) -> U32 {
+    match addr {
+        MSTATUS => value & (MSTATUS_MIE | MSTATUS_MPIE) | MSTATUS_MPP,
+        // the supported extensions can't be changed
+        MISA => old,
+        // modes 2 and 3 are reserved, only direct (0) and vectored (1) stick
+        MTVEC => value & !Wrapping(0b10u32),
+        // no compressed instructions so mepc is always 4 byte aligned
+        MEPC => value & !Wrapping(0b11u32),
+        MIE => value & INTERRUPTS,
+        // pending bits follow the interrupt lines, software can't change them
+        MIP => old,
+        _ => value,
+    }
+}
+
+impl Chip for CsrFile {
+    fn clk(&mut self) {
+        // only the registers written this cycle have a new value waiting
+        self.registers
+            .iter_mut()
+            .filter(|register| *register.load.borrow())
+            .for_each(|register| {
+                register.clk();
+                *register.load.borrow_mut() = false;
+            });
+    }
+}
+
+impl Probe for CsrFile {
+    fn name(&self) -> &'static str {
+        "csr_file"
+    }
+
+    fn children(&self, visit: &mut dyn FnMut(&str, &dyn Probe)) {
+        for (&addr, register) in ADDRESSES.iter().zip(&self.registers) {
+            visit(csr_name(addr).unwrap_or("csr"), register);
+        }
+    }
+}
+
+impl Snapshot for CsrFile {
+    fn save(&self, out: &mut Writer) {
+        self.registers
+            .iter()
+            .for_each(|register| register.save(out));
+        out.put(&self.vector_set);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        self.registers
+            .iter_mut()
+            .try_for_each(|register| register.restore(input))?;
+        self.vector_set = input.get()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // What `csr` reads back after `value` is written to it
+    fn written(csr: u16, value: u32) -> u32 {
+        let mut csrs = CsrFile::new();
+        csrs.write(csr, Wrapping(value)).unwrap();
+        csrs.clk();
+        csrs.read(csr).unwrap().0
+    }
+
+    #[test]
+    fn writes_are_legalized() {
+        assert_eq!(written(MISA, 0), MISA_VALUE.0);
+        // direct and vectored stick, the reserved modes lose their high bit
+        assert_eq!(written(MTVEC, 0x1000), 0x1000);
+        assert_eq!(written(MTVEC, 0x1001), 0x1001);
+        assert_eq!(written(MTVEC, 0x1002), 0x1000);
+        assert_eq!(written(MTVEC, 0x1003), 0x1001);
+        assert_eq!(written(MEPC, 0x1003), 0x1000);
+        // only MIE and MPIE can be changed, MPP stays M
+        assert_eq!(written(MSTATUS, 0xFFFF_FFFF), 0x1888);
+        assert_eq!(written(MSTATUS, 0), 0x1800);
+        assert_eq!(written(MIE, 0xFFFF_FFFF), 0x888);
+        assert_eq!(written(MIP, 0xFFFF_FFFF), 0);
+        assert_eq!(written(MSCRATCH, 0xFFFF_FFFF), 0xFFFF_FFFF);
+    }
+
+    #[test]
+    fn unknown_csrs_are_not_there() {
+        let mut csrs = CsrFile::new();
+        assert_eq!(csrs.read(0x7C0), None);
+        assert_eq!(csrs.write(0x7C0, ZERO), None);
+        assert!(is_read_only(MHARTID) && !is_read_only(MSCRATCH));
+    }
+}
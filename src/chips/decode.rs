@@ -1,24 +1,71 @@
 use crate::chips::dff::DFF;
+use crate::chips::memory::MemWb;
+use crate::chips::probe::{Port, Probe};
+use crate::chips::register_file::RegFile;
 use crate::chips::rom::ROM;
+use crate::chips::trap::Exception;
 use crate::chips::{mux2, wire, Chip, Wire, ONE, U32, ZERO};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Value, Writer};
 use std::num::Wrapping;
 
+/**The ID/EX pipeline register: the decoded instruction and the registers it read*/
+#[derive(Clone, Debug, Default)]
+pub struct IdEx {
+    // false for a bubble
+    pub valid: bool,
+    pub instruction: Instruction,
+    pub rs1_value: U32,
+    pub rs2_value: U32,
+}
+
+/**The ID stage. It decodes the word in IF/ID and reads its source registers into ID/EX*/
 pub struct Decode<T = U32> {
     pub input: Wire<ROM<T>>,
-    pub output: Wire<Instruction<T>>,
+    // the IF/ID pipeline register: the word on the rom output, its address and whether it is one
+    pub pc: Wire<T>,
+    pub valid: Wire<bool>,
+    pub reg_file: Wire<RegFile<T>>,
+    // MEM/WB, written to the register file at the end of this cycle
+    pub write_back: Wire<MemWb>,
+    // from the hazard unit: a load-use stall or a taken branch turn what decode latches into a
+    // bubble
+    pub stall: Wire<bool>,
+    pub flush: Wire<bool>,
+    pub output: Wire<IdEx>,
     // out stores the result of the current operation and transfers it to output at clk
-    out: DFF<Instruction<T>>,
+    out: DFF<IdEx>,
 }
 
 impl Decode {
-    pub fn new(input: Wire<ROM<U32>>, output: Wire<Instruction>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        input: Wire<ROM<U32>>,
+        pc: Wire<U32>,
+        valid: Wire<bool>,
+        reg_file: Wire<RegFile<U32>>,
+        write_back: Wire<MemWb>,
+        stall: Wire<bool>,
+        flush: Wire<bool>,
+    ) -> Self {
+        let output = wire(IdEx::default());
         Self {
             input,
+            pc,
+            valid,
+            reg_file,
+            write_back,
+            stall,
+            flush,
             output: output.clone(),
             // new dff with wire connected to Decode's output
-            out: DFF::new(wire(Instruction::default()), output),
+            out: DFF::new(wire(IdEx::default()), output),
         }
     }
+
+    /**Empty the stage, as if a bubble had been latched*/
+    pub fn flush(&mut self) {
+        self.out.set(IdEx::default());
+    }
 }
 
 fn bit_range(v: U32, msb: usize, lsb: usize) -> U32 {
@@ -27,7 +74,9 @@ fn bit_range(v: U32, msb: usize, lsb: usize) -> U32 {
 }
 
 impl Decode {
-    fn decode(&self, inst: U32) -> Instruction {
+    /**Decode a raw instruction, anything that is not a valid RV32IM encoding is an illegal
+    instruction*/
+    pub fn decode(inst: U32) -> Result<Instruction, Exception> {
         let neg = (inst >> 31) == ONE;
         let ones_21 = Wrapping((0xFFFFF8 << 8) as u32);
         let ones_20 = Wrapping((0xFFFFF << 12) as u32);
@@ -55,10 +104,12 @@ impl Decode {
         let shamtw = bit_range(inst, 24, 20);
         let funct3 = bit_range(inst, 14, 12);
         let funct7 = bit_range(inst, 31, 25);
+        let funct12 = bit_range(inst, 31, 20);
         let rd = bit_range(inst, 11, 7);
         let rs1 = bit_range(inst, 19, 15);
         let rs2 = bit_range(inst, 24, 20);
         let opcode = bit_range(inst, 6, 0);
+        let illegal = Exception::IllegalInstruction(inst);
         let imm;
 
         use Operation::*;
@@ -66,61 +117,42 @@ impl Decode {
             0b0110011 => {
                 imm = ZERO;
                 // OP
-                if funct7 == ZERO {
-                    match funct3.0 {
-                        0b000 => ADD,
-                        0b001 => SLL,
-                        0b010 => SLT,
-                        0b011 => SLTU,
-                        0b100 => XOR,
-                        0b101 => SRL,
-                        0b110 => OR,
-                        0b111 => AND,
-                        _ => panic!("invalid funct3 {funct3} for op"),
-                    }
-                } else if funct7.0 == 0b0100000 {
-                    match funct3.0 {
-                        0b000 => SUB,
-                        0b101 => SRA,
-                        _ => panic!("invalid funct3 {funct3} for op"),
-                    }
-                } else if funct7.0 == 0b0000001 {
-                    match funct3.0 {
-                        0b000 => MUL,
-                        0b001 => MULH,
-                        0b010 => MULHSU,
-                        0b011 => MULHU,
-                        0b100 => DIV,
-                        0b101 => DIVU,
-                        0b110 => REM,
-                        0b111 => REMU,
-                        _ => panic!("invalid funct3 {funct3} for op"),
-                    }
-                } else {
-                    panic!("not recognized {funct7}")
+                match (funct7.0, funct3.0) {
+                    (0b0000000, 0b000) => ADD,
+                    (0b0000000, 0b001) => SLL,
+                    (0b0000000, 0b010) => SLT,
+                    (0b0000000, 0b011) => SLTU,
+                    (0b0000000, 0b100) => XOR,
+                    (0b0000000, 0b101) => SRL,
+                    (0b0000000, 0b110) => OR,
+                    (0b0000000, 0b111) => AND,
+                    (0b0100000, 0b000) => SUB,
+                    (0b0100000, 0b101) => SRA,
+                    (0b0000001, 0b000) => MUL,
+                    (0b0000001, 0b001) => MULH,
+                    (0b0000001, 0b010) => MULHSU,
+                    (0b0000001, 0b011) => MULHU,
+                    (0b0000001, 0b100) => DIV,
+                    (0b0000001, 0b101) => DIVU,
+                    (0b0000001, 0b110) => REM,
+                    (0b0000001, 0b111) => REMU,
+                    _ => return Err(illegal),
                 }
             }
             0b0010011 => {
                 // OP-IMM
                 imm = imm_i;
-                match funct3.0 {
-                    0b000 => ADDI,
-                    0b001 => SLLI,
-                    0b010 => SLTI,
-                    0b011 => SLTIU,
-                    0b100 => XORI,
-                    0b101 => {
-                        if funct7 == ZERO {
-                            SRLI
-                        } else if funct7.0 == 0b0100000 {
-                            SRAI
-                        } else {
-                            panic!("not recognized {funct7}")
-                        }
-                    }
-                    0b110 => ORI,
-                    0b111 => ANDI,
-                    _ => panic!("invalid funct3 {funct3} for op-imm"),
+                match (funct3.0, funct7.0) {
+                    (0b000, _) => ADDI,
+                    (0b001, 0b0000000) => SLLI,
+                    (0b010, _) => SLTI,
+                    (0b011, _) => SLTIU,
+                    (0b100, _) => XORI,
+                    (0b101, 0b0000000) => SRLI,
+                    (0b101, 0b0100000) => SRAI,
+                    (0b110, _) => ORI,
+                    (0b111, _) => ANDI,
+                    _ => return Err(illegal),
                 }
             }
             0b0110111 => {
@@ -135,7 +167,7 @@ impl Decode {
                 imm = imm_j;
                 JAL
             }
-            0b1100111 => {
+            0b1100111 if funct3 == ZERO => {
                 imm = imm_i;
                 JALR
             }
@@ -149,7 +181,7 @@ impl Decode {
                     0b101 => BGE,
                     0b110 => BLTU,
                     0b111 => BGEU,
-                    _ => panic!("invalid instruction"),
+                    _ => return Err(illegal),
                 }
             }
             0b0000011 => {
@@ -161,7 +193,7 @@ impl Decode {
                     0b010 => LW,
                     0b100 => LBU,
                     0b101 => LHU,
-                    _ => LW,
+                    _ => return Err(illegal),
                 }
             }
             0b0100011 => {
@@ -171,40 +203,104 @@ impl Decode {
                     0b000 => SB,
                     0b001 => SH,
                     0b010 => SW,
-                    _ => SW,
+                    _ => return Err(illegal),
                 }
             }
-            0b1110011 => {
-                // SYSTEM
+            0b0001111 if funct3 == ZERO => {
+                // MISC-MEM, memory is always coherent here so fence is a no-op
                 imm = ZERO;
-                match funct7.0 {
-                    0 => ECALL,
-                    1 => EBREAK,
-                    _ => panic!("invalid instruction"),
-                }
+                FENCE
             }
-            _ => {
-                imm = ZERO;
-                ADDI
+            0b1110011 => {
+                // SYSTEM, for the Zicsr instructions imm holds the csr address and rs1 the uimm
+                imm = funct12;
+                match (funct12.0, funct3.0, rs1.0, rd.0) {
+                    (0, 0, 0, 0) => ECALL,
+                    (1, 0, 0, 0) => EBREAK,
+                    (0x302, 0, 0, 0) => MRET,
+                    (0x105, 0, 0, 0) => WFI,
+                    (_, 0b001, _, _) => CSRRW,
+                    (_, 0b010, _, _) => CSRRS,
+                    (_, 0b011, _, _) => CSRRC,
+                    (_, 0b101, _, _) => CSRRWI,
+                    (_, 0b110, _, _) => CSRRSI,
+                    (_, 0b111, _, _) => CSRRCI,
+                    _ => return Err(illegal),
+                }
             }
+            _ => return Err(illegal),
         };
 
-        Instruction {
+        Ok(Instruction {
             rd,
             rs1,
             rs2,
             imm,
             shamtw,
             op,
+            raw: inst,
+            pc: ZERO,
+            exception: None,
+        })
+    }
+}
+
+impl Decode {
+    /**What decode makes of the word `inst` fetched from `pc`. A bad instruction travels down
+    the pipeline and only traps if it gets to commit*/
+    pub fn latch(rom: &ROM, inst: U32, pc: U32) -> Instruction {
+        let mut instruction = if !rom.contains(pc) {
+            Instruction {
+                op: Operation::ILLEGAL,
+                exception: Some(Exception::InstructionAccessFault(pc)),
+                ..Default::default()
+            }
+        } else {
+            Decode::decode(inst).unwrap_or_else(|exception| Instruction {
+                op: Operation::ILLEGAL,
+                raw: inst,
+                exception: Some(exception),
+                ..Default::default()
+            })
+        };
+        instruction.pc = pc;
+        instruction
+    }
+
+    // The register file only takes a write at the end of the cycle, so the value write back is
+    // writing now is passed straight through
+    fn read(&self, index: U32) -> U32 {
+        match self.write_back.borrow().writes(index) {
+            Some(value) => value,
+            None => *self
+                .reg_file
+                .borrow_mut()
+                .get(index.0 as usize)
+                .output
+                .borrow(),
         }
     }
 }
 
 impl Chip for Decode {
     fn compute(&mut self) {
-        let inst = self.input.borrow().output.borrow().clone();
-
-        *self.out.input.borrow_mut() = self.decode(inst);
+        let bubble = !*self.valid.borrow() || *self.stall.borrow() || *self.flush.borrow();
+        let latched = match bubble {
+            true => IdEx::default(),
+            false => {
+                let rom = self.input.borrow();
+                let inst = *rom.output.borrow();
+                let instruction = Decode::latch(&rom, inst, *self.pc.borrow());
+                drop(rom);
+                IdEx {
+                    valid: true,
+                    rs1_value: self.read(instruction.rs1),
+                    rs2_value: self.read(instruction.rs2),
+                    instruction,
+                }
+            }
+        };
+        *self.out.input.borrow_mut() = latched;
         self.out.compute(); // compute karna na bhule
     }
 
@@ -214,6 +310,94 @@ impl Chip for Decode {
     }
 }
 
+// the rom is fetch's child, decode only reads its output. The fields of ID/EX are ports of
+// their own, `output.rd` and so on
+impl Probe for Decode {
+    fn name(&self) -> &'static str {
+        "decode"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        let output = &self.output;
+        vec![
+            Port::field("output.valid", 1, output, |id_ex| id_ex.valid as u64),
+            Port::field("output.pc", 32, output, |id_ex| {
+                id_ex.instruction.pc.0 as u64
+            }),
+            Port::field("output.raw", 32, output, |id_ex| {
+                id_ex.instruction.raw.0 as u64
+            }),
+            Port::field("output.rd", 5, output, |id_ex| {
+                id_ex.instruction.rd.0 as u64
+            }),
+            Port::field("output.rs1", 5, output, |id_ex| {
+                id_ex.instruction.rs1.0 as u64
+            }),
+            Port::field("output.rs2", 5, output, |id_ex| {
+                id_ex.instruction.rs2.0 as u64
+            }),
+            Port::field("output.imm", 32, output, |id_ex| {
+                id_ex.instruction.imm.0 as u64
+            }),
+            Port::field("output.rs1_value", 32, output, |id_ex| {
+                id_ex.rs1_value.0 as u64
+            }),
+            Port::field("output.rs2_value", 32, output, |id_ex| {
+                id_ex.rs2_value.0 as u64
+            }),
+        ]
+    }
+}
+
+impl Snapshot for Decode {
+    fn save(&self, out: &mut Writer) {
+        self.out.save(out);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        self.out.restore(input)
+    }
+}
+
+// Only the word, its address and why it traps are saved, the rest is decoded again
+impl Value for Instruction {
+    fn put(&self, out: &mut Writer) {
+        out.put(&self.raw);
+        out.put(&self.pc);
+        out.put(&self.exception);
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        let (raw, pc) = (input.get()?, input.get()?);
+        let mut instruction = Decode::decode(raw).unwrap_or_else(|_| Instruction {
+            op: Operation::ILLEGAL,
+            raw,
+            ..Default::default()
+        });
+        instruction.pc = pc;
+        instruction.exception = input.get()?;
+        Ok(instruction)
+    }
+}
+
+impl Value for IdEx {
+    fn put(&self, out: &mut Writer) {
+        out.put(&self.valid);
+        out.put(&self.instruction);
+        out.put(&self.rs1_value);
+        out.put(&self.rs2_value);
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            valid: input.get()?,
+            instruction: input.get()?,
+            rs1_value: input.get()?,
+            rs2_value: input.get()?,
+        })
+    }
+}
+
 #[derive(Default, Clone, Debug)]
 pub struct Instruction<T = U32> {
     pub rd: T,  // "rd", 11:7
@@ -222,9 +406,13 @@ pub struct Instruction<T = U32> {
     pub imm: T,
     pub shamtw: T, // "shamtw", 24:20
     pub op: Operation,
+    pub raw: T, // the undecoded instruction
+    pub pc: T,  // where the instruction was fetched from
+    // set when fetching or decoding failed, raised once the instruction reaches the memory stage
+    pub exception: Option<Exception>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub enum Operation {
     LUI,
     AUIPC,
@@ -244,6 +432,7 @@ pub enum Operation {
     SB,
     SH,
     SW,
+    #[default]
     ADDI,
     SLTI,
     SLTIU,
@@ -271,12 +460,123 @@ pub enum Operation {
     DIVU,
     REM,
     REMU,
+    FENCE,
     ECALL,
     EBREAK,
+    MRET,
+    WFI,
+    CSRRW,
+    CSRRS,
+    CSRRC,
+    CSRRWI,
+    CSRRSI,
+    CSRRCI,
+    // could not be fetched or decoded, the instruction's exception says why
+    ILLEGAL,
+}
+
+impl Operation {
+    /**Whether the instruction writes rd*/
+    pub fn writes_rd(&self) -> bool {
+        use Operation::*;
+        !matches!(
+            self,
+            BEQ | BNE
+                | BLT
+                | BGE
+                | BLTU
+                | BGEU
+                | SB
+                | SH
+                | SW
+                | FENCE
+                | ECALL
+                | EBREAK
+                | MRET
+                | WFI
+                | ILLEGAL
+        )
+    }
+
+    /**Whether the instruction reads rs1 and rs2*/
+    pub fn sources(&self) -> (bool, bool) {
+        use Operation::*;
+        match self {
+            LUI | AUIPC | JAL | CSRRWI | CSRRSI | CSRRCI | FENCE | ECALL | EBREAK | MRET | WFI
+            | ILLEGAL => (false, false),
+            BEQ | BNE | BLT | BGE | BLTU | BGEU | SB | SH | SW | ADD | SUB | SLL | SLT | SLTU
+            | XOR | SRL | SRA | OR | AND | MUL | MULH | MULHSU | MULHU | DIV | DIVU | REM
+            | REMU => (true, true),
+            _ => (true, false),
+        }
+    }
+
+    /**Whether the value for rd only comes out of the memory stage: loads and csr reads.
+    It is too late to forward to the next instruction, which has to stall a cycle*/
+    pub fn late(&self) -> bool {
+        use Operation::*;
+        matches!(
+            self,
+            LB | LH | LW | LBU | LHU | CSRRW | CSRRS | CSRRC | CSRRWI | CSRRSI | CSRRCI
+        )
+    }
 }
 
-impl Default for Operation {
-    fn default() -> Self {
-        Operation::ADDI
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bad_encodings_are_illegal() {
+        for raw in [
+            0x40129293, // slli with funct7 0100000
+            0x045282b3, // add with funct7 0000010
+            0x00003503, // ld
+            0x00a03023, // sd
+            0x00002063, // branch with funct3 010
+            0x00009067, // jalr with funct3 001
+            0x00000057, // an opcode outside RV32IM
+            0x00000000, 0xffffffff,
+        ] {
+            let raw = Wrapping(raw);
+            let decoded = Decode::decode(raw);
+            assert!(
+                matches!(decoded, Err(Exception::IllegalInstruction(bits)) if bits == raw),
+                "{:#010x}",
+                raw.0
+            );
+        }
+    }
+
+    #[test]
+    fn illegal_instructions_carry_their_bits() {
+        let rom = ROM::new(wire(ZERO), wire(ZERO), 4);
+        let raw = Wrapping(0x40129293);
+        let instruction = Decode::latch(&rom, raw, Wrapping(4));
+        assert!(matches!(instruction.op, Operation::ILLEGAL));
+        assert_eq!(instruction.raw, raw);
+        assert_eq!(instruction.pc, Wrapping(4));
+        assert_eq!(
+            instruction.exception,
+            Some(Exception::IllegalInstruction(raw))
+        );
+
+        // the good ones next to them still decode
+        let slli = Decode::latch(&rom, Wrapping(0x00129293), Wrapping(4));
+        assert!(matches!(slli.op, Operation::SLLI));
+        assert_eq!((slli.rd, slli.shamtw), (Wrapping(5), Wrapping(1)));
+        let srai = Decode::decode(Wrapping(0x4012d293)).unwrap();
+        assert!(matches!(srai.op, Operation::SRAI));
+    }
+
+    #[test]
+    fn fetching_outside_the_rom_faults() {
+        let rom = ROM::new(wire(ZERO), wire(ZERO), 4);
+        let instruction = Decode::latch(&rom, Wrapping(0x00100513), Wrapping(16));
+        assert!(matches!(instruction.op, Operation::ILLEGAL));
+        assert_eq!(
+            instruction.exception,
+            Some(Exception::InstructionAccessFault(Wrapping(16)))
+        );
     }
 }
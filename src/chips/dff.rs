@@ -1,4 +1,5 @@
 use crate::chips::{Chip, Wire};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Value, Writer};
 
 /**Modelling a D-Flip-Flop */
 /**Equivalent to reg data type in verilog*/
@@ -20,6 +21,12 @@ where
             output,
         }
     }
+
+    /**Hold `value` as if the last clock had latched it*/
+    pub fn set(&mut self, value: T) {
+        self.next_value = value.clone();
+        *self.output.borrow_mut() = value;
+    }
 }
 
 impl<T> Chip for DFF<T>
@@ -34,3 +41,17 @@ where
         *self.output.borrow_mut() = self.next_value.clone();
     }
 }
+
+impl<T> Snapshot for DFF<T>
+where
+    T: Clone + Default + Value,
+{
+    fn save(&self, out: &mut Writer) {
+        out.put(&*self.output.borrow());
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        self.set(input.get()?);
+        Ok(())
+    }
+}
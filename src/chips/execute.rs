@@ -1,263 +1,244 @@
-use crate::chips::decode::Instruction;
-use crate::chips::pc::PC;
-use crate::chips::register::Register;
-use crate::chips::register_file::RegFile;
-use crate::chips::rom::ROM;
-use crate::chips::{mux2, Chip, Wire, FOUR, ONE, U32, ZERO};
-use std::io;
-use std::io::Read;
+use crate::chips::decode::{IdEx, Instruction};
+use crate::chips::dff::DFF;
+use crate::chips::hazard::{FORWARD_EX_MEM, FORWARD_MEM_WB};
+use crate::chips::memory::MemWb;
+use crate::chips::probe::{Port, Probe};
+use crate::chips::{mux2, wire, Chip, Wire, FOUR, ONE, U32, ZERO};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Value, Writer};
 use std::num::Wrapping;
-use std::process::exit;
 
-use super::memory::Memory;
+/**The EX/MEM pipeline register*/
+#[derive(Clone, Debug, Default)]
+pub struct ExMem {
+    // false for a bubble
+    pub valid: bool,
+    pub instruction: Instruction,
+    // the operands after forwarding, rs2 is what a store writes
+    pub rs1_value: U32,
+    pub rs2_value: U32,
+    // the value for rd, or the address of a load or a store
+    pub result: U32,
+    // where a taken branch or jump goes, a misaligned one traps once it commits
+    pub target: Option<U32>,
+}
 
-pub struct Execute<T = U32> {
-    pub input: Wire<Instruction<T>>,
-    pub reg_file: Wire<RegFile<T>>,
-    pub memory: Memory<T>,
-    rom: Wire<ROM<T>>,
-    pc: Wire<PC<T>>,
-    rd: T, // this is the affected register value is stored to target it at clk
-    halt: usize,
+impl ExMem {
+    /**What the instruction writes to register `index`, if it writes it and the value is
+    already known*/
+    pub fn forwards(&self, index: U32) -> Option<U32> {
+        let op = &self.instruction.op;
+        (self.valid && op.writes_rd() && !op.late() && index != ZERO)
+            .then_some(self.result)
+            .filter(|_| self.instruction.rd == index)
+    }
 }
 
-#[derive(Default, Clone, Debug)]
-pub struct IOCode<T = U32> {
-    pub register: T,
-    pub address: T,
-    pub store: bool,
+/**The EX stage: the alu works out the result, the address of a load or a store and whether a
+branch is taken. Taken branches and jumps are resolved here and send fetch to their target*/
+pub struct Execute {
+    pub input: Wire<IdEx>,
+    // from the forwarding unit, where each operand comes from
+    pub forward_a: Wire<u8>,
+    pub forward_b: Wire<u8>,
+    // MEM/WB, the other place operands are forwarded from
+    pub write_back: Wire<MemWb>,
+    // the memory stage is taking a trap or redirecting, what execute latches is dropped
+    pub flush: Wire<bool>,
+    pub branch: Wire<bool>,
+    pub target: Wire<U32>,
+    pub output: Wire<ExMem>,
+    out: DFF<ExMem>,
 }
 
 impl Execute {
     pub fn new(
-        input: Wire<Instruction>,
-        memory: Memory<U32>,
-        rom: Wire<ROM>,
-        reg_file: Wire<RegFile<U32>>,
-        pc: Wire<PC>,
+        input: Wire<IdEx>,
+        output: Wire<ExMem>,
+        forward_a: Wire<u8>,
+        forward_b: Wire<u8>,
+        write_back: Wire<MemWb>,
+        flush: Wire<bool>,
     ) -> Self {
-        // connect the DFF output to Execute output interface
         Self {
             input,
-            memory,
-            rom,
-            reg_file,
-            pc,
-            rd: ZERO,
-            halt: 0,
+            forward_a,
+            forward_b,
+            write_back,
+            flush,
+            branch: wire(false),
+            target: wire(ZERO),
+            output: output.clone(),
+            out: DFF::new(wire(ExMem::default()), output),
         }
     }
 
-    fn execute(&mut self, instruction: Instruction) {
-        // store the value of rd for future use
-        self.rd = instruction.rd;
-
-        let mut reg_file = self.reg_file.borrow_mut();
-
-        let rs1 = reg_file
-            .get(instruction.rs1.0 as usize)
-            .output
-            .borrow()
-            .clone();
-        let rs2 = reg_file
-            .get(instruction.rs2.0 as usize)
-            .output
-            .borrow()
-            .clone();
-        let rd = reg_file.get(instruction.rd.0 as usize);
-
-        use crate::chips::decode::Operation::*;
+    /**Empty the stage, as if a bubble had been latched*/
+    pub fn flush(&mut self) {
+        self.out.set(ExMem::default());
+    }
 
-        let imm = match instruction.op {
-            ADD | SLT | SLTU | XOR | OR | AND | SLL | SRL | SRA => rs2,
-            _ => instruction.imm,
-        };
-        let shamt = match instruction.op {
-            ADD | SLT | SLTU | XOR | OR | AND | SLL | SRL | SRA => rs2,
-            _ => instruction.shamtw,
+    // The operand the forwarding unit picked: what was read in decode, or the newer value of an
+    // instruction ahead in the pipeline
+    fn operand(&self, select: &Wire<u8>, index: U32, read: U32) -> U32 {
+        let forwarded = match *select.borrow() {
+            FORWARD_EX_MEM => self.output.borrow().forwards(index),
+            FORWARD_MEM_WB => self.write_back.borrow().writes(index),
+            _ => None,
         };
+        forwarded.unwrap_or(read)
+    }
+}
 
-        let pc_addr = self.pc.borrow().output.borrow().clone();
-        let target_addr = pc_addr + instruction.imm - FOUR - FOUR;
-
-        // Set the load bits accordingly
-        match instruction.op {
-            JAL | JALR => {
-                *rd.load.borrow_mut() = true;
-                *self.pc.borrow_mut().load.borrow_mut() = true
-            }
-            AUIPC | BEQ | BNE | BLT | BGE | BLTU | BGEU => {
-                *rd.load.borrow_mut() = false;
-                *self.pc.borrow_mut().load.borrow_mut() = true
-            }
-            LB | LH | LW | LBU | LHU | LUI | ADDI | SLTI | SLTIU | XORI | ORI | ANDI | SLLI
-            | SRLI | SRAI | ADD | SUB | SLL | SLT | SLTU | XOR | SRL | SRA | OR | AND | MUL
-            | MULH | MULHSU | MULHU | DIV | DIVU | REM | REMU => {
-                *rd.load.borrow_mut() = true;
-                *self.pc.borrow_mut().load.borrow_mut() = false
-            }
-            _ => {
-                *rd.load.borrow_mut() = false;
-                *self.pc.borrow_mut().load.borrow_mut() = false
-            }
-        }
-
-        // ARITHMETIC and LOGIC INSTRUCTIONS
-        *rd.input.borrow_mut() = match instruction.op {
-            ADDI | ADD => rs1 + imm,
-            SLTI | SLT => mux2(ZERO, ONE, (rs1.0 as i32) < (imm.0 as i32)),
-            SLTIU | SLTU => mux2(ZERO, ONE, rs1 < imm),
-            XORI | XOR => rs1 ^ imm,
-            ORI | OR => rs1 | imm,
-            ANDI | AND => rs1 & imm,
-            SLLI | SLL => rs1 << shamt.0 as usize,
-            SRLI | SRL => rs1 >> shamt.0 as usize,
-            SRAI | SRA => Wrapping(((rs1.0 as i32) >> shamt.0) as u32),
-            SUB => rs1 - rs2,
-            MUL => rs1 * rs2,
-            MULH => Wrapping(((((rs1.0 as i32) as i64) * ((rs2.0 as i32) as i64)) >> 32) as u32),
-            MULHSU => Wrapping(((((rs1.0 as i32) as i64) * (rs2.0 as i64)) >> 32) as u32),
-            MULHU => Wrapping((((rs1.0 as i64) * (rs2.0 as i64)) >> 32) as u32),
-            DIV => Wrapping((rs1.0 as i32).checked_div(rs2.0 as i32).unwrap_or(i32::MAX) as u32),
-            DIVU => Wrapping(rs1.0.checked_div(rs2.0).unwrap_or(u32::MAX)),
-            REM => Wrapping(
-                (rs1.0 as i32)
-                    .checked_rem(rs2.0 as i32)
-                    .unwrap_or(rs1.0 as i32) as u32,
-            ),
-            REMU => Wrapping(rs1.0.checked_rem(rs2.0).unwrap_or(rs1.0)),
-            _ => ZERO,
-        };
+/**What the alu makes of an instruction and its operands, the branch target when it is taken*/
+pub fn alu(instruction: &Instruction, rs1: U32, rs2: U32) -> (U32, Option<U32>) {
+    use crate::chips::decode::Operation::*;
+
+    let pc = instruction.pc;
+    let imm = match instruction.op {
+        ADD | SLT | SLTU | XOR | OR | AND | SLL | SRL | SRA => rs2,
+        _ => instruction.imm,
+    };
+    let shamt = match instruction.op {
+        ADD | SLT | SLTU | XOR | OR | AND | SLL | SRL | SRA => rs2,
+        _ => instruction.shamtw,
+    };
+
+    // CONTROL TRANSFER, the next pc if the instruction changes the flow
+    let target = match instruction.op {
+        BEQ => (rs1 == rs2).then_some(pc + imm),
+        BNE => (rs1 != rs2).then_some(pc + imm),
+        BLT => ((rs1.0 as i32) < (rs2.0 as i32)).then_some(pc + imm),
+        BGE => ((rs1.0 as i32) >= (rs2.0 as i32)).then_some(pc + imm),
+        BLTU => (rs1 < rs2).then_some(pc + imm),
+        BGEU => (rs1 >= rs2).then_some(pc + imm),
+        JAL => Some(pc + imm),
+        JALR => Some((rs1 + imm) & !ONE),
+        _ => None,
+    };
+
+    // ARITHMETIC and LOGIC INSTRUCTIONS
+    let result = match instruction.op {
+        ADDI | ADD => rs1 + imm,
+        SLTI | SLT => mux2(ZERO, ONE, (rs1.0 as i32) < (imm.0 as i32)),
+        SLTIU | SLTU => mux2(ZERO, ONE, rs1 < imm),
+        XORI | XOR => rs1 ^ imm,
+        ORI | OR => rs1 | imm,
+        ANDI | AND => rs1 & imm,
+        SLLI | SLL => rs1 << (shamt.0 & 0x1F) as usize,
+        SRLI | SRL => rs1 >> (shamt.0 & 0x1F) as usize,
+        SRAI | SRA => Wrapping(((rs1.0 as i32) >> (shamt.0 & 0x1F)) as u32),
+        SUB => rs1 - rs2,
+        MUL => rs1 * rs2,
+        MULH => Wrapping(((((rs1.0 as i32) as i64) * ((rs2.0 as i32) as i64)) >> 32) as u32),
+        MULHSU => Wrapping(((((rs1.0 as i32) as i64) * (rs2.0 as i64)) >> 32) as u32),
+        MULHU => Wrapping((((rs1.0 as u64) * (rs2.0 as u64)) >> 32) as u32),
+        DIV => Wrapping((rs1.0 as i32).checked_div(rs2.0 as i32).unwrap_or(i32::MAX) as u32),
+        DIVU => Wrapping(rs1.0.checked_div(rs2.0).unwrap_or(u32::MAX)),
+        REM => Wrapping(
+            (rs1.0 as i32)
+                .checked_rem(rs2.0 as i32)
+                .unwrap_or(rs1.0 as i32) as u32,
+        ),
+        REMU => Wrapping(rs1.0.checked_rem(rs2.0).unwrap_or(rs1.0)),
+        // the memory stage gets the address
+        LB | LH | LW | LBU | LHU | SB | SH | SW => rs1 + imm,
+        LUI => imm,
+        AUIPC => pc + imm,
+        JAL | JALR => pc + FOUR,
+        _ => ZERO,
+    };
+    (result, target)
+}
 
-        // BRANCH INSTRUCTIONS
-        let final_addr = match instruction.op {
-            BEQ => mux2(pc_addr, target_addr, rs1 == rs2),
-            BNE => mux2(pc_addr, target_addr, rs1 != rs2),
-            BLT => mux2(pc_addr, target_addr, (rs1.0 as i32) < (rs2.0 as i32)),
-            BGE => mux2(pc_addr, target_addr, (rs1.0 as i32) >= (rs2.0 as i32)),
-            BLTU => mux2(pc_addr, target_addr, rs1 < rs2),
-            BGEU => mux2(pc_addr, target_addr, rs1 >= rs2),
-            _ => pc_addr,
+impl Chip for Execute {
+    fn compute(&mut self) {
+        let id_ex = self.input.borrow().clone();
+        let instruction = id_ex.instruction;
+        let rs1 = self.operand(&self.forward_a, instruction.rs1, id_ex.rs1_value);
+        let rs2 = self.operand(&self.forward_b, instruction.rs2, id_ex.rs2_value);
+        let (result, target) = alu(&instruction, rs1, rs2);
+
+        // only an aligned target is followed, a misaligned one is left to trap in order
+        let flush = *self.flush.borrow();
+        let taken = target.filter(|target| id_ex.valid && !flush && target.0 % 4 == 0);
+        *self.branch.borrow_mut() = taken.is_some();
+        *self.target.borrow_mut() = taken.unwrap_or_default();
+
+        *self.out.input.borrow_mut() = match id_ex.valid && !flush {
+            true => ExMem {
+                valid: true,
+                instruction,
+                rs1_value: rs1,
+                rs2_value: rs2,
+                result,
+                target,
+            },
+            false => ExMem::default(),
         };
-        if final_addr % FOUR != ZERO {
-            panic!("address-misaligned")
-        }
-        if pc_addr != final_addr {
-            self.halt = 2;
-        }
-        *self.pc.borrow_mut().input.borrow_mut() = final_addr;
+        self.out.compute();
+    }
 
-        let imm = instruction.imm;
-        match instruction.op {
-            LUI => *rd.input.borrow_mut() = imm,
-            AUIPC => {
-                *self.pc.borrow_mut().input.borrow_mut() = pc_addr + imm - FOUR - FOUR;
-                self.halt = 2;
-            }
-            JAL => {
-                *rd.input.borrow_mut() = pc_addr - FOUR;
-                *self.pc.borrow_mut().input.borrow_mut() =
-                    self.pc.borrow().output.borrow().clone() + instruction.imm - FOUR - FOUR;
-                self.halt = 2;
-            }
-            JALR => {
-                *rd.input.borrow_mut() = pc_addr - FOUR;
-                *self.pc.borrow_mut().input.borrow_mut() = (rs1 + imm >> 1) << 1;
-                self.halt = 2;
-            }
-            LB | LH | LW | LBU | LHU => {
-                *self.memory.address.borrow_mut() = rs1 + imm;
-                *self.memory.load.borrow_mut() = false;
-                self.memory.compute();
-                self.memory.clk();
-                *rd.input.borrow_mut() = self.memory.output.borrow().clone();
-            }
-            SB | SH | SW => {
-                *self.memory.address.borrow_mut() = rs1 + imm;
-                *self.memory.load.borrow_mut() = true;
-                *self.memory.input.borrow_mut() = rs2;
-                self.memory.compute();
-                self.memory.clk();
-            }
-            _ => {}
-        }
+    fn clk(&mut self) {
+        self.out.clk();
+    }
+}
 
-        rd.compute();
+impl Probe for Execute {
+    fn name(&self) -> &'static str {
+        "execute"
+    }
 
-        match instruction.op {
-            ECALL => {
-                let a7 = reg_file.get(17).output.borrow().clone();
-                let a1 = reg_file.get(11).output.borrow().clone();
-                let a0 = reg_file.get(10);
-                ecall(a7, a0, a1, self.rom.clone());
-            }
-            EBREAK => {
-                println!("ebreak not implemented yet")
-            }
-            _ => {}
-        }
+    fn ports(&self) -> Vec<Port> {
+        let output = &self.output;
+        vec![
+            Port::wire("branch", &self.branch),
+            Port::wire("target", &self.target),
+            Port::field("output.valid", 1, output, |ex_mem| ex_mem.valid as u64),
+            Port::field("output.pc", 32, output, |ex_mem| {
+                ex_mem.instruction.pc.0 as u64
+            }),
+            Port::field("output.raw", 32, output, |ex_mem| {
+                ex_mem.instruction.raw.0 as u64
+            }),
+            Port::field("output.rd", 5, output, |ex_mem| {
+                ex_mem.instruction.rd.0 as u64
+            }),
+            Port::field("output.result", 32, output, |ex_mem| ex_mem.result.0 as u64),
+            Port::field("output.rs2_value", 32, output, |ex_mem| {
+                ex_mem.rs2_value.0 as u64
+            }),
+        ]
     }
 }
 
-impl Chip for Execute {
-    // #[rustfmt::skip]
-    fn compute(&mut self) {
-        let instruction = self.input.borrow().clone();
-        // println!("decoded instruction: {:?}", instruction);
-
-        if self.halt != 0 {
-            // println!("halted");
-            self.halt -= 1;
-            return;
-        }
-        self.execute(instruction)
+impl Snapshot for Execute {
+    fn save(&self, out: &mut Writer) {
+        self.out.save(out);
     }
 
-    fn clk(&mut self) {
-        // Get the register that got updated
-        let mut reg_file = self.reg_file.borrow_mut();
-        let rd = reg_file.get(self.rd.0 as usize);
-        // Clock the register and self output
-        rd.clk();
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        self.out.restore(input)
     }
 }
 
-fn ecall(a7: U32, a0: &mut Register<U32>, a1: U32, rom: Wire<ROM>) {
-    // handle syscall
-    match a7.0 {
-        1 => {
-            // read char
-            let mut buf = [0; 1];
-            io::stdin().read_exact(&mut buf).unwrap();
-            *a0.input.borrow_mut() = Wrapping(buf[0] as u32);
-            *a0.load.borrow_mut() = true;
-            a0.compute();
-            a0.clk();
-        }
-        2 => {
-            // print char
-            let val = a0.output.borrow().clone();
-            print!("{}", String::from_utf8_lossy(&[val.0 as u8]))
-        }
-        3 => {
-            // read string
-        }
-        4 => {
-            // should be encoded as lui no op
-            // lui x0, `data`
-            // print string
-            let mut string = vec![];
-            let a0 = a0.output.borrow().clone();
-            for i in 0..a1.0 {
-                let val = rom.borrow().peek(a0 + Wrapping(4 * i));
-                string.push((val.0 >> 12) as u8);
-            }
-            print!("{}", String::from_utf8_lossy(&string))
-        }
-        10 => {
-            let val = a0.output.borrow().clone();
-            exit(val.0 as i32)
-        }
-        _ => {}
+impl Value for ExMem {
+    fn put(&self, out: &mut Writer) {
+        out.put(&self.valid);
+        out.put(&self.instruction);
+        out.put(&self.rs1_value);
+        out.put(&self.rs2_value);
+        out.put(&self.result);
+        out.put(&self.target);
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            valid: input.get()?,
+            instruction: input.get()?,
+            rs1_value: input.get()?,
+            rs2_value: input.get()?,
+            result: input.get()?,
+            target: input.get()?,
+        })
     }
 }
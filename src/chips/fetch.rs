@@ -1,30 +1,108 @@
+use crate::chips::dff::DFF;
+use crate::chips::probe::{Port, Probe};
 use crate::chips::rom::ROM;
-use crate::chips::{Chip, Wire, U32};
+use crate::chips::{mux2, wire, Chip, Wire, U32};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
 
+/**The IF stage. The rom reads the word at the pc on the clock edge, so its output together
+with `output` and `valid` is the IF/ID pipeline register*/
 pub struct Fetch<T = U32> {
     pub pc: Wire<T>,
     pub rom: Wire<ROM<T>>,
+    // address of the instruction on the rom output, delayed a cycle like the rom
+    pub output: Wire<T>,
+    // the word on the rom output is an instruction and not a bubble
+    pub valid: Wire<bool>,
+    // from the hazard unit: a stall fetches the word in IF/ID again, a flush drops it
+    pub stall: Wire<bool>,
+    pub flush: Wire<bool>,
+    // the address the rom reads this cycle
+    address: Wire<T>,
+    pc_out: DFF<T>,
+    valid_out: DFF<bool>,
 }
 
 impl<T> Fetch<T>
 where
-    T: Copy,
+    T: Copy + Default,
 {
     // Give the loaded rom to fetch
-    pub fn new(pc: Wire<T>, rom: Wire<ROM<T>>) -> Self {
-        Self { pc, rom }
+    pub fn new(pc: Wire<T>, rom: Wire<ROM<T>>, stall: Wire<bool>, flush: Wire<bool>) -> Self {
+        let output = wire(T::default());
+        let address = wire(T::default());
+        // the pipeline is empty at reset, the first word is read on the first clock
+        let valid = wire(false);
+        Self {
+            pc,
+            rom,
+            output: output.clone(),
+            valid: valid.clone(),
+            stall,
+            flush,
+            address: address.clone(),
+            pc_out: DFF::new(address, output),
+            valid_out: DFF::new(wire(false), valid),
+        }
+    }
+
+    /**Drop the word in IF/ID*/
+    pub fn flush(&mut self) {
+        self.valid_out.set(false);
     }
 }
 
 impl Chip for Fetch<U32> {
     fn compute(&mut self) {
-        // set the address of rom
-        *self.rom.borrow_mut().address.borrow_mut() = self.pc.borrow().clone();
+        // while stalled the rom reads the word it already holds once more
+        let stall = *self.stall.borrow();
+        let address = mux2(*self.pc.borrow(), *self.output.borrow(), stall);
+        *self.address.borrow_mut() = address;
+        *self.rom.borrow_mut().address.borrow_mut() = address;
+        let valid = mux2(true, *self.valid.borrow(), stall);
+        *self.valid_out.input.borrow_mut() = valid && !*self.flush.borrow();
         self.rom.borrow_mut().compute();
+        self.pc_out.compute();
+        self.valid_out.compute();
     }
 
     fn clk(&mut self) {
         // since the output is already piped through the rom clocking the rom should do the job
-        self.rom.borrow_mut().clk()
+        self.rom.borrow_mut().clk();
+        self.pc_out.clk();
+        self.valid_out.clk();
+    }
+}
+
+impl Probe for Fetch<U32> {
+    fn name(&self) -> &'static str {
+        "fetch"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("pc", &self.pc),
+            Port::wire("output", &self.output),
+            Port::wire("instruction", &self.rom.borrow().output),
+            Port::wire("valid", &self.valid),
+        ]
+    }
+
+    fn children(&self, visit: &mut dyn FnMut(&str, &dyn Probe)) {
+        visit("rom", &*self.rom.borrow());
+    }
+}
+
+// the word on the rom output is the instruction fetch hands decode next cycle
+impl Snapshot for Fetch<U32> {
+    fn save(&self, out: &mut Writer) {
+        self.rom.borrow().save(out);
+        self.pc_out.save(out);
+        self.valid_out.save(out);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        self.rom.borrow_mut().restore(input)?;
+        self.pc_out.restore(input)?;
+        self.valid_out.restore(input)
     }
 }
@@ -0,0 +1,378 @@
+use crate::chips::decode::{Decode, IdEx};
+use crate::chips::execute::ExMem;
+use crate::chips::memory::MemWb;
+use crate::chips::pc::PC;
+use crate::chips::probe::{Port, Probe};
+use crate::chips::rom::ROM;
+use crate::chips::{mux2, Chip, Wire, U32, ZERO};
+
+/**Forward select: the operand read in decode is the one to use*/
+pub const FORWARD_NONE: u8 = 0b00;
+/**Forward select: take the result of the instruction in EX/MEM, one ahead*/
+pub const FORWARD_EX_MEM: u8 = 0b10;
+/**Forward select: take the value in MEM/WB, two ahead*/
+pub const FORWARD_MEM_WB: u8 = 0b01;
+
+/**The forwarding unit. When an instruction in ID/EX reads a register one still ahead of it is
+about to write, execute takes the newer value from EX/MEM or MEM/WB instead of the stale one
+decode read. The nearest writer wins, it is the latest value of the register*/
+pub struct Forward {
+    pub id_ex: Wire<IdEx>,
+    pub ex_mem: Wire<ExMem>,
+    pub mem_wb: Wire<MemWb>,
+    // the selects for execute's rs1 and rs2 operands
+    pub a: Wire<u8>,
+    pub b: Wire<u8>,
+}
+
+impl Forward {
+    pub fn new(
+        id_ex: Wire<IdEx>,
+        ex_mem: Wire<ExMem>,
+        mem_wb: Wire<MemWb>,
+        a: Wire<u8>,
+        b: Wire<u8>,
+    ) -> Self {
+        Self {
+            id_ex,
+            ex_mem,
+            mem_wb,
+            a,
+            b,
+        }
+    }
+
+    fn select(&self, reads: bool, index: U32) -> u8 {
+        if !reads {
+            FORWARD_NONE
+        } else if self.ex_mem.borrow().forwards(index).is_some() {
+            FORWARD_EX_MEM
+        } else if self.mem_wb.borrow().writes(index).is_some() {
+            FORWARD_MEM_WB
+        } else {
+            FORWARD_NONE
+        }
+    }
+}
+
+impl Forward {
+    /**Where the instruction in ID/EX takes its rs1 and rs2 operands from*/
+    pub fn selects(&self) -> (u8, u8) {
+        let id_ex = self.id_ex.borrow();
+        let (reads_rs1, reads_rs2) = id_ex.instruction.op.sources();
+        (
+            self.select(id_ex.valid && reads_rs1, id_ex.instruction.rs1),
+            self.select(id_ex.valid && reads_rs2, id_ex.instruction.rs2),
+        )
+    }
+}
+
+impl Chip for Forward {
+    fn compute(&mut self) {
+        let (a, b) = self.selects();
+        *self.a.borrow_mut() = a;
+        *self.b.borrow_mut() = b;
+    }
+}
+
+/**The hazard detection unit.
+
+A load or a csr read only has its value once the memory stage is done with it, too late for
+the instruction right behind it in execute. When the instruction in IF/ID reads what the one in
+ID/EX produces late, it is held in IF/ID for a cycle, the pc doesn't move and a bubble goes
+down the pipeline in its place. A cycle later the value is in MEM/WB and gets forwarded.
+
+It also steers the pc. A taken branch or jump sits in ID/EX while execute resolves it, and sends
+the pc to the target. The two instructions behind it are dropped: the one in IF/ID being decoded
+and the one being fetched, so a bubble goes into ID/EX and IF/ID on the clock. A trap, an mret
+or an ecall in the memory stage sends it to the handler or wherever the instruction asked for,
+dropping everything younger and winning over execute, whose branch is younger and flushed with
+the rest*/
+pub struct Hazard {
+    // IF/ID
+    pub rom: Wire<ROM>,
+    pub valid: Wire<bool>,
+    pub id_ex: Wire<IdEx>,
+    pub branch: Wire<bool>,
+    pub branch_target: Wire<U32>,
+    pub redirect: Wire<bool>,
+    pub redirect_target: Wire<U32>,
+    pub pc: Wire<PC>,
+    pub stall: Wire<bool>,
+    pub flush: Wire<bool>,
+}
+
+impl Hazard {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        rom: Wire<ROM>,
+        valid: Wire<bool>,
+        id_ex: Wire<IdEx>,
+        branch: Wire<bool>,
+        branch_target: Wire<U32>,
+        redirect: Wire<bool>,
+        redirect_target: Wire<U32>,
+        pc: Wire<PC>,
+        stall: Wire<bool>,
+        flush: Wire<bool>,
+    ) -> Self {
+        Self {
+            rom,
+            valid,
+            id_ex,
+            branch,
+            branch_target,
+            redirect,
+            redirect_target,
+            pc,
+            stall,
+            flush,
+        }
+    }
+
+    /**Whether the instruction in IF/ID needs a value the one in ID/EX only has after memory*/
+    pub fn load_use(&self) -> bool {
+        let id_ex = self.id_ex.borrow();
+        let producer = &id_ex.instruction;
+        if !*self.valid.borrow() || !id_ex.valid || !producer.op.late() || producer.rd == ZERO {
+            return false;
+        }
+        // a word that doesn't decode won't read anything, it traps
+        let raw = *self.rom.borrow().output.borrow();
+        let Ok(consumer) = Decode::decode(raw) else {
+            return false;
+        };
+        let (reads_rs1, reads_rs2) = consumer.op.sources();
+        reads_rs1 && consumer.rs1 == producer.rd || reads_rs2 && consumer.rs2 == producer.rd
+    }
+}
+
+impl Chip for Hazard {
+    fn compute(&mut self) {
+        let redirect = *self.redirect.borrow();
+        let flush = redirect || *self.branch.borrow();
+        // nothing to wait for when the instructions are thrown away anyway
+        let stall = !flush && self.load_use();
+        *self.stall.borrow_mut() = stall;
+        *self.flush.borrow_mut() = flush;
+
+        let target = mux2(
+            *self.branch_target.borrow(),
+            *self.redirect_target.borrow(),
+            redirect,
+        );
+        let pc = self.pc.borrow();
+        *pc.input.borrow_mut() = target;
+        *pc.load.borrow_mut() = flush;
+        *pc.inc.borrow_mut() = !stall;
+    }
+}
+
+impl Probe for Hazard {
+    fn name(&self) -> &'static str {
+        "hazard"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("stall", &self.stall),
+            Port::wire("flush", &self.flush),
+        ]
+    }
+}
+
+impl Probe for Forward {
+    fn name(&self) -> &'static str {
+        "forward"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![Port::wire("a", &self.a), Port::wire("b", &self.b)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asm::assemble;
+    use crate::chips::cpu::{Model, CPU};
+    use crate::chips::csr_file::{MCAUSE, MEPC, MSTATUS, MTVAL};
+    use crate::chips::testing;
+    use crate::chips::ZERO;
+    use std::num::Wrapping;
+
+    fn machine(program: &str, model: Model) -> CPU {
+        testing::machine(program, 0, 64, model)
+    }
+
+    // Cycles until `count` instructions have retired
+    fn run(cpu: &mut CPU, count: u64) -> u64 {
+        while cpu.retired() < count {
+            cpu.step().unwrap();
+        }
+        cpu.memory.cycle
+    }
+
+    #[test]
+    fn forwarding_needs_no_stall() {
+        let mut cpu = machine(
+            "
+            li   t0, 5
+            add  t1, t0, t0
+            add  t2, t1, t0
+            sub  t3, t2, t1
+        ",
+            Model::Pipelined,
+        );
+        // three cycles to fill the pipeline up to memory, then one instruction a cycle
+        assert_eq!(run(&mut cpu, 4), 3 + 4);
+        let registers = [5, 6, 7, 28].map(|index| cpu.register(index).0);
+        assert_eq!(registers, [5, 10, 15, 5]);
+    }
+
+    #[test]
+    fn load_use_stalls_a_cycle() {
+        let mut cpu = machine(
+            "
+            li   t0, 42
+            sw   t0, 8(zero)
+            lw   t1, 8(zero)
+            addi t2, t1, 1
+            csrw mscratch, t2
+            csrr t3, mscratch
+            add  t4, t3, t3
+        ",
+            Model::Pipelined,
+        );
+        // the addi waits for the load and the add for the csr read
+        assert_eq!(run(&mut cpu, 7), 3 + 7 + 2);
+        assert_eq!(cpu.register(7), Wrapping(43));
+        assert_eq!(cpu.register(29), Wrapping(86));
+    }
+
+    #[test]
+    fn taken_branches_flush_two() {
+        let mut cpu = machine(
+            "
+            li   t0, 1
+            j    skip
+            li   t0, 2
+            li   t0, 3
+        skip:
+            addi t0, t0, 10
+        ",
+            Model::Pipelined,
+        );
+        // the two instructions fetched behind the jump are thrown away
+        assert_eq!(run(&mut cpu, 3), 3 + 3 + 2);
+        assert_eq!(cpu.register(5), Wrapping(11));
+    }
+
+    #[test]
+    fn the_reference_core_agrees() {
+        let program = "
+            li   t0, 7
+            sw   t0, 12(zero)
+            lw   t1, 12(zero)
+            add  t2, t1, t1
+            bne  t2, t0, skip
+            li   t2, 0
+        skip:
+            csrw mscratch, t2
+            csrr t3, mscratch
+            sub  t4, t3, t1
+        ";
+        let mut pipelined = machine(program, Model::Pipelined);
+        let mut reference = machine(program, Model::SingleCycle);
+        run(&mut pipelined, 8);
+        // no fill, no stalls, no flushes: a clock per instruction
+        assert_eq!(run(&mut reference, 8), 8);
+        for index in 1..32 {
+            assert_eq!(pipelined.register(index), reference.register(index));
+        }
+        assert_eq!(reference.register(29), Wrapping(7));
+    }
+
+    #[test]
+    fn the_reference_core_agrees_on_redirects() {
+        // calls through jal and jalr, a loop of taken branches, an illegal instruction the
+        // handler skips and a timer interrupt that ends the spin
+        let program = "
+            la   t0, handler
+            csrw mtvec, t0
+            jal  ra, sub
+            la   t0, sub2
+            jalr ra, 0(t0)
+            li   t1, 3
+        again:
+            addi t1, t1, -1
+            addi s3, s3, 5
+            bnez t1, again
+        illegal:
+            .word 0
+            li   t0, 0x2004000
+            sw   zero, 0(t0)
+            sw   zero, 4(t0)
+            li   t0, 0x80
+            csrw mie, t0
+            csrsi mstatus, 8
+        spin:
+            beqz s1, spin
+        done:
+            nop
+        sub:
+            addi s0, s0, 1
+            ret
+        sub2:
+            addi s0, s0, 10
+            jalr zero, 0(ra)
+        handler:
+            csrr s4, mcause
+            csrr s5, mepc
+            bltz s4, timer
+            mv   s6, s4
+            mv   s7, s5
+            addi s5, s5, 4
+            csrw mepc, s5
+            mret
+        timer:
+            li   t0, 0x2004000
+            li   t1, -1
+            sw   t1, 4(t0)
+            li   s1, 1
+            mret
+        ";
+        let symbols = assemble(program, ZERO, ZERO).unwrap().symbols;
+        let label = |name: &str| {
+            let symbol = symbols.iter().find(|symbol| symbol.name == name);
+            symbol.unwrap().address
+        };
+        let finish = |model| {
+            let mut cpu = machine(program, model);
+            for _ in 0..1000 {
+                if cpu.pc() == label("done") {
+                    return cpu;
+                }
+                cpu.step().unwrap();
+            }
+            panic!("{model:?} never got done");
+        };
+        let pipelined = finish(Model::Pipelined);
+        let reference = finish(Model::SingleCycle);
+        for index in 1..32 {
+            assert_eq!(
+                pipelined.register(index),
+                reference.register(index),
+                "x{index}"
+            );
+        }
+        for csr in [MSTATUS, MEPC, MCAUSE, MTVAL] {
+            assert_eq!(pipelined.csr(csr), reference.csr(csr), "csr {csr:#x}");
+        }
+        let register = |index| reference.register(index).0;
+        // s0 from both calls, s3 from all three rounds
+        assert_eq!((register(8), register(19)), (11, 15));
+        // the illegal instruction's cause and pc, then the timer's
+        assert_eq!((register(22), register(23)), (2, label("illegal").0));
+        assert_eq!((register(20), register(21)), (1 << 31 | 7, label("spin").0));
+    }
+}
@@ -0,0 +1,402 @@
+use crate::chips::bus::Device;
+use crate::chips::memory::{lane_mask, strobe, Width};
+use crate::chips::probe::{Port, Probe};
+use crate::chips::register::Register;
+use crate::chips::{wire, Chip, Wire, U32, ZERO};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
+use std::collections::VecDeque;
+use std::num::Wrapping;
+
+/**Where the input device sits in the memory map and its plic source*/
+pub const INPUT_BASE: u32 = 0x1000_1000;
+pub const INPUT_SIZE: u32 = 0x100;
+pub const INPUT_IRQ: usize = 11;
+
+// register offsets
+const STATUS: u32 = 0x0;
+const KEY: u32 = 0x4;
+const POINTER: u32 = 0x8;
+const BUTTONS: u32 = 0xC;
+const CONTROL: u32 = 0x10;
+
+const STATUS_KEY: u32 = 1 << 0;
+const STATUS_POINTER: u32 = 1 << 1;
+const KEY_PRESSED: u32 = 1 << 31;
+const CONTROL_KEY_INTERRUPT: u32 = 1 << 0;
+const CONTROL_POINTER_INTERRUPT: u32 = 1 << 1;
+const CONTROL_MASK: U32 = Wrapping(CONTROL_KEY_INTERRUPT | CONTROL_POINTER_INTERRUPT);
+
+pub const BUTTON_LEFT: u8 = 1 << 0;
+pub const BUTTON_MIDDLE: u8 = 1 << 1;
+pub const BUTTON_RIGHT: u8 = 1 << 2;
+
+// key codes: printable keys are their lower case ascii code, the rest live above 0xFF
+pub const KEY_BACKSPACE: u16 = 0x08;
+pub const KEY_TAB: u16 = 0x09;
+pub const KEY_ENTER: u16 = 0x0D;
+pub const KEY_ESCAPE: u16 = 0x1B;
+pub const KEY_LEFT: u16 = 0x100;
+pub const KEY_UP: u16 = 0x101;
+pub const KEY_RIGHT: u16 = 0x102;
+pub const KEY_DOWN: u16 = 0x103;
+pub const KEY_SHIFT: u16 = 0x110;
+pub const KEY_CONTROL: u16 = 0x111;
+pub const KEY_ALT: u16 = 0x112;
+
+const FIFO_DEPTH: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputEvent {
+    Key { code: u16, pressed: bool },
+    // position in screen pixels and the buttons held down
+    Pointer { x: u16, y: u16, buttons: u8 },
+}
+
+/**Where key presses and pointer movement come from*/
+pub trait InputSource {
+    /**The next event that has happened by clock cycle `cycle`, None if there is none yet*/
+    fn poll(&mut self, cycle: u64) -> Option<InputEvent>;
+}
+
+/**Nothing is ever typed*/
+pub struct NoInput;
+
+impl InputSource for NoInput {
+    fn poll(&mut self, _cycle: u64) -> Option<InputEvent> {
+        None
+    }
+}
+
+/**Keyboard and pointer for the screen. Key events wait in a 16 entry fifo, the pointer is
+just its latest position and buttons.
+
+STATUS  (0x00, read)  bit 0 a key event is waiting, bit 1 the pointer changed since the last
+                      STATUS read, which clears it
+KEY     (0x04, read)  pops the oldest key event: bit 31 pressed, bits 15..0 key code, 0 if none
+POINTER (0x08, read)  x in bits 15..0, y in bits 31..16
+BUTTONS (0x0C, read)  bit 0 left, bit 1 middle, bit 2 right
+CONTROL (0x10, r/w)   bit 0 interrupt while a key event waits, bit 1 interrupt on pointer changes*/
+pub struct Input {
+    pub input: Wire<U32>,
+    pub output: Wire<U32>,
+    // offset from INPUT_BASE
+    pub address: Wire<U32>,
+    pub load: Wire<bool>,
+    pub strobe: Wire<u8>,
+    // high for the cycle the bus accesses the device, reading KEY and STATUS has side effects
+    pub select: Wire<bool>,
+    pub interrupt: Wire<bool>,
+    source: Box<dyn InputSource>,
+    control: Register<U32>,
+    keys: VecDeque<u32>,
+    pointer: (u16, u16),
+    buttons: u8,
+    pointer_changed: bool,
+    cycle: u64,
+    // effects of this cycle's access, applied at clk
+    pop: bool,
+    clear_pointer_changed: bool,
+}
+
+impl Input {
+    pub fn new(source: Box<dyn InputSource>, interrupt: Wire<bool>) -> Self {
+        Self {
+            input: wire(ZERO),
+            output: wire(ZERO),
+            address: wire(ZERO),
+            load: wire(false),
+            strobe: wire(0),
+            select: wire(false),
+            interrupt,
+            source,
+            control: Register::default(),
+            keys: VecDeque::with_capacity(FIFO_DEPTH),
+            pointer: (0, 0),
+            buttons: 0,
+            pointer_changed: false,
+            cycle: 0,
+            pop: false,
+            clear_pointer_changed: false,
+        }
+    }
+
+    fn status(&self) -> u32 {
+        let key = match self.keys.is_empty() {
+            true => 0,
+            false => STATUS_KEY,
+        };
+        let pointer = match self.pointer_changed {
+            true => STATUS_POINTER,
+            false => 0,
+        };
+        key | pointer
+    }
+
+    fn receive(&mut self, event: InputEvent) {
+        match event {
+            InputEvent::Key { code, pressed } => {
+                if self.keys.len() == FIFO_DEPTH {
+                    log::debug!("input fifo full, dropping key {code:#x}");
+                    return;
+                }
+                let pressed = match pressed {
+                    true => KEY_PRESSED,
+                    false => 0,
+                };
+                self.keys.push_back(pressed | code as u32);
+            }
+            InputEvent::Pointer { x, y, buttons } => {
+                self.pointer_changed |= (x, y) != self.pointer || buttons != self.buttons;
+                self.pointer = (x, y);
+                self.buttons = buttons;
+            }
+        }
+    }
+
+    // What a read of the addressed register gives, before this cycle's access takes effect
+    fn value(&self) -> U32 {
+        if *self.load.borrow() {
+            return ZERO;
+        }
+        let output = match self.address.borrow().0 & !3 {
+            STATUS => self.status(),
+            KEY => self.keys.front().copied().unwrap_or(0),
+            POINTER => (self.pointer.1 as u32) << 16 | self.pointer.0 as u32,
+            BUTTONS => self.buttons as u32,
+            CONTROL => self.control.output.borrow().0,
+            _ => 0,
+        };
+        Wrapping(output)
+    }
+}
+
+impl Chip for Input {
+    fn compute(&mut self) {
+        let offset = self.address.borrow().0 & !3;
+        let select = *self.select.borrow();
+        let write = select && *self.load.borrow();
+
+        self.pop = false;
+        self.clear_pointer_changed = false;
+        *self.control.load.borrow_mut() = false;
+
+        *self.output.borrow_mut() = self.value();
+        match offset {
+            STATUS => self.clear_pointer_changed = select && !write,
+            KEY => self.pop = select && !write,
+            CONTROL => {
+                let mask = lane_mask(*self.strobe.borrow());
+                let old = *self.control.output.borrow();
+                *self.control.input.borrow_mut() =
+                    (old & !mask | *self.input.borrow() & mask) & CONTROL_MASK;
+                *self.control.load.borrow_mut() = write;
+            }
+            // the pointer and the buttons are only read
+            _ => {}
+        }
+        self.control.compute();
+    }
+
+    fn clk(&mut self) {
+        self.control.clk();
+        if std::mem::take(&mut self.pop) {
+            self.keys.pop_front();
+        }
+        if std::mem::take(&mut self.clear_pointer_changed) {
+            self.pointer_changed = false;
+        }
+
+        self.cycle += 1;
+        while let Some(event) = self.source.poll(self.cycle) {
+            self.receive(event);
+        }
+        // an access lasts a single cycle
+        *self.select.borrow_mut() = false;
+        *self.load.borrow_mut() = false;
+
+        let control = self.control.output.borrow().0;
+        *self.interrupt.borrow_mut() = (control & CONTROL_KEY_INTERRUPT != 0
+            && !self.keys.is_empty())
+            || (control & CONTROL_POINTER_INTERRUPT != 0 && self.pointer_changed);
+    }
+}
+
+// An access only drives the ports, the cpu computes the device once a cycle and a read of
+// KEY or STATUS pops or clears at its clock edge
+impl Device for Input {
+    fn read(&mut self, offset: U32, width: Width) -> Option<U32> {
+        *self.address.borrow_mut() = offset;
+        *self.load.borrow_mut() = false;
+        *self.strobe.borrow_mut() = strobe(offset, width);
+        *self.select.borrow_mut() = true;
+        Some(self.value())
+    }
+
+    fn write(&mut self, offset: U32, value: U32, width: Width) -> Option<()> {
+        *self.address.borrow_mut() = offset;
+        *self.input.borrow_mut() = value;
+        *self.load.borrow_mut() = true;
+        *self.strobe.borrow_mut() = strobe(offset, width);
+        *self.select.borrow_mut() = true;
+        Some(())
+    }
+}
+
+impl Probe for Input {
+    fn name(&self) -> &'static str {
+        "input"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("input", &self.input),
+            Port::wire("output", &self.output),
+            Port::wire("address", &self.address),
+            Port::wire("load", &self.load),
+            Port::wire("strobe", &self.strobe),
+            Port::wire("select", &self.select),
+            Port::wire("interrupt", &self.interrupt),
+        ]
+    }
+}
+
+impl Snapshot for Input {
+    fn save(&self, out: &mut Writer) {
+        self.control.save(out);
+        out.put(&self.keys);
+        out.put(&self.pointer);
+        out.put(&self.buttons);
+        out.put(&self.pointer_changed);
+        out.put(&self.cycle);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        self.control.restore(input)?;
+        self.keys = input.get()?;
+        self.pointer = input.get()?;
+        self.buttons = input.get()?;
+        self.pointer_changed = input.get()?;
+        self.cycle = input.get()?;
+        // a script has already played everything up to the restored cycle, drop it
+        while self.source.poll(self.cycle).is_some() {}
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Events the test hands to the device, all of them show up at the next clock edge
+    #[derive(Clone, Default)]
+    struct Queue(Rc<RefCell<VecDeque<InputEvent>>>);
+
+    impl InputSource for Queue {
+        fn poll(&mut self, _cycle: u64) -> Option<InputEvent> {
+            self.0.borrow_mut().pop_front()
+        }
+    }
+
+    fn input() -> (Input, Queue) {
+        let queue = Queue::default();
+        (Input::new(Box::new(queue.clone()), wire(false)), queue)
+    }
+
+    fn tick(input: &mut Input) {
+        input.compute();
+        input.clk();
+    }
+
+    fn send(input: &mut Input, queue: &Queue, events: &[InputEvent]) {
+        queue.0.borrow_mut().extend(events);
+        tick(input);
+    }
+
+    fn store(input: &mut Input, offset: u32, value: u32) {
+        input.write(Wrapping(offset), Wrapping(value), Width::Word);
+        tick(input);
+    }
+
+    fn load(input: &mut Input, offset: u32) -> u32 {
+        let value = input.read(Wrapping(offset), Width::Word).unwrap().0;
+        tick(input);
+        value
+    }
+
+    fn key(code: u16, pressed: bool) -> InputEvent {
+        InputEvent::Key { code, pressed }
+    }
+
+    #[test]
+    fn keys_come_out_in_order() {
+        let (mut input, queue) = input();
+        assert_eq!(load(&mut input, STATUS), 0);
+        assert_eq!(load(&mut input, KEY), 0);
+        send(
+            &mut input,
+            &queue,
+            &[key(b'a' as u16, true), key(KEY_UP, false)],
+        );
+        assert_eq!(load(&mut input, STATUS), STATUS_KEY);
+        assert_eq!(load(&mut input, KEY), KEY_PRESSED | b'a' as u32);
+        assert_eq!(load(&mut input, KEY), KEY_UP as u32);
+        assert_eq!(load(&mut input, STATUS), 0);
+    }
+
+    #[test]
+    fn a_full_fifo_drops_keys() {
+        let (mut input, queue) = input();
+        let keys: Vec<_> = (0..FIFO_DEPTH as u16 + 2)
+            .map(|code| key(code, true))
+            .collect();
+        send(&mut input, &queue, &keys);
+        for code in 0..FIFO_DEPTH as u32 {
+            assert_eq!(load(&mut input, KEY), KEY_PRESSED | code);
+        }
+        assert_eq!(load(&mut input, KEY), 0);
+    }
+
+    #[test]
+    fn the_pointer_keeps_its_latest_position() {
+        let (mut input, queue) = input();
+        let pointer = |x, y, buttons| InputEvent::Pointer { x, y, buttons };
+        send(
+            &mut input,
+            &queue,
+            &[pointer(1, 2, BUTTON_LEFT), pointer(300, 200, BUTTON_RIGHT)],
+        );
+        assert_eq!(load(&mut input, POINTER), 200 << 16 | 300);
+        assert_eq!(load(&mut input, BUTTONS), BUTTON_RIGHT as u32);
+        // reading STATUS clears the change
+        assert_eq!(load(&mut input, STATUS), STATUS_POINTER);
+        assert_eq!(load(&mut input, STATUS), 0);
+        assert_eq!(load(&mut input, POINTER), 200 << 16 | 300);
+    }
+
+    #[test]
+    fn control_picks_the_interrupts() {
+        let (mut input, queue) = input();
+        let interrupt = |input: &Input| *input.interrupt.borrow();
+        send(&mut input, &queue, &[key(b'x' as u16, true)]);
+        assert!(!interrupt(&input));
+        store(&mut input, CONTROL, u32::MAX);
+        assert_eq!(load(&mut input, CONTROL), CONTROL_MASK.0);
+        assert!(interrupt(&input));
+        load(&mut input, KEY);
+        assert!(!interrupt(&input));
+        send(
+            &mut input,
+            &queue,
+            &[InputEvent::Pointer {
+                x: 5,
+                y: 5,
+                buttons: 0,
+            }],
+        );
+        assert!(interrupt(&input));
+        store(&mut input, CONTROL, CONTROL_KEY_INTERRUPT);
+        assert!(!interrupt(&input));
+    }
+}
@@ -1,53 +1,892 @@
-use super::{ram::RAM, screen::Screen, Chip, Wire, U32, ZERO};
-use std::num::Wrapping;
-
-pub struct Memory<T> {
-    pub input: Wire<T>,
-    pub output: Wire<T>,
-    pub address: Wire<U32>,
-    pub load: Wire<bool>,
-    screen: Screen,
-    ram: RAM<T>,
-}
-
-impl<T> Memory<T>
-where
-    T: Default + Clone,
-{
-    pub fn new(
-        input: Wire<T>,
-        address: Wire<U32>,
-        load: Wire<bool>,
-        screen: Screen,
-        ram: RAM<T>,
-    ) -> Self {
-        Self {
-            input,
-            output: ram.output.clone(),
-            address,
-            load,
-            screen,
-            ram,
-        }
-    }
-}
-
-const SCREEN: u32 = 1024 * 1024 * 4;
-
-impl Chip for Memory<U32> {
-    fn compute(&mut self) {
-        let addr = self.address.borrow().clone();
-        if addr.0 >= SCREEN {
-            *self.screen.address.borrow_mut() = Wrapping(addr.0 - SCREEN);
-            *self.screen.input.borrow_mut() = self.input.borrow().clone();
-        } else {
-            *self.ram.address.borrow_mut() = addr;
-            *self.ram.input.borrow_mut() = self.input.borrow().clone();
-        }
-    }
-
-    fn clk(&mut self) {
-        self.ram.clk();
-        self.screen.clk();
-    }
-}
+use crate::chips::bus::{Bus, Device};
+use crate::chips::csr_file::{is_read_only, CsrFile, MEPC, MSTATUS};
+use crate::chips::decode::{Instruction, Operation};
+use crate::chips::dff::DFF;
+use crate::chips::execute::ExMem;
+use crate::chips::probe::{Port, Probe};
+use crate::chips::register_file::RegFile;
+use crate::chips::rom::ROM;
+use crate::chips::trap::{Exception, Trap};
+use crate::chips::uart::Uart;
+use crate::chips::{wire, Chip, Wire, FOUR, U32, ZERO};
+use crate::debug::{Location, WatchHit, WatchTarget, Watchpoint};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Value, Writer};
+use crate::trace::{Access, Commit, Tracer};
+use std::num::Wrapping;
+
+/**Size of a memory access*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Width {
+    Byte,
+    Half,
+    Word,
+}
+
+impl Width {
+    pub fn bytes(self) -> u32 {
+        match self {
+            Width::Byte => 1,
+            Width::Half => 2,
+            Width::Word => 4,
+        }
+    }
+}
+
+/**Byte lanes touched by an access of `width` at `addr`*/
+pub fn strobe(addr: U32, width: Width) -> u8 {
+    let lanes = (1u8 << width.bytes()) - 1;
+    lanes << (addr.0 & 3)
+}
+
+/**Expand a strobe into a mask with every enabled byte set to 0xFF*/
+pub fn lane_mask(strobe: u8) -> U32 {
+    Wrapping((0..4).fold(0, |mask, lane| match strobe >> lane & 1 {
+        1 => mask | 0xFF << (8 * lane),
+        _ => mask,
+    }))
+}
+
+/**Move store data from the low bits onto the byte lanes of `addr`*/
+pub fn to_lanes(value: U32, addr: U32) -> U32 {
+    value << (8 * (addr.0 & 3) as usize)
+}
+
+/**Pick the lanes of `addr` out of a memory word and sign or zero extend them*/
+pub fn from_lanes(word: U32, addr: U32, width: Width, signed: bool) -> U32 {
+    let bits = 8 * width.bytes();
+    let value = word.0 >> (8 * (addr.0 & 3));
+    if bits == 32 {
+        return Wrapping(value);
+    }
+    let value = value & ((1 << bits) - 1);
+    match signed {
+        true => Wrapping((((value << (32 - bits)) as i32) >> (32 - bits)) as u32),
+        false => Wrapping(value),
+    }
+}
+
+/**The MEM/WB pipeline register: what write back puts in the register file*/
+#[derive(Clone, Debug, Default)]
+pub struct MemWb {
+    // false for a bubble
+    pub valid: bool,
+    pub instruction: Instruction,
+    pub write: bool,
+    // usually the instruction's rd, a0 for an ecall that read a byte
+    pub rd: U32,
+    pub value: U32,
+}
+
+impl MemWb {
+    /**The value going to register `index`, if this writes it*/
+    pub fn writes(&self, index: U32) -> Option<U32> {
+        (self.valid && self.write && self.rd == index && index != ZERO).then_some(self.value)
+    }
+}
+
+/**The MEM stage, where instructions commit. Loads and stores go out on the bus, csrs are read
+and written, ecalls talk to the console and traps and interrupts are taken. Whatever is behind
+an instruction that traps or changes the flow here is flushed, so nothing younger has done
+anything the trap would have to undo*/
+pub struct Memory {
+    pub input: Wire<ExMem>,
+    pub bus: Bus,
+    pub reg_file: Wire<RegFile<U32>>,
+    pub csr_file: Wire<CsrFile>,
+    rom: Wire<ROM>,
+    // the ecall console reads and writes through the uart
+    console: Wire<Uart>,
+    // the pipeline behind is flushed and fetch goes to target
+    pub redirect: Wire<bool>,
+    pub target: Wire<U32>,
+    pub output: Wire<MemWb>,
+    out: DFF<MemWb>,
+    // number of instructions that actually committed
+    pub retired: u64,
+    // set once the guest asks to exit through ecall 10
+    pub exit_code: Option<i32>,
+    // logs the retired instructions and the traps taken
+    pub tracer: Option<Tracer>,
+    // the exception raised by the last instruction
+    pub trap: Option<Trap>,
+    // a debugger is attached: EBREAK stops the machine instead of going to the guest
+    pub debug: bool,
+    // clock cycles run so far, the current one included
+    pub cycle: u64,
+    // the memory and registers being watched and the hits of the last instruction
+    pub watchpoints: Vec<Watchpoint>,
+    pub watch_hits: Vec<WatchHit>,
+}
+
+impl Memory {
+    pub fn new(
+        input: Wire<ExMem>,
+        bus: Bus,
+        rom: Wire<ROM>,
+        console: Wire<Uart>,
+        reg_file: Wire<RegFile<U32>>,
+        csr_file: Wire<CsrFile>,
+    ) -> Self {
+        let output = wire(MemWb::default());
+        Self {
+            input,
+            bus,
+            reg_file,
+            csr_file,
+            rom,
+            console,
+            redirect: wire(false),
+            target: wire(ZERO),
+            output: output.clone(),
+            out: DFF::new(wire(MemWb::default()), output),
+            retired: 0,
+            exit_code: None,
+            tracer: None,
+            trap: None,
+            debug: false,
+            cycle: 0,
+            watchpoints: vec![],
+            watch_hits: vec![],
+        }
+    }
+
+    /**Register `index` as the instructions committed so far left it, the one in write back
+    included*/
+    pub fn register(&self, index: U32) -> U32 {
+        match self.output.borrow().writes(index) {
+            Some(value) => value,
+            None => *self
+                .reg_file
+                .borrow_mut()
+                .get(index.0 as usize)
+                .output
+                .borrow(),
+        }
+    }
+
+    /**Run the stage for a cycle on `ex_mem`, returning what goes to write back instead of
+    latching it. The single-cycle core writes it back in the same cycle*/
+    pub fn step(&mut self, ex_mem: ExMem) -> MemWb {
+        self.cycle += 1;
+        *self.redirect.borrow_mut() = false;
+        self.commit(ex_mem)
+    }
+
+    // Commit the instruction in EX/MEM, returning what goes into MEM/WB
+    fn commit(&mut self, ex_mem: ExMem) -> MemWb {
+        if !ex_mem.valid {
+            return MemWb::default();
+        }
+        let instruction = ex_mem.instruction;
+
+        // an enabled pending interrupt is taken instead of the instruction
+        let interrupt = self.csr_file.borrow().pending_interrupt();
+        if let Some(cause) = interrupt.filter(|_| self.handler()) {
+            let handler = self
+                .csr_file
+                .borrow_mut()
+                .trap_entry(instruction.pc, cause, ZERO);
+            if let Some(tracer) = self.tracer.as_mut() {
+                tracer.trap(self.retired, instruction.pc, cause, ZERO);
+            }
+            self.jump(handler);
+            return MemWb::default();
+        }
+
+        use crate::chips::decode::Operation::*;
+
+        let pc = instruction.pc;
+        if let Some(exception) = instruction.exception {
+            return self.raise(pc, exception);
+        }
+        let (rs1, rs2) = (ex_mem.rs1_value, ex_mem.rs2_value);
+        let target = match instruction.op {
+            MRET => self.csr_file.borrow().read(MEPC),
+            _ => ex_mem.target,
+        };
+        if let Some(target) = target.filter(|target| target.0 % 4 != 0) {
+            return self.raise(pc, Exception::InstructionAddressMisaligned(target));
+        }
+
+        // MEMORY ACCESS, done before anything is written so a faulting access has no effect
+        let addr = ex_mem.result;
+        let width = access_width(&instruction.op);
+        // what a store is about to overwrite, for the watchpoints to report
+        let overwritten = match instruction.op {
+            SB | SH | SW if !self.watchpoints.is_empty() => self
+                .bus
+                .inspect(addr & !Wrapping(3u32))
+                .map(|word| from_lanes(word, addr, width, false)),
+            _ => None,
+        };
+        let loaded = match instruction.op {
+            LB | LH | LW | LBU | LHU if !addr.0.is_multiple_of(width.bytes()) => {
+                Err(Exception::LoadAddressMisaligned(addr))
+            }
+            LB | LH | LW | LBU | LHU => match self.bus.read(addr, width) {
+                Some(word) => Ok(from_lanes(
+                    word,
+                    addr,
+                    width,
+                    matches!(instruction.op, LB | LH),
+                )),
+                None => Err(Exception::LoadAccessFault(addr)),
+            },
+            SB | SH | SW if !addr.0.is_multiple_of(width.bytes()) => {
+                Err(Exception::StoreAddressMisaligned(addr))
+            }
+            SB | SH | SW => match self.bus.write(addr, to_lanes(rs2, addr), width) {
+                Some(()) => Ok(ZERO),
+                None => Err(Exception::StoreAccessFault(addr)),
+            },
+            _ => Ok(ZERO),
+        };
+        let loaded = match loaded {
+            Ok(loaded) => loaded,
+            Err(exception) => return self.raise(pc, exception),
+        };
+        match instruction.op {
+            LB | LH | LW | LBU | LHU => {
+                self.watch_memory(pc, (addr, width), false, Some(loaded), loaded);
+            }
+            SB | SH | SW => {
+                let stored = from_lanes(to_lanes(rs2, addr), addr, width, false);
+                self.watch_memory(pc, (addr, width), true, overwritten, stored);
+            }
+            _ => {}
+        }
+
+        // read the csr before anything else is touched, a bad csr access doesn't execute
+        let csr = match instruction.op {
+            CSRRW | CSRRS | CSRRC | CSRRWI | CSRRSI | CSRRCI => {
+                let access = csr_access(&mut self.csr_file.borrow_mut(), &instruction, rs1);
+                match access {
+                    Some(old) => old,
+                    None => {
+                        return self.raise(pc, Exception::IllegalInstruction(instruction.raw));
+                    }
+                }
+            }
+            _ => ZERO,
+        };
+
+        // ECALL goes to the guest's trap handler when it has one, EBREAK always traps
+        let handler = self.handler();
+        match instruction.op {
+            // hand the machine to the debugger with the ebreak still to run
+            EBREAK if self.debug => {
+                self.trap = Some(Trap {
+                    pc,
+                    exception: Exception::Breakpoint(pc),
+                });
+                self.jump(pc);
+                return MemWb::default();
+            }
+            ECALL if handler => {
+                return self.raise(pc, Exception::EnvironmentCall);
+            }
+            EBREAK => {
+                return self.raise(pc, Exception::Breakpoint(pc));
+            }
+            _ => {}
+        }
+        self.retired += 1;
+        if !self.watchpoints.is_empty() {
+            let (reads_rs1, reads_rs2) = instruction.op.sources();
+            if reads_rs1 {
+                self.watch_register(pc, instruction.rs1, false, rs1, rs1);
+            }
+            if reads_rs2 {
+                self.watch_register(pc, instruction.rs2, false, rs2, rs2);
+            }
+        }
+
+        let value = match instruction.op {
+            LB | LH | LW | LBU | LHU => loaded,
+            CSRRW | CSRRS | CSRRC | CSRRWI | CSRRSI | CSRRCI => csr,
+            _ => ex_mem.result,
+        };
+        let rd = instruction.rd;
+        let writeback = (instruction.op.writes_rd() && rd != ZERO).then_some((rd, value));
+        if let Some((index, new)) = writeback.filter(|_| !self.watchpoints.is_empty()) {
+            let old = self.register(index);
+            self.watch_register(pc, index, true, old, new);
+        }
+        let mut latched = MemWb {
+            valid: true,
+            instruction: instruction.clone(),
+            write: writeback.is_some(),
+            rd,
+            value,
+        };
+
+        match instruction.op {
+            ECALL => {
+                let (a7, a0, a1) = (
+                    self.register(Wrapping(17)),
+                    self.register(Wrapping(10)),
+                    self.register(Wrapping(11)),
+                );
+                let (read, exit_code) = ecall(
+                    a7,
+                    a0,
+                    a1,
+                    &self.rom.borrow(),
+                    &mut self.console.borrow_mut(),
+                );
+                self.exit_code = exit_code;
+                if let Some(byte) = read {
+                    (latched.write, latched.rd, latched.value) = (true, Wrapping(10), byte);
+                }
+                // what is behind read a0 before the ecall changed it, it runs again
+                self.jump(pc + FOUR);
+            }
+            MRET => self.csr_file.borrow_mut().trap_return(),
+            _ => {}
+        }
+
+        if let Some(tracer) = self.tracer.as_mut() {
+            let csr = match instruction.op {
+                CSRRW | CSRRS | CSRRC | CSRRWI | CSRRSI | CSRRCI => instruction.imm.0 as u16,
+                MRET => MSTATUS,
+                _ => 0,
+            };
+            let memory = match instruction.op {
+                LB | LH | LW | LBU | LHU => Some(Access::Load(addr)),
+                SB | SH | SW => Some(Access::Store(addr, rs2, width)),
+                _ => None,
+            };
+            let commit = Commit {
+                rd: writeback,
+                csr: self
+                    .csr_file
+                    .borrow()
+                    .latched(csr)
+                    .map(|value| (csr, value)),
+                memory,
+                instruction,
+            };
+            tracer.commit(self.retired - 1, &commit);
+        }
+
+        // taken branches and jumps already sent fetch on from execute, only mret goes from here
+        if let (MRET, Some(target)) = (&latched.instruction.op, target) {
+            self.jump(target);
+        }
+        latched
+    }
+
+    // Send fetch somewhere else and flush every instruction behind this one
+    fn jump(&mut self, target: U32) {
+        *self.redirect.borrow_mut() = true;
+        *self.target.borrow_mut() = target;
+    }
+
+    // Note the watchpoints a load or store hits, `old` is None when the memory can't be inspected
+    fn watch_memory(
+        &mut self,
+        pc: U32,
+        (addr, width): (U32, Width),
+        write: bool,
+        old: Option<U32>,
+        new: U32,
+    ) {
+        for (index, watchpoint) in self.watchpoints.iter().enumerate() {
+            if watchpoint.covers(addr, width.bytes()) && watchpoint.wants(write, new) {
+                self.watch_hits.push(WatchHit {
+                    index,
+                    kind: watchpoint.kind,
+                    pc,
+                    write,
+                    location: Location::Memory(addr),
+                    old,
+                    new,
+                    cycle: self.cycle,
+                });
+            }
+        }
+    }
+
+    fn watch_register(&mut self, pc: U32, index: U32, write: bool, old: U32, new: U32) {
+        let register = WatchTarget::Register(index.0 as usize);
+        for (n, watchpoint) in self.watchpoints.iter().enumerate() {
+            if watchpoint.target == register && watchpoint.wants(write, new) {
+                self.watch_hits.push(WatchHit {
+                    index: n,
+                    kind: watchpoint.kind,
+                    pc,
+                    write,
+                    location: Location::Register(index.0 as usize),
+                    old: Some(old),
+                    new,
+                    cycle: self.cycle,
+                });
+            }
+        }
+    }
+
+    // Whether the guest has set up a trap handler. A vector at 0 is a handler like any other
+    // once the guest has put it there
+    fn handler(&self) -> bool {
+        self.csr_file.borrow().has_handler()
+    }
+
+    // Take an exception: go to the guest's trap handler, or stop the machine on the faulting
+    // instruction if there is none. Nothing goes to write back
+    fn raise(&mut self, pc: U32, exception: Exception) -> MemWb {
+        if let Some(tracer) = self.tracer.as_mut() {
+            let cause = Wrapping(exception.cause());
+            tracer.trap(self.retired, pc, cause, exception.tval());
+        }
+        if !self.handler() {
+            self.trap = Some(Trap { pc, exception });
+            self.jump(pc);
+            return MemWb::default();
+        }
+        let handler = self.csr_file.borrow_mut().trap_entry(
+            pc,
+            Wrapping(exception.cause()),
+            exception.tval(),
+        );
+        self.jump(handler);
+        MemWb::default()
+    }
+}
+
+// Zicsr read-modify-write, returns the old value or None if the access is illegal
+fn csr_access(csr_file: &mut CsrFile, instruction: &Instruction, rs1: U32) -> Option<U32> {
+    use crate::chips::decode::Operation::*;
+    let addr = instruction.imm.0 as u16;
+    let old = csr_file.read(addr)?;
+    let src = match instruction.op {
+        CSRRWI | CSRRSI | CSRRCI => instruction.rs1,
+        _ => rs1,
+    };
+    // csrrs/csrrc with rs1 = x0 (or uimm 0) only read, so they are fine on read only csrs. It
+    // goes by the register field, another register that happens to hold 0 still writes
+    let value = match instruction.op {
+        CSRRW | CSRRWI => src,
+        CSRRS | CSRRSI if instruction.rs1 != ZERO => old | src,
+        CSRRC | CSRRCI if instruction.rs1 != ZERO => old & !src,
+        _ => return Some(old),
+    };
+    if is_read_only(addr) {
+        return None;
+    }
+    csr_file.write(addr, value)?;
+    Some(old)
+}
+
+fn access_width(op: &Operation) -> Width {
+    use crate::chips::decode::Operation::*;
+    match op {
+        LB | LBU | SB => Width::Byte,
+        LH | LHU | SH => Width::Half,
+        _ => Width::Word,
+    }
+}
+
+impl Chip for Memory {
+    fn compute(&mut self) {
+        let ex_mem = self.input.borrow().clone();
+        *self.out.input.borrow_mut() = self.step(ex_mem);
+        self.out.compute();
+    }
+
+    fn clk(&mut self) {
+        self.out.clk();
+        self.csr_file.borrow_mut().clk();
+    }
+}
+
+// the bus is the data side of memory, its windows are the ram and the devices
+impl Probe for Memory {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        let output = &self.output;
+        vec![
+            Port::wire("redirect", &self.redirect),
+            Port::wire("target", &self.target),
+            Port::field("output.valid", 1, output, |mem_wb| mem_wb.valid as u64),
+            Port::field("output.pc", 32, output, |mem_wb| {
+                mem_wb.instruction.pc.0 as u64
+            }),
+            Port::field("output.raw", 32, output, |mem_wb| {
+                mem_wb.instruction.raw.0 as u64
+            }),
+            Port::field("output.write", 1, output, |mem_wb| mem_wb.write as u64),
+            Port::field("output.rd", 5, output, |mem_wb| mem_wb.rd.0 as u64),
+            Port::field("output.value", 32, output, |mem_wb| mem_wb.value.0 as u64),
+        ]
+    }
+
+    fn children(&self, visit: &mut dyn FnMut(&str, &dyn Probe)) {
+        self.bus.children(visit);
+    }
+}
+
+// The register file and the csrs are saved with the machine, the debugger's settings stay
+impl Snapshot for Memory {
+    fn save(&self, out: &mut Writer) {
+        self.out.save(out);
+        out.put(&self.retired);
+        out.put(&self.cycle);
+        out.put(&self.exit_code);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        self.out.restore(input)?;
+        self.retired = input.get()?;
+        self.cycle = input.get()?;
+        self.exit_code = input.get()?;
+        self.trap = None;
+        self.watch_hits.clear();
+        Ok(())
+    }
+}
+
+impl Value for MemWb {
+    fn put(&self, out: &mut Writer) {
+        out.put(&self.valid);
+        out.put(&self.instruction);
+        out.put(&self.write);
+        out.put(&self.rd);
+        out.put(&self.value);
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        Ok(Self {
+            valid: input.get()?,
+            instruction: input.get()?,
+            write: input.get()?,
+            rd: input.get()?,
+            value: input.get()?,
+        })
+    }
+}
+
+// The byte a0 reads and the exit code when the guest asks to exit, console i/o goes through
+// the uart's terminal
+fn ecall(a7: U32, a0: U32, a1: U32, rom: &ROM, uart: &mut Uart) -> (Option<U32>, Option<i32>) {
+    // handle syscall
+    match a7.0 {
+        1 => {
+            // read char
+            // -1 once the input is closed
+            let val = match uart.receive() {
+                Some(byte) => Wrapping(byte as u32),
+                None => Wrapping(u32::MAX),
+            };
+            return (Some(val), None);
+        }
+        2 => {
+            // print char
+            uart.send(a0.0 as u8)
+        }
+        3 => {
+            // read string
+        }
+        4 => {
+            // should be encoded as lui no op
+            // lui x0, `data`
+            // print string
+            let mut string = vec![];
+            for i in 0..a1.0 {
+                let addr = a0 + Wrapping(4 * i);
+                if !rom.contains(addr) {
+                    break;
+                }
+                let val = rom.peek(addr);
+                string.push((val.0 >> 12) as u8);
+            }
+            string.into_iter().for_each(|byte| uart.send(byte))
+        }
+        10 => {
+            return (None, Some(a0.0 as i32));
+        }
+        _ => log::warn!("unknown ecall {}", a7.0),
+    }
+    (None, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::csr_access;
+    use crate::asm::assemble;
+    use crate::chips::bus::Device;
+    use crate::chips::cpu::{Model, CPU};
+    use crate::chips::csr_file::{CsrFile, MCAUSE, MEPC, MHARTID, MSCRATCH, MSTATUS, MTVAL};
+    use crate::chips::decode::{Instruction, Operation};
+    use crate::chips::testing;
+    use crate::chips::trap::Exception;
+    use crate::chips::{Chip, ZERO};
+    use std::num::Wrapping;
+
+    const RAM: u32 = 0x1_0000;
+
+    // Run `program` until it gets to its ebreak
+    fn run(program: &str) -> CPU {
+        let mut cpu = testing::machine(program, RAM, 64, Model::default());
+        for _ in 0..200 {
+            if let Err(trap) = cpu.step() {
+                assert!(
+                    matches!(trap.exception, Exception::Breakpoint(_)),
+                    "{trap:?}"
+                );
+                return cpu;
+            }
+        }
+        panic!("the program never got to its ebreak");
+    }
+
+    fn word(cpu: &CPU, offset: u32) -> u32 {
+        cpu.memory.bus.inspect(Wrapping(RAM + offset)).unwrap().0
+    }
+
+    #[test]
+    fn sub_word_loads_extend() {
+        let cpu = run("
+            li   t0, 0x10000
+            li   t1, 0x80ff7f01
+            sw   t1, 0(t0)
+            # lb and lbu into a0 to a7, lh and lhu into s2 to s5
+            lb   a0, 0(t0)
+            lb   a1, 1(t0)
+            lb   a2, 2(t0)
+            lb   a3, 3(t0)
+            lbu  a4, 0(t0)
+            lbu  a5, 1(t0)
+            lbu  a6, 2(t0)
+            lbu  a7, 3(t0)
+            lh   s2, 0(t0)
+            lh   s3, 2(t0)
+            lhu  s4, 0(t0)
+            lhu  s5, 2(t0)
+            ebreak
+        ");
+        let loaded = |first: usize| [0, 1, 2, 3].map(|n| cpu.register(first + n).0);
+        assert_eq!(loaded(10), [0x01, 0x7f, 0xffff_ffff, 0xffff_ff80]);
+        assert_eq!(loaded(14), [0x01, 0x7f, 0xff, 0x80]);
+        assert_eq!(loaded(18), [0x7f01, 0xffff_80ff, 0x7f01, 0x80ff]);
+    }
+
+    #[test]
+    fn sub_word_stores_keep_the_other_bytes() {
+        let cpu = run("
+            li   t0, 0x10000
+            li   t1, 0x44332211
+            li   t2, -1
+            sw   t1, 0(t0)
+            sb   t2, 0(t0)
+            sw   t1, 4(t0)
+            sb   t2, 5(t0)
+            sw   t1, 8(t0)
+            sb   t2, 10(t0)
+            sw   t1, 12(t0)
+            sb   t2, 15(t0)
+            sw   t1, 16(t0)
+            sh   t2, 16(t0)
+            sw   t1, 20(t0)
+            sh   t2, 22(t0)
+            ebreak
+        ");
+        let stored = (0..6).map(|n| word(&cpu, 4 * n));
+        assert_eq!(
+            stored.collect::<Vec<_>>(),
+            [0x443322ff, 0x4433ff11, 0x44ff2211, 0xff332211, 0x4433ffff, 0xffff2211]
+        );
+    }
+
+    fn csr(op: Operation, csr: u16, rs1: u32) -> Instruction {
+        Instruction {
+            op,
+            imm: Wrapping(csr as u32),
+            rs1: Wrapping(rs1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn csr_set_and_clear_with_x0_only_read() {
+        use Operation::*;
+        let mut csrs = CsrFile::new();
+        csrs.write(MSCRATCH, Wrapping(0b110));
+        csrs.clk();
+        for op in [CSRRS, CSRRC, CSRRSI, CSRRCI] {
+            let old = csr_access(&mut csrs, &csr(op.clone(), MSCRATCH, 0), Wrapping(0b11));
+            assert_eq!(old, Some(Wrapping(0b110)));
+            assert_eq!(csrs.latched(MSCRATCH), None, "{op:?}");
+            // reading a read only csr is fine
+            assert!(csr_access(&mut csrs, &csr(op.clone(), MHARTID, 0), Wrapping(0)).is_some());
+        }
+
+        // a register other than x0 writes, even when it holds 0
+        assert_eq!(
+            csr_access(&mut csrs, &csr(CSRRS, MHARTID, 6), Wrapping(0)),
+            None
+        );
+        csr_access(&mut csrs, &csr(CSRRC, MSCRATCH, 6), Wrapping(0b10));
+        assert_eq!(csrs.latched(MSCRATCH), Some(Wrapping(0b100)));
+    }
+
+    // Run `program` for `cycles` cycles, it should be spinning in a handler by then. Also
+    // hands back where its labels are
+    fn spin(program: &str, cycles: usize) -> (CPU, impl Fn(&str) -> u32) {
+        let symbols = assemble(program, ZERO, Wrapping(RAM)).unwrap().symbols;
+        let label = move |name: &str| {
+            let symbol = symbols.iter().find(|symbol| symbol.name == name);
+            symbol.unwrap().address.0
+        };
+        let mut cpu = testing::machine(program, RAM, 64, Model::default());
+        for _ in 0..cycles {
+            cpu.step().unwrap();
+        }
+        (cpu, label)
+    }
+
+    fn csrs(cpu: &CPU, addresses: [u16; 3]) -> [u32; 3] {
+        addresses.map(|addr| cpu.csr(addr).unwrap().0)
+    }
+
+    #[test]
+    fn exceptions_go_to_the_handler() {
+        // what faults, then its mcause and where mepc and mtval point given the labels
+        type Expect = fn(&dyn Fn(&str) -> u32) -> (u32, u32);
+        let cases: [(&str, u32, Expect); 9] = [
+            (".word 0xffffffff", 2, |at| (at("fault"), 0xffff_ffff)),
+            ("jalr zero, 2(t0)", 0, |at| (at("fault"), at("handler") + 2)),
+            ("jalr zero, 0(t2)", 1, |_| (0x800, 0x800)),
+            ("lw   a0, 0(t1)", 4, |at| (at("fault"), 0x1_0001)),
+            ("lw   a0, 0x3ff(t1)", 5, |at| (at("fault"), 0x1_0400)),
+            ("sh   a0, 0(t1)", 6, |at| (at("fault"), 0x1_0001)),
+            ("sw   a0, 0x3ff(t1)", 7, |at| (at("fault"), 0x1_0400)),
+            ("ebreak", 3, |at| (at("fault"), at("fault"))),
+            ("ecall", 11, |at| (at("fault"), 0)),
+        ];
+        for (fault, cause, expect) in cases {
+            let program = format!(
+                "
+                la   t0, handler
+                csrw mtvec, t0
+                li   t1, 0x10001
+                li   t2, 0x800
+            fault:
+                {fault}
+                j    fault
+            handler:
+                j    handler
+            "
+            );
+            let (cpu, at) = spin(&program, 30);
+            let (mepc, mtval) = expect(&at);
+            assert_eq!(
+                csrs(&cpu, [MEPC, MCAUSE, MTVAL]),
+                [mepc, cause, mtval],
+                "{fault}"
+            );
+            assert_eq!(cpu.pc().0, at("handler"), "{fault}");
+        }
+    }
+
+    #[test]
+    fn a_vector_at_0_is_a_handler() {
+        let (cpu, at) = spin(
+            "
+            trap:
+                j    trap
+            _start:
+                csrw mtvec, zero
+            fault:
+                ecall
+            ",
+            20,
+        );
+        assert_eq!(cpu.pc(), ZERO);
+        assert_eq!(csrs(&cpu, [MEPC, MCAUSE, MTVAL]), [at("fault"), 11, 0]);
+    }
+
+    #[test]
+    fn vectored_interrupts_go_by_cause() {
+        let (cpu, at) = spin(
+            "
+                la   t0, vectors
+                ori  t0, t0, 1
+                csrw mtvec, t0
+                li   t1, 8
+                csrw mie, t1
+                csrsi mstatus, 8
+                li   t2, 0x2000000
+                li   t3, 1
+                sw   t3, 0(t2)
+            idle:
+                j    idle
+            vectors:
+                j    exception
+                j    exception
+                j    exception
+            software:
+                j    software
+            exception:
+                j    exception
+            ",
+            40,
+        );
+        assert_eq!(cpu.pc().0, at("software"));
+        assert_eq!(at("software"), at("vectors") + 12);
+        let [mepc, mcause, _] = csrs(&cpu, [MEPC, MCAUSE, MTVAL]);
+        assert_eq!((mepc, mcause), (at("idle"), 0x8000_0003));
+        // MIE is stacked into MPIE on the way in
+        assert_eq!(cpu.csr(MSTATUS), Some(Wrapping(0x1880)));
+
+        // exceptions all go to the base
+        let (cpu, at) = spin(
+            "
+                la   t0, vectors
+                ori  t0, t0, 1
+                csrw mtvec, t0
+                ecall
+            vectors:
+                j    vectors
+            ",
+            20,
+        );
+        assert_eq!(cpu.pc().0, at("vectors"));
+    }
+
+    #[test]
+    fn mret_restores_mie() {
+        for enabled in [false, true] {
+            let mie = if enabled { "csrsi mstatus, 8" } else { "nop" };
+            let (cpu, at) = spin(
+                &format!(
+                    "
+                    la   t0, handler
+                    csrw mtvec, t0
+                    {mie}
+                    ecall
+                back:
+                    j    back
+                handler:
+                    csrr s0, mstatus
+                    csrr t0, mepc
+                    addi t0, t0, 4
+                    csrw mepc, t0
+                    mret
+                "
+                ),
+                40,
+            );
+            assert_eq!(cpu.pc().0, at("back"));
+            // in the handler MIE is off and MPIE holds what it was, mret puts it back
+            let (inside, after) = match enabled {
+                true => (0x1880, 0x1888),
+                false => (0x1800, 0x1880),
+            };
+            assert_eq!(cpu.register(8).0, inside);
+            assert_eq!(cpu.csr(MSTATUS), Some(Wrapping(after)));
+        }
+    }
+}
@@ -1,5 +1,7 @@
+use crate::chips::probe::{Port, Probe};
 use crate::chips::register::Register;
 use crate::chips::{wire, Chip, Wire, FOUR, U32, ZERO};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
 
 #[derive(Clone)]
 pub struct PC<T = U32> {
@@ -8,6 +10,8 @@ pub struct PC<T = U32> {
     pub load: Wire<bool>,
     pub inc: Wire<bool>,
     pub output: Wire<T>,
+    // address loaded on reset, the program entry point
+    reset_vector: T,
     register: Register<T>,
 }
 
@@ -27,6 +31,7 @@ impl PC {
             load,
             inc,
             output: output.clone(),
+            reset_vector: ZERO,
             register: Register::new(wire(ZERO), output, wire(true)),
         }
     }
@@ -41,21 +46,30 @@ impl Default for PC {
             load: wire(false),
             inc: wire(true),
             output: output.clone(),
+            reset_vector: ZERO,
             register: Register::new(wire(ZERO), output, wire(true)),
         }
     }
 }
 
+impl PC {
+    /**Set the reset vector and put the pc there as if reset was just released*/
+    pub fn set_reset_vector(&mut self, addr: U32) {
+        self.reset_vector = addr;
+        *self.output.borrow_mut() = addr;
+    }
+}
+
 impl Chip for PC {
     fn compute(&mut self) {
         let val = if *self.reset.borrow() {
-            ZERO
+            self.reset_vector
         } else if *self.load.borrow() {
-            self.input.borrow().clone()
+            *self.input.borrow()
         } else if *self.inc.borrow() {
-            self.output.borrow().clone() + FOUR
+            *self.output.borrow() + FOUR
         } else {
-            self.output.borrow().clone()
+            *self.output.borrow()
         };
         *self.register.input.borrow_mut() = val;
 
@@ -68,3 +82,31 @@ impl Chip for PC {
         self.register.clk()
     }
 }
+
+impl Probe for PC {
+    fn name(&self) -> &'static str {
+        "pc"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("input", &self.input),
+            Port::wire("reset", &self.reset),
+            Port::wire("load", &self.load),
+            Port::wire("inc", &self.inc),
+            Port::wire("output", &self.output),
+        ]
+    }
+}
+
+impl Snapshot for PC {
+    fn save(&self, out: &mut Writer) {
+        out.put(&self.reset_vector);
+        self.register.save(out);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        self.reset_vector = input.get()?;
+        self.register.restore(input)
+    }
+}
@@ -0,0 +1,183 @@
+use crate::chips::cpu::{Core, Model};
+use crate::chips::decode::Decode;
+use crate::chips::execute::Execute;
+use crate::chips::fetch::Fetch;
+use crate::chips::hazard::{Forward, Hazard, FORWARD_NONE};
+use crate::chips::memory::Memory;
+use crate::chips::pc::PC;
+use crate::chips::probe::Probe;
+use crate::chips::register_file::RegFile;
+use crate::chips::rom::ROM;
+use crate::chips::write_back::WriteBack;
+use crate::chips::{wire, Chip, Wire, U32};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
+
+/**The five stage pipeline: fetch, decode, execute, memory and write back, with the pipeline
+registers between them held by the stage in front. The memory stage, where instructions
+commit, belongs to the machine and is shared with the single-cycle core*/
+pub struct Pipeline {
+    pub fetch: Fetch,
+    pub decode: Decode,
+    pub execute: Execute,
+    pub write_back: WriteBack,
+    pub forward: Forward,
+    pub hazard: Hazard,
+}
+
+impl Pipeline {
+    /**Wire the stages around `memory`, whose input is EX/MEM*/
+    pub fn new(
+        pc: &Wire<PC>,
+        rom: &Wire<ROM>,
+        reg_file: &Wire<RegFile<U32>>,
+        memory: &Memory,
+    ) -> Self {
+        // the hazard unit's control lines are wired before the stages using them
+        let stall = wire(false);
+        let flush = wire(false);
+        let ex_mem = memory.input.clone();
+
+        let fetch = Fetch::new(
+            pc.borrow().output.clone(),
+            rom.clone(),
+            stall.clone(),
+            flush.clone(),
+        );
+        let decode = Decode::new(
+            rom.clone(),
+            fetch.output.clone(),
+            fetch.valid.clone(),
+            reg_file.clone(),
+            memory.output.clone(),
+            stall.clone(),
+            flush.clone(),
+        );
+        let (forward_a, forward_b) = (wire(FORWARD_NONE), wire(FORWARD_NONE));
+        let execute = Execute::new(
+            decode.output.clone(),
+            ex_mem.clone(),
+            forward_a.clone(),
+            forward_b.clone(),
+            memory.output.clone(),
+            memory.redirect.clone(),
+        );
+        let write_back = WriteBack::new(memory.output.clone(), reg_file.clone());
+        let forward = Forward::new(
+            decode.output.clone(),
+            ex_mem,
+            memory.output.clone(),
+            forward_a,
+            forward_b,
+        );
+        let hazard = Hazard::new(
+            rom.clone(),
+            fetch.valid.clone(),
+            decode.output.clone(),
+            execute.branch.clone(),
+            execute.target.clone(),
+            memory.redirect.clone(),
+            memory.target.clone(),
+            pc.clone(),
+            stall,
+            flush,
+        );
+        Self {
+            fetch,
+            decode,
+            execute,
+            write_back,
+            forward,
+            hazard,
+        }
+    }
+}
+
+impl Core for Pipeline {
+    fn model(&self) -> Model {
+        Model::Pipelined
+    }
+
+    fn compute(&mut self, memory: &mut Memory) {
+        // back to front: a stage reads the pipeline register in front of it before the stage
+        // ahead decides what this cycle does to it. The forwarding unit only looks at the
+        // pipeline registers, the hazard unit needs to know whether execute or memory redirect
+        self.forward.compute();
+        self.write_back.compute();
+        memory.compute();
+        self.execute.compute();
+        self.hazard.compute();
+        self.decode.compute();
+        self.fetch.compute();
+    }
+
+    fn clk(&mut self) {
+        self.fetch.clk();
+        self.decode.clk();
+        self.execute.clk();
+        self.write_back.clk();
+    }
+
+    fn in_flight(&self) -> Vec<U32> {
+        let ex_mem = self.execute.output.borrow();
+        let id_ex = self.decode.output.borrow();
+        [
+            (ex_mem.valid, ex_mem.instruction.pc),
+            (id_ex.valid, id_ex.instruction.pc),
+            (*self.fetch.valid.borrow(), *self.fetch.output.borrow()),
+        ]
+        .into_iter()
+        .filter_map(|(valid, pc)| valid.then_some(pc))
+        .collect()
+    }
+
+    fn busy(&self) -> bool {
+        self.execute.output.borrow().valid
+    }
+
+    fn flush(&mut self) {
+        self.fetch.flush();
+        self.decode.flush();
+        self.execute.flush();
+    }
+
+    fn pipeline(&self) -> Option<&Pipeline> {
+        Some(self)
+    }
+}
+
+// the stages sit right under the cpu, next to the memory stage they feed
+impl Probe for Pipeline {
+    fn name(&self) -> &'static str {
+        "pipeline"
+    }
+
+    fn children(&self, visit: &mut dyn FnMut(&str, &dyn Probe)) {
+        visit("fetch", &self.fetch);
+        visit("decode", &self.decode);
+        visit("execute", &self.execute);
+        visit("write_back", &self.write_back);
+        visit("forward", &self.forward);
+        visit("hazard", &self.hazard);
+    }
+}
+
+// The pipeline registers in pipeline order, MEM/WB is saved with the memory stage
+impl Snapshot for Pipeline {
+    fn save(&self, out: &mut Writer) {
+        out.section("fetch");
+        self.fetch.save(out);
+        out.section("decode");
+        self.decode.save(out);
+        out.section("execute");
+        self.execute.save(out);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        input.section("fetch")?;
+        self.fetch.restore(input)?;
+        input.section("decode")?;
+        self.decode.restore(input)?;
+        input.section("execute")?;
+        self.execute.restore(input)
+    }
+}
@@ -0,0 +1,352 @@
+use crate::chips::bus::Device;
+use crate::chips::memory::{lane_mask, strobe, Width};
+use crate::chips::probe::{Port, Probe};
+use crate::chips::register::Register;
+use crate::chips::{wire, Chip, Wire, ONE, U32, ZERO};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
+use std::num::Wrapping;
+
+/**Where the plic sits in the memory map, the SiFive/QEMU virt address*/
+pub const PLIC_BASE: u32 = 0x0C00_0000;
+pub const PLIC_SIZE: u32 = 0x0400_0000;
+
+/**Interrupt sources 1..SOURCES, source 0 means "no interrupt"*/
+pub const SOURCES: usize = 32;
+
+const PRIORITY: u32 = 0x0;
+const PENDING: u32 = 0x1000;
+const ENABLE: u32 = 0x2000;
+const THRESHOLD: u32 = 0x20_0000;
+const CLAIM: u32 = 0x20_0004;
+
+// priorities are 3 bits wide
+const PRIORITY_MASK: U32 = Wrapping(0b111);
+
+/**Platform-level interrupt controller with a single context, machine mode on hart 0.
+Sources are level triggered: while a source line is high and the source isn't being
+serviced it is pending. Claim returns the highest priority enabled pending source above
+the threshold and marks it in service until the handler writes it back to complete*/
+pub struct Plic {
+    pub input: Wire<U32>,
+    pub output: Wire<U32>,
+    // offset from PLIC_BASE
+    pub address: Wire<U32>,
+    pub load: Wire<bool>,
+    pub strobe: Wire<u8>,
+    // high for the cycle the bus accesses the plic, a read of claim has a side effect
+    pub select: Wire<bool>,
+    // machine external interrupt line to the hart
+    pub interrupt: Wire<bool>,
+    sources: Vec<Wire<bool>>,
+    priority: Vec<Register<U32>>,
+    pending: Register<U32>,
+    enable: Register<U32>,
+    threshold: Register<U32>,
+    // sources claimed and not completed yet
+    in_service: Register<U32>,
+}
+
+impl Plic {
+    pub fn new() -> Self {
+        Self {
+            input: wire(ZERO),
+            output: wire(ZERO),
+            address: wire(ZERO),
+            load: wire(false),
+            strobe: wire(0),
+            select: wire(false),
+            interrupt: wire(false),
+            sources: (0..SOURCES).map(|_| wire(false)).collect(),
+            priority: (0..SOURCES).map(|_| Register::default()).collect(),
+            pending: Register::default(),
+            enable: Register::default(),
+            threshold: Register::default(),
+            in_service: Register::default(),
+        }
+    }
+
+    /**The line a device drives to raise interrupt `id`*/
+    pub fn source(&self, id: usize) -> Wire<bool> {
+        assert!(id > 0 && id < SOURCES, "plic source {id} out of range");
+        self.sources[id].clone()
+    }
+
+    // The source a claim would return right now, 0 if there is none
+    fn best(&self, pending: u32) -> u32 {
+        let enable = self.enable.output.borrow().0;
+        let threshold = *self.threshold.output.borrow();
+        let mut best = (0, ZERO);
+        for id in 1..SOURCES {
+            let priority = *self.priority[id].output.borrow();
+            if pending >> id & enable >> id & 1 == 1 && priority > threshold && priority > best.1 {
+                best = (id as u32, priority);
+            }
+        }
+        best.0
+    }
+
+    // Gateways: a high line becomes pending unless that source is already in service
+    fn gateways(&self) -> u32 {
+        let in_service = self.in_service.output.borrow().0;
+        let lines = (1..SOURCES)
+            .filter(|&id| *self.sources[id].borrow())
+            .fold(0u32, |lines, id| lines | 1 << id);
+        (self.pending.output.borrow().0 | lines) & !in_service
+    }
+
+    // The word at the address on the port, a claim reads the source it is about to take
+    fn value(&self) -> U32 {
+        let offset = self.address.borrow().0 & !3;
+        let load = *self.load.borrow();
+        match offset {
+            PRIORITY..=0xFFF => {
+                let id = (offset - PRIORITY) as usize / 4;
+                let priority = self.priority.get(id).filter(|_| id > 0);
+                priority.map_or(ZERO, |priority| *priority.output.borrow())
+            }
+            PENDING => *self.pending.output.borrow(),
+            ENABLE => *self.enable.output.borrow(),
+            THRESHOLD => *self.threshold.output.borrow(),
+            CLAIM if *self.select.borrow() && !load => Wrapping(self.best(self.gateways())),
+            _ => ZERO,
+        }
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Chip for Plic {
+    fn compute(&mut self) {
+        let offset = self.address.borrow().0 & !3;
+        let select = *self.select.borrow();
+        let write = select && *self.load.borrow();
+        let mask = lane_mask(*self.strobe.borrow());
+        let input = *self.input.borrow();
+        let merge = |register: &Register<U32>| (*register.output.borrow() & !mask) | (input & mask);
+
+        *self.output.borrow_mut() = self.value();
+        let in_service = self.in_service.output.borrow().0;
+        let mut pending = self.gateways();
+        let mut in_service_next = in_service;
+
+        match offset {
+            PRIORITY..=0xFFF if write => {
+                let id = (offset - PRIORITY) as usize / 4;
+                if let Some(priority) = self.priority.get_mut(id).filter(|_| id > 0) {
+                    *priority.input.borrow_mut() = merge(priority) & PRIORITY_MASK;
+                    *priority.load.borrow_mut() = true;
+                }
+            }
+            ENABLE if write => {
+                // source 0 does not exist
+                *self.enable.input.borrow_mut() = merge(&self.enable) & !ONE;
+                *self.enable.load.borrow_mut() = true;
+            }
+            THRESHOLD if write => {
+                *self.threshold.input.borrow_mut() = merge(&self.threshold) & PRIORITY_MASK;
+                *self.threshold.load.borrow_mut() = true;
+            }
+            CLAIM if write => {
+                // complete: the source can be pending again
+                let id = input.0 as usize;
+                if id > 0 && id < SOURCES {
+                    in_service_next &= !(1 << id);
+                }
+            }
+            CLAIM if select => {
+                let id = self.best(pending);
+                if id != 0 {
+                    pending &= !(1 << id);
+                    in_service_next |= 1 << id;
+                }
+            }
+            _ => {}
+        }
+
+        *self.pending.input.borrow_mut() = Wrapping(pending);
+        *self.in_service.input.borrow_mut() = Wrapping(in_service_next);
+        *self.pending.load.borrow_mut() = true;
+        *self.in_service.load.borrow_mut() = true;
+
+        self.priority
+            .iter_mut()
+            .for_each(|priority| priority.compute());
+        self.pending.compute();
+        self.enable.compute();
+        self.threshold.compute();
+        self.in_service.compute();
+    }
+
+    fn clk(&mut self) {
+        self.priority.iter_mut().for_each(|priority| {
+            priority.clk();
+            *priority.load.borrow_mut() = false;
+        });
+        self.pending.clk();
+        self.enable.clk();
+        self.threshold.clk();
+        self.in_service.clk();
+        *self.enable.load.borrow_mut() = false;
+        *self.threshold.load.borrow_mut() = false;
+        // an access lasts a single cycle
+        *self.select.borrow_mut() = false;
+        *self.load.borrow_mut() = false;
+
+        let pending = self.pending.output.borrow().0;
+        *self.interrupt.borrow_mut() = self.best(pending) != 0;
+    }
+}
+
+// The access drives the port and the cpu computes the plic once it is done with the memory
+// stage. A claim or a store only takes effect when the cpu clocks the plic
+impl Device for Plic {
+    fn read(&mut self, offset: U32, width: Width) -> Option<U32> {
+        *self.address.borrow_mut() = offset;
+        *self.load.borrow_mut() = false;
+        *self.strobe.borrow_mut() = strobe(offset, width);
+        *self.select.borrow_mut() = true;
+        Some(self.value())
+    }
+
+    fn write(&mut self, offset: U32, value: U32, width: Width) -> Option<()> {
+        *self.address.borrow_mut() = offset;
+        *self.input.borrow_mut() = value;
+        *self.load.borrow_mut() = true;
+        *self.strobe.borrow_mut() = strobe(offset, width);
+        *self.select.borrow_mut() = true;
+        Some(())
+    }
+}
+
+impl Probe for Plic {
+    fn name(&self) -> &'static str {
+        "plic"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("input", &self.input),
+            Port::wire("output", &self.output),
+            Port::wire("address", &self.address),
+            Port::wire("load", &self.load),
+            Port::wire("strobe", &self.strobe),
+            Port::wire("select", &self.select),
+            Port::wire("interrupt", &self.interrupt),
+        ]
+    }
+}
+
+// The source lines belong to the devices but they hold their level from one clk to the next,
+// so they are saved here with the gateways they feed
+impl Snapshot for Plic {
+    fn save(&self, out: &mut Writer) {
+        self.priority.iter().for_each(|priority| priority.save(out));
+        self.pending.save(out);
+        self.enable.save(out);
+        self.threshold.save(out);
+        self.in_service.save(out);
+        let lines: Vec<bool> = self.sources.iter().map(|line| *line.borrow()).collect();
+        out.put(&lines);
+        out.put(&*self.interrupt.borrow());
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        for priority in self.priority.iter_mut() {
+            priority.restore(input)?;
+        }
+        self.pending.restore(input)?;
+        self.enable.restore(input)?;
+        self.threshold.restore(input)?;
+        self.in_service.restore(input)?;
+        let lines: Vec<bool> = input.get_exact(SOURCES, "the plic sources")?;
+        for (line, level) in self.sources.iter().zip(lines) {
+            *line.borrow_mut() = level;
+        }
+        *self.interrupt.borrow_mut() = input.get()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tick(plic: &mut Plic) {
+        plic.compute();
+        plic.clk();
+    }
+
+    fn store(plic: &mut Plic, offset: u32, value: u32) {
+        plic.write(Wrapping(offset), Wrapping(value), Width::Word);
+        tick(plic);
+    }
+
+    fn load(plic: &mut Plic, offset: u32) -> u32 {
+        let value = plic.read(Wrapping(offset), Width::Word).unwrap().0;
+        tick(plic);
+        value
+    }
+
+    // sources 3 and 5 enabled, 3 with the higher priority
+    fn plic() -> Plic {
+        let mut plic = Plic::new();
+        store(&mut plic, PRIORITY + 4 * 3, 2);
+        store(&mut plic, PRIORITY + 4 * 5, 1);
+        store(&mut plic, ENABLE, 1 << 3 | 1 << 5);
+        plic
+    }
+
+    #[test]
+    fn claims_go_by_priority_until_completed() {
+        let mut plic = plic();
+        assert!(!*plic.interrupt.borrow());
+        *plic.source(3).borrow_mut() = true;
+        *plic.source(5).borrow_mut() = true;
+        tick(&mut plic);
+        assert!(*plic.interrupt.borrow());
+        assert_eq!(load(&mut plic, PENDING), 1 << 3 | 1 << 5);
+
+        assert_eq!(load(&mut plic, CLAIM), 3);
+        assert!(*plic.interrupt.borrow());
+        assert_eq!(load(&mut plic, CLAIM), 5);
+        // both in service, the lines being still high doesn't matter
+        assert!(!*plic.interrupt.borrow());
+        assert_eq!(load(&mut plic, CLAIM), 0);
+
+        // completing 3 lets its line through again
+        store(&mut plic, CLAIM, 3);
+        tick(&mut plic);
+        assert!(*plic.interrupt.borrow());
+        assert_eq!(load(&mut plic, CLAIM), 3);
+    }
+
+    #[test]
+    fn the_threshold_masks_low_priorities() {
+        let mut plic = plic();
+        *plic.source(3).borrow_mut() = true;
+        store(&mut plic, THRESHOLD, 2);
+        tick(&mut plic);
+        // a source has to be above the threshold, not at it
+        assert!(!*plic.interrupt.borrow());
+        assert_eq!(load(&mut plic, CLAIM), 0);
+        store(&mut plic, THRESHOLD, 1);
+        assert!(*plic.interrupt.borrow());
+        assert_eq!(load(&mut plic, CLAIM), 3);
+    }
+
+    #[test]
+    fn registers_keep_their_legal_bits() {
+        let mut plic = Plic::new();
+        store(&mut plic, PRIORITY + 4, 0xFF);
+        assert_eq!(load(&mut plic, PRIORITY + 4), 0b111);
+        // source 0 has no priority and can't be enabled
+        store(&mut plic, PRIORITY, 0xFF);
+        assert_eq!(load(&mut plic, PRIORITY), 0);
+        store(&mut plic, ENABLE, u32::MAX);
+        assert_eq!(load(&mut plic, ENABLE), u32::MAX - 1);
+    }
+}
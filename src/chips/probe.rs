@@ -0,0 +1,205 @@
+use crate::chips::{Wire, U32};
+
+/**Values a probe can read off a wire, as the bits of a signal of WIDTH bits*/
+pub trait Bits {
+    const WIDTH: u32;
+    fn bits(&self) -> u64;
+}
+
+impl Bits for U32 {
+    const WIDTH: u32 = 32;
+    fn bits(&self) -> u64 {
+        self.0 as u64
+    }
+}
+
+impl Bits for u8 {
+    const WIDTH: u32 = 8;
+    fn bits(&self) -> u64 {
+        *self as u64
+    }
+}
+
+impl Bits for bool {
+    const WIDTH: u32 = 1;
+    fn bits(&self) -> u64 {
+        *self as u64
+    }
+}
+
+/**A named signal of a chip. It keeps its own handle on the wire, so it can still be read
+cycles after the walk that found it*/
+pub struct Port {
+    pub name: String,
+    pub width: u32,
+    read: Box<dyn Fn() -> u64>,
+}
+
+impl Port {
+    pub fn new(name: &str, width: u32, read: impl Fn() -> u64 + 'static) -> Self {
+        Self {
+            name: name.to_string(),
+            width,
+            read: Box::new(read),
+        }
+    }
+
+    /**The whole of `wire` as a port*/
+    pub fn wire<T: Bits + 'static>(name: &str, wire: &Wire<T>) -> Self {
+        let wire = wire.clone();
+        Self::new(name, T::WIDTH, move || wire.borrow().bits())
+    }
+
+    /**One field of what is on `wire`, like a part of a pipeline register*/
+    pub fn field<T: 'static>(name: &str, width: u32, wire: &Wire<T>, read: fn(&T) -> u64) -> Self {
+        let wire = wire.clone();
+        Self::new(name, width, move || read(&wire.borrow()))
+    }
+
+    /**The value on the wire right now*/
+    pub fn read(&self) -> u64 {
+        (self.read)()
+    }
+}
+
+/**A chip tooling can look into: what it is, the signals on its interface and the chips
+it is built from. Children are named by their parent, `ram` on the bus is a RAM*/
+pub trait Probe {
+    fn name(&self) -> &'static str;
+
+    fn ports(&self) -> Vec<Port> {
+        vec![]
+    }
+
+    /**Call `visit` with the name and the chip of every child*/
+    fn children(&self, _visit: &mut dyn FnMut(&str, &dyn Probe)) {}
+}
+
+/**Every port in the tree of `root` with its path, like `cpu.memory.ram.address`*/
+pub fn ports(root: &dyn Probe) -> Vec<(String, Port)> {
+    let mut found = vec![];
+    walk(root, root.name(), &mut found);
+    found
+}
+
+fn walk(chip: &dyn Probe, path: &str, found: &mut Vec<(String, Port)>) {
+    for port in chip.ports() {
+        found.push((format!("{path}.{}", port.name), port));
+    }
+    chip.children(&mut |name, child| walk(child, &format!("{path}.{name}"), found));
+}
+
+/**The ports at `path` or under it, a whole chip gives all of its signals. The root's
+name can be left out of the path*/
+pub fn find(root: &dyn Probe, path: &str) -> Vec<(String, Port)> {
+    let path = full_path(root, path);
+    ports(root)
+        .into_iter()
+        .filter(|(name, _)| under(name, &path))
+        .collect()
+}
+
+/**The ports under any of `paths` in the order of the tree, each one once. Err names the
+first path nothing is under*/
+pub fn select(root: &dyn Probe, paths: &[&str]) -> Result<Vec<(String, Port)>, String> {
+    let paths: Vec<String> = paths.iter().map(|path| full_path(root, path)).collect();
+    let found: Vec<(String, Port)> = ports(root)
+        .into_iter()
+        .filter(|(name, _)| paths.iter().any(|path| under(name, path)))
+        .collect();
+    match paths
+        .iter()
+        .find(|path| !found.iter().any(|(name, _)| under(name, path)))
+    {
+        Some(path) => Err(format!("no signal '{path}'")),
+        None => Ok(found),
+    }
+}
+
+fn full_path(root: &dyn Probe, path: &str) -> String {
+    let path = path.trim_matches('.');
+    if path.is_empty() {
+        root.name().to_string()
+    } else if path.split('.').next() == Some(root.name()) {
+        path.to_string()
+    } else {
+        format!("{}.{path}", root.name())
+    }
+}
+
+fn under(name: &str, path: &str) -> bool {
+    name.strip_prefix(path)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::cpu::{Model, CPU};
+    use crate::chips::testing;
+
+    fn machine() -> CPU {
+        let program = "
+            li  a0, 42
+            li  t0, 0x20
+            sw  a0, 0(t0)
+        end:
+            j   end
+        ";
+        testing::machine(program, 0, 64, Model::default())
+    }
+
+    fn names(found: &[(String, Port)]) -> Vec<&str> {
+        found.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    #[test]
+    fn paths() {
+        let cpu = machine();
+        let address = "cpu.memory.ram.address";
+        assert_eq!(names(&find(&cpu, address)), [address]);
+        assert_eq!(names(&find(&cpu, "memory.ram.address")), [address]);
+        assert_eq!(
+            names(&find(&cpu, "memory.ram")),
+            ["address", "input", "output", "load", "strobe"]
+                .map(|port| { format!("cpu.memory.ram.{port}") })
+        );
+        // a path names whole segments
+        assert!(find(&cpu, "memory.ra").is_empty());
+        assert_eq!(find(&cpu, "decode.output").len(), 9);
+        assert_eq!(find(&cpu, "reg_file").len(), 31 * 3);
+        assert_eq!(find(&cpu, "").len(), ports(&cpu).len());
+
+        let selected = select(&cpu, &["memory.ram.load", "pc.output", "pc"]).unwrap();
+        assert_eq!(
+            names(&selected),
+            [
+                "cpu.pc.input",
+                "cpu.pc.reset",
+                "cpu.pc.load",
+                "cpu.pc.inc",
+                "cpu.pc.output",
+                "cpu.memory.ram.load"
+            ]
+        );
+        assert_eq!(
+            select(&cpu, &["pc", "fetch.nothing"]).err().unwrap(),
+            "no signal 'cpu.fetch.nothing'"
+        );
+    }
+
+    #[test]
+    fn ports_follow_the_machine() {
+        let mut cpu = machine();
+        // found before anything ran, read once the store is done
+        let a0 = find(&cpu, "reg_file.x10.output").pop().unwrap().1;
+        let address = find(&cpu, "memory.ram.address").pop().unwrap().1;
+        let input = find(&cpu, "memory.ram.input").pop().unwrap().1;
+        assert_eq!((a0.width, address.width), (32, 32));
+        for _ in 0..10 {
+            cpu.step().unwrap();
+        }
+        assert_eq!(a0.read(), 42);
+        assert_eq!((address.read(), input.read()), (0x20, 42));
+    }
+}
@@ -1,13 +1,20 @@
+use crate::chips::bus::Device;
+use crate::chips::memory::{lane_mask, strobe, Width};
+use crate::chips::probe::{Port, Probe};
 use crate::chips::register::Register;
 use crate::chips::{Chip, Wire, U32, ZERO};
-use std::fmt::Debug;
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
 
 pub struct RAM<T> {
     pub input: Wire<T>,
     pub output: Wire<T>,
     pub address: Wire<U32>,
     pub load: Wire<bool>,
+    // one write enable per byte lane, bit i enables bits 8i..8i+7 of the word
+    pub strobe: Wire<u8>,
     addr: U32,
+    // an access this cycle selected the register at addr, only that one sees the clock edge
+    selected: bool,
     registers: Vec<Register<T>>,
 }
 
@@ -20,6 +27,7 @@ where
         output: Wire<T>,
         address: Wire<U32>,
         load: Wire<bool>,
+        strobe: Wire<u8>,
         size: usize,
     ) -> Self {
         // Create the specified number of registers
@@ -32,7 +40,9 @@ where
             output,
             address,
             addr: ZERO,
+            selected: false,
             load,
+            strobe,
             registers,
         }
     }
@@ -40,27 +50,109 @@ where
     pub fn peek(&self, addr: U32) -> T {
         self.registers[addr.0 as usize >> 2].output.borrow().clone()
     }
+
+    // Write straight to the register output, used to preload data before the machine runs
+    pub fn poke(&mut self, addr: U32, value: T) {
+        *self.registers[addr.0 as usize >> 2].output.borrow_mut() = value;
+    }
+
+    /**Size of the ram in bytes*/
+    pub fn size(&self) -> usize {
+        self.registers.len() * 4
+    }
 }
 
-impl<T> Chip for RAM<T>
-where
-    T: Default + Clone + Debug,
-{
+impl Chip for RAM<U32> {
     fn compute(&mut self) {
-        let addr = self.address.borrow().clone();
+        let addr = *self.address.borrow();
         self.addr = addr;
-        // Transfer the input and load from ram's interface to the selected register's interface
-        *self.registers[addr.0 as usize >> 2].input.borrow_mut() = self.input.borrow().clone();
-        *self.registers[addr.0 as usize >> 2].load.borrow_mut() = self.load.borrow().clone();
+        self.selected = true;
+        let register = &mut self.registers[addr.0 as usize >> 2];
+        // Only the byte lanes enabled by the strobe take the input, the rest keep the stored bytes
+        let mask = lane_mask(*self.strobe.borrow());
+        let stored = *register.output.borrow();
+        *register.input.borrow_mut() = (stored & !mask) | (*self.input.borrow() & mask);
+        // Transfer the load from ram's interface to the selected register's interface
+        *register.load.borrow_mut() = *self.load.borrow();
         // Now compute the selected ram
-        self.registers[addr.0 as usize >> 2].compute();
+        register.compute();
     }
 
     fn clk(&mut self) {
-        let addr = self.addr;
-        // Clock the selected register
-        self.registers[addr.0 as usize >> 2].clk();
+        *self.load.borrow_mut() = false;
+        if !std::mem::take(&mut self.selected) {
+            return;
+        }
+        // Clock the selected register, a store only lasts its own cycle
+        let register = &mut self.registers[self.addr.0 as usize >> 2];
+        register.clk();
+        *register.load.borrow_mut() = false;
         // Now move the output to ram's interface to see the result
-        *self.output.borrow_mut() = self.registers[addr.0 as usize >> 2].output.borrow().clone();
+        *self.output.borrow_mut() = *register.output.borrow();
+    }
+}
+
+// An access sets up the selected register, the cpu clocks the ram at the end of the cycle
+impl Device for RAM<U32> {
+    fn read(&mut self, offset: U32, _width: Width) -> Option<U32> {
+        if offset.0 as usize >= self.size() {
+            return None;
+        }
+        *self.address.borrow_mut() = offset;
+        *self.load.borrow_mut() = false;
+        self.compute();
+        Some(self.peek(offset))
+    }
+
+    fn write(&mut self, offset: U32, value: U32, width: Width) -> Option<()> {
+        if offset.0 as usize >= self.size() {
+            return None;
+        }
+        *self.address.borrow_mut() = offset;
+        *self.input.borrow_mut() = value;
+        *self.strobe.borrow_mut() = strobe(offset, width);
+        // the write enable stays up until the cpu's clock edge, which commits the store
+        *self.load.borrow_mut() = true;
+        self.compute();
+        Some(())
+    }
+
+    fn inspect(&self, offset: U32) -> Option<U32> {
+        ((offset.0 as usize) < self.size()).then(|| self.peek(offset))
+    }
+}
+
+impl Probe for RAM<U32> {
+    fn name(&self) -> &'static str {
+        "ram"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("address", &self.address),
+            Port::wire("input", &self.input),
+            Port::wire("output", &self.output),
+            Port::wire("load", &self.load),
+            Port::wire("strobe", &self.strobe),
+        ]
+    }
+}
+
+impl Snapshot for RAM<U32> {
+    fn save(&self, out: &mut Writer) {
+        let words: Vec<U32> = self
+            .registers
+            .iter()
+            .map(|register| *register.output.borrow())
+            .collect();
+        out.put(&words);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        let words: Vec<U32> = input.get_exact(self.registers.len(), "the ram")?;
+        for (register, word) in self.registers.iter_mut().zip(words) {
+            register.set(word);
+        }
+        Ok(())
     }
 }
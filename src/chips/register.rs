@@ -1,5 +1,7 @@
 use crate::chips::dff::DFF;
+use crate::chips::probe::{Bits, Port, Probe};
 use crate::chips::{mux2, wire, Chip, Wire};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Value, Writer};
 
 #[derive(Clone)]
 pub struct Register<T> {
@@ -21,6 +23,11 @@ where
             dff: DFF::new(input, output),
         }
     }
+
+    /**Overwrite the stored value right away, without waiting for load and a clock*/
+    pub fn set(&mut self, value: T) {
+        self.dff.set(value);
+    }
 }
 
 impl<T> Default for Register<T>
@@ -60,3 +67,33 @@ where
         self.dff.clk();
     }
 }
+
+impl<T> Probe for Register<T>
+where
+    T: Bits + 'static,
+{
+    fn name(&self) -> &'static str {
+        "register"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("input", &self.input),
+            Port::wire("output", &self.output),
+            Port::wire("load", &self.load),
+        ]
+    }
+}
+
+impl<T> Snapshot for Register<T>
+where
+    T: Clone + Default + Value,
+{
+    fn save(&self, out: &mut Writer) {
+        self.dff.save(out);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        self.dff.restore(input)
+    }
+}
@@ -1,5 +1,7 @@
+use crate::chips::probe::{Bits, Probe};
 use crate::chips::register::Register;
 use crate::chips::Chip;
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Value, Writer};
 use std::fmt::Debug;
 
 #[derive(Clone)]
@@ -25,13 +27,6 @@ where
         }
         &mut self.registers[index]
     }
-
-    pub fn print(&self) {
-        self.registers
-            .iter()
-            .enumerate()
-            .for_each(|(i, v)| println!("x{i}: {:?}", v.output.borrow().clone()));
-    }
 }
 
 impl<T> Chip for RegFile<T>
@@ -51,3 +46,36 @@ where
         });
     }
 }
+
+// x0 is left out: get swaps in a fresh register for it, so a port on its wires would go stale
+impl<T> Probe for RegFile<T>
+where
+    T: Bits + 'static,
+{
+    fn name(&self) -> &'static str {
+        "reg_file"
+    }
+
+    fn children(&self, visit: &mut dyn FnMut(&str, &dyn Probe)) {
+        for (i, register) in self.registers.iter().enumerate().skip(1) {
+            visit(&format!("x{i}"), register);
+        }
+    }
+}
+
+impl<T> Snapshot for RegFile<T>
+where
+    T: Clone + Default + Value,
+{
+    fn save(&self, out: &mut Writer) {
+        self.registers
+            .iter()
+            .for_each(|register| register.save(out));
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        self.registers
+            .iter_mut()
+            .try_for_each(|register| register.restore(input))
+    }
+}
@@ -1,8 +1,13 @@
+use crate::chips::probe::{Port, Probe};
 use crate::chips::{Chip, Wire, U32};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
+use std::num::Wrapping;
 
 pub struct ROM<T = U32> {
     pub address: Wire<T>,
     pub output: Wire<T>,
+    // address of the first word, fetch sees the rom at base..base + size
+    base: U32,
     registers: Vec<T>,
 }
 
@@ -14,20 +19,37 @@ where
         Self {
             output,
             address,
+            base: Wrapping(0),
             registers: vec![T::default(); size],
         }
     }
 
-    pub fn load(&mut self, program: Vec<T>) {
-        // load the program into the rom
-        program
-            .into_iter()
-            .enumerate()
-            .for_each(|(i, p)| self.registers[i] = p);
+    pub fn peek(&self, addr: U32) -> T {
+        self.registers[(addr - self.base).0 as usize >> 2].clone()
     }
 
-    pub fn peek(&self, addr: U32) -> T {
-        self.registers[addr.0 as usize >> 2].clone()
+    pub fn poke(&mut self, addr: U32, value: T) {
+        let index = (addr - self.base).0 as usize >> 2;
+        self.registers[index] = value;
+    }
+
+    /**Size of the rom in bytes*/
+    pub fn size(&self) -> usize {
+        self.registers.len() * 4
+    }
+
+    /**Move the rom to start at `base` instead of address 0*/
+    pub fn set_base(&mut self, base: U32) {
+        self.base = base;
+    }
+
+    pub fn base(&self) -> U32 {
+        self.base
+    }
+
+    /**Whether `addr` is inside the rom*/
+    pub fn contains(&self, addr: U32) -> bool {
+        ((addr - self.base).0 as usize) < self.size()
     }
 }
 
@@ -37,7 +59,48 @@ impl Chip for ROM<U32> {
     }
 
     fn clk(&mut self) {
-        let addr = self.address.borrow().clone();
-        *self.output.borrow_mut() = self.registers[addr.0 as usize / 4].clone();
+        let addr = *self.address.borrow() - self.base;
+        // fetching outside the rom reads zeros, which decode as an illegal instruction
+        *self.output.borrow_mut() = self
+            .registers
+            .get(addr.0 as usize / 4)
+            .cloned()
+            .unwrap_or_default();
+    }
+}
+
+// the words are left out, they are memory rather than signals
+impl Probe for ROM<U32> {
+    fn name(&self) -> &'static str {
+        "rom"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("address", &self.address),
+            Port::wire("output", &self.output),
+        ]
+    }
+}
+
+// the contents go in too, the debugger patches breakpoints into them
+impl Snapshot for ROM<U32> {
+    fn save(&self, out: &mut Writer) {
+        out.put(&self.base);
+        out.put(&self.registers);
+        out.put(&*self.output.borrow());
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        let base: U32 = input.get()?;
+        if base != self.base {
+            return Err(SnapshotError::Mismatch(format!(
+                "the rom was at {:#010x}, not {:#010x}",
+                base.0, self.base.0
+            )));
+        }
+        self.registers = input.get_exact(self.registers.len(), "the rom")?;
+        *self.output.borrow_mut() = input.get()?;
+        Ok(())
     }
 }
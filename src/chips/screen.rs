@@ -1,97 +1,297 @@
-use fltk::{
-    app::{self, App},
-    prelude::*,
-    window::Window,
-};
-use pixels::{Pixels, SurfaceTexture};
-use std::{cell::RefCell, rc::Rc};
+use crate::frame::{self, FrameDump};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
+use std::io;
+use std::path::{Path, PathBuf};
 
+use super::bus::Device;
+use super::memory::{strobe, Width};
+use super::probe::{Port, Probe};
 use super::{Chip, Wire, U32};
+use std::num::Wrapping;
 
+pub const WIDTH: u32 = 600;
+pub const HEIGHT: u32 = 400;
+/**Bytes from the start of one line of pixels to the next*/
+pub const STRIDE: u32 = WIDTH * 4;
+pub const FRAME_SIZE: u32 = STRIDE * HEIGHT;
+
+/**Default place of the screen in the memory map, right above the default 4MiB of ram*/
+pub const SCREEN_BASE: u32 = 1024 * 1024 * 4;
+/**The screen window: the frame at the start, the registers in its last page*/
+pub const SCREEN_SIZE: u32 = 0x10_0000;
+
+// read only registers describing the frame, as offsets in the screen window
+pub const WIDTH_REGISTER: u32 = 0xF_F000;
+pub const HEIGHT_REGISTER: u32 = 0xF_F004;
+pub const STRIDE_REGISTER: u32 = 0xF_F008;
+
+/**Clock cycles between two frames*/
+pub const FRAME_CYCLES: u64 = 100_000;
+
+/**Where the finished frames are shown*/
+pub trait Display {
+    /**Show a frame of WIDTH x HEIGHT RGBA8888 pixels*/
+    fn present(&mut self, frame: &[u8]);
+}
+
+/**No window at all, the frame only lives in memory and in the dumps*/
+pub struct Headless;
+
+impl Display for Headless {
+    fn present(&mut self, _frame: &[u8]) {}
+}
+
+/**The frame buffer. The guest draws into it over the bus and every FRAME_CYCLES cycles
+the frame goes to the display.
+
+Pixels are RGBA8888: pixel (x, y) is the word at offset `y * STRIDE + x * 4`, red in its lowest
+byte, then green, blue and alpha, so the word reads 0xAABBGGRR. Every byte can be loaded and
+stored. The width, height and stride are also readable from WIDTH_REGISTER, HEIGHT_REGISTER and
+STRIDE_REGISTER, anything else outside the frame is an access fault*/
 pub struct Screen {
     pub input: Wire<U32>,
     pub address: Wire<U32>,
-    app: App,
-    pixels: Pixels,
+    // byte lanes of the input to store
+    pub strobe: Wire<u8>,
+    frame: Vec<u8>,
+    display: Box<dyn Display>,
+    // cycles spent on the current frame
+    cycle: u64,
+    // frames finished so far
+    pub frames: u64,
+    pub dump: Option<FrameDump>,
 }
 
-const WIDTH: u32 = 600;
-const HEIGHT: u32 = 400;
-
 impl Screen {
-    pub fn new(input: Wire<U32>, address: Wire<U32>) -> Self {
-        let app = app::App::default();
-        let mut win = Window::default()
-            .with_size(WIDTH as i32, HEIGHT as i32)
-            .with_label("Hello Pixels");
-        win.make_resizable(true);
-        win.end();
-        win.show();
-
-        // Handle resize events
-        let surface_size = Rc::new(RefCell::new(None));
-        let surface_resize = surface_size.clone();
-        win.resize_callback(move |win, _x, _y, width, height| {
-            let scale_factor = win.pixels_per_unit();
-            let width = (width as f32 * scale_factor) as u32;
-            let height = (height as f32 * scale_factor) as u32;
-
-            surface_resize.borrow_mut().replace((width, height));
-        });
-
-        let pixels = {
-            let pixel_width = win.pixel_w() as u32;
-            let pixel_height = win.pixel_h() as u32;
-            let surface_texture = SurfaceTexture::new(pixel_width, pixel_height, &win);
-
-            Pixels::new(WIDTH, HEIGHT, surface_texture).unwrap()
-        };
-
+    pub fn new(
+        input: Wire<U32>,
+        address: Wire<U32>,
+        strobe: Wire<u8>,
+        display: Box<dyn Display>,
+    ) -> Self {
         Screen {
             input,
             address,
-            app,
-            pixels,
+            strobe,
+            frame: vec![0; FRAME_SIZE as usize],
+            display,
+            cycle: 0,
+            frames: 0,
+            dump: None,
         }
     }
+
+    /**Write the frame buffer to `path`, a .ppm or .png file*/
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        frame::save(path, &self.frame, WIDTH, HEIGHT)
+    }
+
+    /**Save the frame buffer as the next numbered dump, returns where it went*/
+    pub fn save_numbered(&self) -> Option<io::Result<PathBuf>> {
+        let path = self.dump.as_ref()?.numbered(self.frames);
+        Some(self.save(&path).map(|_| path))
+    }
 }
 
 impl Chip for Screen {
     fn compute(&mut self) {
-        let addr = self.address.borrow().clone();
-        self.pixels.frame_mut()[addr.0 as usize >> 2] = self.input.borrow().clone().0 as u8;
-        self.pixels.frame_mut()[addr.0 as usize >> 2 + 1] =
-            (self.input.borrow().clone().0 >> 8) as u8;
-        self.pixels.frame_mut()[addr.0 as usize >> 2 + 2] =
-            (self.input.borrow().clone().0 >> 16) as u8;
-        self.pixels.frame_mut()[addr.0 as usize >> 2 + 3] =
-            (self.input.borrow().clone().0 >> 24) as u8;
+        // the address is a byte offset into the frame, the word holding it takes the enabled lanes
+        let word = self.address.borrow().0 as usize & !3;
+        let input = self.input.borrow().0;
+        let strobe = *self.strobe.borrow();
+        for lane in (0..4).filter(|lane| strobe >> lane & 1 == 1) {
+            self.frame[word + lane] = (input >> (8 * lane)) as u8;
+        }
     }
 
     fn clk(&mut self) {
-        let surface_size = Rc::new(RefCell::new(None));
-
-        if self.app.wait() {
-            // Update internal state
-            // world.update();
-
-            // Resize the window
-            if let Some((width, height)) = surface_size.borrow_mut().take() {
-                if let Err(err) = self.pixels.resize_surface(width, height) {
-                    println!("pixels.resize_surface {}", err);
-                    self.app.quit();
-                }
+        self.cycle += 1;
+        if self.cycle < FRAME_CYCLES {
+            return;
+        }
+        self.cycle = 0;
+        self.frames += 1;
+        self.display.present(&self.frame);
+
+        let every = self.dump.as_ref().and_then(|dump| dump.every);
+        if every.is_some_and(|every| self.frames.is_multiple_of(every)) {
+            if let Some(Err(err)) = self.save_numbered() {
+                log::warn!("can't save frame {}: {err}", self.frames);
             }
+        }
+    }
+}
 
-            // Draw the current frame
-            // world.draw(pixels.frame_mut());
-            if let Err(err) = self.pixels.render() {
-                println!("pixels.render {}", err);
-                self.app.quit();
+impl Device for Screen {
+    // reading the frame or the geometry changes nothing
+    fn read(&mut self, offset: U32, _width: Width) -> Option<U32> {
+        self.inspect(offset)
+    }
+
+    fn write(&mut self, offset: U32, value: U32, width: Width) -> Option<()> {
+        // the registers can't be written
+        if offset.0 >= FRAME_SIZE {
+            return None;
+        }
+        *self.address.borrow_mut() = offset;
+        *self.input.borrow_mut() = value;
+        *self.strobe.borrow_mut() = strobe(offset, width);
+        self.compute();
+        Some(())
+    }
+
+    fn inspect(&self, offset: U32) -> Option<U32> {
+        let value = match offset.0 & !3 {
+            word if word < FRAME_SIZE => {
+                let word = word as usize;
+                u32::from_le_bytes(self.frame[word..word + 4].try_into().unwrap())
             }
+            WIDTH_REGISTER => WIDTH,
+            HEIGHT_REGISTER => HEIGHT,
+            STRIDE_REGISTER => STRIDE,
+            _ => return None,
+        };
+        Some(Wrapping(value))
+    }
+}
+
+impl Probe for Screen {
+    fn name(&self) -> &'static str {
+        "screen"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("input", &self.input),
+            Port::wire("address", &self.address),
+            Port::wire("strobe", &self.strobe),
+        ]
+    }
+}
+
+// the display just shows the frame again when the next one is due
+impl Snapshot for Screen {
+    fn save(&self, out: &mut Writer) {
+        out.put_bytes(&self.frame);
+        out.put(&self.cycle);
+        out.put(&self.frames);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        let frame = input.get_bytes()?;
+        if frame.len() != self.frame.len() {
+            return Err(SnapshotError::Mismatch(
+                "the frame has another size".to_string(),
+            ));
+        }
+        self.frame.copy_from_slice(frame);
+        self.cycle = input.get()?;
+        self.frames = input.get()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::memory::{from_lanes, to_lanes};
+    use crate::chips::{wire, ZERO};
+
+    fn screen() -> Screen {
+        Screen::new(wire(ZERO), wire(ZERO), wire(0), Box::new(Headless))
+    }
 
-            app::flush();
-            app::awake();
+    // offset of pixel (x, y) in the screen window
+    fn pixel(x: u32, y: u32) -> U32 {
+        Wrapping(y * STRIDE + x * 4)
+    }
+
+    // the bytes of pixel (x, y) in the frame
+    fn rgba(screen: &Screen, x: u32, y: u32) -> [u8; 4] {
+        let at = ((y * WIDTH + x) * 4) as usize;
+        screen.frame[at..at + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn word_store_lands_on_its_pixel_as_rgba() {
+        let mut screen = screen();
+        screen.write(pixel(7, 3), Wrapping(0x4433_2211), Width::Word);
+        assert_eq!(rgba(&screen, 7, 3), [0x11, 0x22, 0x33, 0x44]);
+        // nothing else was touched
+        assert_eq!(screen.frame.iter().filter(|&&byte| byte != 0).count(), 4);
+    }
+
+    #[test]
+    fn corners() {
+        let mut screen = screen();
+        let corners = [
+            (0, 0),
+            (WIDTH - 1, 0),
+            (0, HEIGHT - 1),
+            (WIDTH - 1, HEIGHT - 1),
+        ];
+        for (i, &(x, y)) in corners.iter().enumerate() {
+            screen.write(
+                pixel(x, y),
+                Wrapping(0xFF00_0000 | (i as u32 + 1)),
+                Width::Word,
+            );
         }
+        for (i, &(x, y)) in corners.iter().enumerate() {
+            assert_eq!(rgba(&screen, x, y), [i as u8 + 1, 0, 0, 0xFF]);
+        }
+        assert_eq!(screen.frame.iter().filter(|&&byte| byte != 0).count(), 8);
+    }
+
+    #[test]
+    fn byte_and_half_stores_only_change_their_channels() {
+        let mut screen = screen();
+        let at = pixel(10, 20);
+        screen.write(at, Wrapping(0x4433_2211), Width::Word);
+        // green through a byte store, blue and alpha through a half store
+        let green = at + Wrapping(1);
+        screen.write(green, to_lanes(Wrapping(0xAB), green), Width::Byte);
+        let blue = at + Wrapping(2);
+        screen.write(blue, to_lanes(Wrapping(0xEFCD), blue), Width::Half);
+        assert_eq!(rgba(&screen, 10, 20), [0x11, 0xAB, 0xCD, 0xEF]);
+    }
+
+    #[test]
+    fn pixels_read_back() {
+        let mut screen = screen();
+        let at = pixel(599, 1);
+        screen.write(at, Wrapping(0x8070_6050), Width::Word);
+        assert_eq!(screen.read(at, Width::Word), Some(Wrapping(0x8070_6050)));
+        let blue = at + Wrapping(2);
+        let word = screen.read(blue, Width::Byte).unwrap();
+        assert_eq!(from_lanes(word, blue, Width::Byte, false), Wrapping(0x70));
+        // the pixel before is still black
+        assert_eq!(screen.read(pixel(598, 1), Width::Word), Some(ZERO));
+    }
+
+    #[test]
+    fn registers_describe_the_frame() {
+        let mut screen = screen();
+        let read = |screen: &mut Screen, offset| screen.read(Wrapping(offset), Width::Word);
+        assert_eq!(read(&mut screen, WIDTH_REGISTER), Some(Wrapping(600)));
+        assert_eq!(read(&mut screen, HEIGHT_REGISTER), Some(Wrapping(400)));
+        assert_eq!(read(&mut screen, STRIDE_REGISTER), Some(Wrapping(2400)));
+        // read only
+        assert_eq!(
+            screen.write(Wrapping(WIDTH_REGISTER), ZERO, Width::Word),
+            None
+        );
+        assert_eq!(read(&mut screen, WIDTH_REGISTER), Some(Wrapping(600)));
+    }
+
+    #[test]
+    fn past_the_frame_is_unmapped() {
+        let mut screen = screen();
+        assert_eq!(screen.read(Wrapping(FRAME_SIZE), Width::Word), None);
+        assert_eq!(screen.write(Wrapping(FRAME_SIZE), ZERO, Width::Word), None);
+        assert_eq!(
+            screen.read(Wrapping(STRIDE_REGISTER + 4), Width::Word),
+            None
+        );
     }
 }
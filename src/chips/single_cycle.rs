@@ -0,0 +1,124 @@
+use crate::chips::cpu::{Core, Model};
+use crate::chips::decode::Decode;
+use crate::chips::execute::{alu, ExMem};
+use crate::chips::memory::{MemWb, Memory};
+use crate::chips::pc::PC;
+use crate::chips::probe::{Port, Probe};
+use crate::chips::register_file::RegFile;
+use crate::chips::rom::ROM;
+use crate::chips::write_back::WriteBack;
+use crate::chips::{wire, Chip, Wire, FOUR, U32, ZERO};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
+
+/**The reference core: every clock runs one instruction from fetch to write back, with nothing
+in flight between two cycles. It is built from the same decoder, alu, memory stage and register
+file as the pipeline, so when the two disagree the pipeline's plumbing is to blame*/
+pub struct SingleCycle {
+    pc: Wire<PC>,
+    rom: Wire<ROM>,
+    reg_file: Wire<RegFile<U32>>,
+    write_back: WriteBack,
+    // the word at the pc this cycle and where the pc goes next
+    pub instruction: Wire<U32>,
+    pub next_pc: Wire<U32>,
+}
+
+impl SingleCycle {
+    pub fn new(pc: &Wire<PC>, rom: &Wire<ROM>, reg_file: &Wire<RegFile<U32>>) -> Self {
+        Self {
+            pc: pc.clone(),
+            rom: rom.clone(),
+            reg_file: reg_file.clone(),
+            write_back: WriteBack::new(wire(MemWb::default()), reg_file.clone()),
+            instruction: wire(ZERO),
+            next_pc: wire(ZERO),
+        }
+    }
+
+    fn read(&self, index: U32) -> U32 {
+        *self
+            .reg_file
+            .borrow_mut()
+            .get(index.0 as usize)
+            .output
+            .borrow()
+    }
+}
+
+impl Core for SingleCycle {
+    fn model(&self) -> Model {
+        Model::SingleCycle
+    }
+
+    fn compute(&mut self, memory: &mut Memory) {
+        let pc = *self.pc.borrow().output.borrow();
+        // the rom is read as soon as the address is there, a pipelined fetch waits for the clock
+        let rom = self.rom.borrow();
+        let raw = match rom.contains(pc) {
+            true => rom.peek(pc),
+            false => ZERO,
+        };
+        let instruction = Decode::latch(&rom, raw, pc);
+        drop(rom);
+        *self.instruction.borrow_mut() = raw;
+
+        let (rs1, rs2) = (self.read(instruction.rs1), self.read(instruction.rs2));
+        let (result, target) = alu(&instruction, rs1, rs2);
+        let mem_wb = memory.step(ExMem {
+            valid: true,
+            instruction,
+            rs1_value: rs1,
+            rs2_value: rs2,
+            result,
+            target,
+        });
+        *self.write_back.input.borrow_mut() = mem_wb;
+        self.write_back.compute();
+
+        // a trap or an mret goes where memory says, a branch that didn't trap to its target
+        let taken = match *memory.redirect.borrow() {
+            true => *memory.target.borrow(),
+            false => target.unwrap_or(pc + FOUR),
+        };
+        *self.next_pc.borrow_mut() = taken;
+        let pc = self.pc.borrow();
+        *pc.input.borrow_mut() = taken;
+        *pc.load.borrow_mut() = true;
+    }
+
+    fn clk(&mut self) {
+        self.write_back.clk();
+    }
+
+    fn in_flight(&self) -> Vec<U32> {
+        vec![]
+    }
+
+    fn busy(&self) -> bool {
+        true
+    }
+
+    fn flush(&mut self) {}
+}
+
+impl Probe for SingleCycle {
+    fn name(&self) -> &'static str {
+        "single_cycle"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("instruction", &self.instruction),
+            Port::wire("next_pc", &self.next_pc),
+        ]
+    }
+}
+
+// Between two cycles everything is in the pc, the registers and memory, the machine saves those
+impl Snapshot for SingleCycle {
+    fn save(&self, _out: &mut Writer) {}
+
+    fn restore(&mut self, _input: &mut Reader) -> Result<(), SnapshotError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+use crate::asm::assemble;
+use crate::chips::bus::MemoryMap;
+use crate::chips::cpu::{Model, CPU};
+use crate::chips::input::NoInput;
+use crate::chips::ram::RAM;
+use crate::chips::rom::ROM;
+use crate::chips::screen::{Headless, Screen};
+use crate::chips::uart::Terminal;
+use crate::chips::{wire, ZERO};
+use std::num::Wrapping;
+
+/**A console that never has anything to read and drops what is written*/
+pub struct Silent;
+
+impl Terminal for Silent {
+    fn poll(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn write(&mut self, _byte: u8) {}
+}
+
+/**A headless machine running `program`, assembled with its text at 0 and its data at the start
+of `ram_words` words of ram mapped at `ram_base`*/
+pub fn machine(program: &str, ram_base: u32, ram_words: usize, model: Model) -> CPU {
+    machine_with(program, ram_base, ram_words, model, Box::new(Silent))
+}
+
+/**The same machine with `terminal` on the uart*/
+pub fn machine_with(
+    program: &str,
+    ram_base: u32,
+    ram_words: usize,
+    model: Model,
+    terminal: Box<dyn Terminal>,
+) -> CPU {
+    let map = MemoryMap {
+        ram_base,
+        ..Default::default()
+    };
+    let ram_base = Wrapping(ram_base);
+    let mut ram = RAM::new(
+        wire(ZERO),
+        wire(ZERO),
+        wire(ZERO),
+        wire(false),
+        wire(0),
+        ram_words,
+    );
+    let mut rom = ROM::new(wire(ZERO), wire(ZERO), 256);
+    let image = assemble(program, ZERO, ram_base).unwrap();
+    image.load_into(&mut rom, &mut ram, ram_base).unwrap();
+    let screen = Screen::new(wire(ZERO), wire(ZERO), wire(0), Box::new(Headless));
+    let mut cpu = CPU::new(ram, rom, screen, terminal, Box::new(NoInput), &map, model).unwrap();
+    cpu.set_entry(image.entry);
+    cpu
+}
@@ -0,0 +1,120 @@
+use crate::chips::{U32, ZERO};
+use crate::snapshot::{Reader, SnapshotError, Value, Writer};
+use std::fmt;
+
+/**Synchronous exceptions raised while executing an instruction*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Exception {
+    // the misaligned target address
+    InstructionAddressMisaligned(U32),
+    // the address that could not be fetched
+    InstructionAccessFault(U32),
+    // holds the raw instruction bits
+    IllegalInstruction(U32),
+    // the address of the ebreak
+    Breakpoint(U32),
+    LoadAddressMisaligned(U32),
+    LoadAccessFault(U32),
+    StoreAddressMisaligned(U32),
+    StoreAccessFault(U32),
+    EnvironmentCall,
+}
+
+impl Exception {
+    /**Exception code as written to mcause*/
+    pub fn cause(&self) -> u32 {
+        use Exception::*;
+        match self {
+            InstructionAddressMisaligned(_) => 0,
+            InstructionAccessFault(_) => 1,
+            IllegalInstruction(_) => 2,
+            Breakpoint(_) => 3,
+            LoadAddressMisaligned(_) => 4,
+            LoadAccessFault(_) => 5,
+            StoreAddressMisaligned(_) => 6,
+            StoreAccessFault(_) => 7,
+            EnvironmentCall => 11,
+        }
+    }
+
+    /**Value written to mtval alongside the cause*/
+    pub fn tval(&self) -> U32 {
+        use Exception::*;
+        match self {
+            InstructionAddressMisaligned(v)
+            | InstructionAccessFault(v)
+            | IllegalInstruction(v)
+            | Breakpoint(v)
+            | LoadAddressMisaligned(v)
+            | LoadAccessFault(v)
+            | StoreAddressMisaligned(v)
+            | StoreAccessFault(v) => *v,
+            EnvironmentCall => ZERO,
+        }
+    }
+}
+
+// an instruction in a pipeline register can carry one, it goes by its cause and tval
+impl Value for Exception {
+    fn put(&self, out: &mut Writer) {
+        out.put(&self.cause());
+        out.put(&self.tval());
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        use Exception::*;
+        let (cause, tval): (u32, U32) = (input.get()?, input.get()?);
+        Ok(match cause {
+            0 => InstructionAddressMisaligned(tval),
+            1 => InstructionAccessFault(tval),
+            2 => IllegalInstruction(tval),
+            3 => Breakpoint(tval),
+            4 => LoadAddressMisaligned(tval),
+            5 => LoadAccessFault(tval),
+            6 => StoreAddressMisaligned(tval),
+            7 => StoreAccessFault(tval),
+            11 => EnvironmentCall,
+            _ => {
+                return Err(SnapshotError::Mismatch(format!(
+                    "there is no exception with cause {cause}"
+                )))
+            }
+        })
+    }
+}
+
+impl fmt::Display for Exception {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Exception::*;
+        match self {
+            InstructionAddressMisaligned(addr) => {
+                write!(f, "misaligned jump target {:#010x}", addr.0)
+            }
+            InstructionAccessFault(addr) => {
+                write!(f, "instruction access fault at {:#010x}", addr.0)
+            }
+            IllegalInstruction(raw) => write!(f, "illegal instruction {:#010x}", raw.0),
+            Breakpoint(_) => write!(f, "breakpoint"),
+            LoadAddressMisaligned(addr) => write!(f, "misaligned load from {:#010x}", addr.0),
+            LoadAccessFault(addr) => write!(f, "load access fault at {:#010x}", addr.0),
+            StoreAddressMisaligned(addr) => write!(f, "misaligned store to {:#010x}", addr.0),
+            StoreAccessFault(addr) => write!(f, "store access fault at {:#010x}", addr.0),
+            EnvironmentCall => write!(f, "environment call"),
+        }
+    }
+}
+
+/**An exception together with the address of the instruction that raised it*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Trap {
+    pub pc: U32,
+    pub exception: Exception,
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at pc {:#010x}", self.exception, self.pc.0)
+    }
+}
+
+impl std::error::Error for Trap {}
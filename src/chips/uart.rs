@@ -0,0 +1,469 @@
+use crate::chips::bus::Device;
+use crate::chips::memory::{strobe, Width};
+use crate::chips::probe::{Port, Probe};
+use crate::chips::register::Register;
+use crate::chips::{wire, Chip, Wire, U32, ZERO};
+use crate::snapshot::{Reader, Snapshot, SnapshotError, Writer};
+use std::collections::VecDeque;
+use std::num::Wrapping;
+
+/**Where the uart sits in the memory map and its plic source, as on QEMU virt*/
+pub const UART_BASE: u32 = 0x1000_0000;
+pub const UART_SIZE: u32 = 0x100;
+pub const UART_IRQ: usize = 10;
+
+// register offsets, the divisor latch replaces RBR/THR and IER while LCR.DLAB is set
+const RBR_THR_DLL: u32 = 0;
+const IER_DLM: u32 = 1;
+const IIR_FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+
+const IER_RX_AVAILABLE: u8 = 0x01;
+const IER_THR_EMPTY: u8 = 0x02;
+const IIR_NO_INTERRUPT: u8 = 0x01;
+const IIR_THR_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+const FCR_FIFO_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+const LCR_DLAB: u8 = 0x80;
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_THR_EMPTY: u8 = 0x20;
+const LSR_TRANSMITTER_EMPTY: u8 = 0x40;
+// carrier detect, data set ready and clear to send, the other end is always there
+const MSR_CONNECTED: u8 = 0xB0;
+
+const FIFO_DEPTH: usize = 16;
+
+/**Where the characters of the serial line come from and go to*/
+pub trait Terminal {
+    /**The next received byte, None if nothing has arrived yet*/
+    fn poll(&mut self) -> Option<u8>;
+    /**Wait for the next received byte, None once the input is closed*/
+    fn read(&mut self) -> Option<u8>;
+    fn write(&mut self, byte: u8);
+}
+
+/**NS16550A compatible uart. Transmission is immediate so THR is always empty,
+received bytes wait in a 16 byte fifo. The interrupt line goes to the plic*/
+pub struct Uart {
+    pub input: Wire<U32>,
+    pub output: Wire<U32>,
+    // offset from UART_BASE
+    pub address: Wire<U32>,
+    pub load: Wire<bool>,
+    pub strobe: Wire<u8>,
+    // high for the cycle the bus accesses the uart, reading RBR and IIR has side effects
+    pub select: Wire<bool>,
+    pub interrupt: Wire<bool>,
+    terminal: Box<dyn Terminal>,
+    ier: Register<u8>,
+    lcr: Register<u8>,
+    mcr: Register<u8>,
+    scr: Register<u8>,
+    fcr: Register<u8>,
+    dll: Register<u8>,
+    dlm: Register<u8>,
+    // the THR empty interrupt is armed by a write to THR or enabling it, reading IIR clears it
+    thr_interrupt: Register<bool>,
+    rx: VecDeque<u8>,
+    // effects of this cycle's access, applied at clk
+    transmit: Option<u8>,
+    pop: bool,
+    clear_rx: bool,
+}
+
+impl Uart {
+    pub fn new(terminal: Box<dyn Terminal>, interrupt: Wire<bool>) -> Self {
+        Self {
+            input: wire(ZERO),
+            output: wire(ZERO),
+            address: wire(ZERO),
+            load: wire(false),
+            strobe: wire(0),
+            select: wire(false),
+            interrupt,
+            terminal,
+            ier: Register::default(),
+            lcr: Register::default(),
+            mcr: Register::default(),
+            scr: Register::default(),
+            fcr: Register::default(),
+            dll: Register::default(),
+            dlm: Register::default(),
+            thr_interrupt: Register::default(),
+            rx: VecDeque::with_capacity(FIFO_DEPTH),
+            transmit: None,
+            pop: false,
+            clear_rx: false,
+        }
+    }
+
+    /**Blocking read for the ecall console, takes what is in the fifo first*/
+    pub fn receive(&mut self) -> Option<u8> {
+        self.rx.pop_front().or_else(|| self.terminal.read())
+    }
+
+    /**Send a byte straight to the terminal*/
+    pub fn send(&mut self, byte: u8) {
+        self.terminal.write(byte);
+    }
+
+    fn lsr(&self) -> u8 {
+        let ready = if self.rx.is_empty() {
+            0
+        } else {
+            LSR_DATA_READY
+        };
+        ready | LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY
+    }
+
+    // Highest priority interrupt the uart is asking for, in IIR encoding
+    fn iir(&self) -> u8 {
+        let ier = *self.ier.output.borrow();
+        let fifo = match *self.fcr.output.borrow() & FCR_FIFO_ENABLE {
+            0 => 0,
+            _ => IIR_FIFO_ENABLED,
+        };
+        let id = if ier & IER_RX_AVAILABLE != 0 && !self.rx.is_empty() {
+            IIR_RX_AVAILABLE
+        } else if ier & IER_THR_EMPTY != 0 && *self.thr_interrupt.output.borrow() {
+            IIR_THR_EMPTY
+        } else {
+            IIR_NO_INTERRUPT
+        };
+        fifo | id
+    }
+
+    // The register a load sees at the address on the port, on the lane of that address
+    fn value(&self) -> U32 {
+        let address = self.address.borrow().0;
+        let dlab = *self.lcr.output.borrow() & LCR_DLAB != 0;
+        if *self.load.borrow() {
+            return ZERO;
+        }
+        let output = match (address & 7, dlab) {
+            (RBR_THR_DLL, false) => self.rx.front().copied().unwrap_or(0),
+            (RBR_THR_DLL, true) => *self.dll.output.borrow(),
+            (IER_DLM, false) => *self.ier.output.borrow(),
+            (IER_DLM, true) => *self.dlm.output.borrow(),
+            (IIR_FCR, _) => self.iir(),
+            (LCR, _) => *self.lcr.output.borrow(),
+            (MCR, _) => *self.mcr.output.borrow(),
+            (LSR, _) => self.lsr(),
+            (MSR, _) => MSR_CONNECTED,
+            _ => *self.scr.output.borrow(),
+        };
+        Wrapping((output as u32) << (8 * (address & 3)))
+    }
+}
+
+impl Chip for Uart {
+    fn compute(&mut self) {
+        let address = self.address.borrow().0;
+        let offset = address & 7;
+        let lane = 8 * (address & 3);
+        let select = *self.select.borrow();
+        let write = select && *self.load.borrow();
+        // byte registers, the value travels on the lane of its address
+        let value = (self.input.borrow().0 >> lane) as u8;
+        let dlab = *self.lcr.output.borrow() & LCR_DLAB != 0;
+
+        self.transmit = None;
+        self.pop = false;
+        self.clear_rx = false;
+        for register in [
+            &self.ier, &self.lcr, &self.mcr, &self.scr, &self.fcr, &self.dll, &self.dlm,
+        ] {
+            *register.load.borrow_mut() = false;
+        }
+        *self.thr_interrupt.load.borrow_mut() = false;
+
+        *self.output.borrow_mut() = self.value();
+        match (offset, write, dlab) {
+            (RBR_THR_DLL, false, false) => self.pop = select,
+            (RBR_THR_DLL, true, false) => {
+                self.transmit = Some(value);
+                *self.thr_interrupt.input.borrow_mut() = true;
+                *self.thr_interrupt.load.borrow_mut() = true;
+            }
+            (RBR_THR_DLL, true, true) => {
+                *self.dll.input.borrow_mut() = value;
+                *self.dll.load.borrow_mut() = true;
+            }
+            (IER_DLM, true, false) => {
+                // enabling the THR empty interrupt fires it straight away, THR is empty
+                let old = *self.ier.output.borrow();
+                if value & IER_THR_EMPTY != 0 && old & IER_THR_EMPTY == 0 {
+                    *self.thr_interrupt.input.borrow_mut() = true;
+                    *self.thr_interrupt.load.borrow_mut() = true;
+                }
+                *self.ier.input.borrow_mut() = value & 0x0F;
+                *self.ier.load.borrow_mut() = true;
+            }
+            (IER_DLM, true, true) => {
+                *self.dlm.input.borrow_mut() = value;
+                *self.dlm.load.borrow_mut() = true;
+            }
+            // reading IIR with the THR empty interrupt up clears it
+            (IIR_FCR, false, _) if select && self.iir() & 0x0F == IIR_THR_EMPTY => {
+                *self.thr_interrupt.input.borrow_mut() = false;
+                *self.thr_interrupt.load.borrow_mut() = true;
+            }
+            (IIR_FCR, true, _) => {
+                self.clear_rx = value & FCR_CLEAR_RX != 0;
+                *self.fcr.input.borrow_mut() = value & FCR_FIFO_ENABLE;
+                *self.fcr.load.borrow_mut() = true;
+            }
+            (LCR, true, _) => {
+                *self.lcr.input.borrow_mut() = value;
+                *self.lcr.load.borrow_mut() = true;
+            }
+            (MCR, true, _) => {
+                *self.mcr.input.borrow_mut() = value & 0x1F;
+                *self.mcr.load.borrow_mut() = true;
+            }
+            (SCR, true, _) => {
+                *self.scr.input.borrow_mut() = value;
+                *self.scr.load.borrow_mut() = true;
+            }
+            // reads have nothing else to do, LSR and MSR ignore writes
+            _ => {}
+        }
+
+        for register in [
+            &mut self.ier,
+            &mut self.lcr,
+            &mut self.mcr,
+            &mut self.scr,
+            &mut self.fcr,
+            &mut self.dll,
+            &mut self.dlm,
+        ] {
+            register.compute();
+        }
+        self.thr_interrupt.compute();
+    }
+
+    fn clk(&mut self) {
+        for register in [
+            &mut self.ier,
+            &mut self.lcr,
+            &mut self.mcr,
+            &mut self.scr,
+            &mut self.fcr,
+            &mut self.dll,
+            &mut self.dlm,
+        ] {
+            register.clk();
+        }
+        self.thr_interrupt.clk();
+
+        if let Some(byte) = self.transmit.take() {
+            self.terminal.write(byte);
+        }
+        if std::mem::take(&mut self.pop) {
+            self.rx.pop_front();
+        }
+        if std::mem::take(&mut self.clear_rx) {
+            self.rx.clear();
+        }
+        // bytes typed on the terminal land in the fifo, the rest wait on the host side
+        if self.rx.len() < FIFO_DEPTH {
+            if let Some(byte) = self.terminal.poll() {
+                self.rx.push_back(byte);
+            }
+        }
+        // an access lasts a single cycle
+        *self.select.borrow_mut() = false;
+        *self.load.borrow_mut() = false;
+
+        *self.interrupt.borrow_mut() = self.iir() & IIR_NO_INTERRUPT == 0;
+    }
+}
+
+// An access only sets up the port, the cpu computes the uart once a cycle after the memory
+// stage. The byte popped from RBR or sent through THR moves when the cpu clocks the uart
+impl Device for Uart {
+    fn read(&mut self, offset: U32, width: Width) -> Option<U32> {
+        *self.address.borrow_mut() = offset;
+        *self.load.borrow_mut() = false;
+        *self.strobe.borrow_mut() = strobe(offset, width);
+        *self.select.borrow_mut() = true;
+        Some(self.value())
+    }
+
+    fn write(&mut self, offset: U32, value: U32, width: Width) -> Option<()> {
+        *self.address.borrow_mut() = offset;
+        *self.input.borrow_mut() = value;
+        *self.load.borrow_mut() = true;
+        *self.strobe.borrow_mut() = strobe(offset, width);
+        *self.select.borrow_mut() = true;
+        Some(())
+    }
+}
+
+impl Probe for Uart {
+    fn name(&self) -> &'static str {
+        "uart"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        vec![
+            Port::wire("input", &self.input),
+            Port::wire("output", &self.output),
+            Port::wire("address", &self.address),
+            Port::wire("load", &self.load),
+            Port::wire("strobe", &self.strobe),
+            Port::wire("select", &self.select),
+            Port::wire("interrupt", &self.interrupt),
+        ]
+    }
+}
+
+// Bytes still in the host side reader aren't the machine's, only the fifo is saved
+impl Snapshot for Uart {
+    fn save(&self, out: &mut Writer) {
+        for register in [
+            &self.ier, &self.lcr, &self.mcr, &self.scr, &self.fcr, &self.dll, &self.dlm,
+        ] {
+            register.save(out);
+        }
+        self.thr_interrupt.save(out);
+        out.put(&self.rx);
+    }
+
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError> {
+        for register in [
+            &mut self.ier,
+            &mut self.lcr,
+            &mut self.mcr,
+            &mut self.scr,
+            &mut self.fcr,
+            &mut self.dll,
+            &mut self.dlm,
+        ] {
+            register.restore(input)?;
+        }
+        self.thr_interrupt.restore(input)?;
+        self.rx = input.get()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // bytes waiting to be typed and what the uart sent
+    #[derive(Clone, Default)]
+    struct Line {
+        typed: Rc<RefCell<VecDeque<u8>>>,
+        sent: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Terminal for Line {
+        fn poll(&mut self) -> Option<u8> {
+            self.typed.borrow_mut().pop_front()
+        }
+
+        fn read(&mut self) -> Option<u8> {
+            self.poll()
+        }
+
+        fn write(&mut self, byte: u8) {
+            self.sent.borrow_mut().push(byte);
+        }
+    }
+
+    fn uart() -> (Uart, Line) {
+        let line = Line::default();
+        (Uart::new(Box::new(line.clone()), wire(false)), line)
+    }
+
+    fn tick(uart: &mut Uart) {
+        uart.compute();
+        uart.clk();
+    }
+
+    fn store(uart: &mut Uart, offset: u32, value: u8) {
+        let value = Wrapping((value as u32) << (8 * (offset & 3)));
+        uart.write(Wrapping(offset), value, Width::Byte);
+        tick(uart);
+    }
+
+    fn load(uart: &mut Uart, offset: u32) -> u8 {
+        let value = uart.read(Wrapping(offset), Width::Byte).unwrap().0;
+        tick(uart);
+        (value >> (8 * (offset & 3))) as u8
+    }
+
+    fn interrupt(uart: &Uart) -> bool {
+        *uart.interrupt.borrow()
+    }
+
+    #[test]
+    fn lsr_shows_received_data() {
+        let (mut uart, line) = uart();
+        assert_eq!(load(&mut uart, LSR), LSR_THR_EMPTY | LSR_TRANSMITTER_EMPTY);
+        line.typed.borrow_mut().extend(b"hi");
+        tick(&mut uart);
+        tick(&mut uart);
+        assert_eq!(load(&mut uart, LSR) & LSR_DATA_READY, LSR_DATA_READY);
+        assert_eq!(load(&mut uart, RBR_THR_DLL), b'h');
+        assert_eq!(load(&mut uart, RBR_THR_DLL), b'i');
+        assert_eq!(load(&mut uart, LSR) & LSR_DATA_READY, 0);
+
+        store(&mut uart, RBR_THR_DLL, b'!');
+        assert_eq!(*line.sent.borrow(), b"!");
+    }
+
+    #[test]
+    fn received_data_interrupts_once_enabled() {
+        let (mut uart, line) = uart();
+        line.typed.borrow_mut().push_back(b'x');
+        tick(&mut uart);
+        assert!(!interrupt(&uart));
+        store(&mut uart, IER_DLM, IER_RX_AVAILABLE);
+        assert!(interrupt(&uart));
+        assert_eq!(load(&mut uart, IIR_FCR), IIR_RX_AVAILABLE);
+        // reading the byte is what acknowledges it
+        assert!(interrupt(&uart));
+        assert_eq!(load(&mut uart, RBR_THR_DLL), b'x');
+        assert!(!interrupt(&uart));
+        assert_eq!(load(&mut uart, IIR_FCR), IIR_NO_INTERRUPT);
+    }
+
+    #[test]
+    fn thr_empty_interrupts_until_iir_is_read() {
+        let (mut uart, _) = uart();
+        store(&mut uart, IER_DLM, IER_THR_EMPTY);
+        assert!(interrupt(&uart));
+        assert_eq!(load(&mut uart, IIR_FCR), IIR_THR_EMPTY);
+        assert!(!interrupt(&uart));
+        // every byte sent empties THR again
+        store(&mut uart, RBR_THR_DLL, b'a');
+        assert!(interrupt(&uart));
+        store(&mut uart, IER_DLM, 0);
+        assert!(!interrupt(&uart));
+    }
+
+    #[test]
+    fn the_divisor_latch_hides_rbr_and_ier() {
+        let (mut uart, line) = uart();
+        store(&mut uart, LCR, LCR_DLAB);
+        store(&mut uart, RBR_THR_DLL, 0x12);
+        store(&mut uart, IER_DLM, 0x34);
+        store(&mut uart, LCR, 0x03);
+        assert!(line.sent.borrow().is_empty());
+        assert_eq!(load(&mut uart, IER_DLM), 0);
+        store(&mut uart, LCR, LCR_DLAB);
+        assert_eq!((load(&mut uart, 0), load(&mut uart, 1)), (0x12, 0x34));
+    }
+}
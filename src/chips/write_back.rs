@@ -0,0 +1,54 @@
+use crate::chips::memory::MemWb;
+use crate::chips::probe::{Port, Probe};
+use crate::chips::register_file::RegFile;
+use crate::chips::{Chip, Wire, U32};
+
+/**The WB stage: the result in MEM/WB goes into the register file on the clock edge. Decode
+reads the register being written in the same cycle straight off MEM/WB*/
+pub struct WriteBack {
+    pub input: Wire<MemWb>,
+    pub reg_file: Wire<RegFile<U32>>,
+}
+
+impl WriteBack {
+    pub fn new(input: Wire<MemWb>, reg_file: Wire<RegFile<U32>>) -> Self {
+        Self { input, reg_file }
+    }
+}
+
+impl Chip for WriteBack {
+    fn compute(&mut self) {
+        let mem_wb = self.input.borrow();
+        let mut reg_file = self.reg_file.borrow_mut();
+        if let Some(value) = mem_wb.writes(mem_wb.rd) {
+            let rd = reg_file.get(mem_wb.rd.0 as usize);
+            *rd.input.borrow_mut() = value;
+            *rd.load.borrow_mut() = true;
+        }
+        reg_file.compute();
+    }
+
+    fn clk(&mut self) {
+        // the register file drops the load bits once clocked
+        self.reg_file.borrow_mut().clk();
+    }
+}
+
+impl Probe for WriteBack {
+    fn name(&self) -> &'static str {
+        "write_back"
+    }
+
+    fn ports(&self) -> Vec<Port> {
+        let input = &self.input;
+        vec![
+            Port::field("input.valid", 1, input, |mem_wb| mem_wb.valid as u64),
+            Port::field("input.pc", 32, input, |mem_wb| {
+                mem_wb.instruction.pc.0 as u64
+            }),
+            Port::field("input.write", 1, input, |mem_wb| mem_wb.write as u64),
+            Port::field("input.rd", 5, input, |mem_wb| mem_wb.rd.0 as u64),
+            Port::field("input.value", 32, input, |mem_wb| mem_wb.value.0 as u64),
+        ]
+    }
+}
@@ -0,0 +1,535 @@
+use crate::chips::bus::MemoryMap;
+use crate::chips::cpu::Model;
+use crate::frame::{FrameDump, ImageFormat};
+use crate::gdb::Endpoint;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::ops::Range;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+usage: riscv_emulator [OPTIONS] <PROGRAM>
+       riscv_emulator disasm [-f <FORMAT>] [--map rom=<ADDR>] <PROGRAM>
+
+The disasm command prints the assembly of the program's code instead of running it, with
+the symbol names of an ELF file on the branch and jump targets.
+
+options:
+  -f, --format <FORMAT>         program format: elf, hex, bin or asm (default: guessed from
+                                the file)
+      --ram-size <BYTES>        size of the RAM, K and M suffixes allowed (default: 4M), the
+                                screen moves up past a bigger one unless --map places it
+      --rom-size <BYTES>        size of the ROM, K and M suffixes allowed (default: 4K)
+      --map <REGION>=<ADDR>     move rom, ram, screen, clint, plic, uart or input to ADDR, may
+                                be repeated
+      --core <MODEL>            the cpu model: pipelined (five stages) or single-cycle, the one
+                                instruction per clock reference (default: pipelined)
+  -n, --max-instructions <N>    stop after N executed instructions (exit status 124)
+      --headless                run without opening the screen window, a build without the
+                                window cargo feature (cargo build --features window) always
+                                runs headless
+      --frame-dump <FILE>       save the screen to FILE (.ppm or .png) at exit, SIGUSR1 saves
+                                a numbered copy while running
+      --dump-every <N>          also save every Nth frame as a numbered copy of the
+                                --frame-dump file
+      --input <FILE>            play the key and pointer events of FILE to the input device
+      --uart <BACKEND>          where the serial console goes: stdio or pty (default: stdio)
+  -t, --trace                   log every retired instruction to stderr in Spike's commit log
+                                format (-l --log-commits)
+      --trace-file <FILE>       write the trace to FILE instead of stderr, implies --trace
+      --trace-pc <START>..<END> only trace instructions at START <= pc < END
+      --trace-window <FROM>..<TO>
+                                only trace retired instructions FROM <= n < TO, counting from 0
+      --vcd <FILE>              dump signals to FILE every clock cycle as a Value Change Dump
+                                for waveform viewers like GTKWave
+      --vcd-signals <SIGNALS>   the signals to dump, a comma separated list of the groups pc,
+                                pipeline, regs and memory and of paths in the chip tree like
+                                memory.uart, see the monitor's probe (default: all groups)
+      --gdb <ENDPOINT>          wait for gdb on ENDPOINT (PORT, HOST:PORT or unix:PATH) and let it
+                                control the machine, which runs on freely once gdb detaches
+      --monitor                 start stopped in the built in debugger, which also takes over on
+                                EBREAK, ctrl-c and fatal traps
+      --watch <SPEC>            watch a register or memory range, see the monitor's help for
+                                SPEC; hits stop a debugger or are logged, may be repeated
+      --snapshot <FILE>         save the whole machine to FILE when the emulator stops
+      --restore <FILE>          start from the machine state saved in FILE instead of the
+                                program's entry, the program is still needed for its symbols
+                                and the machine must be built with the same sizes and map
+      --history <CYCLES>        record the run so the monitor can go back in time, with a
+                                checkpoint every CYCLES cycles; needs --monitor
+      --log <LEVEL>             log level: off, error, warn, info, debug or trace (default: warn)
+  -h, --help                    print this help
+
+The emulator exits with the exit code the guest passes to ecall 10.";
+
+/**Status used when the instruction limit is reached, the same one `timeout` uses*/
+pub const LIMIT_EXIT_CODE: i32 = 124;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Elf,
+    Hex,
+    Binary,
+    // assembly source, assembled when it is loaded
+    Asm,
+}
+
+/**Backend of the serial console*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UartBackend {
+    Stdio,
+    Pty,
+}
+
+#[derive(Debug)]
+pub struct Options {
+    pub program: PathBuf,
+    pub format: Option<Format>,
+    pub ram_size: usize,
+    pub rom_size: usize,
+    pub map: MemoryMap,
+    pub core: Model,
+    pub max_instructions: Option<u64>,
+    pub headless: bool,
+    pub frame_dump: Option<FrameDump>,
+    pub input: Option<PathBuf>,
+    pub uart: UartBackend,
+    pub trace: bool,
+    pub trace_file: Option<PathBuf>,
+    pub trace_pcs: Option<Range<u32>>,
+    pub trace_window: Option<Range<u64>>,
+    pub vcd: Option<PathBuf>,
+    pub vcd_signals: Vec<String>,
+    pub gdb: Option<Endpoint>,
+    pub monitor: bool,
+    // unparsed, the program's symbols are needed to read them
+    pub watch: Vec<String>,
+    pub snapshot: Option<PathBuf>,
+    pub restore: Option<PathBuf>,
+    // cycles between two checkpoints of the recorded run
+    pub history: Option<u64>,
+    pub log_level: LevelFilter,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            program: PathBuf::new(),
+            format: None,
+            ram_size: 4 * 1024 * 1024,
+            rom_size: 4 * 1024,
+            map: MemoryMap::default(),
+            core: Model::default(),
+            max_instructions: None,
+            headless: false,
+            frame_dump: None,
+            input: None,
+            uart: UartBackend::Stdio,
+            trace: false,
+            trace_file: None,
+            trace_pcs: None,
+            trace_window: None,
+            vcd: None,
+            vcd_signals: ["pc", "pipeline", "regs", "memory"]
+                .map(String::from)
+                .to_vec(),
+            gdb: None,
+            monitor: false,
+            watch: Vec::new(),
+            snapshot: None,
+            restore: None,
+            history: None,
+            log_level: LevelFilter::Warn,
+        }
+    }
+}
+
+/**What the command line asked for*/
+pub enum Command {
+    Run(Box<Options>),
+    // print the disassembly of the program, only the format and the rom base matter
+    Disasm(Box<Options>),
+    Help,
+}
+
+fn parse_size(value: &str) -> Result<usize, String> {
+    let (digits, scale) = match value.to_ascii_uppercase() {
+        v if v.ends_with('K') => (value[..value.len() - 1].to_string(), 1024),
+        v if v.ends_with('M') => (value[..value.len() - 1].to_string(), 1024 * 1024),
+        _ => (value.to_string(), 1),
+    };
+    let size = digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|digits| digits.checked_mul(scale))
+        .ok_or_else(|| format!("invalid size '{value}'"))?;
+    if size == 0 || !size.is_multiple_of(4) {
+        return Err(format!("size '{value}' must be a non-zero multiple of 4"));
+    }
+    // the window has to fit the 32 bit address space
+    if u32::try_from(size).is_err() {
+        return Err(format!(
+            "size '{value}' is larger than the 4G address space"
+        ));
+    }
+    Ok(size)
+}
+
+fn parse_address(value: &str) -> Result<u32, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(&hex.replace('_', ""), 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|_| format!("invalid address '{value}'"))
+}
+
+// START..END, the end is excluded
+fn parse_range<T: PartialOrd>(
+    value: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+) -> Result<Range<T>, String> {
+    let (start, end) = value
+        .split_once("..")
+        .ok_or_else(|| format!("expected START..END, got '{value}'"))?;
+    let range = parse(start)?..parse(end)?;
+    if range.is_empty() {
+        return Err(format!("empty range '{value}'"));
+    }
+    Ok(range)
+}
+
+impl Command {
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut args = args.peekable();
+        let disasm = args.next_if(|arg| arg == "disasm").is_some();
+        let mut options = Options::default();
+        let mut program = None;
+        let mut dump_every = None;
+        let mut screen_placed = false;
+
+        while let Some(arg) = args.next() {
+            let mut value = |name: &str| {
+                args.next()
+                    .ok_or_else(|| format!("missing value for {name}"))
+            };
+            match arg.as_str() {
+                "-h" | "--help" => return Ok(Command::Help),
+                "-f" | "--format" => {
+                    options.format = Some(match value(&arg)?.as_str() {
+                        "elf" => Format::Elf,
+                        "hex" | "ihex" => Format::Hex,
+                        "bin" | "raw" => Format::Binary,
+                        "asm" | "s" => Format::Asm,
+                        other => return Err(format!("unknown format '{other}'")),
+                    })
+                }
+                "--ram-size" => options.ram_size = parse_size(&value(&arg)?)?,
+                "--rom-size" => options.rom_size = parse_size(&value(&arg)?)?,
+                "--map" => {
+                    let value = value(&arg)?;
+                    let (region, addr) = value
+                        .split_once('=')
+                        .ok_or_else(|| format!("expected REGION=ADDR, got '{value}'"))?;
+                    if !options.map.set_base(region, parse_address(addr)?) {
+                        return Err(format!("unknown memory region '{region}'"));
+                    }
+                    screen_placed |= region == "screen";
+                }
+                "--core" => {
+                    let name = value(&arg)?;
+                    options.core =
+                        Model::parse(&name).ok_or_else(|| format!("unknown core '{name}'"))?
+                }
+                "-n" | "--max-instructions" => {
+                    let n = value(&arg)?;
+                    options.max_instructions = Some(
+                        n.parse()
+                            .map_err(|_| format!("invalid instruction count '{n}'"))?,
+                    )
+                }
+                "--headless" => options.headless = true,
+                "--frame-dump" => {
+                    let path = PathBuf::from(value(&arg)?);
+                    if ImageFormat::from_path(&path).is_none() {
+                        return Err(format!(
+                            "{}: frames can only be saved as .ppm or .png",
+                            path.display()
+                        ));
+                    }
+                    options.frame_dump = Some(FrameDump { path, every: None });
+                }
+                "--dump-every" => {
+                    let n = value(&arg)?;
+                    dump_every = Some(
+                        n.parse::<u64>()
+                            .ok()
+                            .filter(|&n| n > 0)
+                            .ok_or_else(|| format!("invalid frame count '{n}'"))?,
+                    )
+                }
+                "--input" => options.input = Some(PathBuf::from(value(&arg)?)),
+                "--uart" => {
+                    options.uart = match value(&arg)?.as_str() {
+                        "stdio" => UartBackend::Stdio,
+                        "pty" => UartBackend::Pty,
+                        other => return Err(format!("unknown uart backend '{other}'")),
+                    }
+                }
+                "-t" | "--trace" => options.trace = true,
+                "--trace-file" => {
+                    options.trace = true;
+                    options.trace_file = Some(PathBuf::from(value(&arg)?));
+                }
+                "--trace-pc" => {
+                    options.trace_pcs = Some(parse_range(&value(&arg)?, parse_address)?)
+                }
+                "--trace-window" => {
+                    let count = |n: &str| {
+                        n.parse::<u64>()
+                            .map_err(|_| format!("invalid instruction count '{n}'"))
+                    };
+                    options.trace_window = Some(parse_range(&value(&arg)?, count)?)
+                }
+                "--vcd" => options.vcd = Some(PathBuf::from(value(&arg)?)),
+                "--vcd-signals" => {
+                    options.vcd_signals = value(&arg)?
+                        .split(',')
+                        .map(|name| name.trim().to_string())
+                        .filter(|name| !name.is_empty())
+                        .collect()
+                }
+                "--gdb" => options.gdb = Some(Endpoint::parse(&value(&arg)?)?),
+                "--monitor" => options.monitor = true,
+                "--watch" => options.watch.push(value(&arg)?),
+                "--snapshot" => options.snapshot = Some(PathBuf::from(value(&arg)?)),
+                "--restore" => options.restore = Some(PathBuf::from(value(&arg)?)),
+                "--history" => {
+                    let n = value(&arg)?;
+                    options.history = Some(
+                        n.parse::<u64>()
+                            .ok()
+                            .filter(|&n| n > 0)
+                            .ok_or_else(|| format!("invalid cycle count '{n}'"))?,
+                    )
+                }
+                "--log" => {
+                    let level = value(&arg)?;
+                    options.log_level = level
+                        .parse()
+                        .map_err(|_| format!("invalid log level '{level}'"))?
+                }
+                flag if flag.starts_with('-') => return Err(format!("unknown option '{flag}'")),
+                path => {
+                    if program.replace(PathBuf::from(path)).is_some() {
+                        return Err("more than one program given".to_string());
+                    }
+                }
+            }
+        }
+
+        // a ram bigger than the default runs into the screen, which moves up unless it was placed
+        if !screen_placed {
+            let ram_size = u32::try_from(options.ram_size).unwrap_or(u32::MAX);
+            options.map.make_room_for_ram(ram_size);
+        }
+        match options.frame_dump.as_mut() {
+            Some(dump) => dump.every = dump_every,
+            None if dump_every.is_some() => {
+                return Err("--dump-every needs --frame-dump".to_string())
+            }
+            None => {}
+        }
+        if !options.trace && (options.trace_pcs.is_some() || options.trace_window.is_some()) {
+            return Err("--trace-pc and --trace-window need --trace or --trace-file".to_string());
+        }
+        if options.monitor && options.gdb.is_some() {
+            return Err("--monitor and --gdb can't be used together".to_string());
+        }
+        if options.history.is_some() && !options.monitor {
+            return Err("--history needs --monitor".to_string());
+        }
+        options.program = program.ok_or("no program given")?;
+        match disasm {
+            true => Ok(Command::Disasm(Box::new(options))),
+            false => Ok(Command::Run(Box::new(options))),
+        }
+    }
+}
+
+impl Options {
+    /**The format asked for, or a guess from the magic number and the extension*/
+    pub fn format(&self, bytes: &[u8]) -> Format {
+        if let Some(format) = self.format {
+            return format;
+        }
+        let extension = self.program.extension().and_then(|e| e.to_str());
+        if bytes.starts_with(b"\x7fELF") {
+            Format::Elf
+        } else if matches!(extension, Some("hex" | "ihex")) || bytes.starts_with(b":") {
+            Format::Hex
+        } else if matches!(extension, Some("s" | "S" | "asm")) {
+            Format::Asm
+        } else {
+            Format::Binary
+        }
+    }
+}
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{}] {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+pub fn init_logger(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(line: &str) -> Result<Command, String> {
+        Command::parse(line.split_whitespace().map(String::from))
+    }
+
+    fn run(line: &str) -> Options {
+        match parse(line) {
+            Ok(Command::Run(options)) => *options,
+            Ok(_) => panic!("'{line}' is not a run"),
+            Err(err) => panic!("'{line}': {err}"),
+        }
+    }
+
+    fn error(line: &str) -> String {
+        match parse(line) {
+            Err(err) => err,
+            Ok(_) => panic!("'{line}' parsed"),
+        }
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("64"), Ok(64));
+        assert_eq!(parse_size("16k"), Ok(16 * 1024));
+        assert_eq!(parse_size("8M"), Ok(8 * 1024 * 1024));
+        assert_eq!(parse_size("4095M"), Ok(4095 * 1024 * 1024));
+        let must = "must be a non-zero multiple of 4";
+        assert_eq!(parse_size("0"), Err(format!("size '0' {must}")));
+        assert_eq!(parse_size("0K"), Err(format!("size '0K' {must}")));
+        assert_eq!(parse_size("6"), Err(format!("size '6' {must}")));
+        assert_eq!(parse_size("4G"), Err("invalid size '4G'".to_string()));
+        assert_eq!(parse_size("-4"), Err("invalid size '-4'".to_string()));
+        assert_eq!(
+            parse_size("99999999999999M"),
+            Err("invalid size '99999999999999M'".to_string())
+        );
+        assert_eq!(
+            parse_size("4096M"),
+            Err("size '4096M' is larger than the 4G address space".to_string())
+        );
+    }
+
+    #[test]
+    fn addresses_and_ranges() {
+        assert_eq!(parse_address("0x1000_0000"), Ok(0x1000_0000));
+        assert_eq!(parse_address("0XfF"), Ok(0xff));
+        assert_eq!(parse_address("4096"), Ok(4096));
+        assert!(parse_address("0x1_0000_0000").is_err());
+        assert!(parse_address("4_096").is_err());
+        assert_eq!(parse_range("0x10..0x20", parse_address), Ok(0x10..0x20));
+        assert_eq!(
+            parse_range("0x20..0x20", parse_address),
+            Err("empty range '0x20..0x20'".to_string())
+        );
+        assert_eq!(
+            parse_range("0x20", parse_address),
+            Err("expected START..END, got '0x20'".to_string())
+        );
+    }
+
+    #[test]
+    fn run_options() {
+        let options = run("--ram-size 1M --core single-cycle -n 100 -t --trace-pc 0..0x40 a.elf");
+        assert_eq!(options.program, PathBuf::from("a.elf"));
+        assert_eq!(options.ram_size, 1024 * 1024);
+        assert_eq!(options.core, Model::SingleCycle);
+        assert_eq!(options.max_instructions, Some(100));
+        assert_eq!(options.trace_pcs, Some(0..0x40));
+        assert!(matches!(parse("--help a.elf"), Ok(Command::Help)));
+        assert_eq!(error("a.elf b.elf"), "more than one program given");
+        assert_eq!(error("--ram-size"), "missing value for --ram-size");
+        assert_eq!(error("--frobnicate a.elf"), "unknown option '--frobnicate'");
+        assert_eq!(error("--core"), "missing value for --core");
+        assert_eq!(error("--history 100 a.elf"), "--history needs --monitor");
+        assert_eq!(error(""), "no program given");
+    }
+
+    #[test]
+    fn memory_map() {
+        let options = run("--map uart=0x2000_0000 --map rom=4096 a.elf");
+        assert_eq!(options.map.uart_base, 0x2000_0000);
+        assert_eq!(options.map.rom_base, 4096);
+        assert_eq!(
+            error("--map flash=0 a.elf"),
+            "unknown memory region 'flash'"
+        );
+        assert_eq!(
+            error("--map uart a.elf"),
+            "expected REGION=ADDR, got 'uart'"
+        );
+        assert_eq!(error("--map uart=near a.elf"), "invalid address 'near'");
+        // a bigger ram pushes the screen up, unless the screen was placed
+        assert_eq!(run("--ram-size 8M a.elf").map.screen_base, 0x80_0000);
+        let options = run("--ram-size 8M --map screen=0x4000_0000 a.elf");
+        assert_eq!(options.map.screen_base, 0x4000_0000);
+    }
+
+    #[test]
+    fn disasm_subcommand() {
+        match parse("disasm -f bin --map rom=0x100 a.bin") {
+            Ok(Command::Disasm(options)) => {
+                assert_eq!(options.format, Some(Format::Binary));
+                assert_eq!(options.map.rom_base, 0x100);
+                assert_eq!(options.program, PathBuf::from("a.bin"));
+            }
+            _ => panic!("not a disasm"),
+        }
+        // only the first word names the subcommand
+        assert_eq!(error("a.bin disasm"), "more than one program given");
+        assert_eq!(error("disasm"), "no program given");
+    }
+
+    #[test]
+    fn formats_are_guessed() {
+        let format = |program: &str, bytes: &[u8]| {
+            let options = run(program);
+            options.format(bytes)
+        };
+        assert_eq!(format("a.out", b"\x7fELF\x01"), Format::Elf);
+        // the magic number wins over the extension
+        assert_eq!(format("a.hex", b"\x7fELF\x01"), Format::Elf);
+        assert_eq!(format("a.ihex", b""), Format::Hex);
+        assert_eq!(format("a.txt", b":10000000"), Format::Hex);
+        assert_eq!(format("boot.S", b""), Format::Asm);
+        assert_eq!(format("a.bin", b"\x13\0\0\0"), Format::Binary);
+        assert_eq!(format("-f asm a.bin", b"\x7fELF"), Format::Asm);
+        assert_eq!(error("-f coff a.bin"), "unknown format 'coff'");
+    }
+}
@@ -0,0 +1,480 @@
+use crate::chips::cpu::CPU;
+use crate::chips::trap::{Exception, Trap};
+use crate::chips::U32;
+use crate::disasm::{register_index, Symbols, ABI_NAMES};
+use crate::snapshot::{self, SnapshotError};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::num::Wrapping;
+use std::ops::Range;
+
+/**The encoding of EBREAK, what a software breakpoint puts in place of the instruction*/
+pub const EBREAK: U32 = Wrapping(0x0010_0073);
+
+// how many instructions run between two checks for an interrupt from the user
+const POLL_INTERVAL: u64 = 1024;
+
+/**Which accesses a watchpoint stops on*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+/**What a watchpoint looks at: a range of data addresses or an integer register*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchTarget {
+    Memory(Range<u32>),
+    Register(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/**A test on the value read or written, unsigned*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub comparison: Comparison,
+    pub value: U32,
+}
+
+impl Condition {
+    pub fn holds(&self, value: U32) -> bool {
+        match self.comparison {
+            Comparison::Eq => value == self.value,
+            Comparison::Ne => value != self.value,
+            Comparison::Lt => value < self.value,
+            Comparison::Le => value <= self.value,
+            Comparison::Gt => value > self.value,
+            Comparison::Ge => value >= self.value,
+        }
+    }
+}
+
+// the operators in the order they are looked for, so <= isn't taken for <
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("==", Comparison::Eq),
+    ("!=", Comparison::Ne),
+    ("<=", Comparison::Le),
+    (">=", Comparison::Ge),
+    ("<", Comparison::Lt),
+    (">", Comparison::Gt),
+];
+
+/**Stops the machine, or logs, when a load, a store or an instruction touches its target.
+With a condition only accesses whose value passes it count*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub target: WatchTarget,
+    pub condition: Option<Condition>,
+}
+
+impl Watchpoint {
+    /**Parse `[r:|w:|a:]TARGET[/LEN][OP VALUE]`: a register name, or an address or symbol with
+    the number of bytes to watch (4 by default), stopping on reads, writes (the default) or
+    both, optionally only when the value compares to VALUE with ==, !=, <, <=, > or >=*/
+    pub fn parse(spec: &str, symbols: &Symbols) -> Result<Self, String> {
+        let (kind, rest) = match spec.split_once(':') {
+            Some(("r" | "read", rest)) => (WatchKind::Read, rest),
+            Some(("w" | "write", rest)) => (WatchKind::Write, rest),
+            Some(("a" | "access", rest)) => (WatchKind::Access, rest),
+            Some((kind, _)) => return Err(format!("unknown watch kind '{kind}'")),
+            None => (WatchKind::Write, spec),
+        };
+        let found = COMPARISONS
+            .iter()
+            .find_map(|&(op, comparison)| Some((rest.find(op)?, op, comparison)));
+        let (target, condition) = match found {
+            Some((at, op, comparison)) => {
+                let value = rest[at + op.len()..].trim();
+                let value = parse_number(value).ok_or(format!("invalid value '{value}'"))?;
+                let condition = Condition {
+                    comparison,
+                    value: Wrapping(value),
+                };
+                (rest[..at].trim(), Some(condition))
+            }
+            None => (rest.trim(), None),
+        };
+        let target = match register_index(target) {
+            Some(n) => WatchTarget::Register(n),
+            None => {
+                let (addr, len) = match target.split_once('/') {
+                    Some((addr, len)) => match parse_number(len).filter(|&len| len > 0) {
+                        Some(len) => (addr, len),
+                        None => return Err(format!("invalid length '{len}'")),
+                    },
+                    None => (target, 4),
+                };
+                let start = parse_location(addr, symbols)
+                    .ok_or(format!("unknown address or register '{addr}'"))?
+                    .0;
+                WatchTarget::Memory(start..start.saturating_add(len))
+            }
+        };
+        Ok(Self {
+            kind,
+            target,
+            condition,
+        })
+    }
+
+    /**Whether the watchpoint stops on a read or a write of `value`*/
+    pub fn wants(&self, write: bool, value: U32) -> bool {
+        let kind = matches!(
+            (self.kind, write),
+            (WatchKind::Access, _) | (WatchKind::Write, true) | (WatchKind::Read, false)
+        );
+        kind && self
+            .condition
+            .is_none_or(|condition| condition.holds(value))
+    }
+
+    /**Whether an access of `bytes` bytes at `addr` falls in the watched range*/
+    pub fn covers(&self, addr: U32, bytes: u32) -> bool {
+        let WatchTarget::Memory(range) = &self.target else {
+            return false;
+        };
+        let end = addr.0 as u64 + bytes as u64;
+        (addr.0 as u64) < range.end as u64 && end > range.start as u64
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            WatchKind::Write => "write",
+            WatchKind::Read => "read",
+            WatchKind::Access => "access",
+        };
+        match &self.target {
+            WatchTarget::Memory(range) => {
+                write!(f, "{kind:<6} {:#010x}..{:#010x}", range.start, range.end)?
+            }
+            WatchTarget::Register(n) => write!(f, "{kind:<6} {}", ABI_NAMES[*n])?,
+        }
+        if let Some(condition) = self.condition {
+            let op = COMPARISONS
+                .iter()
+                .find(|(_, comparison)| *comparison == condition.comparison)
+                .map(|(op, _)| op)
+                .unwrap();
+            write!(f, " if {op} {:#x}", condition.value.0)?;
+        }
+        Ok(())
+    }
+}
+
+/**Where a watched access went*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    Memory(U32),
+    Register(usize),
+}
+
+/**An access that hit a watchpoint, made by the instruction at `pc` during clock cycle
+`cycle`. A read has the same old and new value, the old value of a device that can't be
+read without side effects is unknown*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    // the position of the watchpoint in the list and its kind
+    pub index: usize,
+    pub kind: WatchKind,
+    pub pc: U32,
+    pub write: bool,
+    pub location: Location,
+    pub old: Option<U32>,
+    pub new: U32,
+    pub cycle: u64,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let access = match self.write {
+            true => "write to",
+            false => "read of",
+        };
+        let location = match self.location {
+            Location::Memory(addr) => format!("{:#010x}", addr.0),
+            Location::Register(n) => ABI_NAMES[n].to_string(),
+        };
+        write!(
+            f,
+            "watchpoint {}: {access} {location} by pc {:#010x} at cycle {}: ",
+            self.index, self.pc.0, self.cycle
+        )?;
+        match (self.write, self.old) {
+            (false, _) => write!(f, "{:#010x}", self.new.0),
+            (true, Some(old)) => write!(f, "{:#010x} -> {:#010x}", old.0, self.new.0),
+            (true, None) => write!(f, "? -> {:#010x}", self.new.0),
+        }
+    }
+}
+
+/**A number, decimal or 0x hex, negative numbers wrap*/
+pub fn parse_number(value: &str) -> Option<u32> {
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => match value.strip_prefix('-') {
+            Some(negative) => negative.parse::<u32>().ok().map(|n| n.wrapping_neg()),
+            None => value.parse().ok(),
+        },
+    }
+}
+
+/**A number, or a symbol with an optional +OFFSET*/
+pub fn parse_location(value: &str, symbols: &Symbols) -> Option<U32> {
+    if let Some(n) = parse_number(value) {
+        return Some(Wrapping(n));
+    }
+    let (name, offset) = match value.split_once('+') {
+        Some((name, offset)) => (name, parse_number(offset)?),
+        None => (value, 0),
+    };
+    Some(symbols.address(name)? + Wrapping(offset))
+}
+
+/**Why the machine stopped under a debugger*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    // one instruction was handled and nothing else happened
+    Step,
+    // an ebreak at this address is about to run
+    Breakpoint(U32),
+    // every watchpoint the last instruction hit
+    Watch(Vec<WatchHit>),
+    // an exception with no guest handler, the pc is left on the faulting instruction
+    Trap(Trap),
+    Exit(i32),
+    // the user asked the machine to stop
+    Interrupt,
+}
+
+/**Handle one instruction and tell what became of it*/
+pub fn step(cpu: &mut CPU) -> Stop {
+    match cpu.step_instruction() {
+        Err(Trap {
+            exception: Exception::Breakpoint(pc),
+            ..
+        }) if cpu.memory.debug => Stop::Breakpoint(pc),
+        Err(trap) => {
+            cpu.set_pc(trap.pc);
+            Stop::Trap(trap)
+        }
+        Ok(()) => {
+            let hits = std::mem::take(&mut cpu.memory.watch_hits);
+            match cpu.exit_code() {
+                Some(code) => Stop::Exit(code),
+                None if !hits.is_empty() => Stop::Watch(hits),
+                None => Stop::Step,
+            }
+        }
+    }
+}
+
+/**Run until something stops the machine. `interrupted` is asked every so often whether the
+user wants it to stop*/
+pub fn run(cpu: &mut CPU, mut interrupted: impl FnMut() -> bool) -> Stop {
+    for count in 1.. {
+        match step(cpu) {
+            Stop::Step if count % POLL_INTERVAL == 0 && interrupted() => return Stop::Interrupt,
+            Stop::Step => {}
+            stop => return stop,
+        }
+    }
+    unreachable!()
+}
+
+/**Software breakpoints. The instruction at a breakpoint is swapped for an EBREAK in the rom and
+put back when the breakpoint is removed, so they cost nothing while the machine runs*/
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    // the instruction each ebreak replaced
+    saved: BTreeMap<u32, U32>,
+    // and those of removed breakpoints, going back in time brings their ebreaks back
+    lifted: BTreeMap<u32, U32>,
+}
+
+impl Breakpoints {
+    /**Set a breakpoint, false if `addr` is not an instruction in the rom*/
+    pub fn insert(&mut self, cpu: &mut CPU, addr: U32) -> bool {
+        if !addr.0.is_multiple_of(4) {
+            return false;
+        }
+        if self.saved.contains_key(&addr.0) {
+            return true;
+        }
+        match cpu.patch(addr, EBREAK) {
+            Some(old) => {
+                self.saved.insert(addr.0, old);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /**Clear a breakpoint, false if there was none at `addr`*/
+    pub fn remove(&mut self, cpu: &mut CPU, addr: U32) -> bool {
+        match self.saved.remove(&addr.0) {
+            Some(old) => {
+                self.lifted.insert(addr.0, old);
+                cpu.patch(addr, old).is_some()
+            }
+            None => false,
+        }
+    }
+
+    pub fn contains(&self, addr: U32) -> bool {
+        self.saved.contains_key(&addr.0)
+    }
+
+    /**The instruction a breakpoint hides, None where there is no breakpoint*/
+    pub fn original(&self, addr: U32) -> Option<U32> {
+        self.saved.get(&addr.0).copied()
+    }
+
+    pub fn addresses(&self) -> impl Iterator<Item = U32> + '_ {
+        self.saved.keys().map(|&addr| Wrapping(addr))
+    }
+
+    /**Put every instruction back*/
+    pub fn clear(&mut self, cpu: &mut CPU) {
+        for (addr, old) in std::mem::take(&mut self.saved) {
+            self.lifted.insert(addr, old);
+            cpu.patch(Wrapping(addr), old);
+        }
+    }
+
+    /**Make the rom agree with the breakpoints again after the machine went back to a time
+    when they were different*/
+    pub fn reapply(&mut self, cpu: &mut CPU) {
+        for (&addr, &old) in &self.lifted {
+            if !self.saved.contains_key(&addr) && cpu.code(Wrapping(addr)) == Some(EBREAK) {
+                cpu.patch(Wrapping(addr), old);
+            }
+        }
+        for &addr in self.saved.keys() {
+            if cpu.code(Wrapping(addr)) != Some(EBREAK) {
+                cpu.patch(Wrapping(addr), EBREAK);
+            }
+        }
+    }
+
+    /**A snapshot of the machine with the program's instructions where the ebreaks are. They
+    are swapped in the rom behind the pipeline's back, patching would flush it*/
+    pub fn capture(&self, cpu: &mut CPU) -> Vec<u8> {
+        let rom = cpu.rom.clone();
+        for (&addr, &old) in &self.saved {
+            rom.borrow_mut().poke(Wrapping(addr), old);
+        }
+        let bytes = snapshot::capture(cpu);
+        for &addr in self.saved.keys() {
+            rom.borrow_mut().poke(Wrapping(addr), EBREAK);
+        }
+        bytes
+    }
+
+    /**Restore a snapshot and set the breakpoints again in the restored program*/
+    pub fn restore(&mut self, cpu: &mut CPU, bytes: &[u8]) -> Result<(), SnapshotError> {
+        snapshot::restore(cpu, bytes)?;
+        let rom = cpu.rom.clone();
+        for (&addr, old) in self.saved.iter_mut() {
+            *old = rom.borrow().peek(Wrapping(addr));
+            rom.borrow_mut().poke(Wrapping(addr), EBREAK);
+        }
+        // an instruction fetched before the snapshot was taken doesn't have its ebreak
+        if cpu.in_flight().iter().any(|&addr| self.contains(addr)) {
+            let pc = cpu.pc();
+            cpu.set_pc(pc);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::Symbol;
+
+    fn symbols() -> Symbols {
+        Symbols::new(vec![Symbol {
+            name: "loop".to_string(),
+            address: Wrapping(0x100),
+            size: 0,
+            code: true,
+        }])
+    }
+
+    #[test]
+    fn locations() {
+        let symbols = symbols();
+        let at = |value| parse_location(value, &symbols).map(|addr| addr.0);
+        assert_eq!(at("0x1c"), Some(0x1c));
+        assert_eq!(at("28"), Some(28));
+        assert_eq!(at("loop"), Some(0x100));
+        assert_eq!(at("loop+0x8"), Some(0x108));
+        assert_eq!(at("main"), None);
+    }
+
+    #[test]
+    fn register_names() {
+        assert_eq!(register_index("x0"), Some(0));
+        assert_eq!(register_index("a0"), Some(10));
+        assert_eq!(register_index("fp"), Some(8));
+        assert_eq!(register_index("x32"), None);
+        assert_eq!(register_index("mstatus"), None);
+        assert_eq!(parse_number("-1"), Some(u32::MAX));
+    }
+
+    #[test]
+    fn watchpoint_specs() {
+        let parse = |spec| Watchpoint::parse(spec, &symbols());
+        assert_eq!(
+            parse("loop"),
+            Ok(Watchpoint {
+                kind: WatchKind::Write,
+                target: WatchTarget::Memory(0x100..0x104),
+                condition: None,
+            })
+        );
+        assert_eq!(
+            parse("r:0x2000/16"),
+            Ok(Watchpoint {
+                kind: WatchKind::Read,
+                target: WatchTarget::Memory(0x2000..0x2010),
+                condition: None,
+            })
+        );
+        let watchpoint = parse("a:t0<=0x10").unwrap();
+        assert_eq!(watchpoint.kind, WatchKind::Access);
+        assert_eq!(watchpoint.target, WatchTarget::Register(5));
+        assert!(watchpoint.wants(false, Wrapping(0x10)));
+        assert!(!watchpoint.wants(true, Wrapping(0x11)));
+        assert!(parse("x:loop").is_err());
+        assert!(parse("main").is_err());
+        assert!(parse("loop/0").is_err());
+    }
+
+    #[test]
+    fn watched_ranges() {
+        let watchpoint = Watchpoint::parse("0x100/8", &Symbols::default()).unwrap();
+        assert!(watchpoint.covers(Wrapping(0xfe), 4));
+        assert!(watchpoint.covers(Wrapping(0x107), 1));
+        assert!(!watchpoint.covers(Wrapping(0x108), 4));
+        assert!(!watchpoint.covers(Wrapping(0xfc), 4));
+        assert!(watchpoint.wants(true, Wrapping(1)));
+        assert!(!watchpoint.wants(false, Wrapping(1)));
+    }
+}
@@ -0,0 +1,278 @@
+use crate::chips::csr_file::csr_name;
+use crate::chips::decode::{Decode, Instruction, Operation};
+use crate::chips::{U32, ZERO};
+use crate::loader::{Image, Symbol};
+use std::io::{self, Write};
+use std::num::Wrapping;
+
+/**ABI names of the integer registers, by index*/
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/**The index of an integer register from its ABI name, fp or xN*/
+pub fn register_index(name: &str) -> Option<usize> {
+    if name == "fp" {
+        return Some(8);
+    }
+    if let Some(n) = name.strip_prefix('x').and_then(|n| n.parse::<usize>().ok()) {
+        return (n < 32).then_some(n);
+    }
+    ABI_NAMES.iter().position(|&abi| abi == name)
+}
+
+const RA: U32 = Wrapping(1);
+
+fn reg(index: U32) -> &'static str {
+    ABI_NAMES[index.0 as usize & 31]
+}
+
+fn csr(addr: U32) -> String {
+    let addr = addr.0 as u16 & 0xFFF;
+    match csr_name(addr) {
+        Some(name) => name.to_string(),
+        None => format!("{addr:#x}"),
+    }
+}
+
+/**The symbols of a program sorted by address, to put names on branch and jump targets*/
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+}
+
+impl Symbols {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.address);
+        Self { symbols }
+    }
+
+    /**The symbol that starts at `addr`*/
+    pub fn at(&self, addr: U32) -> Option<&str> {
+        let first = self.symbols.partition_point(|symbol| symbol.address < addr);
+        self.symbols
+            .get(first)
+            .filter(|symbol| symbol.address == addr)
+            .map(|symbol| symbol.name.as_str())
+    }
+
+    /**Where the symbol called `name` starts*/
+    pub fn address(&self, name: &str) -> Option<U32> {
+        let symbol = self.symbols.iter().find(|symbol| symbol.name == name)?;
+        Some(symbol.address)
+    }
+
+    /**The closest symbol at or below `addr` and how far `addr` is into it. A symbol with a
+    size only covers its own bytes, a label without one reaches up to the next symbol*/
+    pub fn find(&self, addr: U32) -> Option<(&str, u32)> {
+        let after = self
+            .symbols
+            .partition_point(|symbol| symbol.address <= addr);
+        let symbol = self.symbols[..after].last()?;
+        let offset = (addr - symbol.address).0;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((&symbol.name, offset))
+    }
+}
+
+// an absolute address with the symbol it falls in, `0x1c <loop+0x4>`
+fn address(addr: U32, symbols: &Symbols) -> String {
+    match symbols.find(addr) {
+        Some((name, 0)) => format!("{:#x} <{name}>", addr.0),
+        Some((name, offset)) => format!("{:#x} <{name}+{offset:#x}>", addr.0),
+        None => format!("{:#x}", addr.0),
+    }
+}
+
+/**Assembly text of a decoded instruction. Registers go by their ABI names, the usual
+pseudo-instructions (nop, li, mv, j, ret, beqz, csrr, ...) replace the instructions they stand
+for and branch and jump targets are absolute addresses, computed from the instruction's pc*/
+pub fn disassemble(instruction: &Instruction) -> String {
+    disassemble_with(instruction, &Symbols::default())
+}
+
+/**Like `disassemble`, naming the branch and jump targets after `symbols`*/
+pub fn disassemble_with(instruction: &Instruction, symbols: &Symbols) -> String {
+    let (mnemonic, operands) = match pseudo(instruction, symbols) {
+        Some((mnemonic, operands)) => (mnemonic.to_string(), operands),
+        None => canonical(instruction, symbols),
+    };
+    match operands.is_empty() {
+        true => mnemonic,
+        false => format!("{mnemonic:<8}{operands}"),
+    }
+}
+
+/**Decode and disassemble the word fetched from `pc`, anything that doesn't decode is shown
+as a `.word`*/
+pub fn disassemble_word(raw: U32, pc: U32, symbols: &Symbols) -> String {
+    match Decode::decode(raw) {
+        Ok(instruction) => disassemble_with(&Instruction { pc, ..instruction }, symbols),
+        Err(_) => format!(".word   {:#010x}", raw.0),
+    }
+}
+
+fn canonical(instruction: &Instruction, symbols: &Symbols) -> (String, String) {
+    use Operation::*;
+    let rd = reg(instruction.rd);
+    let rs1 = reg(instruction.rs1);
+    let rs2 = reg(instruction.rs2);
+    let imm = instruction.imm.0 as i32;
+    let target = address(instruction.pc + instruction.imm, symbols);
+
+    let operands = match instruction.op {
+        LUI | AUIPC => format!("{rd}, {:#x}", instruction.imm.0 >> 12),
+        JAL => format!("{rd}, {target}"),
+        JALR | LB | LH | LW | LBU | LHU => format!("{rd}, {imm}({rs1})"),
+        BEQ | BNE | BLT | BGE | BLTU | BGEU => format!("{rs1}, {rs2}, {target}"),
+        SB | SH | SW => format!("{rs2}, {imm}({rs1})"),
+        ADDI | SLTI | SLTIU | XORI | ORI | ANDI => format!("{rd}, {rs1}, {imm}"),
+        SLLI | SRLI | SRAI => format!("{rd}, {rs1}, {}", instruction.shamtw.0),
+        ADD | SUB | SLL | SLT | SLTU | XOR | SRL | SRA | OR | AND | MUL | MULH | MULHSU | MULHU
+        | DIV | DIVU | REM | REMU => format!("{rd}, {rs1}, {rs2}"),
+        CSRRW | CSRRS | CSRRC => format!("{rd}, {}, {rs1}", csr(instruction.imm)),
+        // the immediate forms carry the uimm in the rs1 field
+        CSRRWI | CSRRSI | CSRRCI => {
+            format!("{rd}, {}, {}", csr(instruction.imm), instruction.rs1.0)
+        }
+        FENCE | ECALL | EBREAK | MRET | WFI => String::new(),
+        ILLEGAL => return (".word".to_string(), format!("{:#010x}", instruction.raw.0)),
+    };
+    (format!("{:?}", instruction.op).to_lowercase(), operands)
+}
+
+// the pseudo-instruction an instruction is the expansion of, if any
+fn pseudo(instruction: &Instruction, symbols: &Symbols) -> Option<(&'static str, String)> {
+    use Operation::*;
+    let (rd, rs1, rs2) = (
+        reg(instruction.rd),
+        reg(instruction.rs1),
+        reg(instruction.rs2),
+    );
+    let imm = instruction.imm.0 as i32;
+    let target = address(instruction.pc + instruction.imm, symbols);
+    let csr = csr(instruction.imm);
+    let uimm = instruction.rs1.0;
+    // which of the registers are x0, and whether the link goes to ra
+    let rd_zero = instruction.rd == ZERO;
+    let rs1_zero = instruction.rs1 == ZERO;
+    let rs2_zero = instruction.rs2 == ZERO;
+    let rd_ra = instruction.rd == RA;
+
+    let pseudo = match instruction.op {
+        ADDI if rd_zero && rs1_zero && imm == 0 => ("nop", String::new()),
+        ADDI if rs1_zero => ("li", format!("{rd}, {imm}")),
+        ADDI if imm == 0 => ("mv", format!("{rd}, {rs1}")),
+        XORI if imm == -1 => ("not", format!("{rd}, {rs1}")),
+        SLTIU if imm == 1 => ("seqz", format!("{rd}, {rs1}")),
+        SUB if rs1_zero => ("neg", format!("{rd}, {rs2}")),
+        SLTU if rs1_zero => ("snez", format!("{rd}, {rs2}")),
+        JAL if rd_zero => ("j", target),
+        JAL if rd_ra => ("jal", target),
+        JALR if rd_zero && instruction.rs1 == RA && imm == 0 => ("ret", String::new()),
+        JALR if rd_zero && imm == 0 => ("jr", rs1.to_string()),
+        JALR if rd_ra && imm == 0 => ("jalr", rs1.to_string()),
+        BEQ if rs2_zero => ("beqz", format!("{rs1}, {target}")),
+        BNE if rs2_zero => ("bnez", format!("{rs1}, {target}")),
+        BLT if rs2_zero => ("bltz", format!("{rs1}, {target}")),
+        BLT if rs1_zero => ("bgtz", format!("{rs2}, {target}")),
+        BGE if rs2_zero => ("bgez", format!("{rs1}, {target}")),
+        BGE if rs1_zero => ("blez", format!("{rs2}, {target}")),
+        CSRRS if rs1_zero => ("csrr", format!("{rd}, {csr}")),
+        CSRRW if rd_zero => ("csrw", format!("{csr}, {rs1}")),
+        CSRRS if rd_zero => ("csrs", format!("{csr}, {rs1}")),
+        CSRRC if rd_zero => ("csrc", format!("{csr}, {rs1}")),
+        CSRRWI if rd_zero => ("csrwi", format!("{csr}, {uimm}")),
+        CSRRSI if rd_zero => ("csrsi", format!("{csr}, {uimm}")),
+        CSRRCI if rd_zero => ("csrci", format!("{csr}, {uimm}")),
+        _ => return None,
+    };
+    Some(pseudo)
+}
+
+/**Write the disassembly of the code sections of `image`, one instruction per line with its
+address and encoding, and a label line wherever a symbol starts*/
+pub fn listing(image: &Image, out: &mut dyn Write) -> io::Result<()> {
+    let code = image.symbols.iter().filter(|symbol| symbol.code);
+    let symbols = Symbols::new(code.cloned().collect());
+    for segment in image.rom.iter() {
+        writeln!(
+            out,
+            "section {}, {} bytes at {:#010x}",
+            segment.name,
+            segment.data.len(),
+            segment.address.0
+        )?;
+        let words = segment.data.chunks(4);
+        for (i, bytes) in words.enumerate() {
+            let pc = segment.address + Wrapping(4 * i as u32);
+            if let Some(name) = symbols.at(pc) {
+                writeln!(out, "\n{:08x} <{name}>:", pc.0)?;
+            }
+            // a section that doesn't end on a whole word shows its tail as bytes
+            if bytes.len() < 4 {
+                let tail: Vec<String> = bytes.iter().map(|byte| format!("{byte:#04x}")).collect();
+                writeln!(
+                    out,
+                    "  {:08x}:            .byte   {}",
+                    pc.0,
+                    tail.join(", ")
+                )?;
+                break;
+            }
+            let raw = Wrapping(u32::from_le_bytes(bytes.try_into().unwrap()));
+            let text = disassemble_word(raw, pc, &symbols);
+            writeln!(out, "  {:08x}:  {:08x}  {text}", pc.0, raw.0)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(raw: u32, pc: u32) -> String {
+        disassemble_word(Wrapping(raw), Wrapping(pc), &Symbols::default())
+    }
+
+    #[test]
+    fn canonical_forms_use_abi_names() {
+        assert_eq!(text(0x00c58533, 0), "add     a0, a1, a2");
+        assert_eq!(text(0xfe842783, 0), "lw      a5, -24(s0)");
+        assert_eq!(text(0x00f12623, 0), "sw      a5, 12(sp)");
+        assert_eq!(text(0x02b50533, 0), "mul     a0, a0, a1");
+        assert_eq!(text(0x00000073, 0), "ecall");
+        assert_eq!(text(0x0000_0000, 0), ".word   0x00000000");
+    }
+
+    #[test]
+    fn pseudo_instructions() {
+        assert_eq!(text(0x00000013, 0), "nop");
+        assert_eq!(text(0x00500513, 0), "li      a0, 5");
+        assert_eq!(text(0x00058513, 0), "mv      a0, a1");
+        assert_eq!(text(0x00008067, 0), "ret");
+        assert_eq!(text(0x300025f3, 0), "csrr    a1, mstatus");
+    }
+
+    #[test]
+    fn targets_are_absolute_and_named() {
+        let symbols = Symbols::new(vec![Symbol {
+            name: "loop".to_string(),
+            address: Wrapping(0x100),
+            size: 0,
+            code: true,
+        }]);
+        // j -16 and bnez a0, -12 from further into the code
+        let j = disassemble_word(Wrapping(0xff1ff06f), Wrapping(0x110), &symbols);
+        assert_eq!(j, "j       0x100 <loop>");
+        let bnez = disassemble_word(Wrapping(0xfe051ae3), Wrapping(0x110), &symbols);
+        assert_eq!(bnez, "bnez    a0, 0x104 <loop+0x4>");
+        assert_eq!(text(0xff1ff06f, 0x110), "j       0x100");
+    }
+}
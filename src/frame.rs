@@ -0,0 +1,136 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/**Image formats a frame can be saved as*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageFormat {
+    Ppm,
+    Png,
+}
+
+impl ImageFormat {
+    /**The format for a file name, by its extension*/
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+            "ppm" => Some(ImageFormat::Ppm),
+            "png" => Some(ImageFormat::Png),
+            _ => None,
+        }
+    }
+}
+
+/**Save an RGBA8888 frame of `width` x `height` pixels, in the format the extension of `path` names.
+PPM has no alpha channel so it is dropped there*/
+pub fn save(path: &Path, frame: &[u8], width: u32, height: u32) -> io::Result<()> {
+    let format = ImageFormat::from_path(path).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{}: frames can only be saved as .ppm or .png",
+                path.display()
+            ),
+        )
+    })?;
+    let mut out = BufWriter::new(File::create(path)?);
+    match format {
+        ImageFormat::Ppm => {
+            write!(out, "P6\n{width} {height}\n255\n")?;
+            for pixel in frame.chunks_exact(4) {
+                out.write_all(&pixel[..3])?;
+            }
+        }
+        ImageFormat::Png => {
+            let mut encoder = png::Encoder::new(&mut out, width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder
+                .write_header()
+                .and_then(|mut writer| writer.write_image_data(frame))
+                .map_err(io::Error::other)?;
+        }
+    }
+    out.flush()
+}
+
+/**When frames are written out while the machine runs*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameDump {
+    // file written at exit, numbered copies of it hold the frames saved while running
+    pub path: PathBuf,
+    // save every nth frame
+    pub every: Option<u64>,
+}
+
+impl FrameDump {
+    /**`frame.png` becomes `frame-000042.png` for frame 42*/
+    pub fn numbered(&self, frame: u64) -> PathBuf {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match self.path.extension() {
+            Some(ext) => format!("{stem}-{frame:06}.{}", ext.to_string_lossy()),
+            None => format!("{stem}-{frame:06}"),
+        };
+        self.path.with_file_name(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // Two pixels, red and half transparent blue, on the first row of a 2x1 frame
+    const FRAME: [u8; 8] = [0xFF, 0, 0, 0xFF, 0, 0, 0xFF, 0x80];
+
+    fn scratch(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("riscv_emulator-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn ppm_drops_the_alpha() {
+        let path = scratch("frame.ppm");
+        save(&path, &FRAME, 2, 1).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(bytes, b"P6\n2 1\n255\n\xFF\0\0\0\0\xFF");
+    }
+
+    #[test]
+    fn png_decodes_to_the_frame() {
+        let path = scratch("frame.PNG");
+        save(&path, &FRAME, 2, 1).unwrap();
+        let decoder = png::Decoder::new(File::open(&path).unwrap());
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((info.width, info.height), (2, 1));
+        assert_eq!(info.color_type, png::ColorType::Rgba);
+        assert_eq!(pixels, FRAME);
+    }
+
+    #[test]
+    fn other_extensions_are_refused() {
+        let path = scratch("frame.bmp");
+        let err = save(&path, &FRAME, 2, 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn numbered_copies_keep_the_extension() {
+        let dump = |path: &str| FrameDump {
+            path: PathBuf::from(path),
+            every: None,
+        };
+        assert_eq!(
+            dump("out/frame.png").numbered(42),
+            PathBuf::from("out/frame-000042.png")
+        );
+        assert_eq!(dump("frame").numbered(7), PathBuf::from("frame-000007"));
+    }
+}
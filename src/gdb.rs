@@ -0,0 +1,635 @@
+use crate::chips::cpu::CPU;
+use crate::chips::csr_file::{csr_name, ADDRESSES};
+use crate::chips::trap::Exception;
+use crate::chips::U32;
+use crate::debug::{self, Breakpoints, Location, Stop, WatchKind, WatchTarget, Watchpoint};
+use crate::disasm::ABI_NAMES;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::num::Wrapping;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+// register numbers gdb uses, the csrs follow the numbering of its built in riscv description
+const PC_REGISTER: usize = 32;
+const CSR_REGISTERS: usize = 65;
+
+// the signals of the stop replies, in gdb's own numbering
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGBUS: u8 = 10;
+const SIGSEGV: u8 = 11;
+
+/**Where the stub waits for gdb to connect*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    // HOST:PORT
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /**A port on localhost, HOST:PORT, or unix:PATH for a unix socket*/
+    pub fn parse(value: &str) -> Result<Self, String> {
+        if let Some(path) = value.strip_prefix("unix:") {
+            return Ok(Endpoint::Unix(PathBuf::from(path)));
+        }
+        if value.parse::<u16>().is_ok() {
+            return Ok(Endpoint::Tcp(format!("127.0.0.1:{value}")));
+        }
+        match value.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(Endpoint::Tcp(value.to_string()))
+            }
+            _ => Err(format!(
+                "expected PORT, HOST:PORT or unix:PATH, got '{value}'"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{addr}"),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/**How a debugging session ended*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    // the guest exited with this code
+    Exit(i32),
+    // gdb let go of the machine, or the connection dropped
+    Detach,
+    Kill,
+}
+
+// the stream to gdb, which also has to be polled for ctrl-c while the machine runs
+trait Connection: Read + Write {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+}
+
+impl Connection for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+#[cfg(unix)]
+impl Connection for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+}
+
+fn accept(endpoint: &Endpoint) -> io::Result<Box<dyn Connection>> {
+    match endpoint {
+        Endpoint::Tcp(addr) => {
+            let listener = TcpListener::bind(addr)?;
+            eprintln!("waiting for gdb on {}", listener.local_addr()?);
+            let (stream, peer) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            log::info!("gdb connected from {peer}");
+            Ok(Box::new(stream))
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(path) => {
+            let listener = UnixListener::bind(path)?;
+            eprintln!("waiting for gdb on {}", path.display());
+            let (stream, _) = listener.accept()?;
+            // a single debugger is served, nobody else needs to find the socket
+            if let Err(err) = std::fs::remove_file(path) {
+                log::warn!("can't remove {}: {err}", path.display());
+            }
+            log::info!("gdb connected");
+            Ok(Box::new(stream))
+        }
+        #[cfg(not(unix))]
+        Endpoint::Unix(_) => Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "unix sockets are only supported on unix",
+        )),
+    }
+}
+
+/**Wait for gdb on `endpoint` and let it drive the machine over the remote serial protocol,
+until the guest exits or gdb kills or leaves it. The machine is stopped before the first
+instruction when gdb connects*/
+pub fn serve(cpu: &mut CPU, endpoint: &Endpoint) -> io::Result<Outcome> {
+    let conn = accept(endpoint)?;
+    cpu.memory.debug = true;
+    // the watchpoints given on the command line stop gdb too, its own go when it leaves
+    let watchpoints = cpu.memory.watchpoints.clone();
+    let mut stub = Stub {
+        conn,
+        cpu,
+        breakpoints: Breakpoints::default(),
+        pending: VecDeque::new(),
+        last: vec![],
+        acks: true,
+        stop: format!("S{SIGTRAP:02x}"),
+    };
+    let outcome = stub.serve();
+
+    // whatever happens to the session, the machine goes on as if gdb had never been there
+    stub.breakpoints.clear(stub.cpu);
+    stub.cpu.memory.watchpoints = watchpoints;
+    stub.cpu.memory.debug = false;
+    match outcome {
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => {
+            log::warn!("gdb closed the connection, running on");
+            Ok(Outcome::Detach)
+        }
+        outcome => outcome,
+    }
+}
+
+// what a packet asks for beside a reply
+enum Action {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+    Kill,
+}
+
+struct Stub<'a> {
+    conn: Box<dyn Connection>,
+    cpu: &'a mut CPU,
+    breakpoints: Breakpoints,
+    // bytes received and not handled yet
+    pending: VecDeque<u8>,
+    // the last packet sent, sent again when gdb asks for it
+    last: Vec<u8>,
+    // false once gdb switched to no-ack mode
+    acks: bool,
+    // the reply to `?`, why the machine last stopped
+    stop: String,
+}
+
+impl Stub<'_> {
+    fn serve(&mut self) -> io::Result<Outcome> {
+        loop {
+            // a ctrl-c while the machine is stopped just reports it stopped
+            let Some(packet) = self.receive()? else {
+                let stop = self.stop.clone();
+                self.send(&stop)?;
+                continue;
+            };
+            log::debug!("gdb: {packet}");
+            match self.handle(&packet) {
+                Action::Reply(reply) => self.send(&reply)?,
+                Action::Resume { step } => {
+                    let stop = match step {
+                        true => debug::step(self.cpu),
+                        false => {
+                            let (conn, pending) = (&mut self.conn, &mut self.pending);
+                            debug::run(self.cpu, || interrupted(conn.as_mut(), pending))
+                        }
+                    };
+                    self.stop = stop_reply(&stop);
+                    let reply = self.stop.clone();
+                    self.send(&reply)?;
+                    if let Stop::Exit(code) = stop {
+                        return Ok(Outcome::Exit(code));
+                    }
+                }
+                Action::Detach => {
+                    self.send("OK")?;
+                    return Ok(Outcome::Detach);
+                }
+                Action::Kill => return Ok(Outcome::Kill),
+            }
+        }
+    }
+
+    fn handle(&mut self, packet: &str) -> Action {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+        let Some(kind) = packet.get(..1) else {
+            return reply("");
+        };
+        let args = &packet[1..];
+        match kind {
+            "?" => Action::Reply(self.stop.clone()),
+            "g" => Action::Reply(
+                (0..=PC_REGISTER)
+                    .map(|n| self.register(n).unwrap())
+                    .collect(),
+            ),
+            "G" => {
+                let values = args
+                    .as_bytes()
+                    .chunks(8)
+                    .map(|chunk| std::str::from_utf8(chunk).ok().and_then(parse_register));
+                for (n, value) in values.take(PC_REGISTER + 1).enumerate() {
+                    let Some(value) = value else {
+                        return reply("E01");
+                    };
+                    self.set_register(n, value);
+                }
+                reply("OK")
+            }
+            "p" => match usize::from_str_radix(args, 16)
+                .ok()
+                .and_then(|n| self.register(n))
+            {
+                Some(value) => Action::Reply(value),
+                None => reply("E01"),
+            },
+            "P" => {
+                let set = args.split_once('=').and_then(|(n, value)| {
+                    let n = usize::from_str_radix(n, 16).ok()?;
+                    self.set_register(n, parse_register(value)?)
+                });
+                reply(if set.is_some() { "OK" } else { "E01" })
+            }
+            "m" => {
+                let Some((addr, len)) = address_length(args) else {
+                    return reply("E01");
+                };
+                // a read that runs into unreadable memory returns what it got so far
+                let bytes: Vec<u8> = (0..len.min(0x800))
+                    .map_while(|i| self.cpu.read_memory(addr + Wrapping(i)))
+                    .collect();
+                match bytes.is_empty() && len != 0 {
+                    true => reply("E14"),
+                    false => Action::Reply(bytes.iter().map(|b| format!("{b:02x}")).collect()),
+                }
+            }
+            "M" => {
+                let written = args.split_once(':').and_then(|(range, data)| {
+                    let (addr, len) = address_length(range)?;
+                    let bytes = parse_hex_bytes(data)?;
+                    if bytes.len() != len as usize {
+                        return None;
+                    }
+                    bytes.into_iter().enumerate().try_for_each(|(i, byte)| {
+                        self.cpu.write_memory(addr + Wrapping(i as u32), byte)
+                    })
+                });
+                reply(if written.is_some() { "OK" } else { "E14" })
+            }
+            "c" | "s" => {
+                if !args.is_empty() {
+                    match u32::from_str_radix(args, 16) {
+                        Ok(addr) => self.cpu.set_pc(Wrapping(addr)),
+                        Err(_) => return reply("E01"),
+                    }
+                }
+                Action::Resume { step: kind == "s" }
+            }
+            "Z" | "z" => self.breakpoint(kind == "Z", args),
+            "D" => Action::Detach,
+            "k" => Action::Kill,
+            "H" => reply("OK"),
+            _ => self.query(packet),
+        }
+    }
+
+    // the general queries and the v packets
+    fn query(&mut self, packet: &str) -> Action {
+        let reply = |reply: &str| Action::Reply(reply.to_string());
+        if packet.starts_with("qSupported") {
+            return reply("PacketSize=1000;qXfer:features:read+;QStartNoAckMode+");
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, len)) = address_length(range) else {
+                return reply("E01");
+            };
+            let xml = target_xml();
+            let start = (offset.0 as usize).min(xml.len());
+            let end = (start + len as usize).min(xml.len());
+            let more = if end < xml.len() { 'm' } else { 'l' };
+            return Action::Reply(format!("{more}{}", &xml[start..end]));
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                // the OK still gets its ack, everything after it goes without
+                self.acks = false;
+                reply("OK")
+            }
+            "qAttached" => reply("1"),
+            packet if packet.starts_with("vKill") => Action::Kill,
+            _ => reply(""),
+        }
+    }
+
+    // Z0 is a software breakpoint, Z2, Z3 and Z4 the write, read and access watchpoints
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Action {
+        let reply = |ok: bool| Action::Reply(if ok { "OK" } else { "E01" }.to_string());
+        let mut fields = args.splitn(3, ',');
+        let (Some(kind), Some(addr), Some(len)) = (fields.next(), fields.next(), fields.next())
+        else {
+            return reply(false);
+        };
+        // the kind field may carry conditions after a ';', they are not supported
+        let len = len.split(';').next().unwrap_or_default();
+        let (Ok(addr), Ok(len)) = (u32::from_str_radix(addr, 16), u32::from_str_radix(len, 16))
+        else {
+            return reply(false);
+        };
+        let watch = match kind {
+            "0" if insert => return reply(self.breakpoints.insert(self.cpu, Wrapping(addr))),
+            "0" => return reply(self.breakpoints.remove(self.cpu, Wrapping(addr))),
+            "2" => WatchKind::Write,
+            "3" => WatchKind::Read,
+            "4" => WatchKind::Access,
+            // hardware breakpoints aren't offered, gdb falls back to software ones
+            _ => return Action::Reply(String::new()),
+        };
+        let watchpoint = Watchpoint {
+            kind: watch,
+            target: WatchTarget::Memory(addr..addr.saturating_add(len.max(1))),
+            condition: None,
+        };
+        let watchpoints = &mut self.cpu.memory.watchpoints;
+        match insert {
+            true => {
+                watchpoints.push(watchpoint);
+                reply(true)
+            }
+            false => match watchpoints.iter().position(|w| *w == watchpoint) {
+                Some(index) => {
+                    watchpoints.remove(index);
+                    reply(true)
+                }
+                None => reply(false),
+            },
+        }
+    }
+
+    // register `n` as gdb numbers them, in target byte order
+    fn register(&self, n: usize) -> Option<String> {
+        let value = match n {
+            0..PC_REGISTER => self.cpu.register(n),
+            PC_REGISTER => self.cpu.pc(),
+            _ => self
+                .cpu
+                .csr(u16::try_from(n.checked_sub(CSR_REGISTERS)?).ok()?)?,
+        };
+        Some(format!("{:08x}", value.0.swap_bytes()))
+    }
+
+    fn set_register(&mut self, n: usize, value: U32) -> Option<()> {
+        match n {
+            0..PC_REGISTER => self.cpu.set_register(n, value),
+            PC_REGISTER => self.cpu.set_pc(value),
+            _ => self
+                .cpu
+                .set_csr(u16::try_from(n.checked_sub(CSR_REGISTERS)?).ok()?, value)?,
+        }
+        Some(())
+    }
+
+    fn byte(&mut self) -> io::Result<u8> {
+        loop {
+            if let Some(byte) = self.pending.pop_front() {
+                return Ok(byte);
+            }
+            let mut buffer = [0; 1024];
+            match self.conn.read(&mut buffer) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.pending.extend(&buffer[..n]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // The next packet with its framing and escapes removed, None for a ctrl-c
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            match self.byte()? {
+                b'$' => {}
+                0x03 => return Ok(None),
+                b'-' => {
+                    let last = self.last.clone();
+                    self.conn.write_all(&last)?;
+                    continue;
+                }
+                // acks and line noise
+                _ => continue,
+            }
+            let mut raw = vec![];
+            loop {
+                match self.byte()? {
+                    b'#' => break,
+                    byte => raw.push(byte),
+                }
+            }
+            let digits = [self.byte()?, self.byte()?];
+            let checksum = std::str::from_utf8(&digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+            let ok = checksum == Some(sum(&raw));
+            if self.acks {
+                self.conn.write_all(if ok { b"+" } else { b"-" })?;
+            }
+            if ok {
+                let mut data = vec![];
+                let mut bytes = raw.into_iter();
+                while let Some(byte) = bytes.next() {
+                    match byte {
+                        b'}' => data.push(bytes.next().unwrap_or_default() ^ 0x20),
+                        byte => data.push(byte),
+                    }
+                }
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        log::debug!("stub: {data}");
+        let mut packet = vec![b'$'];
+        for &byte in data.as_bytes() {
+            match needs_escape(byte) {
+                true => packet.extend([b'}', byte ^ 0x20]),
+                false => packet.push(byte),
+            }
+        }
+        let checksum = sum(&packet[1..]);
+        packet.extend(format!("#{checksum:02x}").bytes());
+        self.conn.write_all(&packet)?;
+        self.conn.flush()?;
+        self.last = packet;
+        Ok(())
+    }
+}
+
+fn needs_escape(byte: u8) -> bool {
+    matches!(byte, b'#' | b'$' | b'}' | b'*')
+}
+
+fn sum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+// Whether gdb sent a ctrl-c while the machine was running, a closed connection counts as one
+fn interrupted(conn: &mut dyn Connection, pending: &mut VecDeque<u8>) -> bool {
+    let mut buffer = [0; 256];
+    let read = conn
+        .set_nonblocking(true)
+        .and_then(|()| conn.read(&mut buffer));
+    if let Err(err) = conn.set_nonblocking(false) {
+        log::warn!("can't poll the gdb connection: {err}");
+    }
+    match read {
+        Ok(0) => return true,
+        Ok(n) => pending.extend(&buffer[..n]),
+        Err(_) => {}
+    }
+    match pending.iter().position(|&byte| byte == 0x03) {
+        Some(index) => {
+            pending.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+fn stop_reply(stop: &Stop) -> String {
+    match stop {
+        Stop::Step | Stop::Breakpoint(_) => format!("S{SIGTRAP:02x}"),
+        Stop::Watch(hits) => {
+            // gdb only knows about memory, a register hit is a plain stop
+            let memory = hits.iter().find_map(|hit| match hit.location {
+                Location::Memory(addr) => Some((hit.kind, addr)),
+                Location::Register(_) => None,
+            });
+            let Some((kind, addr)) = memory else {
+                return format!("S{SIGTRAP:02x}");
+            };
+            let kind = match kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            format!("T{SIGTRAP:02x}{kind}:{:x};", addr.0)
+        }
+        Stop::Trap(trap) => {
+            let signal = match trap.exception {
+                Exception::IllegalInstruction(_) => SIGILL,
+                Exception::Breakpoint(_) => SIGTRAP,
+                Exception::InstructionAddressMisaligned(_)
+                | Exception::LoadAddressMisaligned(_)
+                | Exception::StoreAddressMisaligned(_) => SIGBUS,
+                _ => SIGSEGV,
+            };
+            format!("S{signal:02x}")
+        }
+        Stop::Exit(code) => format!("W{:02x}", *code as u8),
+        Stop::Interrupt => format!("S{SIGINT:02x}"),
+    }
+}
+
+// ADDR,LENGTH in hex
+fn address_length(args: &str) -> Option<(U32, u32)> {
+    let (addr, len) = args.split_once(',')?;
+    let addr = u32::from_str_radix(addr, 16).ok()?;
+    Some((Wrapping(addr), u32::from_str_radix(len, 16).ok()?))
+}
+
+fn parse_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// a register value in target byte order
+fn parse_register(hex: &str) -> Option<U32> {
+    let value = u32::from_str_radix(hex, 16).ok()?;
+    (hex.len() == 8).then_some(Wrapping(value.swap_bytes()))
+}
+
+// The register layout gdb reads with qXfer, the integer registers and pc, then the csrs
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         <architecture>riscv:rv32</architecture>\n\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    );
+    for (n, name) in ABI_NAMES.iter().enumerate() {
+        let kind = match n {
+            1 => "code_ptr",
+            2..=4 => "data_ptr",
+            _ => "int",
+        };
+        xml += &format!("  <reg name=\"{name}\" bitsize=\"32\" type=\"{kind}\" regnum=\"{n}\"/>\n");
+    }
+    xml += &format!(
+        "  <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{PC_REGISTER}\"/>\n\
+         </feature>\n\
+         <feature name=\"org.gnu.gdb.riscv.csr\">\n"
+    );
+    for addr in ADDRESSES {
+        let name = csr_name(addr).unwrap_or_default();
+        let n = CSR_REGISTERS + addr as usize;
+        xml += &format!("  <reg name=\"{name}\" bitsize=\"32\" regnum=\"{n}\" group=\"csr\"/>\n");
+    }
+    xml + "</feature>\n</target>\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::trap::Trap;
+
+    #[test]
+    fn endpoints() {
+        let tcp = |addr: &str| Ok(Endpoint::Tcp(addr.to_string()));
+        assert_eq!(Endpoint::parse("1234"), tcp("127.0.0.1:1234"));
+        assert_eq!(Endpoint::parse("0.0.0.0:1234"), tcp("0.0.0.0:1234"));
+        assert_eq!(
+            Endpoint::parse("unix:/tmp/gdb.sock"),
+            Ok(Endpoint::Unix(PathBuf::from("/tmp/gdb.sock")))
+        );
+        assert!(Endpoint::parse("localhost").is_err());
+        assert!(Endpoint::parse(":1234").is_err());
+    }
+
+    #[test]
+    fn registers_travel_in_target_byte_order() {
+        assert_eq!(parse_register("78563412"), Some(Wrapping(0x12345678)));
+        assert_eq!(parse_register("785634"), None);
+        assert_eq!(parse_hex_bytes("2a00ff"), Some(vec![0x2a, 0, 0xff]));
+    }
+
+    #[test]
+    fn stop_replies() {
+        let trap = Trap {
+            pc: Wrapping(8),
+            exception: Exception::LoadAccessFault(Wrapping(0x100)),
+        };
+        assert_eq!(stop_reply(&Stop::Trap(trap)), "S0b");
+        assert_eq!(stop_reply(&Stop::Exit(-1)), "Wff");
+        let hit = crate::debug::WatchHit {
+            index: 0,
+            kind: WatchKind::Read,
+            pc: Wrapping(8),
+            write: false,
+            location: Location::Memory(Wrapping(0x1004)),
+            old: Some(Wrapping(5)),
+            new: Wrapping(5),
+            cycle: 20,
+        };
+        let register = crate::debug::WatchHit {
+            location: Location::Register(10),
+            ..hit.clone()
+        };
+        assert_eq!(stop_reply(&Stop::Watch(vec![register.clone()])), "S05");
+        assert_eq!(
+            stop_reply(&Stop::Watch(vec![register, hit])),
+            "T05rwatch:1004;"
+        );
+    }
+}
@@ -0,0 +1,447 @@
+use crate::chips::cpu::CPU;
+use crate::chips::input::{InputEvent, InputSource};
+use crate::chips::uart::Terminal;
+use crate::debug::WatchHit;
+use crate::snapshot;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::mem;
+use std::rc::Rc;
+
+/**How many checkpoints are kept, each is a whole snapshot of the machine. The oldest go first,
+and the history with them*/
+pub const MAX_CHECKPOINTS: usize = 32;
+
+const NOT_RECORDED: &str = "there is no history, start the emulator with --history";
+
+/**What the host handed the machine, by the cycle it arrived on. The rest of the machine only
+follows from its own state, so with the journal a run from a checkpoint does exactly what it
+did the first time*/
+#[derive(Default)]
+pub struct Journal {
+    // the cycle being run, the uart has no count of its own
+    cycle: u64,
+    // the last cycle that has run, the host isn't asked again for anything up to it
+    frontier: u64,
+    // bytes the uart polled, and what ecall's blocking reads got
+    received: BTreeMap<u64, u8>,
+    read: BTreeMap<u64, Option<u8>>,
+    events: BTreeMap<u64, Vec<InputEvent>>,
+    // the cycle whose events a replay is handing out, and how many it has
+    replayed: (u64, usize),
+}
+
+impl Journal {
+    fn replaying(&self, cycle: u64) -> bool {
+        cycle <= self.frontier
+    }
+
+    // the cycles after `cycle` never happened
+    fn truncate(&mut self, cycle: u64) {
+        self.received.split_off(&(cycle + 1));
+        self.read.split_off(&(cycle + 1));
+        self.events.split_off(&(cycle + 1));
+        self.frontier = cycle;
+    }
+
+    // no replay starts before `cycle` any more
+    fn forget(&mut self, cycle: u64) {
+        self.received = self.received.split_off(&(cycle + 1));
+        self.read = self.read.split_off(&(cycle + 1));
+        self.events = self.events.split_off(&(cycle + 1));
+    }
+}
+
+/**A terminal or input source whose input is written to the journal, and read back from it
+when cycles that have already run are run again. Output is only written the first time*/
+pub struct Recorded<T> {
+    inner: T,
+    journal: Rc<RefCell<Journal>>,
+}
+
+impl<T> Recorded<T> {
+    pub fn new(inner: T, journal: Rc<RefCell<Journal>>) -> Self {
+        Self { inner, journal }
+    }
+}
+
+impl Terminal for Recorded<Box<dyn Terminal>> {
+    fn poll(&mut self) -> Option<u8> {
+        let mut journal = self.journal.borrow_mut();
+        let cycle = journal.cycle;
+        if journal.replaying(cycle) {
+            return journal.received.get(&cycle).copied();
+        }
+        let byte = self.inner.poll();
+        if let Some(byte) = byte {
+            journal.received.insert(cycle, byte);
+        }
+        byte
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        let mut journal = self.journal.borrow_mut();
+        let cycle = journal.cycle;
+        if journal.replaying(cycle) {
+            if let Some(&byte) = journal.read.get(&cycle) {
+                return byte;
+            }
+        }
+        let byte = self.inner.read();
+        journal.read.insert(cycle, byte);
+        byte
+    }
+
+    fn write(&mut self, byte: u8) {
+        let journal = self.journal.borrow();
+        if !journal.replaying(journal.cycle) {
+            self.inner.write(byte);
+        }
+    }
+}
+
+impl InputSource for Recorded<Box<dyn InputSource>> {
+    fn poll(&mut self, cycle: u64) -> Option<InputEvent> {
+        let mut journal = self.journal.borrow_mut();
+        if journal.replaying(cycle) {
+            if journal.replayed.0 != cycle {
+                journal.replayed = (cycle, 0);
+            }
+            let event = journal.events.get(&cycle)?.get(journal.replayed.1).copied();
+            journal.replayed.1 += 1;
+            return event;
+        }
+        let event = self.inner.poll(cycle)?;
+        journal.events.entry(cycle).or_default().push(event);
+        Some(event)
+    }
+}
+
+/**The recorded run of the machine: checkpoints, and the journal to get from one to any cycle
+after it. A checkpoint is also taken whenever a debugger changes the machine, so a replay
+never has to guess what a debugger did*/
+pub struct History {
+    journal: Rc<RefCell<Journal>>,
+    checkpoints: BTreeMap<u64, Vec<u8>>,
+    // cycles between two checkpoints while the machine runs on its own
+    interval: u64,
+}
+
+impl History {
+    pub fn new(interval: u64) -> Self {
+        Self {
+            journal: Rc::new(RefCell::new(Journal::default())),
+            checkpoints: BTreeMap::new(),
+            interval: interval.max(1),
+        }
+    }
+
+    /**The journal to record the terminal and the input source into*/
+    pub fn journal(&self) -> Rc<RefCell<Journal>> {
+        self.journal.clone()
+    }
+
+    /**Before a cycle: the journal files what arrives under it*/
+    pub fn begin(&self, cycle: u64) {
+        self.journal.borrow_mut().cycle = cycle;
+    }
+
+    /**After a cycle, taking a checkpoint every interval while the machine runs new cycles*/
+    pub fn record(&mut self, cpu: &CPU) {
+        let cycle = cpu.memory.cycle;
+        let mut journal = self.journal.borrow_mut();
+        if cycle > journal.frontier {
+            journal.frontier = cycle;
+            drop(journal);
+            if cycle.is_multiple_of(self.interval) {
+                self.checkpoint(cpu);
+            }
+        }
+    }
+
+    /**The debugger changed the machine, what was recorded after this cycle won't happen*/
+    pub fn changed(&mut self, cpu: &CPU) {
+        let now = cpu.memory.cycle;
+        self.checkpoints.split_off(&(now + 1));
+        self.journal.borrow_mut().truncate(now);
+        self.checkpoint(cpu);
+    }
+
+    /**Forget everything, the history starts again from the machine as it is*/
+    pub fn restart(&mut self, cpu: &CPU) {
+        self.checkpoints.clear();
+        *self.journal.borrow_mut() = Journal::default();
+        self.changed(cpu);
+    }
+
+    /**The first cycle the history goes back to*/
+    pub fn start(&self) -> u64 {
+        self.checkpoints.keys().next().copied().unwrap_or_default()
+    }
+
+    /**The last cycle that has run*/
+    pub fn frontier(&self) -> u64 {
+        self.journal.borrow().frontier
+    }
+
+    pub fn checkpoints(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    fn checkpoint(&mut self, cpu: &CPU) {
+        self.checkpoints
+            .insert(cpu.memory.cycle, snapshot::capture(cpu));
+        if self.checkpoints.len() > MAX_CHECKPOINTS {
+            self.checkpoints.pop_first();
+            self.journal.borrow_mut().forget(self.start());
+        }
+    }
+
+    // Run the recorded cycles up to `to` from the last checkpoint at or before `from`, taking
+    // up each later checkpoint on the way as the debugger left the machine there. `visit`
+    // sees the end of every cycle after `from` in which an instruction went through the memory
+    // stage, with its watchpoint hits, and stops the run by returning true
+    fn replay(
+        &self,
+        cpu: &mut CPU,
+        from: u64,
+        to: u64,
+        mut visit: impl FnMut(&CPU, &[WatchHit]) -> bool,
+    ) -> bool {
+        let first = self
+            .checkpoints
+            .range(..=from)
+            .next_back()
+            .map(|(&at, _)| at);
+        let Some(first) = first else {
+            return false;
+        };
+        // the instructions were traced and the waveform dumped the first time they ran
+        let tracer = cpu.memory.tracer.take();
+        let vcd = cpu.vcd.take();
+        let mut checkpoints = self.checkpoints.range(first..=to).peekable();
+        let mut stopped = false;
+        'run: while let Some((_, bytes)) = checkpoints.next() {
+            snapshot::restore(cpu, bytes).expect("checkpoints restore on their own machine");
+            let end = checkpoints.peek().map_or(to, |(&next, _)| next);
+            while cpu.memory.cycle < end {
+                self.begin(cpu.memory.cycle + 1);
+                let busy = cpu.busy();
+                // what was done about a trap the first time is in the next checkpoint
+                let _ = cpu.step();
+                let hits = mem::take(&mut cpu.memory.watch_hits);
+                if busy && cpu.memory.cycle > from && visit(cpu, &hits) {
+                    stopped = true;
+                    break 'run;
+                }
+            }
+        }
+        cpu.memory.tracer = tracer;
+        cpu.vcd = vcd;
+        stopped
+    }
+}
+
+// The machine's history, out of it while it is replayed so the replay isn't recorded
+fn with_history<T>(
+    cpu: &mut CPU,
+    travel: impl FnOnce(&mut CPU, &History) -> Result<T, String>,
+) -> Result<T, String> {
+    let history = cpu.history.take().ok_or(NOT_RECORDED)?;
+    let result = travel(cpu, &history);
+    cpu.history = Some(history);
+    result
+}
+
+/**Put the machine back to where it was at the end of `cycle`*/
+pub fn goto(cpu: &mut CPU, cycle: u64) -> Result<(), String> {
+    with_history(cpu, |cpu, history| {
+        if cycle < history.start() {
+            return Err(format!("the history starts at cycle {}", history.start()));
+        }
+        if cycle > history.frontier() {
+            return Err(format!("cycle {cycle} hasn't run yet"));
+        }
+        history.replay(cpu, cycle, cycle, |_, _| false);
+        Ok(())
+    })
+}
+
+/**Go back to the `n`th latest point before now where `stop` holds, a point being the end of
+a cycle in which an instruction went through the memory stage. Returns its cycle, or None
+when the history doesn't go back that far, the machine is then at its start*/
+pub fn back(
+    cpu: &mut CPU,
+    n: usize,
+    mut stop: impl FnMut(&CPU, &[WatchHit]) -> bool,
+) -> Result<Option<u64>, String> {
+    with_history(cpu, |cpu, history| {
+        let now = cpu.memory.cycle;
+        let starts: Vec<u64> = history
+            .checkpoints
+            .range(..now)
+            .map(|(&at, _)| at)
+            .collect();
+        // from the latest stretch between two checkpoints back, the points in cycle order
+        let mut points: Vec<u64> = vec![];
+        let mut end = now;
+        for &at in starts.iter().rev() {
+            let mut found = vec![];
+            history.replay(cpu, at, end, |cpu, hits| {
+                let cycle = cpu.memory.cycle;
+                if cycle < now && stop(cpu, hits) {
+                    found.push(cycle);
+                }
+                false
+            });
+            found.append(&mut points);
+            points = found;
+            if points.len() >= n {
+                break;
+            }
+            end = at;
+        }
+        let found = points.len().checked_sub(n).map(|i| points[i]);
+        let target = found.unwrap_or(history.start());
+        history.replay(cpu, target, target, |_, _| false);
+        Ok(found)
+    })
+}
+
+/**Go forward through the recorded cycles to the first point after now where `stop` holds.
+Returns its cycle, or None when the history ran out first, the machine is then at the last
+cycle that has run and goes on live from there*/
+pub fn forward(
+    cpu: &mut CPU,
+    stop: impl FnMut(&CPU, &[WatchHit]) -> bool,
+) -> Result<Option<u64>, String> {
+    with_history(cpu, |cpu, history| {
+        let now = cpu.memory.cycle;
+        let stopped = history.replay(cpu, now, history.frontier(), stop);
+        Ok(stopped.then_some(cpu.memory.cycle))
+    })
+}
+
+/**Start the history again from the machine as it is, after it was loaded from elsewhere*/
+pub fn restart(cpu: &mut CPU) {
+    if let Some(mut history) = cpu.history.take() {
+        history.restart(cpu);
+        cpu.history = Some(history);
+    }
+}
+
+/**Whether the machine is back in its history rather than at the last cycle that has run*/
+pub fn in_past(cpu: &CPU) -> bool {
+    cpu.history
+        .as_ref()
+        .is_some_and(|history| cpu.memory.cycle < history.frontier())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::cpu::Model;
+    use crate::chips::testing;
+    use std::collections::VecDeque;
+    use std::num::Wrapping;
+
+    // what is typed, handed out once only, and what was written
+    struct Typed {
+        input: VecDeque<u8>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Terminal for Typed {
+        fn poll(&mut self) -> Option<u8> {
+            None
+        }
+
+        fn read(&mut self) -> Option<u8> {
+            self.input.pop_front()
+        }
+
+        fn write(&mut self, byte: u8) {
+            self.output.borrow_mut().push(byte);
+        }
+    }
+
+    // echoes what is read and sums it in memory
+    const PROGRAM: &str = "
+        .data
+    sum: .word 0
+        .text
+    _start:
+        la   t1, sum
+    loop:
+        li   a7, 1
+        ecall
+        lw   t2, 0(t1)
+        add  t2, t2, a0
+        sw   t2, 0(t1)
+        li   a7, 2
+        ecall
+        j    loop
+    ";
+
+    fn machine(output: Rc<RefCell<Vec<u8>>>) -> CPU {
+        let history = History::new(16);
+        let terminal: Box<dyn Terminal> = Box::new(Typed {
+            input: b"time travel".iter().copied().collect(),
+            output,
+        });
+        let terminal = Box::new(Recorded::new(terminal, history.journal()));
+        let mut cpu = testing::machine_with(PROGRAM, 0x1_0000, 256, Model::default(), terminal);
+        cpu.history = Some(history);
+        restart(&mut cpu);
+        cpu
+    }
+
+    #[test]
+    fn replays_are_the_run() {
+        let output = Rc::new(RefCell::new(vec![]));
+        let mut cpu = machine(output.clone());
+        let mut states = vec![snapshot::capture(&cpu)];
+        for _ in 0..200 {
+            let _ = cpu.step();
+            states.push(snapshot::capture(&cpu));
+        }
+        let written = output.borrow().clone();
+        assert!(written.starts_with(b"time travel"));
+        for cycle in [0, 1, 15, 16, 17, 42, 199, 200, 3] {
+            goto(&mut cpu, cycle).unwrap();
+            assert!(
+                snapshot::capture(&cpu) == states[cycle as usize],
+                "cycle {cycle}"
+            );
+        }
+        // the input came from the journal and nothing was written twice
+        assert_eq!(*output.borrow(), written);
+        assert!(goto(&mut cpu, 201).is_err());
+    }
+
+    #[test]
+    fn going_back_and_forth() {
+        let mut cpu = machine(Rc::new(RefCell::new(vec![])));
+        for _ in 0..60 {
+            let _ = cpu.step();
+        }
+        let at_loop = |cpu: &CPU, _: &[WatchHit]| cpu.pc() == Wrapping(8);
+        let last = back(&mut cpu, 1, at_loop).unwrap().unwrap();
+        let first = back(&mut cpu, 100, at_loop).unwrap();
+        assert_eq!(first, None);
+        assert_eq!(cpu.memory.cycle, 0);
+        assert_eq!(
+            forward(&mut cpu, at_loop).unwrap().map(|_| cpu.pc()),
+            Some(Wrapping(8))
+        );
+        assert_eq!(forward(&mut cpu, |_, _| false).unwrap(), None);
+        assert_eq!(cpu.memory.cycle, 60);
+
+        // changing the past drops what came after it
+        goto(&mut cpu, last).unwrap();
+        assert!(in_past(&cpu));
+        cpu.set_register(5, Wrapping(1));
+        assert!(!in_past(&cpu));
+        assert_eq!(cpu.history.as_ref().unwrap().frontier(), last);
+    }
+}
@@ -0,0 +1,594 @@
+use crate::asm::AsmError;
+use crate::chips::ram::RAM;
+use crate::chips::rom::ROM;
+use crate::chips::U32;
+use std::fmt;
+use std::num::Wrapping;
+
+/**A chunk of bytes that has to be placed at `address` before the machine starts*/
+#[derive(Clone, Debug)]
+pub struct Segment {
+    pub name: String,
+    pub address: U32,
+    pub data: Vec<u8>,
+    // zeroed bytes after the data, a .bss takes no room in the file so it only has a length
+    pub zeros: u32,
+}
+
+/**A named address from the symbol table of an ELF file*/
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: U32,
+    pub size: u32,
+    // in the code rather than the data, the two can share addresses
+    pub code: bool,
+}
+
+/**Everything needed to bring a program up: what goes in ROM, what goes in RAM and where to
+start*/
+#[derive(Clone, Debug, Default)]
+pub struct Image {
+    pub entry: U32,
+    pub rom: Vec<Segment>,
+    pub ram: Vec<Segment>,
+    // only ELF files have symbols
+    pub symbols: Vec<Symbol>,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    TooShort,
+    BadMagic,
+    NotElf32,
+    NotLittleEndian,
+    BadVersion(u32),
+    NotExecutable(u16),
+    NotRiscV(u16),
+    CompressedInstructions,
+    BadSectionHeaderSize(u16),
+    Truncated(&'static str),
+    DoesNotFit {
+        name: String,
+        address: U32,
+        size: usize,
+    },
+    EntryOutOfRange(U32),
+    BadHexRecord {
+        line: usize,
+        reason: &'static str,
+    },
+    Assembly(AsmError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use LoadError::*;
+        match self {
+            TooShort => write!(f, "file is too short to be an ELF executable"),
+            BadMagic => write!(f, "not an ELF file (bad magic)"),
+            NotElf32 => write!(f, "only 32-bit (ELFCLASS32) executables are supported"),
+            NotLittleEndian => write!(f, "only little-endian executables are supported"),
+            BadVersion(v) => write!(f, "unsupported ELF version {v}"),
+            NotExecutable(t) => write!(f, "ELF type {t} is not an executable (ET_EXEC)"),
+            NotRiscV(m) => write!(f, "ELF machine {m} is not RISC-V"),
+            CompressedInstructions => {
+                write!(
+                    f,
+                    "executable uses compressed (RVC) instructions, which are not supported"
+                )
+            }
+            BadSectionHeaderSize(size) => write!(
+                f,
+                "section headers of {size} bytes are too small to hold one (40 bytes)"
+            ),
+            Truncated(what) => write!(f, "file is truncated: {what} lies outside the file"),
+            DoesNotFit {
+                name,
+                address,
+                size,
+            } => write!(
+                f,
+                "section {name} ({size} bytes at {:#010x}) does not fit in memory",
+                address.0
+            ),
+            EntryOutOfRange(entry) => write!(f, "entry point {:#010x} is outside ROM", entry.0),
+            BadHexRecord { line, reason } => write!(f, "line {line}: {reason}"),
+            Assembly(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u32 = 1;
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+const EF_RISCV_RVC: u32 = 0x1;
+
+const SECTION_HEADER_SIZE: usize = 40;
+
+const SHT_SYMTAB: u32 = 2;
+const SHT_NOBITS: u32 = 8;
+const SHN_UNDEF: u16 = 0;
+const STT_NOTYPE: u8 = 0;
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+const SHF_ALLOC: u32 = 0x2;
+const SHF_EXECINSTR: u32 = 0x4;
+
+fn read_u16(bytes: &[u8], at: usize) -> Result<u16, LoadError> {
+    bytes
+        .get(at..at + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or(LoadError::TooShort)
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Result<u32, LoadError> {
+    bytes
+        .get(at..at + 4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(LoadError::TooShort)
+}
+
+fn read_str(bytes: &[u8], at: usize) -> String {
+    let tail = bytes.get(at..).unwrap_or(&[]);
+    let end = tail.iter().position(|&b| b == 0).unwrap_or(tail.len());
+    String::from_utf8_lossy(&tail[..end]).into_owned()
+}
+
+struct SectionHeader {
+    name: u32,
+    kind: u32,
+    flags: u32,
+    addr: u32,
+    offset: u32,
+    size: u32,
+    link: u32,
+}
+
+/**Parse a RV32 ELF executable. Allocated executable sections (`.text`) go to ROM,
+every other allocated section (`.data`, `.rodata`, `.bss`, ...) goes to RAM.*/
+pub fn parse_elf(bytes: &[u8]) -> Result<Image, LoadError> {
+    if bytes.len() < 52 {
+        return Err(LoadError::TooShort);
+    }
+    if bytes[0..4] != [0x7f, b'E', b'L', b'F'] {
+        return Err(LoadError::BadMagic);
+    }
+    if bytes[4] != ELFCLASS32 {
+        return Err(LoadError::NotElf32);
+    }
+    if bytes[5] != ELFDATA2LSB {
+        return Err(LoadError::NotLittleEndian);
+    }
+
+    let e_type = read_u16(bytes, 16)?;
+    let e_machine = read_u16(bytes, 18)?;
+    let e_version = read_u32(bytes, 20)?;
+    let e_entry = read_u32(bytes, 24)?;
+    let e_shoff = read_u32(bytes, 32)? as usize;
+    let e_flags = read_u32(bytes, 36)?;
+    let e_shentsize = read_u16(bytes, 46)?;
+    let e_shnum = read_u16(bytes, 48)? as usize;
+    let e_shstrndx = read_u16(bytes, 50)? as usize;
+
+    if e_version != EV_CURRENT || bytes[6] as u32 != EV_CURRENT {
+        return Err(LoadError::BadVersion(e_version));
+    }
+    if e_type != ET_EXEC {
+        return Err(LoadError::NotExecutable(e_type));
+    }
+    if e_machine != EM_RISCV {
+        return Err(LoadError::NotRiscV(e_machine));
+    }
+    if e_flags & EF_RISCV_RVC != 0 {
+        return Err(LoadError::CompressedInstructions);
+    }
+
+    // smaller entries would overlap, 0 would read the same one over and over
+    if e_shnum != 0 && (e_shentsize as usize) < SECTION_HEADER_SIZE {
+        return Err(LoadError::BadSectionHeaderSize(e_shentsize));
+    }
+    let mut sections = Vec::with_capacity(e_shnum);
+    for i in 0..e_shnum {
+        let at = e_shoff + i * e_shentsize as usize;
+        if bytes.len() < at + SECTION_HEADER_SIZE {
+            return Err(LoadError::Truncated("section header table"));
+        }
+        sections.push(SectionHeader {
+            name: read_u32(bytes, at)?,
+            kind: read_u32(bytes, at + 4)?,
+            flags: read_u32(bytes, at + 8)?,
+            addr: read_u32(bytes, at + 12)?,
+            offset: read_u32(bytes, at + 16)?,
+            size: read_u32(bytes, at + 20)?,
+            link: read_u32(bytes, at + 24)?,
+        });
+    }
+    let names = sections.get(e_shstrndx).map(|s| s.offset as usize);
+
+    let mut image = Image {
+        entry: Wrapping(e_entry),
+        ..Default::default()
+    };
+    for section in sections.iter() {
+        if section.flags & SHF_ALLOC == 0 || section.size == 0 {
+            continue;
+        }
+        let name = names
+            .map(|base| read_str(bytes, base + section.name as usize))
+            .unwrap_or_default();
+        // the size of a .bss comes straight from the file, it is only checked against the
+        // memory when loading so nothing gets allocated for it here
+        let (data, zeros) = if section.kind == SHT_NOBITS {
+            (vec![], section.size)
+        } else {
+            let start = section.offset as usize;
+            let end = start + section.size as usize;
+            let data = bytes
+                .get(start..end)
+                .ok_or(LoadError::Truncated("section contents"))?;
+            (data.to_vec(), 0)
+        };
+        let segment = Segment {
+            name,
+            address: Wrapping(section.addr),
+            data,
+            zeros,
+        };
+        if section.flags & SHF_EXECINSTR != 0 {
+            image.rom.push(segment);
+        } else {
+            image.ram.push(segment);
+        }
+    }
+
+    for table in sections.iter().filter(|s| s.kind == SHT_SYMTAB) {
+        let strings = sections
+            .get(table.link as usize)
+            .ok_or(LoadError::Truncated("symbol names"))?
+            .offset as usize;
+        image
+            .symbols
+            .extend(parse_symbols(bytes, table, strings, &sections)?);
+    }
+    image.symbols.sort_by_key(|symbol| symbol.address);
+
+    Ok(image)
+}
+
+// The functions, objects and labels a symbol table defines. Sections, files and the `$x`/`$d`
+// mapping symbols say nothing about the code so they are left out
+fn parse_symbols(
+    bytes: &[u8],
+    table: &SectionHeader,
+    strings: usize,
+    sections: &[SectionHeader],
+) -> Result<Vec<Symbol>, LoadError> {
+    let start = table.offset as usize;
+    let entries = bytes
+        .get(start..start + table.size as usize)
+        .ok_or(LoadError::Truncated("symbol table"))?;
+    let mut symbols = vec![];
+    for entry in entries.chunks_exact(16) {
+        let kind = entry[12] & 0xF;
+        let section = read_u16(entry, 14)?;
+        if section == SHN_UNDEF || !matches!(kind, STT_NOTYPE | STT_OBJECT | STT_FUNC) {
+            continue;
+        }
+        let name = read_str(bytes, strings + read_u32(entry, 0)? as usize);
+        if name.is_empty() || name.starts_with('$') {
+            continue;
+        }
+        symbols.push(Symbol {
+            name,
+            address: Wrapping(read_u32(entry, 4)?),
+            size: read_u32(entry, 8)?,
+            code: sections
+                .get(section as usize)
+                .is_some_and(|s| s.flags & SHF_EXECINSTR != 0),
+        });
+    }
+    Ok(symbols)
+}
+
+/**A raw binary is copied to the start of ROM at `base` and executed from there*/
+pub fn parse_binary(bytes: &[u8], base: U32) -> Image {
+    Image {
+        entry: base,
+        rom: vec![Segment {
+            name: "binary".to_string(),
+            address: base,
+            data: bytes.to_vec(),
+            zeros: 0,
+        }],
+        ram: vec![],
+        symbols: vec![],
+    }
+}
+
+/**Parse an Intel HEX file. The data records all go to ROM, a start linear address
+record (type 05) sets the entry point, otherwise execution starts at `base`, the start of ROM.*/
+pub fn parse_ihex(text: &str, base: U32) -> Result<Image, LoadError> {
+    let mut image = Image {
+        entry: base,
+        ..Image::default()
+    };
+    // upper bits of the address set by extended segment/linear address records
+    let mut base = 0u32;
+
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bad = |reason| LoadError::BadHexRecord {
+            line: i + 1,
+            reason,
+        };
+        let hex = line
+            .strip_prefix(':')
+            .ok_or(bad("record does not start with ':'"))?;
+        if hex.len() % 2 != 0 || hex.len() < 10 {
+            return Err(bad("record is too short"));
+        }
+        let record = (0..hex.len())
+            .step_by(2)
+            .map(|j| u8::from_str_radix(&hex[j..j + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| bad("invalid hex digit"))?;
+
+        let count = record[0] as usize;
+        if record.len() != count + 5 {
+            return Err(bad("byte count does not match record length"));
+        }
+        if record.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(bad("checksum mismatch"));
+        }
+        let offset = u16::from_be_bytes([record[1], record[2]]) as u32;
+        let data = &record[4..4 + count];
+        let word = |data: &[u8]| data.iter().fold(0u32, |acc, b| acc << 8 | *b as u32);
+
+        match record[3] {
+            0x00 => {
+                let address = Wrapping(base.wrapping_add(offset));
+                // extend the previous segment when the data is contiguous
+                match image.rom.last_mut() {
+                    Some(last) if last.address + Wrapping(last.data.len() as u32) == address => {
+                        last.data.extend_from_slice(data)
+                    }
+                    _ => image.rom.push(Segment {
+                        name: format!("hex@{:#010x}", address.0),
+                        address,
+                        data: data.to_vec(),
+                        zeros: 0,
+                    }),
+                }
+            }
+            0x01 => break,
+            0x02 if count == 2 => base = word(data) << 4,
+            0x04 if count == 2 => base = word(data) << 16,
+            0x03 if count == 4 => {
+                // CS:IP, only meaningful for x86 but honour it anyway
+                let (cs, ip) = (word(&data[..2]), word(&data[2..]));
+                image.entry = Wrapping((cs << 4).wrapping_add(ip));
+            }
+            0x05 if count == 4 => image.entry = Wrapping(word(data)),
+            0x02..=0x05 => return Err(bad("wrong byte count for record type")),
+            _ => return Err(bad("unknown record type")),
+        }
+    }
+
+    Ok(image)
+}
+
+impl Image {
+    /**Copy the segments into the memories, checking that everything fits.
+    The ram is mapped at `ram_base`*/
+    pub fn load_into(
+        &self,
+        rom: &mut ROM,
+        ram: &mut RAM<U32>,
+        ram_base: U32,
+    ) -> Result<(), LoadError> {
+        for segment in self.rom.iter() {
+            segment.check_fits(rom.base(), rom.size())?;
+            for (addr, mask, value) in segment.words() {
+                rom.poke(addr, rom.peek(addr) & !mask | value);
+            }
+        }
+        for segment in self.ram.iter() {
+            segment.check_fits(ram_base, ram.size())?;
+            for (addr, mask, value) in segment.words() {
+                let offset = addr - ram_base;
+                ram.poke(offset, ram.peek(offset) & !mask | value);
+            }
+        }
+        if !rom.contains(self.entry) || !self.entry.0.is_multiple_of(4) {
+            return Err(LoadError::EntryOutOfRange(self.entry));
+        }
+        Ok(())
+    }
+}
+
+impl Segment {
+    // bytes the segment takes in memory, the data and the zeros after it
+    fn size(&self) -> u64 {
+        self.data.len() as u64 + self.zeros as u64
+    }
+
+    fn check_fits(&self, base: U32, size: usize) -> Result<(), LoadError> {
+        let start = self.address.0 as u64;
+        let end = start + self.size();
+        if start < base.0 as u64 || end > base.0 as u64 + size as u64 {
+            return Err(LoadError::DoesNotFit {
+                name: self.name.clone(),
+                address: self.address,
+                size: self.size() as usize,
+            });
+        }
+        Ok(())
+    }
+
+    // The memories are word organised, so split the bytes into (word address, byte mask, value)
+    fn words(&self) -> Vec<(U32, U32, U32)> {
+        let mut words: Vec<(U32, U32, U32)> = vec![];
+        let zeros = std::iter::repeat_n(&0, self.zeros as usize);
+        for (i, byte) in self.data.iter().chain(zeros).enumerate() {
+            let addr = self.address + Wrapping(i as u32);
+            let word_addr = addr & !Wrapping(3u32);
+            let shift = (addr.0 & 3) * 8;
+            let mask = Wrapping(0xFF << shift);
+            let value = Wrapping((*byte as u32) << shift);
+            match words.last_mut() {
+                Some(last) if last.0 == word_addr => {
+                    last.1 |= mask;
+                    last.2 |= value;
+                }
+                _ => words.push((word_addr, mask, value)),
+            }
+        }
+        words
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::{wire, ZERO};
+
+    const TEXT: [u8; 8] = [0x13, 0x05, 0x10, 0x00, 0x73, 0x00, 0x10, 0x00];
+    const NAMES: &[u8] = b"\0.text\0.bss\0.shstrtab\0";
+
+    // A minimal executable: the header, `.text` at 0, a `.bss` of `bss` bytes at 0x100 and the
+    // section names, followed by the section header table
+    fn elf(bss: u32) -> Vec<u8> {
+        let mut bytes = vec![0u8; 52];
+        bytes[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', ELFCLASS32, ELFDATA2LSB, 1]);
+        let text = bytes.len() as u32;
+        bytes.extend_from_slice(&TEXT);
+        let names = bytes.len() as u32;
+        bytes.extend_from_slice(NAMES);
+        while !bytes.len().is_multiple_of(4) {
+            bytes.push(0);
+        }
+        let shoff = bytes.len() as u32;
+        let sections = [
+            [0; 7],
+            [
+                1,
+                1,
+                SHF_ALLOC | SHF_EXECINSTR,
+                0,
+                text,
+                TEXT.len() as u32,
+                0,
+            ],
+            [7, SHT_NOBITS, SHF_ALLOC, 0x100, 0, bss, 0],
+            [12, 3, 0, 0, names, NAMES.len() as u32, 0],
+        ];
+        for header in sections {
+            header
+                .iter()
+                .for_each(|field| bytes.extend(field.to_le_bytes()));
+            bytes.extend([0; 12]);
+        }
+        let mut put = |at: usize, field: &[u8]| bytes[at..at + field.len()].copy_from_slice(field);
+        put(16, &ET_EXEC.to_le_bytes());
+        put(18, &EM_RISCV.to_le_bytes());
+        put(20, &EV_CURRENT.to_le_bytes());
+        put(32, &shoff.to_le_bytes());
+        put(46, &(SECTION_HEADER_SIZE as u16).to_le_bytes());
+        put(48, &4u16.to_le_bytes());
+        put(50, &3u16.to_le_bytes());
+        bytes
+    }
+
+    fn load(image: &Image) -> Result<(ROM, RAM<U32>), LoadError> {
+        let mut rom = ROM::new(wire(ZERO), wire(ZERO), 16);
+        let mut ram = RAM::new(
+            wire(ZERO),
+            wire(ZERO),
+            wire(ZERO),
+            wire(false),
+            wire(0),
+            128,
+        );
+        image.load_into(&mut rom, &mut ram, ZERO)?;
+        Ok((rom, ram))
+    }
+
+    #[test]
+    fn minimal_executable() {
+        let image = parse_elf(&elf(8)).unwrap();
+        assert_eq!(image.entry, ZERO);
+        assert_eq!(image.rom.len(), 1);
+        assert_eq!(
+            (image.rom[0].name.as_str(), &image.rom[0].data[..]),
+            (".text", &TEXT[..])
+        );
+        let bss = &image.ram[0];
+        assert_eq!(
+            (bss.name.as_str(), bss.address, bss.zeros),
+            (".bss", Wrapping(0x100), 8)
+        );
+        assert!(bss.data.is_empty());
+
+        let (rom, ram) = load(&image).unwrap();
+        assert_eq!(rom.peek(Wrapping(4)), Wrapping(0x00100073));
+        assert_eq!(ram.peek(Wrapping(0x104)), ZERO);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut bytes = elf(8);
+        bytes[0] = b'e';
+        assert!(matches!(parse_elf(&bytes), Err(LoadError::BadMagic)));
+
+        let mut bytes = elf(8);
+        bytes[4] = 2;
+        assert!(matches!(parse_elf(&bytes), Err(LoadError::NotElf32)));
+
+        let mut bytes = elf(8);
+        bytes[18..20].copy_from_slice(&62u16.to_le_bytes());
+        assert!(matches!(parse_elf(&bytes), Err(LoadError::NotRiscV(62))));
+
+        let mut bytes = elf(8);
+        bytes[36] = EF_RISCV_RVC as u8;
+        assert!(matches!(
+            parse_elf(&bytes),
+            Err(LoadError::CompressedInstructions)
+        ));
+    }
+
+    #[test]
+    fn rejects_broken_section_tables() {
+        let bytes = elf(8);
+        assert!(matches!(
+            parse_elf(&bytes[..bytes.len() - 1]),
+            Err(LoadError::Truncated("section header table"))
+        ));
+
+        for size in [0u16, 39] {
+            let mut bytes = elf(8);
+            bytes[46..48].copy_from_slice(&size.to_le_bytes());
+            assert!(matches!(
+                parse_elf(&bytes),
+                Err(LoadError::BadSectionHeaderSize(s)) if s == size
+            ));
+        }
+    }
+
+    #[test]
+    fn oversized_bss_does_not_fit() {
+        // nearly 4 GiB of .bss is only a length until it is checked against the ram
+        let image = parse_elf(&elf(0xFFFF_FF00)).unwrap();
+        assert!(matches!(
+            load(&image),
+            Err(LoadError::DoesNotFit { name, size: 0xFFFF_FF00, .. }) if name == ".bss"
+        ));
+    }
+}
@@ -1,42 +1,410 @@
-use chips::screen::Screen;
+// chips and instructions keep the names they have in hardware and in the ISA
+#![allow(clippy::upper_case_acronyms)]
 
 use crate::chips::cpu::CPU;
+use crate::chips::input::{InputSource, NoInput};
 use crate::chips::ram::RAM;
 use crate::chips::rom::ROM;
-use crate::chips::{wire, Chip, U32, ZERO};
+use crate::chips::screen::{Display, Headless, Screen};
+use crate::chips::trap::{Exception, Trap};
+use crate::chips::uart::Terminal;
+use crate::chips::{wire, U32, ZERO};
+use crate::cli::{Command, Format, Options, UartBackend};
+use crate::debug::{Stop, Watchpoint};
+use crate::disasm::Symbols;
+use crate::gdb::Outcome;
+use crate::history::{History, Recorded};
+use crate::loader::{Image, LoadError};
+use crate::monitor::Monitor;
+use crate::script::Script;
+use crate::trace::Tracer;
+use crate::vcd::Vcd;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
 use std::num::Wrapping;
-use std::ops::{Deref, DerefMut};
-use std::thread;
+use std::process::exit;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::{env, fs};
 
+mod asm;
 mod chips;
+mod cli;
+mod debug;
+mod disasm;
+mod frame;
+mod gdb;
+mod history;
+mod loader;
+mod monitor;
+mod script;
+mod snapshot;
+mod terminal;
+mod trace;
+mod vcd;
+#[cfg(feature = "window")]
+mod window;
+
+// set by SIGUSR1, the screen is saved before the next cycle
+static SAVE_FRAME: AtomicBool = AtomicBool::new(false);
+
+// set by ctrl-c when the monitor is on, it takes over before the next cycle
+static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+fn monitor_on_sigint() {
+    extern "C" fn handler(_: libc::c_int) {
+        INTERRUPT.store(true, Ordering::Relaxed);
+    }
+    // SAFETY: as for SIGUSR1, the handler only touches an atomic
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handler as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+#[cfg(unix)]
+fn save_frame_on_sigusr1() {
+    extern "C" fn handler(_: libc::c_int) {
+        SAVE_FRAME.store(true, Ordering::Relaxed);
+    }
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        libc::signal(
+            libc::SIGUSR1,
+            handler as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+    }
+}
+
+// The screen window and the key and pointer events that go to it
+#[cfg(feature = "window")]
+fn open_window() -> (Box<dyn Display>, Box<dyn InputSource>) {
+    match window::Window::open() {
+        Ok(window) => {
+            let input = window.input();
+            (Box::new(window), Box::new(input))
+        }
+        Err(err) => {
+            log::warn!("can't open the screen window, running headless: {err}");
+            (Box::new(Headless), Box::new(NoInput))
+        }
+    }
+}
+
+#[cfg(not(feature = "window"))]
+fn open_window() -> (Box<dyn Display>, Box<dyn InputSource>) {
+    log::warn!("built without the window feature, running headless");
+    (Box::new(Headless), Box::new(NoInput))
+}
+
+// The program named on the command line, a bad file ends the emulator
+fn read_image(options: &Options) -> Image {
+    let path = options.program.display();
+    let bytes = fs::read(&options.program).unwrap_or_else(|err| {
+        eprintln!("{path}: {err}");
+        exit(1)
+    });
+    let rom_base = Wrapping(options.map.rom_base);
+    match options.format(&bytes) {
+        Format::Elf => loader::parse_elf(&bytes),
+        Format::Hex => loader::parse_ihex(&String::from_utf8_lossy(&bytes), rom_base),
+        Format::Binary => Ok(loader::parse_binary(&bytes, rom_base)),
+        Format::Asm => {
+            let source = String::from_utf8_lossy(&bytes);
+            let ram_base = Wrapping(options.map.ram_base);
+            asm::assemble(&source, rom_base, ram_base).map_err(LoadError::Assembly)
+        }
+    }
+    .unwrap_or_else(|err| {
+        eprintln!("{path}: {err}");
+        exit(1)
+    })
+}
 
 fn main() {
-    let ram: RAM<U32> = RAM::new(wire(ZERO), wire(ZERO), wire(ZERO), wire(false), 1024 * 1024);
-    let mut rom = ROM::new(wire(ZERO), wire(ZERO), 1024);
-
-    rom.load(
-        [
-            0x00000263, 0x03800513, 0x00D00593, 0x00400893, 0x00000073, 0x00A00493, 0x00100893,
-            0x00000073, 0x00200893, 0x00000073, 0xFE9518E3, 0x00000513, 0x00A00893, 0x00000073,
-            0x00048037, 0x00065037, 0x0006C037, 0x0006C037, 0x0006F037, 0x0002C037, 0x00020037,
-            0x00057037, 0x0006F037, 0x00072037, 0x0006C037, 0x00064037, 0x0000A037,
-        ]
-        .map(|x: u32| Wrapping(x))
-        .into_iter()
-        .collect::<Vec<_>>(),
+    let options = match Command::parse(env::args().skip(1)) {
+        Ok(Command::Run(options)) => *options,
+        Ok(Command::Disasm(options)) => {
+            let image = read_image(&options);
+            if let Err(err) = disasm::listing(&image, &mut io::stdout().lock()) {
+                // a closed pipe, e.g. into head, is not worth a message
+                if err.kind() != io::ErrorKind::BrokenPipe {
+                    eprintln!("error: {err}");
+                    exit(1)
+                }
+            }
+            return;
+        }
+        Ok(Command::Help) => {
+            println!("{}", cli::USAGE);
+            return;
+        }
+        Err(err) => {
+            eprintln!("error: {err}\n\n{}", cli::USAGE);
+            exit(2)
+        }
+    };
+    cli::init_logger(options.log_level);
+
+    let mut ram: RAM<U32> = RAM::new(
+        wire(ZERO),
+        wire(ZERO),
+        wire(ZERO),
+        wire(false),
+        wire(0),
+        options.ram_size / 4,
+    );
+    let mut rom = ROM::new(wire(ZERO), wire(ZERO), options.rom_size / 4);
+    let map = &options.map;
+    rom.set_base(Wrapping(map.rom_base));
+
+    let path = options.program.display();
+    let image = read_image(&options);
+    if let Err(err) = image.load_into(&mut rom, &mut ram, Wrapping(map.ram_base)) {
+        eprintln!("{path}: {err}");
+        exit(1)
+    }
+    log::info!(
+        "loaded {path}: {} rom and {} ram sections, entry {:#010x}",
+        image.rom.len(),
+        image.ram.len(),
+        image.entry.0
     );
 
-    let screen = Screen::new(wire(ZERO), wire(ZERO));
+    let (display, events): (Box<dyn Display>, Box<dyn InputSource>) = match options.headless {
+        true => (Box::new(Headless), Box::new(NoInput)),
+        false => open_window(),
+    };
+    // a script replaces whatever is typed in the window
+    let events: Box<dyn InputSource> = match &options.input {
+        Some(path) => {
+            let script = fs::read_to_string(path)
+                .map_err(|err| err.to_string())
+                .and_then(|text| Script::parse(&text).map_err(|err| err.to_string()))
+                .unwrap_or_else(|err| {
+                    eprintln!("{}: {err}", path.display());
+                    exit(1)
+                });
+            Box::new(script)
+        }
+        None => events,
+    };
+    let mut screen = Screen::new(wire(ZERO), wire(ZERO), wire(0), display);
+    screen.dump = options.frame_dump.clone();
+    #[cfg(unix)]
+    if options.frame_dump.is_some() {
+        save_frame_on_sigusr1();
+    }
+
+    let terminal: Box<dyn Terminal> = match options.uart {
+        UartBackend::Stdio => Box::new(terminal::Stdio::new()),
+        #[cfg(unix)]
+        UartBackend::Pty => match terminal::Pty::open() {
+            Ok(pty) => {
+                eprintln!("uart connected to {}", pty.path);
+                Box::new(pty)
+            }
+            Err(err) => {
+                eprintln!("error: can't open a pseudo-terminal: {err}");
+                exit(1)
+            }
+        },
+        #[cfg(not(unix))]
+        UartBackend::Pty => {
+            eprintln!("error: pseudo-terminals are only supported on unix");
+            exit(1)
+        }
+    };
 
-    let mut cpu = CPU::new(ram, rom, screen);
+    // a recorded run replays the host's input when it goes back over cycles that have run
+    let history = options.history.map(History::new);
+    let (terminal, events): (Box<dyn Terminal>, Box<dyn InputSource>) = match &history {
+        Some(history) => (
+            Box::new(Recorded::new(terminal, history.journal())),
+            Box::new(Recorded::new(events, history.journal())),
+        ),
+        None => (terminal, events),
+    };
 
-    // let mut i = 0;
-    loop {
-        // i += 1;
-        cpu.compute();
-        cpu.clk();
-        // println!("cycle: {i}");
-        // println!("ram[2000] = {}", cpu.execute.ram.peek(Wrapping(2000)));
-        // cpu.memory_access.reg_file.borrow().print();
+    let mut cpu =
+        CPU::new(ram, rom, screen, terminal, events, map, options.core).unwrap_or_else(|err| {
+            eprintln!("error: bad memory map: {err}, move one of them with --map");
+            exit(1)
+        });
+    cpu.set_entry(image.entry);
+    if options.trace {
+        let out: Box<dyn Write> = match &options.trace_file {
+            Some(path) => match File::create(path) {
+                Ok(file) => Box::new(BufWriter::new(file)),
+                Err(err) => {
+                    eprintln!("{}: {err}", path.display());
+                    exit(1)
+                }
+            },
+            None => Box::new(io::stderr()),
+        };
+        let mut tracer = Tracer::new(out);
+        tracer.pcs = options.trace_pcs.clone();
+        tracer.window = options.trace_window.clone();
+        cpu.memory.tracer = Some(tracer);
+    }
+
+    if let Some(path) = &options.vcd {
+        match File::create(path) {
+            Ok(file) => {
+                let out = Box::new(BufWriter::new(file));
+                match Vcd::new(out, &cpu, &options.vcd_signals) {
+                    Ok(vcd) => cpu.vcd = Some(vcd),
+                    Err(err) => {
+                        eprintln!("--vcd-signals: {err}");
+                        exit(1)
+                    }
+                }
+            }
+            Err(err) => {
+                eprintln!("{}: {err}", path.display());
+                exit(1)
+            }
+        }
+    }
+
+    if let Some(path) = &options.restore {
+        if let Err(err) = snapshot::load_file(&mut cpu, path) {
+            eprintln!("{}: {err}", path.display());
+            exit(1)
+        }
+        log::info!(
+            "restored {} after {} instructions",
+            path.display(),
+            cpu.retired()
+        );
+    }
+
+    cpu.history = history;
+    history::restart(&mut cpu);
+
+    let symbols = Symbols::new(image.symbols.clone());
+    for spec in &options.watch {
+        match Watchpoint::parse(spec, &symbols) {
+            Ok(watchpoint) => cpu.memory.watchpoints.push(watchpoint),
+            Err(err) => {
+                eprintln!("error: --watch {spec}: {err}");
+                exit(1)
+            }
+        }
+    }
+
+    let mut session = None;
+    if let Some(endpoint) = &options.gdb {
+        session = match gdb::serve(&mut cpu, endpoint) {
+            Ok(Outcome::Detach) => None,
+            Ok(Outcome::Exit(code)) => Some(code),
+            Ok(Outcome::Kill) => {
+                eprintln!("killed by gdb after {} instructions", cpu.retired());
+                Some(1)
+            }
+            Err(err) => {
+                eprintln!("error: gdb on {endpoint}: {err}");
+                Some(1)
+            }
+        };
+    }
+
+    let mut monitor = None;
+    if options.monitor {
+        #[cfg(unix)]
+        monitor_on_sigint();
+        let mut started = Monitor::new(&mut cpu, &image.symbols, &INTERRUPT);
+        session = started.enter(&mut cpu, Stop::Step);
+        monitor = Some(started);
+    }
+
+    let code = session.unwrap_or_else(|| loop {
+        let stop = match cpu.step() {
+            Ok(()) if cpu.memory.watch_hits.is_empty() => None,
+            Ok(()) => {
+                let hits = std::mem::take(&mut cpu.memory.watch_hits);
+                match monitor.is_some() {
+                    true => Some(Stop::Watch(hits)),
+                    // nothing to stop in, the hits are only reported
+                    false => {
+                        hits.iter().for_each(|hit| log::warn!("{hit}"));
+                        None
+                    }
+                }
+            }
+            Err(Trap {
+                exception: Exception::Breakpoint(pc),
+                ..
+            }) if monitor.is_some() => Some(Stop::Breakpoint(pc)),
+            Err(trap) if monitor.is_some() => {
+                cpu.set_pc(trap.pc);
+                Some(Stop::Trap(trap))
+            }
+            Err(trap) => {
+                eprintln!("error: {trap}");
+                // a snapshot saved at exit then starts on the faulting instruction
+                cpu.set_pc(trap.pc);
+                break 1;
+            }
+        };
+        if let Some(monitor) = monitor.as_mut() {
+            let stop = stop.or_else(|| {
+                INTERRUPT
+                    .swap(false, Ordering::Relaxed)
+                    .then_some(Stop::Interrupt)
+            });
+            if let Some(code) = stop.and_then(|stop| monitor.enter(&mut cpu, stop)) {
+                break code;
+            }
+        }
+
+        if SAVE_FRAME.swap(false, Ordering::Relaxed) {
+            match cpu.screen.borrow().save_numbered() {
+                Some(Ok(path)) => log::info!("saved the screen to {}", path.display()),
+                Some(Err(err)) => eprintln!("error: can't save the screen: {err}"),
+                None => {}
+            }
+        }
+        if let Some(code) = cpu.exit_code() {
+            log::info!(
+                "guest exited with {code} after {} instructions",
+                cpu.retired()
+            );
+            break code;
+        }
+        if options
+            .max_instructions
+            .is_some_and(|max| cpu.retired() >= max)
+        {
+            eprintln!("stopped after {} instructions", cpu.retired());
+            break cli::LIMIT_EXIT_CODE;
+        }
+    });
+
+    if let Some(tracer) = cpu.memory.tracer.as_mut() {
+        tracer.flush();
+    }
+    if let Some(vcd) = cpu.vcd.as_mut() {
+        vcd.flush();
+    }
+    if let Some(path) = &options.snapshot {
+        let saved = match monitor.as_ref() {
+            Some(monitor) => monitor.save(&mut cpu, path),
+            None => snapshot::save_file(&cpu, path),
+        };
+        match saved {
+            Ok(()) => log::info!("saved the machine to {}", path.display()),
+            Err(err) => eprintln!("error: can't save the machine to {}: {err}", path.display()),
+        }
+    }
+    // the final frame
+    if let Some(dump) = &options.frame_dump {
+        if let Err(err) = cpu.screen.borrow().save(&dump.path) {
+            eprintln!("error: can't save the screen: {err}");
+        }
     }
+    exit(code)
 }
@@ -0,0 +1,928 @@
+use crate::chips::cpu::CPU;
+use crate::chips::csr_file::{csr_address, csr_name, ADDRESSES};
+use crate::chips::hazard::{FORWARD_EX_MEM, FORWARD_MEM_WB};
+use crate::chips::probe;
+use crate::chips::trap::Exception;
+use crate::chips::U32;
+use crate::debug::{
+    self, parse_location, parse_number, Breakpoints, Location, Stop, WatchHit, Watchpoint,
+};
+use crate::disasm::{disassemble_word, register_index, Symbols, ABI_NAMES};
+use crate::history;
+use crate::loader::Symbol;
+use crate::terminal;
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::num::Wrapping;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+pub const HELP: &str = "\
+commands, an empty line repeats the last one:
+  s, step [N]              run N instructions (default 1)
+  cycle [N]                run N clock cycles (default 1)
+  c, continue              let the machine run
+  u, until <LOC>           run until the pc reaches LOC
+  b, break [LOC]           set a breakpoint at LOC, or list the breakpoints
+  d, delete <LOC>          clear the breakpoint at LOC
+  watch <SPEC>             stop on writes to a register or memory, see below
+  rwatch <SPEC>            stop on reads
+  awatch <SPEC>            stop on reads and writes
+  watches                  list the watchpoints
+  unwatch <N>              remove watchpoint N
+  r, regs                  dump the integer registers and the pc
+  csrs                     dump the control and status registers
+  x <ADDR> [LEN]           examine LEN bytes of memory at ADDR (default 64)
+  set <REG> <VALUE>        change a register, the pc or a csr
+  set *<ADDR> <VALUE>      change the memory word at ADDR
+  l, disas [LOC] [N]       disassemble N instructions at LOC, or around the pc
+  p, pipeline              show the five stages, the stalls and the forwarding
+  probe [PATH]             show the signals at or under PATH in the chip tree, like
+                           memory.ram.address, or all of them
+  save <FILE>              save the whole machine to FILE
+  load <FILE>              go back to the machine saved in FILE
+  rs, rstep [N]            go back N instructions (default 1)
+  rc, rcontinue            go back to the last breakpoint or watchpoint hit
+  who <SPEC>               find the last write to a register or memory, SPEC as for watch
+  goto <CYCLE>             go to the end of any clock cycle that has run
+  history                  show how far back the machine can go
+  q, quit                  stop the emulator
+  h, help                  this text
+LOC and ADDR are numbers (0x for hex) or symbols, with an optional +OFFSET. A watch SPEC is
+a register name or ADDR[/LEN] watching LEN bytes (default 4), optionally followed by a
+condition on the value like ==0x2a, !=0, <10, <=, > or >=.
+Going back needs --history. Back in time s, c, u and cycle replay what happened, breakpoints
+can't change there, and changing a register or memory drops the history after it";
+
+// what the monitor leaves the prompt for
+enum Resume {
+    // back to the prompt
+    Stay,
+    Run,
+    // the emulator ends with this code
+    Exit(i32),
+}
+
+/**A small interactive debugger reading commands from stdin. It takes over when the machine
+stops, on a breakpoint or EBREAK, a watchpoint, a trap or a ctrl-c, and hands the machine back
+when told to continue*/
+pub struct Monitor {
+    // the code symbols name locations, any symbol can be typed in place of an address
+    symbols: Symbols,
+    names: Symbols,
+    breakpoints: Breakpoints,
+    // raised by ctrl-c
+    interrupt: &'static AtomicBool,
+    // the command an empty line repeats
+    last: String,
+}
+
+impl Monitor {
+    pub fn new(cpu: &mut CPU, symbols: &[Symbol], interrupt: &'static AtomicBool) -> Self {
+        cpu.memory.debug = true;
+        let code = symbols.iter().filter(|symbol| symbol.code).cloned();
+        Self {
+            symbols: Symbols::new(code.collect()),
+            names: Symbols::new(symbols.to_vec()),
+            breakpoints: Breakpoints::default(),
+            interrupt,
+            last: String::new(),
+        }
+    }
+
+    /**Report why the machine stopped and take commands until one lets it run again.
+    Returns the exit code when the emulator should end instead*/
+    pub fn enter(&mut self, cpu: &mut CPU, stop: Stop) -> Option<i32> {
+        self.interrupt.store(false, Ordering::Relaxed);
+        let mut stop = stop;
+        loop {
+            if let Some(code) = self.report(cpu, &stop) {
+                return Some(code);
+            }
+            match self.prompt(cpu) {
+                Ok(Some(next)) => stop = next,
+                Ok(None) => return None,
+                Err(code) => return Some(code),
+            }
+        }
+    }
+
+    // Ok(None) to let the machine run, Ok(Some) when a command stopped it again
+    fn prompt(&mut self, cpu: &mut CPU) -> Result<Option<Stop>, i32> {
+        loop {
+            print!("(monitor) ");
+            let _ = io::stdout().flush();
+            let Some(line) = terminal::read_line() else {
+                println!();
+                return Err(1);
+            };
+            let line = match line.trim() {
+                "" => self.last.clone(),
+                line => line.to_string(),
+            };
+            self.last = line.clone();
+            match self.command(cpu, &line) {
+                Ok((Resume::Stay, Some(stop))) => return Ok(Some(stop)),
+                Ok((Resume::Stay, None)) => {}
+                Ok((Resume::Run, _)) => return Ok(None),
+                Ok((Resume::Exit(code), _)) => return Err(code),
+                Err(err) => println!("{err}"),
+            }
+        }
+    }
+
+    // Says why the machine stopped, returns the exit code if the guest has exited
+    fn report(&mut self, cpu: &mut CPU, stop: &Stop) -> Option<i32> {
+        match stop {
+            Stop::Step => {}
+            Stop::Breakpoint(pc) if self.breakpoints.contains(*pc) => {
+                println!("breakpoint at {}", self.location(*pc));
+            }
+            Stop::Breakpoint(pc) => {
+                // the guest's own ebreak, which is done once the monitor has been entered
+                println!("ebreak at {}", self.location(*pc));
+                cpu.set_pc(*pc + Wrapping(4));
+            }
+            Stop::Watch(hits) => {
+                for hit in hits {
+                    println!("{hit}");
+                }
+            }
+            Stop::Trap(trap) => println!("error: {trap}"),
+            Stop::Exit(code) => {
+                println!("guest exited with {code}");
+                return Some(*code);
+            }
+            Stop::Interrupt => println!("interrupted"),
+        }
+        let pc = cpu.pc();
+        println!("=> {}", self.instruction(cpu, pc));
+        None
+    }
+
+    fn command(&mut self, cpu: &mut CPU, line: &str) -> Result<(Resume, Option<Stop>), String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let count = |i: usize| -> Result<u64, String> {
+            match words.get(i) {
+                Some(n) => parse_number(n)
+                    .map(|n| n as u64)
+                    .ok_or(format!("invalid count '{n}'")),
+                None => Ok(1),
+            }
+        };
+        let stay = Ok((Resume::Stay, None));
+        let Some(&name) = words.first() else {
+            return stay;
+        };
+        match name {
+            "s" | "step" if history::in_past(cpu) => {
+                let here = (cpu.retired(), cpu.pc());
+                let mut left = count(1)?.max(1);
+                let mut seen = vec![];
+                let found = history::forward(cpu, |cpu, hits| {
+                    if (cpu.retired(), cpu.pc()) == here {
+                        return false;
+                    }
+                    left -= 1;
+                    seen = hits.to_vec();
+                    left == 0 || !hits.is_empty()
+                })?;
+                if found.is_none() {
+                    println!("end of the history");
+                }
+                let stop = match seen.is_empty() {
+                    true => Stop::Step,
+                    false => Stop::Watch(seen),
+                };
+                Ok((Resume::Stay, Some(stop)))
+            }
+            "s" | "step" => {
+                let n = count(1)?;
+                let mut stop = self.resume(cpu);
+                for _ in 1..n {
+                    if stop != Stop::Step || self.interrupted() {
+                        break;
+                    }
+                    stop = debug::step(cpu);
+                }
+                Ok((Resume::Stay, Some(stop)))
+            }
+            "cycle" | "cycles" if history::in_past(cpu) => {
+                let frontier = cpu.history.as_ref().map_or(0, |history| history.frontier());
+                history::goto(cpu, (cpu.memory.cycle + count(1)?).min(frontier))?;
+                self.pipeline(cpu);
+                stay
+            }
+            "cycle" | "cycles" => {
+                for _ in 0..count(1)? {
+                    // breakpoints and traps come out of single cycles too
+                    if let Err(trap) = cpu.step() {
+                        let stop = match trap.exception {
+                            Exception::Breakpoint(pc) => Stop::Breakpoint(pc),
+                            _ => {
+                                cpu.set_pc(trap.pc);
+                                Stop::Trap(trap)
+                            }
+                        };
+                        return Ok((Resume::Stay, Some(stop)));
+                    }
+                    if let Some(code) = cpu.exit_code() {
+                        return Ok((Resume::Stay, Some(Stop::Exit(code))));
+                    }
+                    let hits = mem::take(&mut cpu.memory.watch_hits);
+                    if !hits.is_empty() {
+                        return Ok((Resume::Stay, Some(Stop::Watch(hits))));
+                    }
+                }
+                self.pipeline(cpu);
+                stay
+            }
+            "c" | "continue" if history::in_past(cpu) => {
+                let mut seen = vec![];
+                let found = history::forward(cpu, stops(&self.breakpoints, cpu, &mut seen))?;
+                if found.is_some() {
+                    return Ok((Resume::Stay, Some(self.arrival(cpu, seen))));
+                }
+                println!("end of the history, running on");
+                match self.resume(cpu) {
+                    Stop::Step => Ok((Resume::Run, None)),
+                    stop => Ok((Resume::Stay, Some(stop))),
+                }
+            }
+            "c" | "continue" => match self.resume(cpu) {
+                Stop::Step => Ok((Resume::Run, None)),
+                stop => Ok((Resume::Stay, Some(stop))),
+            },
+            "u" | "until" if history::in_past(cpu) => {
+                let target = self.location_arg(&words, 1)?;
+                let here = (cpu.retired(), cpu.pc());
+                let found = history::forward(cpu, |cpu, _| {
+                    (cpu.retired(), cpu.pc()) != here && cpu.pc() == target
+                })?;
+                if found.is_none() {
+                    println!("end of the history");
+                }
+                Ok((Resume::Stay, Some(Stop::Step)))
+            }
+            "u" | "until" => {
+                let target = self.location_arg(&words, 1)?;
+                let mut stop = self.resume(cpu);
+                while stop == Stop::Step && cpu.pc() != target {
+                    if self.interrupted() {
+                        stop = Stop::Interrupt;
+                        break;
+                    }
+                    stop = debug::step(cpu);
+                }
+                Ok((Resume::Stay, Some(stop)))
+            }
+            "b" | "break" | "d" | "delete" if words.len() > 1 && history::in_past(cpu) => Err(
+                "breakpoints can't change back in time, continue to the end of the history first"
+                    .to_string(),
+            ),
+            "b" | "break" if words.len() == 1 => {
+                for addr in self.breakpoints.addresses() {
+                    println!("  {}", self.location(addr));
+                }
+                stay
+            }
+            "b" | "break" => {
+                let addr = self.location_arg(&words, 1)?;
+                match self.breakpoints.insert(cpu, addr) {
+                    true => println!("breakpoint at {}", self.location(addr)),
+                    false => return Err(format!("no instruction at {:#010x}", addr.0)),
+                }
+                stay
+            }
+            "d" | "delete" => {
+                let addr = self.location_arg(&words, 1)?;
+                match self.breakpoints.remove(cpu, addr) {
+                    true => stay,
+                    false => Err(format!("no breakpoint at {:#010x}", addr.0)),
+                }
+            }
+            "watch" | "rwatch" | "awatch" => {
+                // the rest of the line, so a condition may have spaces around its operator
+                let spec = words[1..].concat();
+                if spec.is_empty() {
+                    return Err(format!("{name} needs a register or an address"));
+                }
+                let kind = match name {
+                    "watch" => "w",
+                    "rwatch" => "r",
+                    _ => "a",
+                };
+                let watchpoint = Watchpoint::parse(&format!("{kind}:{spec}"), &self.names)?;
+                cpu.memory.watchpoints.push(watchpoint);
+                self.watches(cpu);
+                stay
+            }
+            "watches" => {
+                self.watches(cpu);
+                stay
+            }
+            "unwatch" => {
+                let n = words.get(1).and_then(|n| n.parse::<usize>().ok());
+                let watchpoints = &mut cpu.memory.watchpoints;
+                match n.filter(|&n| n < watchpoints.len()) {
+                    Some(n) => {
+                        watchpoints.remove(n);
+                        stay
+                    }
+                    None => Err("unwatch needs the number of a watchpoint".to_string()),
+                }
+            }
+            "r" | "regs" => {
+                self.registers(cpu);
+                stay
+            }
+            "csrs" => {
+                for addr in ADDRESSES {
+                    let value = cpu.csr(addr).unwrap_or_default();
+                    let name = csr_name(addr).unwrap_or_default();
+                    println!("  {name:<10} {:08x}", value.0);
+                }
+                stay
+            }
+            "x" => {
+                let addr = self.location_arg(&words, 1)?;
+                let len = match words.get(2) {
+                    Some(len) => parse_number(len).ok_or(format!("invalid length '{len}'"))?,
+                    None => 64,
+                };
+                self.examine(cpu, addr, len);
+                stay
+            }
+            "set" => {
+                let (Some(target), Some(value)) = (words.get(1), words.get(2)) else {
+                    return Err("set needs a register or *ADDR and a value".to_string());
+                };
+                let value =
+                    Wrapping(parse_number(value).ok_or(format!("invalid value '{value}'"))?);
+                let past = history::in_past(cpu);
+                self.set(cpu, target, value)?;
+                if past {
+                    // the rom is as it was then, with the breakpoints of that time
+                    println!("the history after cycle {} is dropped", cpu.memory.cycle);
+                    self.breakpoints.reapply(cpu);
+                }
+                stay
+            }
+            "l" | "disas" => {
+                let pc = cpu.pc();
+                let (start, n) = match words.get(1) {
+                    Some(_) => {
+                        let n = match words.get(2) {
+                            Some(_) => count(2)?,
+                            None => 10,
+                        };
+                        (self.location_arg(&words, 1)?, n)
+                    }
+                    // a few instructions either side of the pc
+                    None => (pc - Wrapping(16), 10),
+                };
+                for i in 0..n as u32 {
+                    let addr = start + Wrapping(4 * i);
+                    let Some(text) = self.instruction_at(cpu, addr) else {
+                        continue;
+                    };
+                    let mark = match (addr == pc, self.breakpoints.contains(addr)) {
+                        (true, _) => "=>",
+                        (false, true) => " *",
+                        (false, false) => "  ",
+                    };
+                    println!("{mark} {text}");
+                }
+                stay
+            }
+            "p" | "pipeline" => {
+                self.pipeline(cpu);
+                stay
+            }
+            "probe" => {
+                let path = words.get(1).copied().unwrap_or_default();
+                let ports = probe::find(cpu, path);
+                if ports.is_empty() {
+                    return Err(format!("no signal '{path}'"));
+                }
+                for (name, port) in ports {
+                    let digits = port.width.div_ceil(4) as usize;
+                    println!("  {name:<36} {:0digits$x}", port.read());
+                }
+                stay
+            }
+            "save" => {
+                let path = words.get(1).ok_or("save needs a file name")?;
+                self.save(cpu, Path::new(path))
+                    .map_err(|err| format!("{path}: {err}"))?;
+                stay
+            }
+            "load" => {
+                let path = words.get(1).ok_or("load needs a file name")?;
+                let bytes = fs::read(path).map_err(|err| format!("{path}: {err}"))?;
+                self.breakpoints
+                    .restore(cpu, &bytes)
+                    .map_err(|err| format!("{path}: {err}"))?;
+                history::restart(cpu);
+                println!(
+                    "restored {path} after {} instructions, cycle {}",
+                    cpu.retired(),
+                    cpu.memory.cycle
+                );
+                Ok((Resume::Stay, Some(Stop::Step)))
+            }
+            "rs" | "rstep" => {
+                let here = (cpu.retired(), cpu.pc());
+                let n = count(1)?.max(1) as usize;
+                let found = history::back(cpu, n, |cpu, _| (cpu.retired(), cpu.pc()) != here)?;
+                if found.is_none() {
+                    println!("start of the history");
+                }
+                Ok((Resume::Stay, Some(Stop::Step)))
+            }
+            "rc" | "rcontinue" => {
+                let mut seen = vec![];
+                let found = history::back(cpu, 1, stops(&self.breakpoints, cpu, &mut seen))?;
+                if found.is_none() {
+                    println!("start of the history");
+                }
+                Ok((Resume::Stay, Some(self.arrival(cpu, seen))))
+            }
+            "who" => {
+                let spec = words[1..].concat();
+                if spec.is_empty() {
+                    return Err("who needs a register or an address".to_string());
+                }
+                self.who(cpu, &spec)?;
+                stay
+            }
+            "goto" => {
+                let cycle = words.get(1).and_then(|n| n.parse::<u64>().ok());
+                history::goto(cpu, cycle.ok_or("goto needs a cycle number")?)?;
+                Ok((Resume::Stay, Some(Stop::Step)))
+            }
+            "history" => {
+                let history = cpu.history.as_ref().ok_or("the run isn't recorded")?;
+                println!(
+                    "  cycles {} to {} in {} checkpoints, now at cycle {}",
+                    history.start(),
+                    history.frontier(),
+                    history.checkpoints(),
+                    cpu.memory.cycle
+                );
+                stay
+            }
+            "q" | "quit" => Ok((Resume::Exit(1), None)),
+            "h" | "help" => {
+                println!("{HELP}");
+                stay
+            }
+            other => Err(format!("unknown command '{other}', try help")),
+        }
+    }
+
+    /**Save the machine as the program has it, the breakpoints left out*/
+    pub fn save(&self, cpu: &mut CPU, path: &Path) -> io::Result<()> {
+        fs::write(path, self.breakpoints.capture(cpu))
+    }
+
+    // The first instruction after a stop, stepping over a breakpoint at the pc if there is one
+    fn resume(&mut self, cpu: &mut CPU) -> Stop {
+        let pc = cpu.pc();
+        if !self.breakpoints.contains(pc) {
+            return debug::step(cpu);
+        }
+        self.breakpoints.remove(cpu, pc);
+        let stop = debug::step(cpu);
+        self.breakpoints.insert(cpu, pc);
+        stop
+    }
+
+    // Look back for the last write that hit `spec`, leaving the machine where it is
+    fn who(&self, cpu: &mut CPU, spec: &str) -> Result<(), String> {
+        let watchpoint = Watchpoint::parse(&format!("w:{spec}"), &self.names)?;
+        let now = cpu.memory.cycle;
+        let watchpoints = mem::replace(&mut cpu.memory.watchpoints, vec![watchpoint]);
+        let mut last = None;
+        let found = history::back(cpu, 1, |_, hits| {
+            if let Some(hit) = hits.last() {
+                last = Some(hit.clone());
+            }
+            !hits.is_empty()
+        });
+        cpu.memory.watchpoints = watchpoints;
+        found?;
+        history::goto(cpu, now)?;
+        let Some(hit) = last else {
+            let start = cpu.history.as_ref().map_or(0, |history| history.start());
+            println!("nothing wrote to {spec} since cycle {start}");
+            return Ok(());
+        };
+        let location = match hit.location {
+            Location::Memory(addr) => format!("{:#010x}", addr.0),
+            Location::Register(n) => ABI_NAMES[n].to_string(),
+        };
+        let old = hit
+            .old
+            .map_or("?".to_string(), |old| format!("{:#010x}", old.0));
+        println!(
+            "{location} was last written by {} in cycle {}: {old} -> {:#010x}",
+            self.location(hit.pc),
+            hit.cycle,
+            hit.new.0
+        );
+        Ok(())
+    }
+
+    // Why a trip through the history stopped where it did, as if the machine had run there
+    fn arrival(&self, cpu: &CPU, hits: Vec<WatchHit>) -> Stop {
+        let pc = cpu.pc();
+        match hits.is_empty() {
+            false => Stop::Watch(hits),
+            true if self.breakpoints.contains(pc) => Stop::Breakpoint(pc),
+            true => Stop::Step,
+        }
+    }
+
+    fn interrupted(&self) -> bool {
+        self.interrupt.swap(false, Ordering::Relaxed)
+    }
+
+    // a number or SYMBOL[+OFFSET] from the command line
+    fn location_arg(&self, words: &[&str], i: usize) -> Result<U32, String> {
+        let word = words.get(i).ok_or("missing address")?;
+        parse_location(word, &self.names).ok_or(format!("unknown address '{word}'"))
+    }
+
+    // `0x1c <loop+0x4>`
+    fn location(&self, addr: U32) -> String {
+        match self.symbols.find(addr) {
+            Some((name, 0)) => format!("{:#010x} <{name}>", addr.0),
+            Some((name, offset)) => format!("{:#010x} <{name}+{offset:#x}>", addr.0),
+            None => format!("{:#010x}", addr.0),
+        }
+    }
+
+    // the instruction at `addr` as the program has it, without the breakpoints
+    fn instruction_at(&self, cpu: &CPU, addr: U32) -> Option<String> {
+        let raw = self.breakpoints.original(addr).or_else(|| cpu.code(addr))?;
+        let text = disassemble_word(raw, addr, &self.symbols);
+        Some(format!("{}:  {:08x}  {text}", self.location(addr), raw.0))
+    }
+
+    fn instruction(&self, cpu: &CPU, addr: U32) -> String {
+        self.instruction_at(cpu, addr)
+            .unwrap_or_else(|| format!("{:#010x}:  outside the rom", addr.0))
+    }
+
+    fn registers(&self, cpu: &CPU) {
+        for row in 0..8 {
+            let line: Vec<String> = (0..4)
+                .map(|column| {
+                    let n = row * 4 + column;
+                    format!("{:<4} {:08x}", ABI_NAMES[n], cpu.register(n).0)
+                })
+                .collect();
+            println!("  {}", line.join("  "));
+        }
+        println!("  pc   {}", self.location(cpu.pc()));
+    }
+
+    fn watches(&self, cpu: &CPU) {
+        for (i, watchpoint) in cpu.memory.watchpoints.iter().enumerate() {
+            println!("  {i}: {watchpoint}");
+        }
+    }
+
+    fn examine(&self, cpu: &CPU, addr: U32, len: u32) {
+        for line in hex_dump(cpu, addr, len) {
+            println!("{line}");
+        }
+    }
+
+    fn set(&mut self, cpu: &mut CPU, target: &str, value: U32) -> Result<(), String> {
+        if let Some(addr) = target.strip_prefix('*') {
+            let addr =
+                parse_location(addr, &self.names).ok_or(format!("unknown address '{addr}'"))?;
+            for (i, byte) in value.0.to_le_bytes().into_iter().enumerate() {
+                cpu.write_memory(addr + Wrapping(i as u32), byte)
+                    .ok_or(format!("can't write to {:#010x}", addr.0))?;
+            }
+            return Ok(());
+        }
+        if target == "pc" {
+            cpu.set_pc(value);
+            return Ok(());
+        }
+        if let Some(n) = register_index(target) {
+            cpu.set_register(n, value);
+            return Ok(());
+        }
+        let addr = csr_address(target).ok_or(format!("unknown register '{target}'"))?;
+        cpu.set_csr(addr, value)
+            .ok_or(format!("can't write {target}"))
+    }
+
+    // Each pipeline register with what it holds between two cycles, oldest stage last. Then
+    // what the hazard and forwarding units make of it for the next cycle. The single-cycle
+    // core holds nothing between cycles
+    fn pipeline(&self, cpu: &CPU) {
+        let Some(pipeline) = cpu.core.pipeline() else {
+            let pc = cpu.pc();
+            println!("  single-cycle core, nothing in flight");
+            return println!("  next {}", self.instruction(cpu, pc));
+        };
+        let fetching = *pipeline.fetch.pc.borrow();
+        println!("  IF   {}", self.location(fetching));
+        let fetched = *pipeline.fetch.output.borrow();
+        let raw = *cpu.rom.borrow().output.borrow();
+        match *pipeline.fetch.valid.borrow() {
+            true => self.stage("ID", fetched, raw),
+            false => println!("  ID   bubble"),
+        }
+        let id_ex = pipeline.decode.output.borrow();
+        match id_ex.valid {
+            true => self.stage("EX", id_ex.instruction.pc, id_ex.instruction.raw),
+            false => println!("  EX   bubble"),
+        }
+        let ex_mem = pipeline.execute.output.borrow();
+        match ex_mem.valid {
+            true => self.stage("MEM", ex_mem.instruction.pc, ex_mem.instruction.raw),
+            false => println!("  MEM  bubble"),
+        }
+        let mem_wb = cpu.memory.output.borrow();
+        match (mem_wb.valid, mem_wb.write) {
+            (true, true) => println!(
+                "  WB   {}:  x{} = {:08x}",
+                self.location(mem_wb.instruction.pc),
+                mem_wb.rd.0,
+                mem_wb.value.0
+            ),
+            (true, false) => println!("  WB   {}:  no write", self.location(mem_wb.instruction.pc)),
+            (false, _) => println!("  WB   bubble"),
+        }
+        if pipeline.hazard.load_use() {
+            println!("  stall: ID needs what EX only has after MEM");
+        }
+        let from = |select| match select {
+            FORWARD_EX_MEM => "EX/MEM",
+            FORWARD_MEM_WB => "MEM/WB",
+            _ => "ID/EX",
+        };
+        let (a, b) = pipeline.forward.selects();
+        println!("  EX operands rs1 from {}, rs2 from {}", from(a), from(b));
+    }
+
+    fn stage(&self, name: &str, pc: U32, raw: U32) {
+        println!(
+            "  {name:<4} {}:  {:08x}  {}",
+            self.location(pc),
+            raw.0,
+            disassemble_word(raw, pc, &self.symbols)
+        );
+    }
+}
+
+// Where continuing stops, in either direction: breakpoints and watchpoint hits, leaving the
+// hits of the latest one found in `seen`. A point the same as the one the machine is at
+// doesn't count, hitting a breakpoint ends a cycle on the pc it started on
+fn stops<'a>(
+    breakpoints: &'a Breakpoints,
+    cpu: &CPU,
+    seen: &'a mut Vec<WatchHit>,
+) -> impl FnMut(&CPU, &[WatchHit]) -> bool + 'a {
+    let here = (cpu.retired(), cpu.pc());
+    move |cpu, hits| {
+        let stop = (cpu.retired(), cpu.pc()) != here
+            && (!hits.is_empty() || breakpoints.contains(cpu.pc()));
+        if stop {
+            *seen = hits.to_vec();
+        }
+        stop
+    }
+}
+
+// Hex and ascii, 16 bytes a line, ?? for the bytes nothing answers for
+fn hex_dump(cpu: &CPU, addr: U32, len: u32) -> Vec<String> {
+    (0..len)
+        .step_by(16)
+        .map(|line| {
+            let start = addr + Wrapping(line);
+            let bytes: Vec<Option<u8>> = (0..16.min(len - line))
+                .map(|i| cpu.read_memory(start + Wrapping(i)))
+                .collect();
+            let hex: Vec<String> = bytes
+                .iter()
+                .map(|byte| match byte {
+                    Some(byte) => format!("{byte:02x}"),
+                    None => "??".to_string(),
+                })
+                .collect();
+            let text: String = bytes
+                .iter()
+                .map(|byte| match byte {
+                    Some(byte) if byte.is_ascii_graphic() || *byte == b' ' => *byte as char,
+                    _ => '.',
+                })
+                .collect();
+            format!("  {:08x}:  {:<47}  |{text}|", start.0, hex.join(" "))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::assemble;
+    use crate::chips::cpu::Model;
+    use crate::chips::testing;
+    use crate::chips::ZERO;
+    use crate::debug::EBREAK;
+    use crate::history::History;
+
+    const RAM: u32 = 0x1_0000;
+
+    // counts in t0 and keeps the count in memory
+    const PROGRAM: &str = "
+        .text
+    _start:
+        li   t0, 0
+    loop:
+        addi t0, t0, 1
+        la   t1, counter
+        sw   t0, 0(t1)
+        j    loop
+        .data
+    counter: .word 0
+    ";
+
+    static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+    fn monitor() -> (Monitor, CPU) {
+        let symbols = assemble(PROGRAM, ZERO, Wrapping(RAM)).unwrap().symbols;
+        let mut cpu = testing::machine(PROGRAM, RAM, 64, Model::default());
+        let monitor = Monitor::new(&mut cpu, &symbols, &INTERRUPT);
+        (monitor, cpu)
+    }
+
+    // what the command stopped on, None when it left the machine alone
+    fn run(monitor: &mut Monitor, cpu: &mut CPU, line: &str) -> Option<Stop> {
+        match monitor.command(cpu, line) {
+            Ok((_, stop)) => stop,
+            Err(err) => panic!("{line}: {err}"),
+        }
+    }
+
+    #[test]
+    fn step_and_until() {
+        let (mut monitor, mut cpu) = monitor();
+        assert_eq!(run(&mut monitor, &mut cpu, "step 3"), Some(Stop::Step));
+        assert_eq!((cpu.retired(), cpu.pc().0), (3, 0x0c));
+        assert_eq!(run(&mut monitor, &mut cpu, "s"), Some(Stop::Step));
+        assert_eq!((cpu.retired(), cpu.pc().0), (4, 0x10));
+        assert_eq!(run(&mut monitor, &mut cpu, "until loop"), Some(Stop::Step));
+        assert_eq!((cpu.retired(), cpu.pc().0), (6, 0x04));
+        assert_eq!(cpu.register(5).0, 1);
+        assert_eq!(run(&mut monitor, &mut cpu, "u loop+0x10"), Some(Stop::Step));
+        assert_eq!((cpu.retired(), cpu.pc().0), (10, 0x14));
+        assert_eq!(cpu.register(5).0, 2);
+        assert!(monitor.command(&mut cpu, "until nowhere").is_err());
+    }
+
+    #[test]
+    fn breakpoints_patch_the_rom() {
+        let (mut monitor, mut cpu) = monitor();
+        let addi = cpu.code(Wrapping(0x04)).unwrap();
+        run(&mut monitor, &mut cpu, "break loop");
+        assert_eq!(cpu.code(Wrapping(0x04)), Some(EBREAK));
+        // the monitor shows the program's instruction, not the ebreak
+        let text = monitor.instruction(&cpu, Wrapping(0x04));
+        assert!(text.ends_with("addi    t0, t0, 1"), "{text}");
+        assert_eq!(
+            run(&mut monitor, &mut cpu, "step 10"),
+            Some(Stop::Breakpoint(Wrapping(0x04)))
+        );
+        assert_eq!(cpu.retired(), 1);
+        // stepping off the breakpoint runs the real instruction
+        run(&mut monitor, &mut cpu, "step");
+        assert_eq!((cpu.pc().0, cpu.register(5).0), (0x08, 1));
+        assert_eq!(cpu.code(Wrapping(0x04)), Some(EBREAK));
+        run(&mut monitor, &mut cpu, "delete 0x4");
+        assert_eq!(cpu.code(Wrapping(0x04)), Some(addi));
+        let error =
+            |monitor: &mut Monitor, cpu: &mut CPU, line| monitor.command(cpu, line).err().unwrap();
+        assert_eq!(
+            error(&mut monitor, &mut cpu, "d loop"),
+            "no breakpoint at 0x00000004"
+        );
+        assert_eq!(
+            error(&mut monitor, &mut cpu, "b 0x6"),
+            "no instruction at 0x00000006"
+        );
+        assert_eq!(
+            error(&mut monitor, &mut cpu, "b 0x8000"),
+            "no instruction at 0x00008000"
+        );
+    }
+
+    #[test]
+    fn set_registers_and_memory() {
+        let (mut monitor, mut cpu) = monitor();
+        run(&mut monitor, &mut cpu, "set t0 0x2a");
+        assert_eq!(cpu.register(5).0, 0x2a);
+        run(&mut monitor, &mut cpu, "set mscratch 7");
+        assert_eq!(cpu.csr(0x340).unwrap().0, 7);
+        run(&mut monitor, &mut cpu, "set *counter 0x12345678");
+        let bytes: Vec<_> = (0..4).map(|i| cpu.read_memory(Wrapping(RAM + i))).collect();
+        assert_eq!(bytes, [0x78, 0x56, 0x34, 0x12].map(Some));
+        // straight to the jump back
+        run(&mut monitor, &mut cpu, "set pc 0x14");
+        assert_eq!(run(&mut monitor, &mut cpu, "step"), Some(Stop::Step));
+        assert_eq!((cpu.retired(), cpu.pc().0), (1, 0x04));
+        assert_eq!(
+            monitor.command(&mut cpu, "set *0x80000000 1").err(),
+            Some("can't write to 0x80000000".to_string())
+        );
+        assert_eq!(
+            monitor.command(&mut cpu, "set x99 1").err(),
+            Some("unknown register 'x99'".to_string())
+        );
+    }
+
+    #[test]
+    fn watchpoints() {
+        let (mut monitor, mut cpu) = monitor();
+        run(&mut monitor, &mut cpu, "watch counter");
+        run(&mut monitor, &mut cpu, "rwatch t0 == 3");
+        let names = Symbols::new(assemble(PROGRAM, ZERO, Wrapping(RAM)).unwrap().symbols);
+        let watch = |spec| Watchpoint::parse(spec, &names).unwrap();
+        assert_eq!(
+            cpu.memory.watchpoints,
+            [watch("w:counter"), watch("r:t0==3")]
+        );
+        run(&mut monitor, &mut cpu, "unwatch 1");
+        assert_eq!(cpu.memory.watchpoints, [watch("w:counter")]);
+        assert!(monitor.command(&mut cpu, "unwatch 1").is_err());
+        assert!(monitor.command(&mut cpu, "watch").is_err());
+        // the store to the counter stops the run right after it
+        let Some(Stop::Watch(hits)) = run(&mut monitor, &mut cpu, "step 100") else {
+            panic!("no watchpoint hit");
+        };
+        assert_eq!(hits.len(), 1);
+        assert_eq!((cpu.retired(), cpu.pc().0), (5, 0x14));
+    }
+
+    #[test]
+    fn examine_marks_what_cant_be_read() {
+        let (mut monitor, mut cpu) = monitor();
+        run(&mut monitor, &mut cpu, "set *counter 0x6b6f");
+        // the ram is 256 bytes, what comes after it answers nothing
+        assert_eq!(
+            hex_dump(&cpu, Wrapping(RAM + 0xf8), 24),
+            [
+                "  000100f8:  00 00 00 00 00 00 00 00 ?? ?? ?? ?? ?? ?? ?? ??  |................|",
+                "  00010108:  ?? ?? ?? ?? ?? ?? ?? ??                          |........|",
+            ]
+        );
+        assert_eq!(
+            hex_dump(&cpu, Wrapping(RAM), 4),
+            ["  00010000:  6f 6b 00 00                                      |ok..|"]
+        );
+        let unknown = ["??"; 16].join(" ");
+        assert_eq!(
+            hex_dump(&cpu, Wrapping(0x8000_0000), 32),
+            [
+                format!("  80000000:  {unknown}  |................|"),
+                format!("  80000010:  {unknown}  |................|"),
+            ]
+        );
+        assert_eq!(run(&mut monitor, &mut cpu, "x 0x80000000 32"), None);
+        assert!(monitor.command(&mut cpu, "x nowhere").is_err());
+    }
+
+    #[test]
+    fn going_back_with_history() {
+        let (mut monitor, mut cpu) = monitor();
+        cpu.history = Some(History::new(16));
+        history::restart(&mut cpu);
+        run(&mut monitor, &mut cpu, "until 0x14");
+        run(&mut monitor, &mut cpu, "u loop");
+        assert_eq!((cpu.retired(), cpu.pc().0), (6, 0x04));
+        run(&mut monitor, &mut cpu, "rs");
+        assert_eq!((cpu.retired(), cpu.pc().0), (5, 0x14));
+        run(&mut monitor, &mut cpu, "rstep 2");
+        assert_eq!((cpu.retired(), cpu.pc().0), (3, 0x0c));
+        assert!(history::in_past(&cpu));
+        assert!(monitor.command(&mut cpu, "break loop").is_err());
+        // back to the last write of t0, the addi before the la
+        run(&mut monitor, &mut cpu, "watch t0");
+        let stop = run(&mut monitor, &mut cpu, "rc");
+        assert!(matches!(stop, Some(Stop::Watch(_))), "{stop:?}");
+        assert_eq!((cpu.retired(), cpu.pc().0, cpu.register(5).0), (2, 0x08, 1));
+        // and forward again, replaying the run
+        run(&mut monitor, &mut cpu, "unwatch 0");
+        run(&mut monitor, &mut cpu, "step 4");
+        assert_eq!((cpu.retired(), cpu.pc().0), (6, 0x04));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::chips::input::{
+    InputEvent, InputSource, BUTTON_LEFT, BUTTON_MIDDLE, BUTTON_RIGHT, KEY_ALT, KEY_BACKSPACE,
+    KEY_CONTROL, KEY_DOWN, KEY_ENTER, KEY_ESCAPE, KEY_LEFT, KEY_RIGHT, KEY_SHIFT, KEY_TAB, KEY_UP,
+};
+use std::collections::VecDeque;
+use std::fmt;
+
+/**A line of an input script that doesn't parse*/
+#[derive(Debug, PartialEq, Eq)]
+pub struct ScriptError {
+    pub line: usize,
+    pub reason: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/**Input events played back at fixed clock cycles, for running interactive programs headless.
+One event per line, `#` starts a comment:
+
+    1000 key a down
+    1200 key a up
+    5000 key left down
+    8000 pointer 120 80 left
+
+Keys are a single character, a name (enter, escape, backspace, tab, left, up, right, down,
+shift, control, alt) or a key code like 0x41. A pointer event gives x, y and the buttons held down*/
+#[derive(Debug, Default)]
+pub struct Script {
+    events: VecDeque<(u64, InputEvent)>,
+}
+
+impl Script {
+    pub fn parse(text: &str) -> Result<Self, ScriptError> {
+        let mut events: Vec<(u64, InputEvent)> = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let bad = |reason: String| ScriptError {
+                line: i + 1,
+                reason,
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let cycle = words[0]
+                .parse::<u64>()
+                .map_err(|_| bad(format!("invalid cycle '{}'", words[0])))?;
+            let event = match &words[1..] {
+                ["key", key, state] => InputEvent::Key {
+                    code: key_code(key).ok_or_else(|| bad(format!("unknown key '{key}'")))?,
+                    pressed: match *state {
+                        "down" => true,
+                        "up" => false,
+                        _ => return Err(bad(format!("expected down or up, got '{state}'"))),
+                    },
+                },
+                ["pointer", x, y, buttons @ ..] => {
+                    let coordinate = |value: &str| {
+                        value
+                            .parse::<u16>()
+                            .map_err(|_| bad(format!("invalid coordinate '{value}'")))
+                    };
+                    let mut held = 0;
+                    for button in buttons {
+                        held |= match *button {
+                            "left" => BUTTON_LEFT,
+                            "middle" => BUTTON_MIDDLE,
+                            "right" => BUTTON_RIGHT,
+                            _ => return Err(bad(format!("unknown button '{button}'"))),
+                        };
+                    }
+                    InputEvent::Pointer {
+                        x: coordinate(x)?,
+                        y: coordinate(y)?,
+                        buttons: held,
+                    }
+                }
+                _ => return Err(bad(format!("can't understand '{line}'"))),
+            };
+            if events.last().is_some_and(|&(last, _)| last > cycle) {
+                return Err(bad("events must be in cycle order".to_string()));
+            }
+            events.push((cycle, event));
+        }
+        Ok(Self {
+            events: events.into(),
+        })
+    }
+}
+
+fn key_code(key: &str) -> Option<u16> {
+    let named = match key {
+        "enter" => KEY_ENTER,
+        "escape" => KEY_ESCAPE,
+        "backspace" => KEY_BACKSPACE,
+        "tab" => KEY_TAB,
+        "space" => b' ' as u16,
+        "left" => KEY_LEFT,
+        "up" => KEY_UP,
+        "right" => KEY_RIGHT,
+        "down" => KEY_DOWN,
+        "shift" => KEY_SHIFT,
+        "control" => KEY_CONTROL,
+        "alt" => KEY_ALT,
+        _ => {
+            let mut chars = key.chars();
+            return match (chars.next(), chars.next()) {
+                (Some(c), None) if c.is_ascii_graphic() => Some(c.to_ascii_lowercase() as u16),
+                _ => match key.strip_prefix("0x") {
+                    Some(hex) => u16::from_str_radix(hex, 16).ok(),
+                    None => key.parse().ok(),
+                },
+            };
+        }
+    };
+    Some(named)
+}
+
+impl InputSource for Script {
+    fn poll(&mut self, cycle: u64) -> Option<InputEvent> {
+        match self.events.front() {
+            Some(&(at, _)) if at <= cycle => self.events.pop_front().map(|(_, event)| event),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: u16, pressed: bool) -> InputEvent {
+        InputEvent::Key { code, pressed }
+    }
+
+    #[test]
+    fn events_come_out_at_their_cycle() {
+        let mut script = Script::parse(
+            "# a comment line\n\
+             \n\
+             10 key a down\n\
+             10 key A up   # same key, same cycle\n\
+             20 key left down\n\
+             30 key 0x41 down\n\
+             40 pointer 120 80 left right\n\
+             50 pointer 0 0\n",
+        )
+        .unwrap();
+        assert_eq!(script.poll(9), None);
+        assert_eq!(script.poll(10), Some(key(b'a' as u16, true)));
+        assert_eq!(script.poll(10), Some(key(b'a' as u16, false)));
+        assert_eq!(script.poll(10), None);
+        // a late poll still gets the events in order, one at a time
+        assert_eq!(script.poll(100), Some(key(KEY_LEFT, true)));
+        assert_eq!(script.poll(100), Some(key(0x41, true)));
+        assert_eq!(
+            script.poll(100),
+            Some(InputEvent::Pointer {
+                x: 120,
+                y: 80,
+                buttons: BUTTON_LEFT | BUTTON_RIGHT,
+            })
+        );
+        assert_eq!(
+            script.poll(100),
+            Some(InputEvent::Pointer {
+                x: 0,
+                y: 0,
+                buttons: 0,
+            })
+        );
+        assert_eq!(script.poll(u64::MAX), None);
+    }
+
+    #[test]
+    fn bad_lines_are_reported_by_number() {
+        let error = |text: &str| Script::parse(text).unwrap_err();
+        assert_eq!(
+            error("1 key a down\nsoon key a up"),
+            ScriptError {
+                line: 2,
+                reason: "invalid cycle 'soon'".to_string(),
+            }
+        );
+        assert_eq!(error("1 key f13 down").reason, "unknown key 'f13'");
+        assert_eq!(
+            error("1 key a pressed").reason,
+            "expected down or up, got 'pressed'"
+        );
+        assert_eq!(error("1 pointer -1 0").reason, "invalid coordinate '-1'");
+        assert_eq!(error("1 pointer 0 0 back").reason, "unknown button 'back'");
+        assert_eq!(error("1 wiggle").reason, "can't understand '1 wiggle'");
+        assert_eq!(
+            error("5 key a down\n\n4 key a up").to_string(),
+            "line 3: events must be in cycle order"
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use crate::chips::cpu::CPU;
+use crate::chips::U32;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::num::Wrapping;
+use std::path::Path;
+
+/**The first bytes of every snapshot file*/
+pub const MAGIC: &[u8; 8] = b"RVSNAP\r\n";
+
+/**Bumped whenever the layout changes, older snapshots are refused rather than misread*/
+pub const VERSION: u32 = 4;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    Version(u32),
+    Truncated,
+    // the next section isn't the one the machine expects
+    Section {
+        expected: &'static str,
+        found: String,
+    },
+    // the snapshot was taken on a machine built differently
+    Mismatch(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use SnapshotError::*;
+        match self {
+            Io(err) => write!(f, "{err}"),
+            BadMagic => write!(f, "not a snapshot file (bad magic)"),
+            Version(v) => write!(
+                f,
+                "snapshot version {v}, this emulator only reads version {VERSION}"
+            ),
+            Truncated => write!(f, "snapshot is truncated"),
+            Section { expected, found } => {
+                write!(
+                    f,
+                    "snapshot is corrupt: expected {expected} state, found '{found}'"
+                )
+            }
+            Mismatch(what) => write!(f, "snapshot doesn't fit this machine: {what}"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+/**Chips whose state goes into a snapshot. Only what survives a clock edge is saved, the
+wires a chip drives in compute are rebuilt by the next cycle anyway*/
+pub trait Snapshot {
+    fn save(&self, out: &mut Writer);
+    /**Read the state back in the order `save` wrote it*/
+    fn restore(&mut self, input: &mut Reader) -> Result<(), SnapshotError>;
+}
+
+/**A plain value in a snapshot, stored little endian*/
+pub trait Value: Sized {
+    fn put(&self, out: &mut Writer);
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError>;
+}
+
+#[derive(Default)]
+pub struct Writer {
+    pub bytes: Vec<u8>,
+}
+
+impl Writer {
+    pub fn put<T: Value>(&mut self, value: &T) {
+        value.put(self);
+    }
+
+    /**A block of bytes with its length, quicker than a Vec<u8> going byte by byte*/
+    pub fn put_bytes(&mut self, bytes: &[u8]) {
+        self.put(&(bytes.len() as u32));
+        self.bytes.extend_from_slice(bytes);
+    }
+
+    /**Name the state that follows, so a reader out of step fails instead of misreading*/
+    pub fn section(&mut self, name: &str) {
+        self.put_bytes(name.as_bytes());
+    }
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn get<T: Value>(&mut self) -> Result<T, SnapshotError> {
+        T::get(self)
+    }
+
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], SnapshotError> {
+        if len > self.bytes.len() {
+            return Err(SnapshotError::Truncated);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn get_bytes(&mut self) -> Result<&'a [u8], SnapshotError> {
+        let len = self.get::<u32>()? as usize;
+        self.take(len)
+    }
+
+    pub fn section(&mut self, expected: &'static str) -> Result<(), SnapshotError> {
+        let found = self.get_bytes()?;
+        match found == expected.as_bytes() {
+            true => Ok(()),
+            false => Err(SnapshotError::Section {
+                expected,
+                found: String::from_utf8_lossy(found).into_owned(),
+            }),
+        }
+    }
+
+    /**Read a vector that must have as many items as the machine's, `what` names it*/
+    pub fn get_exact<T: Value>(&mut self, len: usize, what: &str) -> Result<Vec<T>, SnapshotError> {
+        let items: Vec<T> = self.get()?;
+        match items.len() == len {
+            true => Ok(items),
+            false => Err(SnapshotError::Mismatch(format!(
+                "{what} has {} entries instead of {len}",
+                items.len()
+            ))),
+        }
+    }
+}
+
+macro_rules! value_le {
+    ($($t:ty),*) => {$(
+        impl Value for $t {
+            fn put(&self, out: &mut Writer) {
+                out.bytes.extend_from_slice(&self.to_le_bytes());
+            }
+
+            fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+                let bytes = input.take(std::mem::size_of::<$t>())?;
+                Ok(<$t>::from_le_bytes(bytes.try_into().unwrap()))
+            }
+        }
+    )*};
+}
+
+value_le!(u8, u16, u32, u64, i32);
+
+impl Value for bool {
+    fn put(&self, out: &mut Writer) {
+        out.put(&(*self as u8));
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        Ok(input.get::<u8>()? != 0)
+    }
+}
+
+impl Value for U32 {
+    fn put(&self, out: &mut Writer) {
+        out.put(&self.0);
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        Ok(Wrapping(input.get()?))
+    }
+}
+
+impl Value for usize {
+    fn put(&self, out: &mut Writer) {
+        out.put(&(*self as u64));
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        usize::try_from(input.get::<u64>()?).map_err(|_| SnapshotError::Truncated)
+    }
+}
+
+impl<T: Value> Value for Option<T> {
+    fn put(&self, out: &mut Writer) {
+        out.put(&self.is_some());
+        if let Some(value) = self {
+            out.put(value);
+        }
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        match input.get::<bool>()? {
+            true => Ok(Some(input.get()?)),
+            false => Ok(None),
+        }
+    }
+}
+
+impl<A: Value, B: Value> Value for (A, B) {
+    fn put(&self, out: &mut Writer) {
+        out.put(&self.0);
+        out.put(&self.1);
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        Ok((input.get()?, input.get()?))
+    }
+}
+
+// length first, as a u32
+impl<T: Value> Value for Vec<T> {
+    fn put(&self, out: &mut Writer) {
+        out.put(&(self.len() as u32));
+        self.iter().for_each(|item| out.put(item));
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        let len = input.get::<u32>()? as usize;
+        // every item takes at least a byte, a bad length can't make us allocate wildly
+        if len > input.bytes.len() {
+            return Err(SnapshotError::Truncated);
+        }
+        (0..len).map(|_| input.get()).collect()
+    }
+}
+
+impl<T: Value> Value for VecDeque<T> {
+    fn put(&self, out: &mut Writer) {
+        out.put(&(self.len() as u32));
+        self.iter().for_each(|item| out.put(item));
+    }
+
+    fn get(input: &mut Reader) -> Result<Self, SnapshotError> {
+        Ok(input.get::<Vec<T>>()?.into())
+    }
+}
+
+/**The whole machine as a snapshot*/
+pub fn capture(cpu: &CPU) -> Vec<u8> {
+    let mut out = Writer::default();
+    out.bytes.extend_from_slice(MAGIC);
+    out.put(&VERSION);
+    cpu.save(&mut out);
+    out.bytes
+}
+
+/**Put the machine back in the state of a snapshot. A snapshot that can't be read leaves the
+machine as it was*/
+pub fn restore(cpu: &mut CPU, bytes: &[u8]) -> Result<(), SnapshotError> {
+    let mut input = Reader::new(bytes);
+    if input.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+        return Err(SnapshotError::BadMagic);
+    }
+    match input.get::<u32>()? {
+        VERSION => {}
+        version => return Err(SnapshotError::Version(version)),
+    }
+    // the chips are restored one after the other, a failure half way needs undoing
+    let backup = capture(cpu);
+    cpu.restore(&mut input).inspect_err(|_| {
+        let mut input = Reader::new(&backup[MAGIC.len() + 4..]);
+        cpu.restore(&mut input)
+            .expect("a snapshot of this machine restores");
+    })
+}
+
+pub fn save_file(cpu: &CPU, path: &Path) -> io::Result<()> {
+    fs::write(path, capture(cpu))
+}
+
+pub fn load_file(cpu: &mut CPU, path: &Path) -> Result<(), SnapshotError> {
+    restore(cpu, &fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::cpu::Model;
+    use crate::chips::testing;
+
+    // stores, loads, branches and a trap handler, so every part of the pipeline moves
+    const PROGRAM: &str = "
+        .data
+    counter: .word 0
+        .text
+    _start:
+        la   t0, handler
+        csrw mtvec, t0
+        li   t1, 0
+        la   t2, counter
+    loop:
+        addi t1, t1, 1
+        sw   t1, 0(t2)
+        lw   t3, 0(t2)
+        andi t4, t1, 3
+        bnez t4, loop
+        ecall
+        j    loop
+    handler:
+        csrr t5, mepc
+        addi t5, t5, 4
+        csrw mepc, t5
+        mret
+    ";
+
+    fn machine(ram_words: usize) -> CPU {
+        testing::machine(PROGRAM, 0x1_0000, ram_words, Model::default())
+    }
+
+    fn run(cpu: &mut CPU, cycles: usize) {
+        for _ in 0..cycles {
+            cpu.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn restored_machines_run_on_the_same() {
+        // cut at different points of the pipeline, bubbles included
+        for cut in [0, 1, 2, 3, 17, 40, 41] {
+            let mut cpu = machine(1024);
+            run(&mut cpu, cut);
+            let saved = capture(&cpu);
+            run(&mut cpu, 60);
+            let later = capture(&cpu);
+
+            let mut fresh = machine(1024);
+            restore(&mut fresh, &saved).unwrap();
+            run(&mut fresh, 60);
+            assert!(capture(&fresh) == later, "fresh machine cut at cycle {cut}");
+
+            // going back on the machine that ran on
+            restore(&mut cpu, &saved).unwrap();
+            run(&mut cpu, 60);
+            assert!(capture(&cpu) == later, "same machine cut at cycle {cut}");
+        }
+    }
+
+    #[test]
+    fn bad_snapshots_change_nothing() {
+        let mut cpu = machine(1024);
+        run(&mut cpu, 25);
+        let saved = capture(&cpu);
+        run(&mut cpu, 10);
+        let now = capture(&cpu);
+
+        let truncated = &saved[..saved.len() / 2];
+        assert!(matches!(
+            restore(&mut cpu, truncated),
+            Err(SnapshotError::Truncated)
+        ));
+        let mut version = saved.clone();
+        version[MAGIC.len()] = VERSION as u8 + 1;
+        assert!(matches!(
+            restore(&mut cpu, &version),
+            Err(SnapshotError::Version(v)) if v == VERSION + 1
+        ));
+        assert!(matches!(
+            restore(&mut cpu, b"not a snapshot"),
+            Err(SnapshotError::BadMagic)
+        ));
+        assert!(matches!(
+            restore(&mut machine(512), &saved),
+            Err(SnapshotError::Mismatch(_))
+        ));
+        assert!(capture(&cpu) == now);
+    }
+
+    #[test]
+    fn values() {
+        let mut out = Writer::default();
+        out.section("values");
+        out.put(&0x1234_5678u32);
+        out.put(&Some(-3i32));
+        out.put(&None::<u64>);
+        out.put(&VecDeque::from([1u8, 2, 3]));
+        let mut input = Reader::new(&out.bytes);
+        assert!(input.section("values").is_ok());
+        assert_eq!(input.get::<U32>().unwrap(), Wrapping(0x1234_5678));
+        assert_eq!(input.get::<Option<i32>>().unwrap(), Some(-3));
+        assert_eq!(input.get::<Option<u64>>().unwrap(), None);
+        assert_eq!(input.get::<Vec<u8>>().unwrap(), vec![1, 2, 3]);
+        assert!(matches!(input.get::<u8>(), Err(SnapshotError::Truncated)));
+        assert!(matches!(
+            Reader::new(&out.bytes).section("other"),
+            Err(SnapshotError::Section { .. })
+        ));
+    }
+}
@@ -0,0 +1,139 @@
+use crate::chips::uart::Terminal;
+#[cfg(unix)]
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::thread;
+
+// Read a byte stream on a background thread so the emulator can poll it without blocking
+fn spawn_reader(mut input: impl Read + Send + 'static) -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 1];
+        while let Ok(1) = input.read(&mut buf) {
+            if tx.send(buf[0]).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
+
+// There is a single reader of stdin, the uart and the monitor take turns at it: the monitor
+// only reads while the machine is stopped
+fn stdin() -> MutexGuard<'static, Receiver<u8>> {
+    static STDIN: OnceLock<Mutex<Receiver<u8>>> = OnceLock::new();
+    let stdin = STDIN.get_or_init(|| Mutex::new(spawn_reader(io::stdin())));
+    stdin
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/**A line typed on stdin without its line ending, None once stdin is closed*/
+pub fn read_line() -> Option<String> {
+    let stdin = stdin();
+    let mut line = vec![];
+    loop {
+        match stdin.recv() {
+            Ok(b'\n') => break,
+            Ok(byte) => line.push(byte),
+            Err(_) if line.is_empty() => return None,
+            Err(_) => break,
+        }
+    }
+    let line = String::from_utf8_lossy(&line);
+    Some(line.trim_end_matches('\r').to_string())
+}
+
+/**The uart talks to the emulator's own stdin and stdout*/
+pub struct Stdio;
+
+impl Stdio {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Terminal for Stdio {
+    fn poll(&mut self) -> Option<u8> {
+        stdin().try_recv().ok()
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        stdin().recv().ok()
+    }
+
+    fn write(&mut self, byte: u8) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(&[byte]);
+        let _ = stdout.flush();
+    }
+}
+
+/**The uart is the master side of a new pseudo-terminal, connect to the slave with
+screen, minicom or picocom*/
+#[cfg(unix)]
+pub struct Pty {
+    master: File,
+    rx: Receiver<u8>,
+    pub path: String,
+}
+
+#[cfg(unix)]
+impl Pty {
+    pub fn open() -> io::Result<Self> {
+        use std::ffi::CStr;
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: plain libc calls on a descriptor we own, ptsname is read before any other pty
+        // call
+        unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let master = File::from_raw_fd(fd);
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let name = libc::ptsname(fd);
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            let path = CStr::from_ptr(name).to_string_lossy().into_owned();
+
+            // raw mode, the guest sees every byte as it is typed and does its own echo
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            if libc::tcgetattr(fd, &mut termios) == 0 {
+                libc::cfmakeraw(&mut termios);
+                libc::tcsetattr(fd, libc::TCSANOW, &termios);
+            }
+
+            let rx = spawn_reader(master.try_clone()?);
+            Ok(Self { master, rx, path })
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Terminal for Pty {
+    fn poll(&mut self) -> Option<u8> {
+        self.rx.try_recv().ok()
+    }
+
+    fn read(&mut self) -> Option<u8> {
+        self.rx.recv().ok()
+    }
+
+    fn write(&mut self, byte: u8) {
+        // nobody connected yet is not an error, the byte is just lost
+        let _ = self.master.write_all(&[byte]);
+    }
+}
@@ -0,0 +1,242 @@
+use crate::chips::csr_file::csr_name;
+use crate::chips::decode::Instruction;
+use crate::chips::memory::Width;
+use crate::chips::U32;
+use crate::disasm::disassemble;
+use std::io::{self, Write};
+use std::ops::Range;
+
+/**A memory access made by a retired instruction*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Load(U32),
+    // the value is the stored data, not yet moved to its byte lanes
+    Store(U32, U32, Width),
+}
+
+/**What a retired instruction changed*/
+#[derive(Clone, Debug, Default)]
+pub struct Commit {
+    pub instruction: Instruction,
+    // register index and the value written to it, x0 never shows up
+    pub rd: Option<(U32, U32)>,
+    pub csr: Option<(u16, U32)>,
+    pub memory: Option<Access>,
+}
+
+/**Writes every retired instruction in the format of Spike's `-l --log-commits`, so runs can be
+diffed against the reference simulator. Each instruction takes two lines, the disassembly and
+then the commit with the register, csr and memory it touched:
+
+    core   0: 0x00000008 (0x00a00513) li      a0, 10
+    core   0: 3 0x00000008 (0x00a00513) x10 0x0000000a
+
+Only instructions with their pc in `pcs` and their retire index (counting from 0) in `window`
+are written, traps are logged like Spike does when they hit a traced instruction*/
+pub struct Tracer {
+    out: Box<dyn Write>,
+    pub pcs: Option<Range<u32>>,
+    pub window: Option<Range<u64>>,
+}
+
+impl Tracer {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self {
+            out,
+            pcs: None,
+            window: None,
+        }
+    }
+
+    fn wanted(&self, pc: U32, index: u64) -> bool {
+        self.pcs.as_ref().is_none_or(|pcs| pcs.contains(&pc.0))
+            && self
+                .window
+                .as_ref()
+                .is_none_or(|window| window.contains(&index))
+    }
+
+    /**Log instruction number `index`, the count of instructions retired before it*/
+    pub fn commit(&mut self, index: u64, commit: &Commit) {
+        let instruction = &commit.instruction;
+        if !self.wanted(instruction.pc, index) {
+            return;
+        }
+        let (pc, raw) = (instruction.pc.0, instruction.raw.0);
+        let mut line = format!("core   0: 3 0x{pc:08x} (0x{raw:08x})");
+        if let Some((rd, value)) = commit.rd {
+            line += &format!(" x{:<2} 0x{:08x}", rd.0, value.0);
+        }
+        if let Some((addr, value)) = commit.csr {
+            let name = csr_name(addr).unwrap_or("unknown");
+            line += &format!(" c{addr}_{name} 0x{:08x}", value.0);
+        }
+        match commit.memory {
+            Some(Access::Load(addr)) => line += &format!(" mem 0x{:08x}", addr.0),
+            Some(Access::Store(addr, value, width)) => {
+                let digits = 2 * width.bytes() as usize;
+                let mask = (1u64 << (8 * width.bytes())) - 1;
+                let value = value.0 as u64 & mask;
+                line += &format!(" mem 0x{:08x} 0x{value:0digits$x}", addr.0);
+            }
+            None => {}
+        }
+        let result = writeln!(
+            self.out,
+            "core   0: 0x{pc:08x} (0x{raw:08x}) {}\n{line}",
+            disassemble(instruction)
+        );
+        self.check(result);
+    }
+
+    /**Log a trap taken instead of retiring the instruction at `pc`, `index` being the number
+    of instructions retired so far. `cause` is the mcause value*/
+    pub fn trap(&mut self, index: u64, pc: U32, cause: U32, tval: U32) {
+        if !self.wanted(pc, index) {
+            return;
+        }
+        let name = match cause.0 >> 31 {
+            1 => format!("interrupt #{}", cause.0 & !(1 << 31)),
+            _ => exception_name(cause.0).to_string(),
+        };
+        let result = writeln!(
+            self.out,
+            "core   0: exception {name}, epc 0x{:08x}\ncore   0:           tval 0x{:08x}",
+            pc.0, tval.0
+        );
+        self.check(result);
+    }
+
+    pub fn flush(&mut self) {
+        let result = self.out.flush();
+        self.check(result);
+    }
+
+    // a trace that can't be written stops, the machine keeps running
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(err) = result {
+            log::error!("can't write the trace, stopping it: {err}");
+            self.out = Box::new(io::sink());
+        }
+    }
+}
+
+// the names Spike gives the exception causes
+fn exception_name(cause: u32) -> &'static str {
+    match cause {
+        0 => "trap_instruction_address_misaligned",
+        1 => "trap_instruction_access_fault",
+        2 => "trap_illegal_instruction",
+        3 => "trap_breakpoint",
+        4 => "trap_load_address_misaligned",
+        5 => "trap_load_access_fault",
+        6 => "trap_store_address_misaligned",
+        7 => "trap_store_access_fault",
+        11 => "trap_machine_ecall",
+        _ => "trap_unknown",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::cpu::Model;
+    use crate::chips::testing;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    const PROGRAM: &str = "
+        addi a0, zero, 10
+        lui  t0, 0x10
+        sw   a0, 4(t0)
+        lbu  a1, 6(t0)
+        sh   a0, 8(t0)
+        addi zero, a0, 1
+        ebreak
+    ";
+
+    // The trace the tracer writes, kept where the test can read it back
+    #[derive(Clone, Default)]
+    struct Log(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Log {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    // the program's trace up to its ebreak with the filters of `setup`
+    fn trace(setup: impl FnOnce(&mut Tracer)) -> Vec<String> {
+        let log = Log::default();
+        let mut tracer = Tracer::new(Box::new(log.clone()));
+        setup(&mut tracer);
+        let mut cpu = testing::machine(PROGRAM, 0x1_0000, 64, Model::default());
+        cpu.memory.tracer = Some(tracer);
+        for _ in 0..100 {
+            if cpu.step().is_err() {
+                break;
+            }
+        }
+        let text = String::from_utf8(log.0.take()).unwrap();
+        text.lines().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn writebacks_and_memory_accesses() {
+        assert_eq!(
+            trace(|_| {}),
+            [
+                "core   0: 0x00000000 (0x00a00513) li      a0, 10",
+                "core   0: 3 0x00000000 (0x00a00513) x10 0x0000000a",
+                "core   0: 0x00000004 (0x000102b7) lui     t0, 0x10",
+                "core   0: 3 0x00000004 (0x000102b7) x5  0x00010000",
+                "core   0: 0x00000008 (0x00a2a223) sw      a0, 4(t0)",
+                "core   0: 3 0x00000008 (0x00a2a223) mem 0x00010004 0x0000000a",
+                "core   0: 0x0000000c (0x0062c583) lbu     a1, 6(t0)",
+                "core   0: 3 0x0000000c (0x0062c583) x11 0x00000000 mem 0x00010006",
+                "core   0: 0x00000010 (0x00a29423) sh      a0, 8(t0)",
+                "core   0: 3 0x00000010 (0x00a29423) mem 0x00010008 0x000a",
+                // a write to x0 has no register
+                "core   0: 0x00000014 (0x00150013) addi    zero, a0, 1",
+                "core   0: 3 0x00000014 (0x00150013)",
+                "core   0: exception trap_breakpoint, epc 0x00000018",
+                "core   0:           tval 0x00000018",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_pcs_in_range() {
+        let lines = trace(|tracer| tracer.pcs = Some(0x8..0x10));
+        assert_eq!(
+            lines,
+            [
+                "core   0: 0x00000008 (0x00a2a223) sw      a0, 4(t0)",
+                "core   0: 3 0x00000008 (0x00a2a223) mem 0x00010004 0x0000000a",
+                "core   0: 0x0000000c (0x0062c583) lbu     a1, 6(t0)",
+                "core   0: 3 0x0000000c (0x0062c583) x11 0x00000000 mem 0x00010006",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_counts_in_the_window() {
+        // the ebreak is the seventh instruction, its trap counts as number 6
+        let lines = trace(|tracer| tracer.window = Some(5..7));
+        assert_eq!(
+            lines,
+            [
+                "core   0: 0x00000014 (0x00150013) addi    zero, a0, 1",
+                "core   0: 3 0x00000014 (0x00150013)",
+                "core   0: exception trap_breakpoint, epc 0x00000018",
+                "core   0:           tval 0x00000018",
+            ]
+        );
+        assert_eq!(trace(|tracer| tracer.window = Some(1..2)).len(), 2);
+        assert!(trace(|tracer| tracer.window = Some(7..100)).is_empty());
+    }
+}
@@ -0,0 +1,239 @@
+use crate::chips::cpu::CPU;
+use crate::chips::probe::{self, Port};
+use std::io::{self, Write};
+
+/**The sets of signals that can be dumped by name instead of listing their paths*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Group {
+    // the program counter and the address fetch has on the rom output
+    Pc,
+    // the pipeline registers between the stages and the hazard unit's stall and flush, or the
+    // single-cycle core's instruction and next pc
+    Pipeline,
+    Registers,
+    // the ram's interface, set by the loads and stores of the cycle
+    Memory,
+}
+
+impl Group {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "pc" => Some(Group::Pc),
+            "pipeline" => Some(Group::Pipeline),
+            "regs" | "registers" => Some(Group::Registers),
+            "memory" => Some(Group::Memory),
+            _ => None,
+        }
+    }
+
+    /**Where the signals of the group are in the chip tree, the core has only some of them*/
+    pub fn paths(self) -> Vec<String> {
+        match self {
+            Group::Pc => vec!["pc.output".into(), "fetch.output".into()],
+            Group::Pipeline => [
+                "fetch.valid",
+                "decode.output",
+                "execute.output",
+                "memory.output",
+                "hazard",
+                "instruction",
+                "next_pc",
+            ]
+            .map(String::from)
+            .to_vec(),
+            Group::Registers => (1..32).map(|n| format!("reg_file.x{n}.output")).collect(),
+            Group::Memory => vec!["memory.ram".into()],
+        }
+    }
+}
+
+// VCD names signals with short codes of printable characters
+fn code(mut index: usize) -> String {
+    let mut code = String::new();
+    loop {
+        code.push((b'!' + (index % 94) as u8) as char);
+        index /= 94;
+        if index == 0 {
+            return code;
+        }
+        index -= 1;
+    }
+}
+
+/**Dumps signals of the machine to a Value Change Dump file for waveform viewers like
+GTKWave. A cycle is sampled once its logic has settled, before the clock edge, and lasts two
+time units with `clk` high for the first. Only the values that changed are written*/
+pub struct Vcd {
+    out: Box<dyn Write>,
+    signals: Vec<(String, Port)>,
+    last: Vec<Option<u64>>,
+}
+
+impl Vcd {
+    /**Dump the signals of `cpu` named by `names`, groups or paths in the chip tree like
+    `memory.ram.address`. A path that names a chip dumps all of its ports*/
+    pub fn new(out: Box<dyn Write>, cpu: &CPU, names: &[String]) -> Result<Self, String> {
+        let paths: Vec<String> = names
+            .iter()
+            .flat_map(|name| match Group::parse(name) {
+                Some(group) => group
+                    .paths()
+                    .into_iter()
+                    .filter(|path| !probe::find(cpu, path).is_empty())
+                    .collect(),
+                None => vec![name.clone()],
+            })
+            .collect();
+        let paths: Vec<&str> = paths.iter().map(String::as_str).collect();
+        let signals = probe::select(cpu, &paths)?;
+        let mut vcd = Self {
+            out,
+            last: vec![None; signals.len()],
+            signals,
+        };
+        let result = vcd.header();
+        vcd.check(result);
+        Ok(vcd)
+    }
+
+    fn header(&mut self) -> io::Result<()> {
+        writeln!(self.out, "$version riscv_emulator $end")?;
+        writeln!(self.out, "$timescale 1ns $end")?;
+        writeln!(self.out, "$scope module cpu $end")?;
+        // clk takes the first code, the signals follow in their order
+        writeln!(self.out, "$var wire 1 {} clk $end", code(0))?;
+        // the signals come in the order of the tree, so every chip is one scope
+        let mut open = vec!["cpu"];
+        for (i, (path, port)) in self.signals.iter().enumerate() {
+            let mut scopes: Vec<&str> = path.split('.').collect();
+            let name = scopes.pop().unwrap_or_default();
+            let common = open
+                .iter()
+                .zip(&scopes)
+                .take_while(|(open, scope)| open == scope)
+                .count();
+            for _ in common..open.len() {
+                writeln!(self.out, "$upscope $end")?;
+            }
+            open.truncate(common);
+            for scope in &scopes[common..] {
+                writeln!(self.out, "$scope module {scope} $end")?;
+                open.push(scope);
+            }
+            let kind = match port.width {
+                1 => "wire",
+                _ => "reg",
+            };
+            let width = port.width;
+            writeln!(self.out, "$var {kind} {width} {} {name} $end", code(i + 1))?;
+        }
+        for _ in open {
+            writeln!(self.out, "$upscope $end")?;
+        }
+        writeln!(self.out, "$enddefinitions $end")
+    }
+
+    /**Sample the cycle `cpu` is in, between its compute and its clock edge*/
+    pub fn sample(&mut self, cpu: &CPU) {
+        let result = self.dump(cpu);
+        self.check(result);
+    }
+
+    fn dump(&mut self, cpu: &CPU) -> io::Result<()> {
+        let time = 2 * cpu.memory.cycle;
+        writeln!(self.out, "#{time}\n1{}", code(0))?;
+        for (i, (_, port)) in self.signals.iter().enumerate() {
+            let value = port.read();
+            if self.last[i] == Some(value) {
+                continue;
+            }
+            self.last[i] = Some(value);
+            match port.width {
+                1 => writeln!(self.out, "{value}{}", code(i + 1))?,
+                _ => writeln!(self.out, "b{value:b} {}", code(i + 1))?,
+            }
+        }
+        writeln!(self.out, "#{}\n0{}", time + 1, code(0))
+    }
+
+    pub fn flush(&mut self) {
+        let result = self.out.flush();
+        self.check(result);
+    }
+
+    // like the trace, a dump that can't be written stops without stopping the machine
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(err) = result {
+            log::error!("can't write the waveform, stopping it: {err}");
+            self.out = Box::new(io::sink());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chips::cpu::Model;
+    use crate::chips::testing;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // The dump as the vcd writes it, shared with the test
+    #[derive(Clone, Default)]
+    struct Dump(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Dump {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn pc_and_register_changes() {
+        let program = "addi a0, zero, 5\naddi a0, a0, 1\nnop\nebreak";
+        let mut cpu = testing::machine(program, 0x1_0000, 16, Model::SingleCycle);
+        let dump = Dump::default();
+        // the pc group has no fetch stage in the single-cycle core, so only the pc is left
+        let names = ["pc".to_string(), "reg_file.x10".to_string()];
+        cpu.vcd = Some(Vcd::new(Box::new(dump.clone()), &cpu, &names).unwrap());
+        for _ in 0..3 {
+            cpu.step().unwrap();
+        }
+        let text = String::from_utf8(dump.0.take()).unwrap();
+        let (header, changes) = text.split_once("$enddefinitions $end\n").unwrap();
+        let vars: Vec<&str> = header
+            .lines()
+            .filter(|line| line.starts_with("$var"))
+            .collect();
+        assert_eq!(
+            vars,
+            [
+                "$var wire 1 ! clk $end",
+                "$var reg 32 \" output $end",
+                "$var reg 32 # input $end",
+                "$var reg 32 $ output $end",
+                "$var wire 1 % load $end",
+            ]
+        );
+        assert!(header.contains("$scope module reg_file $end\n$scope module x10 $end\n"));
+        // every cycle takes two time units, only what changed is written
+        let expected = "\
+            #2\n1!\nb0 \"\nb101 #\nb0 $\n1%\n#3\n0!\n\
+            #4\n1!\nb100 \"\nb110 #\nb101 $\n#5\n0!\n\
+            #6\n1!\nb1000 \"\nb110 $\n0%\n#7\n0!\n";
+        assert_eq!(changes, expected);
+    }
+
+    #[test]
+    fn codes() {
+        assert_eq!(code(0), "!");
+        assert_eq!(code(93), "~");
+        assert_eq!(code(94), "!!");
+        assert_eq!(code(95), "\"!");
+        assert_ne!(code(94 + 94 * 94), code(94));
+    }
+}
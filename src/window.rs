@@ -0,0 +1,179 @@
+use crate::chips::input::{
+    InputEvent, InputSource, BUTTON_LEFT, BUTTON_MIDDLE, BUTTON_RIGHT, KEY_ALT, KEY_BACKSPACE,
+    KEY_CONTROL, KEY_DOWN, KEY_ENTER, KEY_ESCAPE, KEY_LEFT, KEY_RIGHT, KEY_SHIFT, KEY_TAB, KEY_UP,
+};
+use crate::chips::screen::{Display, HEIGHT, WIDTH};
+use fltk::{
+    app::{self, App},
+    enums::{Event, EventState, Key},
+    prelude::*,
+    window::Window as FltkWindow,
+};
+use pixels::{Pixels, SurfaceTexture};
+use std::collections::VecDeque;
+use std::{cell::RefCell, rc::Rc};
+
+// Cycles between two rounds of fltk event handling, ten rounds a frame keep the window
+// and the keys responsive without slowing the emulation down
+const EVENT_CYCLES: u64 = 10_000;
+
+/**Shows the frames in an fltk window through a pixels surface*/
+pub struct Window {
+    app: App,
+    pixels: Pixels,
+    // new surface size in physical pixels after the window was resized
+    surface_size: Rc<RefCell<Option<(u32, u32)>>>,
+    // key and pointer events waiting for the input device
+    events: Rc<RefCell<VecDeque<InputEvent>>>,
+}
+
+impl Window {
+    pub fn open() -> Result<Self, pixels::Error> {
+        let app = app::App::default();
+        let mut win = FltkWindow::default()
+            .with_size(WIDTH as i32, HEIGHT as i32)
+            .with_label("Hello Pixels");
+        win.make_resizable(true);
+        win.end();
+        win.show();
+
+        // Handle resize events
+        let surface_size = Rc::new(RefCell::new(None));
+        let surface_resize = surface_size.clone();
+        win.resize_callback(move |win, _x, _y, width, height| {
+            let scale_factor = win.pixels_per_unit();
+            let width = (width as f32 * scale_factor) as u32;
+            let height = (height as f32 * scale_factor) as u32;
+
+            surface_resize.borrow_mut().replace((width, height));
+        });
+
+        // Handle keyboard and mouse events
+        let events = Rc::new(RefCell::new(VecDeque::new()));
+        let queue = events.clone();
+        win.handle(move |win, event| {
+            let input = match event {
+                Event::KeyDown | Event::KeyUp => match key_code(app::event_key()) {
+                    Some(code) => InputEvent::Key {
+                        code,
+                        pressed: event == Event::KeyDown,
+                    },
+                    None => return false,
+                },
+                Event::Move | Event::Drag | Event::Push | Event::Released => {
+                    // the frame is stretched over the whole window
+                    let (x, y) = app::event_coords();
+                    let scale = |v: i32, size: i32, frame: u32| {
+                        (v.max(0) as u32 * frame / size.max(1) as u32).min(frame - 1) as u16
+                    };
+                    let state = app::event_state();
+                    let held = |button, bit| match state.contains(button) {
+                        true => bit,
+                        false => 0,
+                    };
+                    InputEvent::Pointer {
+                        x: scale(x, win.w(), WIDTH),
+                        y: scale(y, win.h(), HEIGHT),
+                        buttons: held(EventState::Button1, BUTTON_LEFT)
+                            | held(EventState::Button2, BUTTON_MIDDLE)
+                            | held(EventState::Button3, BUTTON_RIGHT),
+                    }
+                }
+                // take the focus so the key events come to the window
+                Event::Focus | Event::Unfocus => return true,
+                _ => return false,
+            };
+            queue.borrow_mut().push_back(input);
+            true
+        });
+
+        let pixels = {
+            let pixel_width = win.pixel_w() as u32;
+            let pixel_height = win.pixel_h() as u32;
+            let surface_texture = SurfaceTexture::new(pixel_width, pixel_height, &win);
+
+            Pixels::new(WIDTH, HEIGHT, surface_texture)?
+        };
+
+        Ok(Window {
+            app,
+            pixels,
+            surface_size,
+            events,
+        })
+    }
+
+    /**The key and pointer events of the window, for the input device*/
+    pub fn input(&self) -> WindowInput {
+        WindowInput {
+            events: self.events.clone(),
+            pumped: 0,
+        }
+    }
+}
+
+/**Events are handed over as soon as fltk delivers them. The input device polls every cycle,
+so fltk gets to run every EVENT_CYCLES cycles and not just when a frame is presented*/
+pub struct WindowInput {
+    events: Rc<RefCell<VecDeque<InputEvent>>>,
+    // the cycle fltk last handled its events at
+    pumped: u64,
+}
+
+impl InputSource for WindowInput {
+    fn poll(&mut self, cycle: u64) -> Option<InputEvent> {
+        if cycle >= self.pumped + EVENT_CYCLES {
+            self.pumped = cycle;
+            // a failure shows up again at the next present, which handles it
+            let _ = app::wait_for(0.0);
+        }
+        self.events.borrow_mut().pop_front()
+    }
+}
+
+fn key_code(key: Key) -> Option<u16> {
+    let code = match key {
+        Key::Enter | Key::KPEnter => KEY_ENTER,
+        Key::Escape => KEY_ESCAPE,
+        Key::BackSpace => KEY_BACKSPACE,
+        Key::Tab => KEY_TAB,
+        Key::Left => KEY_LEFT,
+        Key::Up => KEY_UP,
+        Key::Right => KEY_RIGHT,
+        Key::Down => KEY_DOWN,
+        Key::ShiftL | Key::ShiftR => KEY_SHIFT,
+        Key::ControlL | Key::ControlR => KEY_CONTROL,
+        Key::AltL | Key::AltR => KEY_ALT,
+        // fltk reports printable keys by their lower case character
+        key if (0x20..0x7F).contains(&key.bits()) => key.bits() as u16,
+        _ => return None,
+    };
+    Some(code)
+}
+
+impl Display for Window {
+    fn present(&mut self, frame: &[u8]) {
+        // handle pending events without blocking the emulation
+        if app::wait_for(0.0).is_err() {
+            return;
+        }
+
+        // Resize the window
+        if let Some((width, height)) = self.surface_size.borrow_mut().take() {
+            if let Err(err) = self.pixels.resize_surface(width, height) {
+                log::error!("pixels.resize_surface {}", err);
+                self.app.quit();
+            }
+        }
+
+        // Draw the current frame
+        self.pixels.frame_mut().copy_from_slice(frame);
+        if let Err(err) = self.pixels.render() {
+            log::error!("pixels.render {}", err);
+            self.app.quit();
+        }
+
+        app::flush();
+        app::awake();
+    }
+}